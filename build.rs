@@ -0,0 +1,21 @@
+//! With the `embedded-spec` feature, copy the spec file named by `REGEX_MAP_EMBEDDED_SPEC`
+//! into the build output for `init` to fall back on
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+const SPEC_PATH_VAR: &str = "REGEX_MAP_EMBEDDED_SPEC";
+
+fn main() {
+    println!("cargo:rerun-if-env-changed={SPEC_PATH_VAR}");
+    if env::var_os("CARGO_FEATURE_EMBEDDED_SPEC").is_none() {
+        return;
+    }
+    let path = env::var(SPEC_PATH_VAR)
+        .unwrap_or_else(|_| panic!("the `embedded-spec` feature needs `{SPEC_PATH_VAR}` set to the path of a spec file"));
+    println!("cargo:rerun-if-changed={path}");
+    let spec = fs::read_to_string(&path).unwrap_or_else(|err| panic!("cannot read embedded spec `{path}`: {err}"));
+    let out_dir = env::var("OUT_DIR").expect("cargo sets OUT_DIR for build scripts");
+    fs::write(Path::new(&out_dir).join("embedded_spec"), spec).expect("cannot write embedded spec");
+}
@@ -0,0 +1,34 @@
+//! Arbitrary records through representative specs, run with `cargo +nightly fuzz run pipeline`.
+//! Records must not panic the operations, UTF-8 text must stay UTF-8 and JSON must stay JSON.
+
+#![no_main]
+
+use std::sync::LazyLock;
+
+use libfuzzer_sys::fuzz_target;
+use regex_map::Pipeline;
+
+const SPEC: &str = r#"[
+    {"normalize": {"presets": ["strip_control_chars", "collapse_whitespace", "trim"]}},
+    {"mask": {"pattern": "ssn"}},
+    {"mask": {"pattern": "email"}},
+    {"mask_credit_card": {}},
+    {"anonymize_ip": {}},
+    {"url": {"strip": ["token"], "redact_userinfo": true}},
+    {"replace": {"regex": "(?P<first>[A-Z])[a-z]+ (?P<last>[A-Z])[a-z]+", "with": "${first|upper}. ${last}."}},
+    {"hash": {"regex": "secret-\\w+"}}
+]"#;
+
+static TEXT: LazyLock<Pipeline> = LazyLock::new(|| Pipeline::from_params([("spec", SPEC), ("format", "text")]).unwrap());
+static JSON: LazyLock<Pipeline> = LazyLock::new(|| Pipeline::from_params([("spec", SPEC), ("format", "json")]).unwrap());
+
+fuzz_target!(|record: &[u8]| {
+    let output = TEXT.apply(record).expect("text records do not fail");
+    if std::str::from_utf8(record).is_ok() {
+        assert!(std::str::from_utf8(&output).is_ok(), "{record:?} became {output:?}");
+    }
+    if serde_json::from_slice::<serde_json::Value>(record).is_ok() {
+        let output = JSON.apply(record).expect("JSON records do not fail");
+        assert!(serde_json::from_slice::<serde_json::Value>(&output).is_ok(), "{record:?} became {output:?}");
+    }
+});
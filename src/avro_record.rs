@@ -0,0 +1,74 @@
+use apache_avro::types::Value as AvroValue;
+use apache_avro::reader::datum::GenericDatumReader;
+use apache_avro::writer::datum::GenericDatumWriter;
+use apache_avro::Schema;
+use serde_json::Value;
+
+use fluvio_smartmodule::{Result, eyre};
+
+/// Schema of Avro records, single datums without container file or registry framing,
+/// which operations rewrite through their JSON mapping
+#[derive(Debug)]
+pub struct AvroCodec {
+    schema: Schema,
+}
+
+impl AvroCodec {
+    /// `schema` is the Avro schema in its JSON form
+    pub fn new(schema: &str) -> Result<Self> {
+        let schema = Schema::parse_str(schema).map_err(|err| eyre!("invalid Avro schema: {err}"))?;
+        Ok(AvroCodec { schema })
+    }
+
+    /// JSON text of an Avro record, unions map to their value and bytes to arrays of numbers
+    pub fn decode(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let reader = GenericDatumReader::builder(&self.schema).build()?;
+        let value = reader.read_value(&mut &data[..])
+            .map_err(|err| eyre!("cannot decode Avro record: {err}"))?;
+        let json = Value::try_from(value).map_err(|err| eyre!("cannot map Avro record to JSON: {err}"))?;
+        Ok(serde_json::to_vec(&json)?)
+    }
+
+    /// Avro record from the JSON text of a decoded one
+    pub fn encode(&self, json: &[u8]) -> Result<Vec<u8>> {
+        let json: Value = serde_json::from_slice(json)?;
+        let value = AvroValue::try_from(json)
+            .and_then(|value| value.resolve(&self.schema))
+            .map_err(|err| eyre!("cannot encode Avro record: {err}"))?;
+        let writer = GenericDatumWriter::builder(&self.schema).build()?;
+        writer.write_value_to_vec(value).map_err(|err| eyre!("cannot encode Avro record: {err}"))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// `school.Student` with a string name, an optional ssn, a long id and email contacts
+    pub const STUDENT_SCHEMA: &str = r#"{
+        "type": "record", "name": "Student", "namespace": "school",
+        "fields": [
+            {"name": "name", "type": "string"},
+            {"name": "ssn", "type": ["null", "string"], "default": null},
+            {"name": "id", "type": "long"},
+            {"name": "contacts", "type": {"type": "array", "items": {
+                "type": "record", "name": "Contact", "fields": [{"name": "email", "type": "string"}]
+            }}}
+        ]
+    }"#;
+
+    #[test]
+    fn avro_codec_test() {
+        let codec = AvroCodec::new(STUDENT_SCHEMA).unwrap();
+        let json = br#"{"contacts":[{"email":"abby@school.edu"}],"id":42,"name":"Abby","ssn":"123-45-6789"}"#;
+        let record = codec.encode(json).unwrap();
+        assert_eq!(codec.decode(&record).unwrap(), json);
+
+        let anonymous = codec.encode(br#"{"contacts":[],"id":7,"name":"Cindy","ssn":null}"#).unwrap();
+        assert_eq!(codec.decode(&anonymous).unwrap(), br#"{"contacts":[],"id":7,"name":"Cindy","ssn":null}"#);
+
+        assert!(codec.encode(br#"{"name":"Abby"}"#).unwrap_err().to_string().contains("cannot encode Avro record"));
+        assert!(codec.decode(b"\x01").is_err());
+        assert!(AvroCodec::new(r#"{"type": "record"}"#).is_err());
+    }
+}
@@ -0,0 +1,160 @@
+//! Run a spec over sample records without a cluster, one record per input line:
+//!
+//! ```bash
+//! cargo run --features pipeline --bin regex-map-cli -- --spec spec.json --input samples.ndjson
+//! ```
+//!
+//! or validate a spec once and print the `compiled_spec` param to deploy instead of `spec`:
+//!
+//! ```bash
+//! cargo run --features pipeline --bin regex-map-cli -- --spec spec.yaml --compile
+//! ```
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::ExitCode;
+
+use fluvio_smartmodule::{Result, eyre};
+
+use regex_map::{Pipeline, compile_spec};
+
+const USAGE: &str = "usage: regex-map-cli --spec FILE [--input FILE] [--param NAME=VALUE]... [--no-stats] [--compile]
+
+Applies the spec to each line of the input (stdin by default) as the map build does, prints the
+output records to stdout and the matches per operation to stderr. Specs in .yaml or .yml files
+are read as YAML, other params are passed like the SmartModule `-e` params. With --compile, the
+spec is validated and printed as a `compiled_spec` param value instead.";
+
+#[derive(Debug, Default, PartialEq)]
+struct Args {
+    spec: String,
+    input: Option<String>,
+    params: Vec<(String, String)>,
+    stats: bool,
+    compile: bool,
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Args> {
+    let mut parsed = Args { stats: true, ..Args::default() };
+    let mut spec = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| eyre!("missing value after `{arg}`"));
+        match arg.as_str() {
+            "--spec" => spec = Some(value()?),
+            "--input" => parsed.input = Some(value()?).filter(|input| input != "-"),
+            "--param" => {
+                let param = value()?;
+                let (name, value) = param.split_once('=')
+                    .ok_or_else(|| eyre!("invalid `--param {param}`, expected NAME=VALUE"))?;
+                parsed.params.push((name.to_string(), value.to_string()));
+            }
+            "--no-stats" => parsed.stats = false,
+            "--compile" => parsed.compile = true,
+            _ => return Err(eyre!("unknown argument `{arg}`")),
+        }
+    }
+    parsed.spec = spec.ok_or_else(|| eyre!("missing `--spec`"))?;
+    Ok(parsed)
+}
+
+fn params(args: &Args) -> Result<Vec<(String, String)>> {
+    let spec = fs::read_to_string(&args.spec).map_err(|err| eyre!("cannot read `{}`: {err}", args.spec))?;
+    let mut params = vec![("spec".to_string(), spec)];
+    if args.spec.ends_with(".yaml") || args.spec.ends_with(".yml") {
+        params.push(("spec_format".to_string(), "yaml".to_string()));
+    }
+    params.extend(args.params.iter().cloned());
+    Ok(params)
+}
+
+/// Number of records that failed
+fn run(pipeline: &Pipeline, input: impl BufRead, stats: bool) -> Result<usize> {
+    let mut stdout = io::stdout().lock();
+    let mut totals: BTreeMap<String, usize> = BTreeMap::new();
+    let (mut records, mut failed) = (0, 0);
+    for (number, line) in input.split(b'\n').enumerate() {
+        let line = line?;
+        let line = line.strip_suffix(b"\r").unwrap_or(&line);
+        if line.is_empty() {
+            continue;
+        }
+        records += 1;
+        let output = pipeline.apply(line).and_then(|output| Ok((output, pipeline.matches(None, line)?)));
+        match output {
+            Ok((output, matches)) => {
+                stdout.write_all(&output)?;
+                stdout.write_all(b"\n")?;
+                for (operation, count) in matches {
+                    *totals.entry(operation).or_default() += count;
+                }
+            }
+            Err(err) => {
+                failed += 1;
+                eprintln!("line {}: {err:#}", number + 1);
+            }
+        }
+    }
+    if stats {
+        eprintln!("{records} records, {failed} failed");
+        for (operation, count) in totals {
+            eprintln!("  {operation}: {count} matches");
+        }
+    }
+    Ok(failed)
+}
+
+fn main() -> ExitCode {
+    let result = parse_args(std::env::args().skip(1)).and_then(|args| {
+        let params = params(&args)?;
+        let params = params.iter().map(|(name, value)| (name.as_str(), value.as_str()));
+        if args.compile {
+            println!("{}", compile_spec(params)?);
+            return Ok(0);
+        }
+        let pipeline = Pipeline::from_params(params)?;
+        match &args.input {
+            Some(path) => {
+                let file = fs::File::open(path).map_err(|err| eyre!("cannot read `{path}`: {err}"))?;
+                run(&pipeline, BufReader::new(file), args.stats)
+            }
+            None => run(&pipeline, io::stdin().lock(), args.stats),
+        }
+    });
+    match result {
+        Ok(0) => ExitCode::SUCCESS,
+        Ok(_) => ExitCode::FAILURE,
+        Err(err) => {
+            eprintln!("error: {err:#}\n\n{USAGE}");
+            ExitCode::from(2)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Result<Args> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parse_args_test() {
+        assert_eq!(args(&["--spec", "spec.yaml", "--input", "-", "--param", "format=json", "--no-stats"]).unwrap(), Args {
+            spec: "spec.yaml".to_string(),
+            input: None,
+            params: vec![("format".to_string(), "json".to_string())],
+            stats: false,
+            compile: false,
+        });
+        assert!(args(&["--spec", "spec.json", "--compile"]).unwrap().compile);
+        assert_eq!(args(&["--input", "samples.ndjson", "--spec", "spec.json"]).unwrap().input.as_deref(), Some("samples.ndjson"));
+
+        assert!(args(&["--input", "samples.ndjson"]).unwrap_err().to_string().contains("missing `--spec`"));
+        assert!(args(&["--spec"]).unwrap_err().to_string().contains("missing value after `--spec`"));
+        assert!(args(&["--spec", "a", "--param", "format"]).is_err());
+        assert!(args(&["--spec", "a", "--verbose"]).is_err());
+    }
+}
@@ -0,0 +1,206 @@
+use serde::{Deserialize, Deserializer};
+use serde::de::{self, IntoDeserializer};
+
+/// Vetted regexes that a spec can reference by name with `pattern` instead of writing a `regex`
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BuiltinPattern {
+    Ssn,
+    Email,
+    CreditCard,
+    Phone,
+    Ipv4,
+    Ipv6,
+}
+
+/// US social security numbers, excluding area `000`, `666` and `9xx`, group `00` and serial `0000`
+const SSN: &str = r"\b(?:00[1-9]|0[1-9]\d|[1-578]\d{2}|6[0-57-9]\d|66[0-57-9])-(?:0[1-9]|[1-9]\d)-(?:000[1-9]|00[1-9]\d|0[1-9]\d{2}|[1-9]\d{3})\b";
+
+const EMAIL: &str = r"\b[A-Za-z0-9._%+-]+@[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)*\.[A-Za-z]{2,}\b";
+
+/// Visa, Mastercard, Amex, Discover, Diners and JCB numbers, contiguous or in groups of four
+const CREDIT_CARD: &str = r"\b(?:4\d{12}(?:\d{3})?|(?:5[1-5]\d{2}|222[1-9]|22[3-9]\d|2[3-6]\d{2}|27[01]\d|2720)\d{12}|3[47]\d{13}|6(?:011|5\d{2})\d{12}|3(?:0[0-5]|[68]\d)\d{11}|(?:2131|1800|35\d{3})\d{11}|\d{4}[ -]\d{4}[ -]\d{4}[ -]\d{4})\b";
+
+/// North American numbers with optional country code, e.g. `+1 (408) 555-0100`
+const PHONE: &str = r"(?:\+\d{1,3}[ .-]?)?(?:\(\d{3}\)|\b\d{3})[ .-]?\d{3}[ .-]?\d{4}\b";
+
+const IPV4: &str = r"\b(?:(?:25[0-5]|2[0-4]\d|1\d\d|[1-9]?\d)\.){3}(?:25[0-5]|2[0-4]\d|1\d\d|[1-9]?\d)\b";
+
+/// First release of `ipv6`, which matched the leading groups of longer hex runs
+const IPV6_V1: &str = concat!(
+    r"(?:(?:[0-9A-Fa-f]{1,4}:){7}[0-9A-Fa-f]{1,4}",
+    r"|[0-9A-Fa-f]{1,4}:(?::[0-9A-Fa-f]{1,4}){1,6}",
+    r"|(?:[0-9A-Fa-f]{1,4}:){1,2}(?::[0-9A-Fa-f]{1,4}){1,5}",
+    r"|(?:[0-9A-Fa-f]{1,4}:){1,3}(?::[0-9A-Fa-f]{1,4}){1,4}",
+    r"|(?:[0-9A-Fa-f]{1,4}:){1,4}(?::[0-9A-Fa-f]{1,4}){1,3}",
+    r"|(?:[0-9A-Fa-f]{1,4}:){1,5}(?::[0-9A-Fa-f]{1,4}){1,2}",
+    r"|(?:[0-9A-Fa-f]{1,4}:){1,6}:[0-9A-Fa-f]{1,4}",
+    r"|(?:[0-9A-Fa-f]{1,4}:){1,7}:",
+    r"|:(?:(?::[0-9A-Fa-f]{1,4}){1,7}|:))",
+);
+
+/// Full and `::` compressed forms, longest compressed tails first since alternation is leftmost-first.
+/// Forms ending in a group end at a word boundary, so the start of a longer hex run is not taken for an address.
+const IPV6: &str = concat!(
+    r"(?:(?:(?:[0-9A-Fa-f]{1,4}:){7}[0-9A-Fa-f]{1,4}",
+    r"|[0-9A-Fa-f]{1,4}:(?::[0-9A-Fa-f]{1,4}){1,6}",
+    r"|(?:[0-9A-Fa-f]{1,4}:){1,2}(?::[0-9A-Fa-f]{1,4}){1,5}",
+    r"|(?:[0-9A-Fa-f]{1,4}:){1,3}(?::[0-9A-Fa-f]{1,4}){1,4}",
+    r"|(?:[0-9A-Fa-f]{1,4}:){1,4}(?::[0-9A-Fa-f]{1,4}){1,3}",
+    r"|(?:[0-9A-Fa-f]{1,4}:){1,5}(?::[0-9A-Fa-f]{1,4}){1,2}",
+    r"|(?:[0-9A-Fa-f]{1,4}:){1,6}:[0-9A-Fa-f]{1,4})\b",
+    r"|(?:[0-9A-Fa-f]{1,4}:){1,7}:",
+    r"|:(?:(?::[0-9A-Fa-f]{1,4}){1,7}\b|:))",
+);
+
+/// One released regex of a built-in pattern. Released versions never change, so a spec pinning one
+/// matches the same text across SmartModule upgrades; fixes ship as a new version.
+struct PatternVersion {
+    regex: &'static str,
+    /// Why the version should no longer be used, logged at init by specs that pin it
+    deprecated: Option<&'static str>,
+}
+
+impl BuiltinPattern {
+    /// Regex of the latest version
+    pub fn regex(&self) -> &'static str {
+        self.latest().regex
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            BuiltinPattern::Ssn => "ssn",
+            BuiltinPattern::Email => "email",
+            BuiltinPattern::CreditCard => "credit_card",
+            BuiltinPattern::Phone => "phone",
+            BuiltinPattern::Ipv4 => "ipv4",
+            BuiltinPattern::Ipv6 => "ipv6",
+        }
+    }
+
+    /// Released versions, `v1` first
+    fn versions(&self) -> &'static [PatternVersion] {
+        match self {
+            BuiltinPattern::Ssn => &[PatternVersion { regex: SSN, deprecated: None }],
+            BuiltinPattern::Email => &[PatternVersion { regex: EMAIL, deprecated: None }],
+            BuiltinPattern::CreditCard => &[PatternVersion { regex: CREDIT_CARD, deprecated: None }],
+            BuiltinPattern::Phone => &[PatternVersion { regex: PHONE, deprecated: None }],
+            BuiltinPattern::Ipv4 => &[PatternVersion { regex: IPV4, deprecated: None }],
+            BuiltinPattern::Ipv6 => &[
+                PatternVersion { regex: IPV6_V1, deprecated: Some("it matches the leading groups of longer hex runs, e.g. `fe80::1234` in `fe80::12345`") },
+                PatternVersion { regex: IPV6, deprecated: None },
+            ],
+        }
+    }
+
+    fn latest(&self) -> &'static PatternVersion {
+        self.versions().last().expect("every pattern has a version")
+    }
+}
+
+/// A built-in pattern as named in a spec: `ipv6` for the latest version, or `ipv6@v1` to pin one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PatternRef {
+    pub pattern: BuiltinPattern,
+    /// Pinned version, starting at 1
+    pub version: Option<usize>,
+}
+
+impl PatternRef {
+    fn resolved(&self) -> &'static PatternVersion {
+        match self.version {
+            Some(version) => &self.pattern.versions()[version - 1],
+            None => self.pattern.latest(),
+        }
+    }
+
+    pub fn regex(&self) -> &'static str {
+        self.resolved().regex
+    }
+
+    /// Init warning for a pinned version that is deprecated
+    pub fn deprecation(&self) -> Option<String> {
+        let reason = self.resolved().deprecated?;
+        let (name, latest) = (self.pattern.name(), self.pattern.versions().len());
+        Some(format!("pattern `{name}@v{}` is deprecated since {reason}, pin `{name}@v{latest}` once the change is reviewed", self.version.unwrap_or(latest)))
+    }
+}
+
+impl<'de> Deserialize<'de> for PatternRef {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        let (pattern, version) = match name.split_once('@') {
+            Some((pattern, version)) => (pattern, Some(version)),
+            None => (name.as_str(), None),
+        };
+        let pattern = BuiltinPattern::deserialize(pattern.into_deserializer())
+            .map_err(|err: de::value::Error| de::Error::custom(err))?;
+        let version = version.map(|version| {
+            let number = version.strip_prefix('v').and_then(|number| number.parse::<usize>().ok())
+                .ok_or_else(|| de::Error::custom(format!("invalid pattern version `{version}`, expected e.g. `v1`")))?;
+            match pattern.versions().len() {
+                latest if (1..=latest).contains(&number) => Ok(number),
+                latest => Err(de::Error::custom(format!("pattern `{}` has no version `{version}`, the latest is `v{latest}`", pattern.name()))),
+            }
+        }).transpose()?;
+        Ok(PatternRef { pattern, version })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+
+    fn find_all(pattern: BuiltinPattern, text: &str) -> Vec<String> {
+        let regex = Regex::new(pattern.regex()).unwrap();
+        regex.find_iter(text).map(|m| m.as_str().to_string()).collect()
+    }
+
+    #[test]
+    fn builtin_patterns_test() {
+        assert_eq!(
+            find_all(BuiltinPattern::Ssn, "123-45-6789, 000-12-3456, 666-12-3456, 912-34-5678, 123-00-4567, 123-45-0000, 1123-45-6789"),
+            vec!["123-45-6789"]
+        );
+        assert_eq!(
+            find_all(BuiltinPattern::Email, "abby.hardy+news@mail.school.edu, bob@localhost, @nope.com"),
+            vec!["abby.hardy+news@mail.school.edu"]
+        );
+        assert_eq!(
+            find_all(BuiltinPattern::CreditCard, "4111111111111111, 5500 0000 0000 0004, 340000000000009, 1234567890, 41111111111111111111"),
+            vec!["4111111111111111", "5500 0000 0000 0004", "340000000000009"]
+        );
+        assert_eq!(
+            find_all(BuiltinPattern::Phone, "+1 (408) 555-0100, 408.555.0100, 4085550100, 555-0100, 123-45-6789"),
+            vec!["+1 (408) 555-0100", "408.555.0100", "4085550100"]
+        );
+        assert_eq!(
+            find_all(BuiltinPattern::Ipv4, "10.0.0.1, 192.168.255.254, 256.1.1.1, 1.2.3"),
+            vec!["10.0.0.1", "192.168.255.254"]
+        );
+        assert_eq!(
+            find_all(BuiltinPattern::Ipv6, "2001:0db8:85a3:0000:0000:8a2e:0370:7334 2001:db8::1 fe80::1:2:3 ::1 12:30:45 fe80::12345"),
+            vec!["2001:0db8:85a3:0000:0000:8a2e:0370:7334", "2001:db8::1", "fe80::1:2:3", "::1", "fe80::"]
+        );
+    }
+
+    #[test]
+    fn pattern_versions_test() {
+        let parse = |name: &str| serde_json::from_value::<PatternRef>(name.into()).map_err(|err| err.to_string());
+        let find = |pattern: PatternRef| Regex::new(pattern.regex()).unwrap().find("fe80::12345").unwrap().as_str().to_string();
+
+        let v1 = parse("ipv6@v1").unwrap();
+        assert_eq!(find(v1), "fe80::1234");
+        assert!(v1.deprecation().unwrap().contains("pin `ipv6@v2`"));
+        let v2 = parse("ipv6@v2").unwrap();
+        assert_eq!((find(v2), v2.deprecation()), ("fe80::".to_string(), None));
+        // unpinned names follow the latest version
+        assert_eq!(parse("ipv6").unwrap().regex(), v2.regex());
+        assert_eq!(parse("ssn@v1").unwrap().regex(), BuiltinPattern::Ssn.regex());
+
+        assert!(parse("ssn@v2").unwrap_err().contains("the latest is `v1`"));
+        assert!(parse("ssn@2").unwrap_err().contains("expected e.g. `v1`"));
+        assert!(parse("passport").is_err());
+    }
+}
@@ -0,0 +1,76 @@
+use aes_siv::siv::{Aes128Siv, Aes256Siv};
+use aes_siv::KeyInit;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+
+use fluvio_smartmodule::{Result, eyre};
+
+/// Deterministic AES-SIV encryption of matched text into URL-safe base64 tokens,
+/// equal plaintexts give equal tokens so they can still be joined on
+pub struct TokenCipher {
+    key: Vec<u8>,
+}
+
+impl std::fmt::Debug for TokenCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "TokenCipher(AES-{}-SIV)", self.key.len() * 4)
+    }
+}
+
+impl TokenCipher {
+    /// `key` is the base64 of a 32 byte (AES-128-SIV) or 64 byte (AES-256-SIV) key
+    pub fn new(key: &str) -> Result<Self> {
+        let key = base64::engine::general_purpose::STANDARD.decode(key.trim())
+            .map_err(|err| eyre!("invalid base64 key: {err}"))?;
+        if key.len() != 32 && key.len() != 64 {
+            return Err(eyre!("AES-SIV keys are 32 or 64 bytes, got {}", key.len()));
+        }
+        Ok(TokenCipher { key })
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+        let headers = std::iter::empty::<&[u8]>();
+        let ciphertext = match self.key.len() {
+            32 => Aes128Siv::new_from_slice(&self.key).expect("key length checked").encrypt(headers, plaintext),
+            _ => Aes256Siv::new_from_slice(&self.key).expect("key length checked").encrypt(headers, plaintext),
+        };
+        // only fails with more associated data headers than SIV supports
+        URL_SAFE_NO_PAD.encode(ciphertext.expect("no associated data")).into_bytes()
+    }
+
+    /// Plaintext of a token from `encrypt` with the same key
+    pub fn decrypt(&self, token: &[u8]) -> Result<Vec<u8>> {
+        let ciphertext = URL_SAFE_NO_PAD.decode(token).map_err(|err| eyre!("invalid token: {err}"))?;
+        let headers = std::iter::empty::<&[u8]>();
+        let plaintext = match self.key.len() {
+            32 => Aes128Siv::new_from_slice(&self.key).expect("key length checked").decrypt(headers, &ciphertext),
+            _ => Aes256Siv::new_from_slice(&self.key).expect("key length checked").decrypt(headers, &ciphertext),
+        };
+        plaintext.map_err(|_| eyre!("token was not encrypted with this key"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cipher_test() {
+        let key = base64::engine::general_purpose::STANDARD.encode([7u8; 64]);
+        let cipher = TokenCipher::new(&key).unwrap();
+
+        let token = cipher.encrypt(b"123-45-6789");
+        assert_eq!(cipher.encrypt(b"123-45-6789"), token);
+        assert_ne!(cipher.encrypt(b"123-45-6780"), token);
+        assert!(token.iter().all(|b| b.is_ascii_alphanumeric() || *b == b'-' || *b == b'_'));
+        assert_eq!(cipher.decrypt(&token).unwrap(), b"123-45-6789");
+
+        let other = TokenCipher::new(&base64::engine::general_purpose::STANDARD.encode([7u8; 32])).unwrap();
+        assert_eq!(other.decrypt(&other.encrypt(b"abby")).unwrap(), b"abby");
+        assert!(other.decrypt(&token).is_err());
+        assert!(other.decrypt(b"not a token").is_err());
+
+        assert!(TokenCipher::new("c2hvcnQ=").unwrap_err().to_string().contains("got 5"));
+        assert!(TokenCipher::new("***").is_err());
+    }
+}
@@ -0,0 +1,79 @@
+use fluvio_smartmodule::{Result, eyre};
+
+/// Rows of a CSV record, split into unquoted fields so operations can target a single column
+#[derive(Debug, Clone, PartialEq)]
+pub struct CsvRecord {
+    rows: Vec<Vec<Vec<u8>>>,
+    terminated: bool,
+}
+
+impl CsvRecord {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(data);
+        let rows = reader.byte_records()
+            .map(|row| row.map(|row| row.iter().map(<[u8]>::to_vec).collect()))
+            .collect::<std::result::Result<_, _>>()
+            .map_err(|err| eyre!("column operations require a CSV record: {err}"))?;
+
+        Ok(CsvRecord { rows, terminated: data.ends_with(b"\n") })
+    }
+
+    /// Call `f` on the field at `index` of every row, rows without that column are left as is
+    pub fn for_each_field_mut(&mut self, index: usize, f: &mut dyn FnMut(&mut Vec<u8>)) {
+        self.rows.iter_mut().filter_map(|row| row.get_mut(index)).for_each(f);
+    }
+
+    /// Fields at `index` of every row
+    pub fn fields(&self, index: usize) -> Vec<&[u8]> {
+        self.rows.iter().filter_map(|row| row.get(index)).map(Vec::as_slice).collect()
+    }
+
+    /// Write the rows back, quoting only the fields that need it
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = csv::WriterBuilder::new()
+            .flexible(true)
+            .from_writer(vec![]);
+        for row in &self.rows {
+            // writing to a Vec cannot fail
+            let _ = writer.write_record(row);
+        }
+        let mut bytes = writer.into_inner().unwrap_or_default();
+        if !self.terminated && bytes.ends_with(b"\n") {
+            bytes.pop();
+        }
+        bytes
+    }
+}
+
+/// Resolve a `column` name against the comma separated `csv_columns` header
+pub fn column_index(header: &str, column: &str) -> Option<usize> {
+    header.split(',').position(|name| name.trim() == column)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_record_test() {
+        let mut record = CsvRecord::parse(b"Abby,\"1 Main St, Oakland\",123-45-6789\nBob,\"2 \"\"Elm\"\" St\"\n").unwrap();
+        assert_eq!(record.fields(1), vec![&b"1 Main St, Oakland"[..], b"2 \"Elm\" St"]);
+        assert_eq!(record.fields(2), vec![&b"123-45-6789"[..]]);
+
+        record.for_each_field_mut(2, &mut |field| *field = b"***".to_vec());
+        record.for_each_field_mut(0, &mut |field| field.push(b','));
+        assert_eq!(
+            record.to_bytes(),
+            b"\"Abby,\",\"1 Main St, Oakland\",***\n\"Bob,\",\"2 \"\"Elm\"\" St\"\n"
+        );
+
+        let record = CsvRecord::parse(b"a,b").unwrap();
+        assert_eq!(record.to_bytes(), b"a,b");
+
+        assert_eq!(column_index("first, address ,ssn", "address"), Some(1));
+        assert_eq!(column_index("first,address", "ssn"), None);
+    }
+}
@@ -0,0 +1,120 @@
+use std::borrow::Cow;
+
+use serde::Deserialize;
+
+/// Layout applied to JSON output records by the `post_process` param, other records are left as is
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PostProcess {
+    MinifyJson,
+    /// Two space indentation, as `serde_json::to_string_pretty`
+    PrettyJson,
+}
+
+const INDENT: &[u8] = b"  ";
+
+impl PostProcess {
+    /// Reformat the whitespace between tokens, field order and values are kept byte for byte
+    pub fn apply(self, data: Cow<[u8]>) -> Cow<[u8]> {
+        if serde_json::from_slice::<serde::de::IgnoredAny>(&data).is_err() {
+            return data;
+        }
+        let output = reformat(&data, self == PostProcess::PrettyJson);
+        match output == *data {
+            true => data,
+            false => Cow::Owned(output),
+        }
+    }
+}
+
+/// Only called on valid JSON, whitespace outside of strings is dropped and, when `pretty`, put back
+fn reformat(data: &[u8], pretty: bool) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len());
+    let (mut depth, mut in_string, mut escaped, mut opened) = (0, false, false, false);
+    let newline = |output: &mut Vec<u8>, depth: usize| {
+        output.push(b'\n');
+        output.extend(INDENT.repeat(depth));
+    };
+    for &byte in data {
+        if in_string {
+            output.push(byte);
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        if byte.is_ascii_whitespace() {
+            continue;
+        }
+        if !pretty {
+            in_string = byte == b'"';
+            output.push(byte);
+            continue;
+        }
+        // empty objects and arrays stay on one line
+        if std::mem::take(&mut opened) && !matches!(byte, b'}' | b']') {
+            newline(&mut output, depth);
+        } else if matches!(byte, b'}' | b']') && output.last().is_some_and(|last| !matches!(last, b'{' | b'[')) {
+            newline(&mut output, depth - 1);
+        }
+        match byte {
+            b'{' | b'[' => {
+                depth += 1;
+                opened = true;
+            }
+            b'}' | b']' => depth -= 1,
+            b'"' => in_string = true,
+            _ => {}
+        }
+        output.push(byte);
+        match byte {
+            b',' => newline(&mut output, depth),
+            b':' => output.push(b' '),
+            _ => {}
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(post_process: PostProcess, data: &str) -> String {
+        String::from_utf8(post_process.apply(Cow::Borrowed(data.as_bytes())).into_owned()).unwrap()
+    }
+
+    #[test]
+    fn post_process_test() {
+        let record = "{\n  \"name\": \"Abby  Hardy\",\n  \"ssn\" : \"***-**-****\", \"tags\": [ ],\n  \"meta\": {\"q\": \"a \\\" b\", \"n\": [1, {}]}\n}\n";
+        let minified = r#"{"name":"Abby  Hardy","ssn":"***-**-****","tags":[],"meta":{"q":"a \" b","n":[1,{}]}}"#;
+        assert_eq!(apply(PostProcess::MinifyJson, record), minified);
+        assert!(matches!(PostProcess::MinifyJson.apply(Cow::Borrowed(minified.as_bytes())), Cow::Borrowed(_)));
+
+        let pretty = apply(PostProcess::PrettyJson, record);
+        let expected: serde_json::Value = serde_json::from_str(record).unwrap();
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&pretty).unwrap(), expected);
+        assert_eq!(pretty, concat!(
+            "{\n",
+            "  \"name\": \"Abby  Hardy\",\n",
+            "  \"ssn\": \"***-**-****\",\n",
+            "  \"tags\": [],\n",
+            "  \"meta\": {\n",
+            "    \"q\": \"a \\\" b\",\n",
+            "    \"n\": [\n",
+            "      1,\n",
+            "      {}\n",
+            "    ]\n",
+            "  }\n",
+            "}",
+        ));
+        assert_eq!(apply(PostProcess::PrettyJson, "[1,2]"), "[\n  1,\n  2\n]");
+        assert_eq!(apply(PostProcess::PrettyJson, "\"a\""), "\"a\"");
+
+        assert_eq!(apply(PostProcess::MinifyJson, "ssn 123 45"), "ssn 123 45");
+        assert_eq!(apply(PostProcess::MinifyJson, "{\"a\": 1} {\"b\": 2}"), "{\"a\": 1} {\"b\": 2}");
+    }
+}
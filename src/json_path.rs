@@ -0,0 +1,389 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+
+use fluvio_smartmodule::{Result, eyre};
+
+/// Subset of JSONPath used to target values inside JSON records:
+/// `$`, `.field`, `['field']`, `[N]`, `[*]`, `.*`, `..field` and `..*`,
+/// or a JSON pointer such as `/students/0/ssn`
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonPath {
+    raw: String,
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Field(String),
+    Index(usize),
+    Wildcard,
+    Descendant(String),
+    /// Every value below the current one, at any depth
+    AllDescendants,
+    /// JSON pointer token, an object field or an array index
+    Token(String),
+}
+
+impl JsonPath {
+    /// `$..*`, every value of the document
+    pub fn every_value() -> Self {
+        JsonPath { raw: "$..*".to_string(), segments: vec![Segment::AllDescendants] }
+    }
+
+    /// Call `f` on every value selected by the path
+    pub fn for_each_mut(&self, value: &mut Value, f: &mut dyn FnMut(&mut Value)) {
+        visit_mut(&self.segments, value, f);
+    }
+
+    /// Collect references to every value selected by the path
+    pub fn select<'a>(&self, value: &'a Value) -> Vec<&'a Value> {
+        let mut found = vec![];
+        visit(&self.segments, value, &mut found);
+        found
+    }
+
+    /// Remove every value selected by the path from its parent, returning how many were removed
+    pub fn remove(&self, value: &mut Value) -> usize {
+        let Some((last, parents)) = self.segments.split_last() else {
+            return 0;
+        };
+        let mut removed = 0;
+        visit_mut(parents, value, &mut |parent| removed += remove_child(last, parent));
+        removed
+    }
+
+    /// Whether the path is just `$`, selecting the whole document
+    pub fn is_root(&self) -> bool {
+        self.segments.is_empty()
+    }
+}
+
+fn remove_child(segment: &Segment, parent: &mut Value) -> usize {
+    match (segment, parent) {
+        (Segment::Field(name), Value::Object(map)) => usize::from(map.remove(name).is_some()),
+        (Segment::Index(index), Value::Array(items)) if *index < items.len() => {
+            items.remove(*index);
+            1
+        }
+        (Segment::Wildcard, Value::Object(map)) => {
+            let removed = map.len();
+            map.clear();
+            removed
+        }
+        (Segment::Wildcard, Value::Array(items)) => {
+            let removed = items.len();
+            items.clear();
+            removed
+        }
+        (Segment::Descendant(name), Value::Object(map)) => {
+            let removed = usize::from(map.remove(name).is_some());
+            removed + map.values_mut().map(|child| remove_child(segment, child)).sum::<usize>()
+        }
+        (Segment::Descendant(_), Value::Array(items)) => {
+            items.iter_mut().map(|child| remove_child(segment, child)).sum()
+        }
+        (Segment::AllDescendants, Value::Object(map)) => {
+            let removed = map.len();
+            map.clear();
+            removed
+        }
+        (Segment::AllDescendants, Value::Array(items)) => {
+            let removed = items.len();
+            items.clear();
+            removed
+        }
+        (Segment::Token(token), Value::Object(map)) => usize::from(map.remove(token).is_some()),
+        (Segment::Token(token), Value::Array(items)) => match token.parse::<usize>() {
+            Ok(index) if index < items.len() => {
+                items.remove(index);
+                1
+            }
+            _ => 0,
+        },
+        _ => 0,
+    }
+}
+
+fn visit_mut(segments: &[Segment], value: &mut Value, f: &mut dyn FnMut(&mut Value)) {
+    let Some((segment, rest)) = segments.split_first() else {
+        f(value);
+        return;
+    };
+
+    match (segment, value) {
+        (Segment::Field(name), Value::Object(map)) => {
+            if let Some(child) = map.get_mut(name) {
+                visit_mut(rest, child, f);
+            }
+        }
+        (Segment::Index(index), Value::Array(items)) => {
+            if let Some(child) = items.get_mut(*index) {
+                visit_mut(rest, child, f);
+            }
+        }
+        (Segment::Wildcard, Value::Object(map)) => {
+            map.values_mut().for_each(|child| visit_mut(rest, child, f));
+        }
+        (Segment::Wildcard, Value::Array(items)) => {
+            items.iter_mut().for_each(|child| visit_mut(rest, child, f));
+        }
+        (Segment::Descendant(name), Value::Object(map)) => {
+            for (key, child) in map.iter_mut() {
+                if key == name {
+                    visit_mut(rest, child, f);
+                } else {
+                    visit_mut(segments, child, f);
+                }
+            }
+        }
+        (Segment::Descendant(_), Value::Array(items)) => {
+            items.iter_mut().for_each(|child| visit_mut(segments, child, f));
+        }
+        (Segment::AllDescendants, Value::Object(map)) => {
+            map.values_mut().for_each(|child| {
+                visit_mut(rest, child, f);
+                visit_mut(segments, child, f);
+            });
+        }
+        (Segment::AllDescendants, Value::Array(items)) => {
+            items.iter_mut().for_each(|child| {
+                visit_mut(rest, child, f);
+                visit_mut(segments, child, f);
+            });
+        }
+        (Segment::Token(token), Value::Object(map)) => {
+            if let Some(child) = map.get_mut(token) {
+                visit_mut(rest, child, f);
+            }
+        }
+        (Segment::Token(token), Value::Array(items)) => {
+            if let Some(child) = token.parse().ok().and_then(|index: usize| items.get_mut(index)) {
+                visit_mut(rest, child, f);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn visit<'a>(segments: &[Segment], value: &'a Value, found: &mut Vec<&'a Value>) {
+    let Some((segment, rest)) = segments.split_first() else {
+        found.push(value);
+        return;
+    };
+
+    match (segment, value) {
+        (Segment::Field(name), Value::Object(map)) => {
+            if let Some(child) = map.get(name) {
+                visit(rest, child, found);
+            }
+        }
+        (Segment::Index(index), Value::Array(items)) => {
+            if let Some(child) = items.get(*index) {
+                visit(rest, child, found);
+            }
+        }
+        (Segment::Wildcard, Value::Object(map)) => {
+            map.values().for_each(|child| visit(rest, child, found));
+        }
+        (Segment::Wildcard, Value::Array(items)) => {
+            items.iter().for_each(|child| visit(rest, child, found));
+        }
+        (Segment::Descendant(name), Value::Object(map)) => {
+            for (key, child) in map.iter() {
+                if key == name {
+                    visit(rest, child, found);
+                } else {
+                    visit(segments, child, found);
+                }
+            }
+        }
+        (Segment::Descendant(_), Value::Array(items)) => {
+            items.iter().for_each(|child| visit(segments, child, found));
+        }
+        (Segment::AllDescendants, Value::Object(map)) => {
+            map.values().for_each(|child| {
+                visit(rest, child, found);
+                visit(segments, child, found);
+            });
+        }
+        (Segment::AllDescendants, Value::Array(items)) => {
+            items.iter().for_each(|child| {
+                visit(rest, child, found);
+                visit(segments, child, found);
+            });
+        }
+        (Segment::Token(token), Value::Object(map)) => {
+            if let Some(child) = map.get(token) {
+                visit(rest, child, found);
+            }
+        }
+        (Segment::Token(token), Value::Array(items)) => {
+            if let Some(child) = token.parse().ok().and_then(|index: usize| items.get(index)) {
+                visit(rest, child, found);
+            }
+        }
+        _ => {}
+    }
+}
+
+impl FromStr for JsonPath {
+    type Err = eyre::Error;
+
+    fn from_str(raw: &str) -> Result<Self> {
+        if raw.starts_with('/') {
+            return Ok(JsonPath { raw: raw.to_string(), segments: parse_pointer(raw) });
+        }
+        let rest = raw.strip_prefix('$')
+            .ok_or_else(|| eyre!("json path `{raw}` must start with `$`, or `/` for a JSON pointer"))?;
+        let mut chars = rest.chars().peekable();
+        let mut segments = vec![];
+
+        while let Some(c) = chars.next() {
+            match c {
+                '.' if chars.peek() == Some(&'.') => {
+                    chars.next();
+                    let name = take_name(&mut chars);
+                    match name.as_str() {
+                        "" => return Err(eyre!("json path `{raw}` has an empty name after `..`")),
+                        "*" => segments.push(Segment::AllDescendants),
+                        _ => segments.push(Segment::Descendant(name)),
+                    }
+                }
+                '.' => {
+                    let name = take_name(&mut chars);
+                    match name.as_str() {
+                        "" => return Err(eyre!("json path `{raw}` has an empty name after `.`")),
+                        "*" => segments.push(Segment::Wildcard),
+                        _ => segments.push(Segment::Field(name)),
+                    }
+                }
+                '[' => {
+                    let mut inner = String::new();
+                    for c in chars.by_ref() {
+                        if c == ']' {
+                            break;
+                        }
+                        inner.push(c);
+                    }
+                    let inner = inner.trim();
+                    let segment = if inner == "*" {
+                        Segment::Wildcard
+                    } else if let Some(name) = quoted(inner) {
+                        Segment::Field(name.to_string())
+                    } else {
+                        inner.parse().map(Segment::Index)
+                            .map_err(|_| eyre!("json path `{raw}` has an invalid selector `[{inner}]`"))?
+                    };
+                    segments.push(segment);
+                }
+                _ => return Err(eyre!("json path `{raw}` has an unexpected `{c}`")),
+            }
+        }
+
+        Ok(JsonPath { raw: raw.to_string(), segments })
+    }
+}
+
+/// Split a JSON pointer into tokens, unescaping `~1` to `/` and `~0` to `~`
+fn parse_pointer(raw: &str) -> Vec<Segment> {
+    raw.split('/').skip(1)
+        .map(|token| Segment::Token(token.replace("~1", "/").replace("~0", "~")))
+        .collect()
+}
+
+fn take_name(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut name = String::new();
+    while let Some(c) = chars.peek() {
+        if *c == '.' || *c == '[' {
+            break;
+        }
+        name.push(*c);
+        chars.next();
+    }
+    name
+}
+
+fn quoted(inner: &str) -> Option<&str> {
+    inner.strip_prefix('\'').and_then(|s| s.strip_suffix('\''))
+        .or_else(|| inner.strip_prefix('"').and_then(|s| s.strip_suffix('"')))
+}
+
+impl fmt::Display for JsonPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.raw)
+    }
+}
+
+impl<'de> Deserialize<'de> for JsonPath {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let raw = String::deserialize(deserializer)?;
+        raw.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn json_path_test() {
+        let doc = json!({
+            "class": "2025-A",
+            "students": [
+                {"first": "Abby", "ssn": "123-45-6789", "guardian": {"ssn": "111-22-3333"}},
+                {"first": "Bob", "ssn": "987-65-4321"}
+            ]
+        });
+        let select = |path: &str| -> Vec<Value> {
+            let path: JsonPath = path.parse().unwrap();
+            path.select(&doc).into_iter().cloned().collect()
+        };
+
+        assert_eq!(select("$.class"), vec![json!("2025-A")]);
+        assert_eq!(select("$.students[*].ssn"), vec![json!("123-45-6789"), json!("987-65-4321")]);
+        assert_eq!(select("$['students'][1].first"), vec![json!("Bob")]);
+        assert_eq!(select("$..ssn"), vec![json!("111-22-3333"), json!("123-45-6789"), json!("987-65-4321")]);
+        assert_eq!(select("$.students[5]"), Vec::<Value>::new());
+        assert_eq!(select("$"), vec![doc.clone()]);
+
+        let mut doc = doc.clone();
+        let path: JsonPath = "$.students.*.first".parse().unwrap();
+        path.for_each_mut(&mut doc, &mut |v| *v = json!("x"));
+        assert_eq!(doc["students"][0]["first"], json!("x"));
+        assert_eq!(doc["students"][1]["first"], json!("x"));
+
+        let remove = |path: &str| {
+            let mut doc = doc.clone();
+            let removed = path.parse::<JsonPath>().unwrap().remove(&mut doc);
+            (removed, doc)
+        };
+        let (removed, doc) = remove("$..ssn");
+        assert_eq!(removed, 3);
+        assert_eq!(doc["students"][0], json!({"first": "x", "guardian": {}}));
+        let (removed, doc) = remove("$.students[0]");
+        assert_eq!((removed, doc["students"].as_array().unwrap().len()), (1, 1));
+        assert_eq!(remove("$.students[*].guardian.ssn").0, 1);
+        assert_eq!(remove("$.missing").0, 0);
+        assert_eq!(remove("$").0, 0);
+        assert!("$".parse::<JsonPath>().unwrap().is_root());
+
+        let doc = json!({"a/b": {"items": [{"n": 1}, {"n": "two"}]}, "c": "three"});
+        let select = |path: &str| -> Vec<Value> {
+            let path: JsonPath = path.parse().unwrap();
+            path.select(&doc).into_iter().cloned().collect()
+        };
+        assert_eq!(select("/a~1b/items/1/n"), vec![json!("two")]);
+        assert_eq!(select("/a~1b/items/x"), Vec::<Value>::new());
+        assert_eq!(JsonPath::every_value().select(&doc).into_iter().filter(|v| v.is_string()).count(), 2);
+        assert_eq!(select("$..*").len(), 7);
+        assert_eq!(select("$['a/b']..*").len(), 5);
+
+        assert!("students".parse::<JsonPath>().is_err());
+        assert!("$.students[x]".parse::<JsonPath>().is_err());
+        assert!("$.".parse::<JsonPath>().is_err());
+    }
+}
@@ -0,0 +1,220 @@
+use regex::Regex;
+use serde_json::Value;
+
+use fluvio_smartmodule::{Result, eyre};
+
+/// Subset of JSON Schema checked on output records: `type`, `enum`, `const`, `properties`,
+/// `required`, `additionalProperties`, `items`, `pattern`, `minLength`/`maxLength`,
+/// `minimum`/`maximum` and `minItems`/`maxItems`. Other keywords are rejected rather than ignored.
+#[derive(Debug, Default)]
+pub struct JsonSchema {
+    types: Vec<String>,
+    values: Option<Vec<Value>>,
+    properties: Vec<(String, JsonSchema)>,
+    required: Vec<String>,
+    additional_properties: Option<Box<JsonSchema>>,
+    no_additional_properties: bool,
+    items: Option<Box<JsonSchema>>,
+    pattern: Option<Regex>,
+    min_length: Option<u64>,
+    max_length: Option<u64>,
+    minimum: Option<f64>,
+    maximum: Option<f64>,
+    min_items: Option<u64>,
+    max_items: Option<u64>,
+}
+
+/// Keywords that don't constrain values
+const ANNOTATIONS: [&str; 7] = ["$schema", "$id", "$comment", "title", "description", "default", "examples"];
+
+const TYPES: [&str; 7] = ["null", "boolean", "object", "array", "number", "integer", "string"];
+
+impl JsonSchema {
+    pub fn new(schema: &Value) -> Result<Self> {
+        compile(schema, "#")
+    }
+
+    /// The first violation, located by JSON pointer
+    pub fn validate(&self, value: &Value) -> Result<()> {
+        self.check(value, &mut String::new())
+    }
+
+    fn check(&self, value: &Value, pointer: &mut String) -> Result<()> {
+        let at = |pointer: &str| match pointer {
+            "" => "record".to_string(),
+            pointer => format!("`{pointer}`"),
+        };
+        if !self.types.is_empty() && !self.types.iter().any(|name| has_type(value, name)) {
+            return Err(eyre!("{} is not of type {}", at(pointer), self.types.join(" or ")));
+        }
+        if let Some(values) = &self.values {
+            if !values.contains(value) {
+                return Err(eyre!("{} is not one of the allowed values", at(pointer)));
+            }
+        }
+        match value {
+            Value::String(text) => {
+                let length = text.chars().count() as u64;
+                if self.min_length.is_some_and(|min| length < min) || self.max_length.is_some_and(|max| length > max) {
+                    return Err(eyre!("{} has {length} characters, out of the allowed length", at(pointer)));
+                }
+                if let Some(pattern) = self.pattern.as_ref().filter(|pattern| !pattern.is_match(text)) {
+                    return Err(eyre!("{} does not match pattern `{pattern}`", at(pointer)));
+                }
+            }
+            Value::Number(number) => {
+                let number = number.as_f64().unwrap_or(f64::NAN);
+                if self.minimum.is_some_and(|min| number < min) || self.maximum.is_some_and(|max| number > max) {
+                    return Err(eyre!("{} is {number}, out of the allowed range", at(pointer)));
+                }
+            }
+            Value::Array(items) => {
+                let count = items.len() as u64;
+                if self.min_items.is_some_and(|min| count < min) || self.max_items.is_some_and(|max| count > max) {
+                    return Err(eyre!("{} has {count} items, out of the allowed count", at(pointer)));
+                }
+                if let Some(schema) = &self.items {
+                    for (index, item) in items.iter().enumerate() {
+                        let len = pointer.len();
+                        pointer.push_str(&format!("/{index}"));
+                        schema.check(item, pointer)?;
+                        pointer.truncate(len);
+                    }
+                }
+            }
+            Value::Object(fields) => {
+                if let Some(name) = self.required.iter().find(|name| !fields.contains_key(*name)) {
+                    return Err(eyre!("{} is missing required field `{name}`", at(pointer)));
+                }
+                for (name, field) in fields {
+                    let schema = match self.properties.iter().find(|(property, _)| property == name) {
+                        Some((_, schema)) => schema,
+                        None if self.no_additional_properties => {
+                            return Err(eyre!("{} has unexpected field `{name}`", at(pointer)));
+                        }
+                        None => match &self.additional_properties {
+                            Some(schema) => schema,
+                            None => continue,
+                        },
+                    };
+                    let len = pointer.len();
+                    pointer.push('/');
+                    pointer.push_str(&name.replace('~', "~0").replace('/', "~1"));
+                    schema.check(field, pointer)?;
+                    pointer.truncate(len);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+fn has_type(value: &Value, name: &str) -> bool {
+    match (name, value) {
+        ("null", Value::Null) | ("boolean", Value::Bool(_)) | ("string", Value::String(_)) => true,
+        ("object", Value::Object(_)) | ("array", Value::Array(_)) | ("number", Value::Number(_)) => true,
+        ("integer", Value::Number(number)) => number.is_i64() || number.is_u64() || number.as_f64().is_some_and(|n| n.fract() == 0.0),
+        _ => false,
+    }
+}
+
+fn compile(schema: &Value, location: &str) -> Result<JsonSchema> {
+    let keywords = match schema {
+        Value::Bool(true) => return Ok(JsonSchema::default()),
+        Value::Object(keywords) => keywords,
+        _ => return Err(eyre!("schema at `{location}` must be an object")),
+    };
+    let mut compiled = JsonSchema::default();
+    for (keyword, value) in keywords {
+        let invalid = || eyre!("invalid `{keyword}` at `{location}`");
+        match keyword.as_str() {
+            "type" => {
+                compiled.types = match value {
+                    Value::String(name) => vec![name.clone()],
+                    Value::Array(names) => names.iter().map(|name| name.as_str().map(str::to_string)).collect::<Option<_>>().ok_or_else(invalid)?,
+                    _ => return Err(invalid()),
+                };
+                if let Some(name) = compiled.types.iter().find(|name| !TYPES.contains(&name.as_str())) {
+                    return Err(eyre!("unknown type `{name}` at `{location}`"));
+                }
+            }
+            "enum" => compiled.values = Some(value.as_array().ok_or_else(invalid)?.clone()),
+            "const" => compiled.values = Some(vec![value.clone()]),
+            "properties" => {
+                compiled.properties = value.as_object().ok_or_else(invalid)?.iter()
+                    .map(|(name, schema)| Ok((name.clone(), compile(schema, &format!("{location}/properties/{name}"))?)))
+                    .collect::<Result<_>>()?;
+            }
+            "required" => {
+                compiled.required = value.as_array().ok_or_else(invalid)?.iter()
+                    .map(|name| name.as_str().map(str::to_string))
+                    .collect::<Option<_>>().ok_or_else(invalid)?;
+            }
+            "additionalProperties" => match value {
+                Value::Bool(false) => compiled.no_additional_properties = true,
+                schema => compiled.additional_properties = Some(Box::new(compile(schema, &format!("{location}/additionalProperties"))?)),
+            },
+            "items" => compiled.items = Some(Box::new(compile(value, &format!("{location}/items"))?)),
+            "pattern" => {
+                let pattern = value.as_str().ok_or_else(invalid)?;
+                compiled.pattern = Some(Regex::new(pattern).map_err(|err| eyre!("invalid `pattern` at `{location}`: {err}"))?);
+            }
+            "minLength" => compiled.min_length = Some(value.as_u64().ok_or_else(invalid)?),
+            "maxLength" => compiled.max_length = Some(value.as_u64().ok_or_else(invalid)?),
+            "minimum" => compiled.minimum = Some(value.as_f64().ok_or_else(invalid)?),
+            "maximum" => compiled.maximum = Some(value.as_f64().ok_or_else(invalid)?),
+            "minItems" => compiled.min_items = Some(value.as_u64().ok_or_else(invalid)?),
+            "maxItems" => compiled.max_items = Some(value.as_u64().ok_or_else(invalid)?),
+            keyword if ANNOTATIONS.contains(&keyword) => {}
+            keyword => return Err(eyre!("unsupported keyword `{keyword}` at `{location}`")),
+        }
+    }
+    Ok(compiled)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn json_schema_test() {
+        let schema = JsonSchema::new(&json!({
+            "$schema": "https://json-schema.org/draft/2020-12/schema",
+            "type": "object",
+            "required": ["name", "ssn"],
+            "properties": {
+                "name": {"type": "string", "minLength": 1},
+                "ssn": {"type": "string", "pattern": "^(\\*{3}-\\*{2}-\\*{4}|\\d{3}-\\d{2}-\\d{4})$"},
+                "id": {"type": "integer", "minimum": 0},
+                "tags": {"type": "array", "maxItems": 2, "items": {"enum": ["a", "b"]}},
+                "a/b": {"const": 1}
+            },
+            "additionalProperties": false
+        })).unwrap();
+
+        assert!(schema.validate(&json!({"name": "Abby", "ssn": "***-**-****", "id": 4, "tags": ["a"]})).is_ok());
+        let error = |value: Value| schema.validate(&value).unwrap_err().to_string();
+        assert_eq!(error(json!([])), "record is not of type object");
+        assert_eq!(error(json!({"name": "Abby"})), "record is missing required field `ssn`");
+        assert_eq!(error(json!({"name": "Abby", "ssn": "***-**-6789x"})), r"`/ssn` does not match pattern `^(\*{3}-\*{2}-\*{4}|\d{3}-\d{2}-\d{4})$`");
+        assert_eq!(error(json!({"name": "", "ssn": "***-**-****"})), "`/name` has 0 characters, out of the allowed length");
+        assert_eq!(error(json!({"name": "A", "ssn": "***-**-****", "id": 1.5})), "`/id` is not of type integer");
+        assert_eq!(error(json!({"name": "A", "ssn": "***-**-****", "id": -1})), "`/id` is -1, out of the allowed range");
+        assert_eq!(error(json!({"name": "A", "ssn": "***-**-****", "tags": ["a", "c"]})), "`/tags/1` is not one of the allowed values");
+        assert_eq!(error(json!({"name": "A", "ssn": "***-**-****", "tags": ["a", "a", "b"]})), "`/tags` has 3 items, out of the allowed count");
+        assert_eq!(error(json!({"name": "A", "ssn": "***-**-****", "a/b": 2})), "`/a~1b` is not one of the allowed values");
+        assert_eq!(error(json!({"name": "A", "ssn": "***-**-****", "age": 3})), "record has unexpected field `age`");
+
+        let open = JsonSchema::new(&json!({"additionalProperties": {"type": "string"}})).unwrap();
+        assert!(open.validate(&json!({"a": "b"})).is_ok());
+        assert!(open.validate(&json!({"a": 1})).is_err());
+
+        let unsupported = JsonSchema::new(&json!({"properties": {"a": {"$ref": "#/defs/a"}}})).unwrap_err();
+        assert_eq!(unsupported.to_string(), "unsupported keyword `$ref` at `#/properties/a`");
+        assert!(JsonSchema::new(&json!({"type": "text"})).is_err());
+        assert!(JsonSchema::new(&json!({"pattern": "("})).is_err());
+    }
+}
@@ -1,7 +1,36 @@
-use regex::Regex;
-use once_cell::sync::OnceCell;
-use eyre::ContextCompat;
-use serde::Deserialize;
+#[cfg(feature = "avro")]
+mod avro_record;
+mod builtin;
+mod cipher;
+mod csv_record;
+mod json_format;
+mod json_path;
+mod json_schema;
+mod logging;
+mod pattern;
+#[cfg(any(test, feature = "pipeline"))]
+pub mod pipeline;
+#[cfg(feature = "protobuf")]
+mod proto_record;
+mod template;
+mod url;
+mod xml_record;
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write;
+use std::cell::{Cell, RefCell};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+
+use aho_corasick::{AhoCorasick, MatchKind};
+use regex::bytes::{Captures, RegexSet, RegexSetBuilder};
+
+use eyre::{ContextCompat, WrapErr};
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use unicode_normalization::{is_nfc_quick, is_nfkc_quick, IsNormalized, UnicodeNormalization};
 
 use fluvio_smartmodule::{
     smartmodule, Result, SmartModuleRecord, RecordData,
@@ -11,218 +40,7616 @@ use fluvio_smartmodule::{
     eyre
 };
 
-static OPS: OnceCell<Vec<Operation>> = OnceCell::new();
+#[cfg(feature = "avro")]
+use crate::avro_record::AvroCodec;
+use crate::builtin::BuiltinPattern;
+use crate::cipher::TokenCipher;
+use crate::csv_record::CsvRecord;
+use crate::json_format::PostProcess;
+use crate::json_path::JsonPath;
+use crate::json_schema::JsonSchema;
+use crate::logging::LogLevel;
+use crate::pattern::{Engine, Flags, Pattern, RegexLimits};
+#[cfg(feature = "protobuf")]
+use crate::proto_record::ProtoCodec;
+use crate::template::Template;
+use crate::url::UrlParts;
+use crate::xml_record::{XmlPath, XmlRecord};
+
+#[cfg(feature = "pipeline")]
+pub use crate::pipeline::{Pipeline, compile_spec};
+
+/// Spec file baked in at build time with the `embedded-spec` feature, used when no `spec` param is set
+#[cfg(feature = "embedded-spec")]
+const EMBEDDED_SPEC: Option<&str> = Some(include_str!(concat!(env!("OUT_DIR"), "/embedded_spec")));
+#[cfg(not(feature = "embedded-spec"))]
+const EMBEDDED_SPEC: Option<&str> = None;
+
+/// Active spec, replaced when a control record carries new operations
+static SPEC: RwLock<Option<Arc<Spec>>> = RwLock::new(None);
+/// Params of the last `init`, to tell a repeated init from one with new params
+static INIT_PARAMS: RwLock<Option<String>> = RwLock::new(None);
 const PARAM_NAME: &str = "spec";
+const SPEC_FORMAT_PARAM: &str = "spec_format";
+const SPEC_ENCODING_PARAM: &str = "spec_encoding";
+const COMPILED_SPEC_PARAM: &str = "compiled_spec";
+const SPEC_REF_PARAM: &str = "spec_ref";
+/// Leading bytes of a decoded `compiled_spec` param, bumped when its layout changes
+const COMPILED_SPEC_MAGIC: &[u8] = b"RXMAP1";
+const FILTER_MODE_PARAM: &str = "filter_mode";
+const ON_ERROR_PARAM: &str = "on_error";
+const OVERRIDES_PARAM: &str = "overrides";
+const STATS_FIELD_PARAM: &str = "stats_field";
+const FORMAT_PARAM: &str = "format";
+const CSV_COLUMNS_PARAM: &str = "csv_columns";
+const AUDIT_PARAM: &str = "audit";
+const MAX_RECORD_BYTES_PARAM: &str = "max_record_bytes";
+const ON_OVERSIZE_PARAM: &str = "on_oversize";
+const REGEX_LIMITS_PARAM: &str = "regex_limits";
+const WINDOW_RECORDS_PARAM: &str = "window_records";
+const UTF8_PARAM: &str = "utf8";
+const INPUT_ENCODING_PARAM: &str = "input_encoding";
+const MODE_PARAM: &str = "mode";
+const CONTROL_KEY_PARAM: &str = "control_key";
+const DROP_REGEX_PARAM: &str = "drop_regex";
+#[cfg(feature = "protobuf")]
+const PROTO_DESCRIPTOR_PARAM: &str = "proto_descriptor";
+#[cfg(feature = "protobuf")]
+const PROTO_MESSAGE_PARAM: &str = "proto_message";
+#[cfg(feature = "avro")]
+const AVRO_SCHEMA_PARAM: &str = "avro_schema";
+const LOG_LEVEL_PARAM: &str = "log_level";
+const OUTPUT_SCHEMA_PARAM: &str = "output_schema";
+const FORCE_REINIT_PARAM: &str = "force_reinit";
+const ON_LINE_ERROR_PARAM: &str = "on_line_error";
+const DESCRIBE_PARAM: &str = "describe";
+const POST_PROCESS_PARAM: &str = "post_process";
+const STRICT_PARAM: &str = "strict";
+const CHECKSUM_FIELD_PARAM: &str = "checksum_field";
+const CHECKSUM_ALGORITHM_PARAM: &str = "checksum_algorithm";
+const TENANT_SELECTOR_PARAM: &str = "tenant_selector";
+const DICTIONARY_KEY_PARAM: &str = "dictionary_key";
+const SPANS_FIELD_PARAM: &str = "spans_field";
+const SPANS_COORDINATES_PARAM: &str = "spans_coordinates";
+const NORMALIZE_PATTERN_WHITESPACE_PARAM: &str = "normalize_pattern_whitespace";
+const ALLOW_EMPTY_SPEC_PARAM: &str = "allow_empty_spec";
+const MAX_PROCESSING_MS_PARAM: &str = "max_processing_ms";
+const ARRAY_ROOT_PARAM: &str = "array_root";
+const ON_TIMEOUT_PARAM: &str = "on_timeout";
+/// Field that `on_timeout: annotate` adds to JSON object records, holding the budget in milliseconds
+const TIMEOUT_FIELD: &str = "_processing_timeout_ms";
+/// Tenant whose operations apply to records of tenants the `spec` map does not list
+const DEFAULT_TENANT: &str = "*";
 
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "snake_case")]
-enum Operation {
-    Replace(Replace)
+/// Transport encoding of the `spec` param, for specs too large to pass inline
+#[derive(Debug, Default, Deserialize, PartialEq)]
+enum SpecEncoding {
+    #[default]
+    #[serde(rename = "plain")]
+    Plain,
+    #[serde(rename = "base64")]
+    Base64,
+    #[serde(rename = "gzip+base64")]
+    GzipBase64,
 }
 
-#[derive(Debug, Deserialize)]
-struct Replace {
-    #[serde(with = "serde_regex")]
-    regex: Regex,
-    with: String,
-}
+impl SpecEncoding {
+    fn decode<'a>(&self, raw: &'a str) -> Result<Cow<'a, str>> {
+        use base64::Engine;
+        use std::io::Read;
 
-impl Operation {
-    pub fn run_regex(&self, text: &String) -> String {
+        let decode_base64 = |raw: &str| {
+            let compact: String = raw.split_whitespace().collect();
+            base64::engine::general_purpose::STANDARD.decode(compact)
+                .map_err(|err| eyre!("cannot decode base64 `{PARAM_NAME}` param: {err}"))
+        };
         match self {
-            Operation::Replace(r) => {
-                r.regex.replace_all(text,  &r.with).to_string()
+            SpecEncoding::Plain => Ok(Cow::Borrowed(raw)),
+            SpecEncoding::Base64 => Ok(Cow::Owned(String::from_utf8(decode_base64(raw)?)?)),
+            SpecEncoding::GzipBase64 => {
+                let mut spec = String::new();
+                flate2::read::GzDecoder::new(decode_base64(raw)?.as_slice())
+                    .read_to_string(&mut spec)
+                    .map_err(|err| eyre!("cannot decompress gzip `{PARAM_NAME}` param: {err}"))?;
+                Ok(Cow::Owned(spec))
             }
         }
     }
 }
 
-/// Parse input paramters
-fn get_params(params: SmartModuleExtraParams) -> Result<Vec<Operation>> {
-    if let Some(raw_spec) = params.get(PARAM_NAME) {
-        match serde_json::from_str(raw_spec) {
-            Ok(operations) => {
-                Ok(operations)
+/// Operation lists of a validated spec, packed into a `compiled_spec` param so engines load a single
+/// param checked ahead of deployment. Lists are stored as compact JSON, which also parses as YAML.
+/// Compiled regexes cannot be serialized, they are still built at init.
+#[derive(Debug, Serialize, Deserialize)]
+struct CompiledSpec {
+    /// Module version the spec was validated with
+    version: String,
+    sources: Vec<(String, String)>,
+}
+
+impl CompiledSpec {
+    /// Validate the spec params as `init` does and pack their operation lists
+    #[cfg(any(test, feature = "pipeline"))]
+    fn compile(params: &SmartModuleExtraParams) -> Result<String> {
+        get_spec(params.clone())?;
+        let format: SpecFormat = get_keyword_param(params, SPEC_FORMAT_PARAM)?;
+        let mut sources = vec![];
+        for (param, raw_spec) in spec_sources(params)? {
+            let lists: Value = format.parse(&raw_spec)?;
+            sources.push((param, lists.to_string()));
+        }
+        CompiledSpec { version: env!("CARGO_PKG_VERSION").to_string(), sources }.encode()
+    }
+
+    #[cfg(any(test, feature = "pipeline"))]
+    fn encode(&self) -> Result<String> {
+        use base64::Engine;
+        use std::io::Write;
+
+        let mut encoder = flate2::write::GzEncoder::new(COMPILED_SPEC_MAGIC.to_vec(), flate2::Compression::best());
+        encoder.write_all(&serde_json::to_vec(self)?)?;
+        Ok(base64::engine::general_purpose::STANDARD.encode(encoder.finish()?))
+    }
+
+    fn decode(raw: &str) -> Result<Self> {
+        use base64::Engine;
+
+        let compact: String = raw.split_whitespace().collect();
+        let bytes = base64::engine::general_purpose::STANDARD.decode(compact)
+            .map_err(|err| eyre!("cannot decode base64 `{COMPILED_SPEC_PARAM}` param: {err}"))?;
+        let gzip = bytes.strip_prefix(COMPILED_SPEC_MAGIC)
+            .ok_or_else(|| eyre!("`{COMPILED_SPEC_PARAM}` param was not produced by `compile_spec`"))?;
+        // the gzip trailer checksum rejects truncated or altered params
+        let compiled: CompiledSpec = serde_json::from_reader(flate2::read::GzDecoder::new(gzip))
+            .map_err(|err| eyre!("corrupted `{COMPILED_SPEC_PARAM}` param: {err}"))?;
+        if compiled.version != env!("CARGO_PKG_VERSION") {
+            return Err(eyre!("`{COMPILED_SPEC_PARAM}` was compiled for regex-map {}, compile it again for {}",
+                compiled.version, env!("CARGO_PKG_VERSION")));
+        }
+        Ok(compiled)
+    }
+}
+
+/// Operations and record handling settings collected at init
+#[derive(Debug)]
+struct Spec {
+    ops: Vec<Operation>,
+    filter_mode: FilterMode,
+    on_error: OnError,
+    /// Field of JSON records that receives per-operation match counts
+    stats_field: Option<String>,
+    /// All value regexes, lets `map` forward records none of them match without running the operations
+    prefilter: Option<RegexSet>,
+    /// Emit an audit record describing the matches after the records of each input (array_map build)
+    audit: bool,
+    size_limit: Option<SizeLimit>,
+    time_budget: Option<TimeBudget>,
+    /// Records per tumbling window of the aggregate build, totals never reset when `None`
+    window_records: Option<u64>,
+    utf8: Utf8Mode,
+    input_encoding: InputEncoding,
+    mode: Mode,
+    control: Option<Control>,
+    /// Output values the filter-map build drops, besides empty ones
+    drop_regex: Option<Pattern>,
+    /// Set with `format: protobuf` or `avro`, records are decoded to JSON before the operations and encoded back after
+    codec: Option<RecordCodec>,
+    /// Checked on the JSON output of each record, violations are handled by `on_error`
+    output_schema: Option<JsonSchema>,
+    /// Set with `format: ndjson`, operations run on each line and failing lines are handled by this policy
+    lines: Option<OnError>,
+    array_root: ArrayRoot,
+    /// Layout of JSON output records, applied after the operations
+    post_process: Option<PostProcess>,
+    checksum: Option<Checksum>,
+    spans: Option<SpanReport>,
+    /// Set when `spec` maps tenant ids to operation lists, `ops` is then empty
+    tenants: Option<Tenants>,
+}
+
+impl Spec {
+    /// One line per operation as resolved at init: regexes after `pattern` and definition expansion, flags and targets
+    fn describe(&self) -> Vec<String> {
+        match &self.tenants {
+            Some(tenants) => tenants.specs.iter()
+                .flat_map(|(tenant, spec)| spec.describe().into_iter().map(move |line| format!("tenant `{tenant}` {line}")))
+                .collect(),
+            None => self.ops.iter().enumerate().map(|(index, op)| op.summary(index + 1)).collect(),
+        }
+    }
+
+    fn operation_count(&self) -> usize {
+        match &self.tenants {
+            Some(tenants) => tenants.specs.values().map(Spec::operation_count).sum(),
+            None => self.ops.len(),
+        }
+    }
+}
+
+/// Specs built from the operation list of each tenant, the record handling params are shared
+#[derive(Debug)]
+struct Tenants {
+    selector: TenantSelector,
+    specs: BTreeMap<String, Spec>,
+}
+
+/// Reads the tenant id of a record from its value as received
+#[derive(Debug)]
+enum TenantSelector {
+    /// JSON pointer to a string or number field, e.g. `/tenant`
+    Pointer(String),
+    /// Regex whose first capture group, or whole match without groups, is the tenant id
+    Regex(Pattern),
+}
+
+impl TenantSelector {
+    fn from_param(raw: &str, limits: &RegexLimits) -> Result<Self> {
+        if raw.starts_with('/') {
+            return Ok(TenantSelector::Pointer(raw.to_string()));
+        }
+        let mut regex = Pattern::from(raw);
+        regex.compile_with(&Flags::default(), Engine::Default, limits)
+            .map_err(|err| eyre!("invalid `{TENANT_SELECTOR_PARAM}` param: {err}"))?;
+        Ok(TenantSelector::Regex(regex))
+    }
+
+    fn tenant(&self, value: &[u8]) -> Option<String> {
+        match self {
+            TenantSelector::Pointer(pointer) => match serde_json::from_slice::<Value>(value).ok()?.pointer(pointer)? {
+                Value::String(tenant) => Some(tenant.clone()),
+                Value::Number(tenant) => Some(tenant.to_string()),
+                _ => None,
+            },
+            TenantSelector::Regex(regex) => {
+                let caps = regex.captures(value)?;
+                let tenant = caps.get(1).or_else(|| caps.get(0))?;
+                Some(String::from_utf8_lossy(tenant.as_bytes()).into_owned())
             }
-            Err(err) => {
-                eprintln!("unable to parse spec from params: {err:?}");
-                Err(eyre!("cannot parse `spec` param: {:#?}", err))
+        }
+    }
+}
+
+/// Reset the per-record state before the operations run on a record: hands the record offset and timestamp
+/// to `${__offset}` and `${__timestamp}` templates, starts the `max_processing_ms` clock of the record and
+/// restarts `placeholder` numbering. Every entrypoint calls it once per record.
+fn begin_record(spec: &Spec, record: &SmartModuleRecord) {
+    template::set_record(record.offset(), record.timestamp());
+    TimeBudget::start(spec.time_budget.as_ref());
+    Placeholder::reset();
+}
+
+/// The spec of the record tenant, the `*` tenant when its own is not listed
+fn tenant_spec<'s>(spec: &'s Spec, record: &SmartModuleRecord) -> Result<&'s Spec> {
+    let Some(tenants) = &spec.tenants else {
+        return Ok(spec);
+    };
+    let tenant = tenants.selector.tenant(record.value.as_ref());
+    tenant.as_deref().and_then(|tenant| tenants.specs.get(tenant))
+        .or_else(|| tenants.specs.get(DEFAULT_TENANT))
+        .ok_or_else(|| match tenant {
+            Some(tenant) => eyre!("no operations for tenant `{tenant}` and no `{DEFAULT_TENANT}` tenant in `{PARAM_NAME}`"),
+            None => eyre!("`{TENANT_SELECTOR_PARAM}` found no tenant in the record and there is no `{DEFAULT_TENANT}` tenant in `{PARAM_NAME}`"),
+        })
+}
+
+/// Binary record format that operations rewrite through its JSON mapping,
+/// without variants in builds with neither the `protobuf` nor the `avro` feature
+#[derive(Debug)]
+enum RecordCodec {
+    #[cfg(feature = "protobuf")]
+    Protobuf(ProtoCodec),
+    #[cfg(feature = "avro")]
+    Avro(AvroCodec),
+}
+
+#[cfg_attr(not(any(feature = "protobuf", feature = "avro")), allow(unused_variables))]
+impl RecordCodec {
+    fn decode(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match *self {
+            #[cfg(feature = "protobuf")]
+            RecordCodec::Protobuf(ref codec) => codec.decode(data),
+            #[cfg(feature = "avro")]
+            RecordCodec::Avro(ref codec) => codec.decode(data),
+        }
+    }
+
+    fn encode(&self, json: &[u8]) -> Result<Vec<u8>> {
+        match *self {
+            #[cfg(feature = "protobuf")]
+            RecordCodec::Protobuf(ref codec) => codec.encode(json),
+            #[cfg(feature = "avro")]
+            RecordCodec::Avro(ref codec) => codec.encode(json),
+        }
+    }
+}
+
+/// Records with the `control_key` key replace the operations at runtime, resolved
+/// against the init params so record handling settings and `overrides` still apply.
+/// Records with the `dictionary_key` key replace `translate` dictionaries the same way.
+#[derive(Debug)]
+struct Control {
+    key: Option<String>,
+    dictionary_key: Option<String>,
+    /// Init params, with the dictionaries of the last dictionary records
+    params: SmartModuleExtraParams,
+    /// Operations of the last control record, the `spec` params apply until one arrives
+    raw_spec: Option<String>,
+}
+
+/// Whether the map build rewrites records or only reports what the operations would match
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Mode {
+    #[default]
+    Transform,
+    /// Emit each record unchanged, wrapped with the matches of the operations on its value
+    Detect,
+}
+
+/// Largest record value processed, and what to do with larger ones
+#[derive(Debug)]
+struct SizeLimit {
+    max_bytes: usize,
+    on_oversize: OnOversize,
+}
+
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum OnOversize {
+    /// Treat the record as an error, handled according to `on_error`
+    #[default]
+    Fail,
+    /// Drop the record, not available in the map build
+    Skip,
+    /// Process and emit only the first `max_record_bytes`, cut on a UTF-8 character boundary
+    Truncate,
+}
+
+impl SizeLimit {
+    fn from_params(params: &SmartModuleExtraParams) -> Result<Option<Self>> {
+        let on_oversize = get_keyword_param(params, ON_OVERSIZE_PARAM)?;
+        if on_oversize == OnOversize::Skip && cfg!(feature = "map") {
+            return Err(eyre!("`{ON_OVERSIZE_PARAM}: skip` cannot drop records in the map build, use `fail` or `truncate`"));
+        }
+        let Some(raw) = params.get(MAX_RECORD_BYTES_PARAM) else {
+            return Ok(None);
+        };
+        let max_bytes = raw.trim().parse()
+            .map_err(|err| eyre!("invalid `{MAX_RECORD_BYTES_PARAM}` param `{raw}`: {err}"))?;
+        Ok(Some(SizeLimit { max_bytes, on_oversize }))
+    }
+
+    /// The value to process, `None` when the record is skipped
+    fn check<'a>(&self, value: &'a [u8]) -> Result<Option<&'a [u8]>> {
+        if value.len() <= self.max_bytes {
+            return Ok(Some(value));
+        }
+        match self.on_oversize {
+            OnOversize::Fail => Err(eyre!(
+                "record of {} bytes exceeds `{MAX_RECORD_BYTES_PARAM}` of {}", value.len(), self.max_bytes
+            )),
+            OnOversize::Skip => Ok(None),
+            OnOversize::Truncate => {
+                let cut = &value[..self.max_bytes];
+                match std::str::from_utf8(cut) {
+                    Err(err) if err.error_len().is_none() => Ok(Some(&cut[..err.valid_up_to()])),
+                    _ => Ok(Some(cut)),
+                }
             }
         }
-    } else {
-        Err(SmartModuleInitError::MissingParam(PARAM_NAME.to_string()).into())
     }
 }
 
-/// Traverse the regex list, compute regex, and collect output
-fn apply_regex_ops_to_json_record(record: &SmartModuleRecord, ops: &Vec<Operation>) -> Result<String> {
-    let data_str: &str = std::str::from_utf8(record.value.as_ref())?;
-    let mut data = data_str.to_string();
+/// Longest time the operations may spend on a record, and what to do with records that take longer.
+/// The budget is checked before each operation, so one slow regex still runs to its end.
+#[derive(Debug)]
+struct TimeBudget {
+    max: Duration,
+    on_timeout: OnTimeout,
+}
+
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum OnTimeout {
+    /// Treat the record as an error, handled according to `on_error`
+    #[default]
+    Fail,
+    /// Forward the record as received
+    Passthrough,
+    /// Drop the record, not available in the map build
+    #[serde(alias = "drop")]
+    Skip,
+    /// Forward the record as received, with `TIMEOUT_FIELD` added to JSON objects
+    Annotate,
+}
 
-    let mut iter = ops.into_iter();
-    while let Some(op) = iter.next() {
-        data = op.run_regex(&data);
+/// Error of a record that ran out of `max_processing_ms`, told apart from other errors by `on_timeout`
+#[derive(Debug)]
+struct ProcessingTimeout(Duration);
+
+impl std::fmt::Display for ProcessingTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "record processing exceeded `{MAX_PROCESSING_MS_PARAM}` of {}ms", self.0.as_millis())
     }
+}
 
-    Ok(data)
-}    
+impl std::error::Error for ProcessingTimeout {}
 
-#[smartmodule(map)]
-pub fn map(record: &SmartModuleRecord) -> Result<(Option<RecordData>, RecordData)> {
-    let key = record.key.clone();
-    let ops = OPS.get().wrap_err("regex operations not initialized")?;
+thread_local! {
+    /// When the record being processed runs out of its time budget
+    static DEADLINE: Cell<Option<(Instant, Duration)>> = const { Cell::new(None) };
+}
 
-    let result = apply_regex_ops_to_json_record(record, ops)?;
-    Ok((key, result.into()))
+impl TimeBudget {
+    fn from_params(params: &SmartModuleExtraParams) -> Result<Option<Self>> {
+        let on_timeout = get_keyword_param(params, ON_TIMEOUT_PARAM)?;
+        if on_timeout == OnTimeout::Skip && cfg!(feature = "map") {
+            return Err(eyre!("`{ON_TIMEOUT_PARAM}: skip` cannot drop records in the map build, use `fail`, `passthrough` or `annotate`"));
+        }
+        let Some(raw) = params.get(MAX_PROCESSING_MS_PARAM) else {
+            return Ok(None);
+        };
+        // `Instant::now` panics without a clock, as in the wasm32-unknown-unknown target
+        if cfg!(all(target_arch = "wasm32", target_os = "unknown")) {
+            return Err(eyre!("`{MAX_PROCESSING_MS_PARAM}` needs a clock, build the SmartModule for `wasm32-wasip1`"));
+        }
+        let millis = raw.trim().parse()
+            .map_err(|err| eyre!("invalid `{MAX_PROCESSING_MS_PARAM}` param `{raw}`: {err}"))?;
+        Ok(Some(TimeBudget { max: Duration::from_millis(millis), on_timeout }))
+    }
+
+    /// Start the clock of a record, or clear the one of the previous record without a budget
+    fn start(budget: Option<&TimeBudget>) {
+        let deadline = budget.map(|budget| (Instant::now() + budget.max, budget.max));
+        DEADLINE.with(|cell| cell.set(deadline));
+    }
+
+    /// Fail once the record being processed is over its budget
+    fn check() -> Result<()> {
+        match DEADLINE.with(Cell::get) {
+            Some((deadline, max)) if Instant::now() >= deadline => Err(ProcessingTimeout(max).into()),
+            _ => Ok(()),
+        }
+    }
 }
 
-#[smartmodule(init)]
-fn init(params: SmartModuleExtraParams) -> Result<()> {
-    let ops = get_params(params)?;
+/// The record to emit in place of one that ran out of `max_processing_ms`, `None` to drop it.
+/// Other errors, and timeouts with `on_timeout: fail`, are returned for `on_error` to handle.
+fn timeout_fallback(record: &SmartModuleRecord, spec: &Spec, err: eyre::Report) -> Result<Option<(Option<RecordData>, RecordData)>> {
+    let Some(budget) = spec.time_budget.as_ref().filter(|budget| budget.on_timeout != OnTimeout::Fail) else {
+        return Err(err);
+    };
+    if !err.is::<ProcessingTimeout>() {
+        return Err(err);
+    }
+    let action = format!("{:?}", budget.on_timeout).to_lowercase();
+    logging::log(LogLevel::Warn, &[("offset", &record.offset()), ("action", &action)], format_args!("{err:#}"));
+    let value = match budget.on_timeout {
+        OnTimeout::Fail | OnTimeout::Skip => return Ok(None),
+        OnTimeout::Passthrough => record.value.clone(),
+        OnTimeout::Annotate => match serde_json::from_slice::<Value>(record.value.as_ref()) {
+            Ok(Value::Object(mut object)) => {
+                object.insert(TIMEOUT_FIELD.to_string(), (budget.max.as_millis() as u64).into());
+                serde_json::to_vec(&object)?.into()
+            }
+            _ => record.value.clone(),
+        },
+    };
+    Ok(Some((record.key.clone(), value)))
+}
 
-    OPS.set(ops).expect("regex operations already initialized");
+/// Field of JSON output records receiving a checksum of the value as received, before any
+/// operation ran, so duplicates can be detected downstream without the raw data
+#[derive(Debug)]
+struct Checksum {
+    field: String,
+    algorithm: ChecksumAlgorithm,
+}
 
-    Ok(())
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum ChecksumAlgorithm {
+    #[default]
+    Sha256,
+    Crc32,
 }
 
+impl Checksum {
+    fn from_params(params: &SmartModuleExtraParams, mode: Mode) -> Result<Option<Self>> {
+        let Some(field) = params.get(CHECKSUM_FIELD_PARAM) else {
+            if params.get(CHECKSUM_ALGORITHM_PARAM).is_some() {
+                return Err(eyre!("`{CHECKSUM_ALGORITHM_PARAM}` needs the `{CHECKSUM_FIELD_PARAM}` param"));
+            }
+            return Ok(None);
+        };
+        if cfg!(any(feature = "filter", feature = "aggregate")) {
+            return Err(eyre!("`{CHECKSUM_FIELD_PARAM}` adds a field to output records and needs the map, filter-map or array-map build"));
+        }
+        if mode == Mode::Detect {
+            return Err(eyre!("`{MODE_PARAM}: detect` outputs match reports, remove `{CHECKSUM_FIELD_PARAM}`"));
+        }
+        if matches!(get_keyword_param(params, FORMAT_PARAM)?, RecordFormat::Ndjson | RecordFormat::Protobuf | RecordFormat::Avro) {
+            return Err(eyre!("`{CHECKSUM_FIELD_PARAM}` adds a field to single JSON documents, it is not supported with `{FORMAT_PARAM}: ndjson`, `protobuf` or `avro`"));
+        }
+        if field.is_empty() {
+            return Err(eyre!("`{CHECKSUM_FIELD_PARAM}` must not be empty"));
+        }
+        let algorithm = get_keyword_param(params, CHECKSUM_ALGORITHM_PARAM)?;
+        Ok(Some(Checksum { field: field.clone(), algorithm }))
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use serde_json::Value;
-    use fluvio_smartmodule::Record;
-    
-    static INPUT: &str = r#"{
-        "description": "Independence High School",
-        "class": "2025-A",
-        "students": [
-          {
-              "first": "Abby",
-              "last": "Hardy",
-              "address": "285 LA PALA DR APT 2343, SAN JOSE CA 95127",
-              "ssn": "123-45-6789"
-          },
-          {
-              "first": "Bob",
-              "last": "Newmal",
-              "address": "21 E TRIMBLE RD, Santa Clara CA 95347",
-              "ssn": "987-65-4321"
-          },
-          {
-              "first": "Cindy",
-              "last": "Hall",
-              "address": "1601 PRIME PL, Milpitas CA 95344",
-              "ssn": "999-88-7777"
-          }
-        ]
-    }"#;
+    /// Lower case hex, 8 digits for `crc32`
+    fn hex_digest(&self, value: &[u8]) -> String {
+        match self.algorithm {
+            ChecksumAlgorithm::Sha256 => HashAlgorithm::Sha256.hex_digest(b"", value),
+            ChecksumAlgorithm::Crc32 => {
+                let mut crc = flate2::Crc::new();
+                crc.update(value);
+                format!("{:08x}", crc.sum())
+            }
+        }
+    }
 
-    #[test]
-    fn run_regex_test() {
-        // Replace exact
-        let input = r"123-45-6789".to_owned();
-        let regex = r"\d{3}-\d{2}-\d{4}";
-        let op = Operation::Replace(Replace {
-            regex: Regex::new(regex).unwrap(),
-            with: "***-**-****".to_owned()
-        });
-        let expected = "***-**-****".to_owned();
+    /// The output record with the checksum of `original` under `field`
+    fn attach(&self, data: &[u8], original: &[u8]) -> Result<Vec<u8>> {
+        let Ok(Value::Object(mut record)) = serde_json::from_slice(data) else {
+            return Err(eyre!("`{CHECKSUM_FIELD_PARAM}` requires JSON object records"));
+        };
+        record.insert(self.field.clone(), Value::String(self.hex_digest(original)));
+        Ok(serde_json::to_vec(&record)?)
+    }
+}
 
-        let result = op.run_regex(&input);
-        assert_eq!(result, expected);
+/// Field of JSON output records receiving `[op_index, start, end]` byte ranges of the text each
+/// operation rewrote, so reviewers can audit which regions changed
+#[derive(Debug)]
+struct SpanReport {
+    field: String,
+    coordinates: SpanCoordinates,
+}
 
-        // Replace subset
-        let input = r"Alice Jackson, ssn 123-45-6789, location: NY".to_owned();
-        let regex = r"\d{3}-\d{2}-\d{4}";
-        let op = Operation::Replace(Replace {
-            regex: Regex::new(regex).unwrap(),
-            with: "***-**-****".to_owned()
-        });
-        let expected = "Alice Jackson, ssn ***-**-****, location: NY".to_owned();
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum SpanCoordinates {
+    /// Ranges of the matches in the text the operation ran on
+    #[default]
+    Input,
+    /// Ranges of the replacements in the text the operation wrote
+    Output,
+}
 
-        let result = op.run_regex(&input);
-        assert_eq!(result, expected);
+impl SpanReport {
+    fn from_params(params: &SmartModuleExtraParams, mode: Mode) -> Result<Option<Self>> {
+        let Some(field) = params.get(SPANS_FIELD_PARAM) else {
+            if params.get(SPANS_COORDINATES_PARAM).is_some() {
+                return Err(eyre!("`{SPANS_COORDINATES_PARAM}` needs the `{SPANS_FIELD_PARAM}` param"));
+            }
+            return Ok(None);
+        };
+        if !cfg!(any(feature = "map", feature = "filter-map")) {
+            return Err(eyre!("`{SPANS_FIELD_PARAM}` adds a field to rewritten records and needs the map or filter-map build"));
+        }
+        if mode == Mode::Detect {
+            return Err(eyre!("`{MODE_PARAM}: detect` already reports spans, remove `{SPANS_FIELD_PARAM}`"));
+        }
+        if get_keyword_param::<RecordFormat>(params, FORMAT_PARAM)? != RecordFormat::Text {
+            return Err(eyre!("`{SPANS_FIELD_PARAM}` reports byte ranges of the record text and is only supported with `{FORMAT_PARAM}: text`"));
+        }
+        if params.get(STATS_FIELD_PARAM).is_some() {
+            return Err(eyre!("`{SPANS_FIELD_PARAM}` and `{STATS_FIELD_PARAM}` cannot be combined"));
+        }
+        if field.is_empty() {
+            return Err(eyre!("`{SPANS_FIELD_PARAM}` must not be empty"));
+        }
+        let coordinates = get_keyword_param(params, SPANS_COORDINATES_PARAM)?;
+        Ok(Some(SpanReport { field: field.clone(), coordinates }))
+    }
 
-        // Replace multiple
-        let input = r"Alice, ssn 123-45-6789, Jack, ssn 987-65-4321".to_owned();
-        let regex = r"\d{3}-\d{2}-\d{4}";
-        let op = Operation::Replace(Replace {
-            regex: Regex::new(regex).unwrap(),
-            with: "***-**-****".to_owned()
-        });
-        let expected = "Alice, ssn ***-**-****, Jack, ssn ***-**-****".to_owned();
+    /// Spans are byte ranges of the whole record text, which operations on fields do not have
+    fn check(&self, ops: &[Operation]) -> Result<()> {
+        let partial = ops.iter().position(|op| op.options().target.value()
+            && (op.path().is_some() || op.column().is_some() || op.xml_path().is_some() || op.options().input != Input::Value));
+        match partial {
+            Some(index) => Err(eyre!("`{SPANS_FIELD_PARAM}` needs operations on the whole record text, {} reads part of it", ops[index].describe(index))),
+            None => Ok(()),
+        }
+    }
+}
 
-        let result = op.run_regex(&input);
-        assert_eq!(result, expected);
+/// How record values that are not valid UTF-8 are matched
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Utf8Mode {
+    /// Match the raw bytes, invalid sequences are kept as is
+    #[default]
+    Bytes,
+    /// Treat the record as an error, handled according to `on_error`
+    Strict,
+    /// Replace invalid sequences with U+FFFD before matching
+    Lossy,
+}
 
-        // Replace address
-        let input = r#""address": "285 LA PALA DR APT 2343, SAN JOSE CA 95127""#.to_owned();
-        let regex = r#"(?P<first>"address":\s+\")([\w\d\s]+),"#;
-        let op = Operation::Replace(Replace {
-            regex: Regex::new(regex).unwrap(),
-            with: "${first}...".to_owned()
-        });
-        let expected = r#""address": "... SAN JOSE CA 95127""#.to_owned();
+impl Utf8Mode {
+    fn decode<'a>(&self, value: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+        match self {
+            Utf8Mode::Bytes => Ok(Cow::Borrowed(value)),
+            Utf8Mode::Strict => match std::str::from_utf8(value) {
+                Ok(_) => Ok(Cow::Borrowed(value)),
+                Err(err) => Err(eyre!("record is not valid UTF-8 with `{UTF8_PARAM}: strict`: {err}")),
+            },
+            Utf8Mode::Lossy => match String::from_utf8_lossy(value) {
+                Cow::Borrowed(text) => Ok(Cow::Borrowed(text.as_bytes())),
+                Cow::Owned(text) => Ok(Cow::Owned(text.into_bytes())),
+            },
+        }
+    }
+}
 
-        let result = op.run_regex(&input);
-        assert_eq!(result, expected);
+/// Character encoding of record values, transcoded to UTF-8 before matching and back on output
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+enum InputEncoding {
+    #[default]
+    #[serde(rename = "utf-8", alias = "utf8")]
+    Utf8,
+    #[serde(rename = "latin1", alias = "iso-8859-1")]
+    Latin1,
+    #[serde(rename = "windows-1252", alias = "cp1252")]
+    Windows1252,
+}
 
-        // Replace none
-        let input = r"not a match".to_owned();
-        let regex = r"\d{3}-\d{2}-\d{4}";
-        let op = Operation::Replace(Replace {
-            regex: Regex::new(regex).unwrap(),
-            with: "***-**-****".to_owned()
-        });
-        let expected = r"not a match".to_owned();
+/// Characters of Windows-1252 bytes 0x80 to 0x9F, the undefined ones map to the C1 controls like in browsers
+const WINDOWS_1252_HIGH: [char; 32] = [
+    '\u{20ac}', '\u{81}', '\u{201a}', '\u{192}', '\u{201e}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{2c6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8d}', '\u{17d}', '\u{8f}',
+    '\u{90}', '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2dc}', '\u{2122}', '\u{161}', '\u{203a}', '\u{153}', '\u{9d}', '\u{17e}', '\u{178}',
+];
 
-        let result = op.run_regex(&input);
-        assert_eq!(result, expected);
+impl InputEncoding {
+    fn decode<'a>(&self, value: &'a [u8]) -> Cow<'a, [u8]> {
+        if *self == InputEncoding::Utf8 || value.is_ascii() {
+            return Cow::Borrowed(value);
+        }
+        let text: String = value.iter().map(|&byte| match (self, byte) {
+            (InputEncoding::Windows1252, 0x80..=0x9f) => WINDOWS_1252_HIGH[usize::from(byte - 0x80)],
+            _ => char::from(byte),
+        }).collect();
+        Cow::Owned(text.into_bytes())
     }
 
-    #[test]
-    fn apply_regex_ops_to_json_record_tests() {
-        static EXPECTED: &str = r#"{
-            "description": "Independence High School",
-            "class": "2025-A",
-            "students": [
-              {
-                  "first": "Abby",
-                  "last": "Hardy",
-                  "ssn": "***-**-****",
-                  "address": "... SAN JOSE CA 95127"
-              },
-              {
-                  "first": "Bob",
-                  "last": "Newmal",
-                  "ssn": "***-**-****",
-                  "address": "... Santa Clara CA 95347"
-              },
-              {
-                  "first": "Cindy",
-                  "last": "Hall",
-                  "ssn": "***-**-****",
-                  "address": "... Milpitas CA 95344"
-              }
-            ]
-        }"#;
-        let ops = vec![
-            Operation::Replace(Replace {
-                regex: Regex::new(r"\d{3}-\d{2}-\d{4}").unwrap(),
-                with: "***-**-****".to_owned()
-            }),
-            Operation::Replace(Replace {
-                regex: Regex::new(r#"(?P<first>"address":\s+\")([\w\d\s]+),"#).unwrap(),
-                with: "${first}...".to_owned()
-            })
-        ];
+    /// The UTF-8 output in this encoding, characters it cannot represent become `?`
+    fn encode<'a>(&self, data: Cow<'a, [u8]>) -> Cow<'a, [u8]> {
+        if *self == InputEncoding::Utf8 || data.is_ascii() {
+            return data;
+        }
+        let encoded = String::from_utf8_lossy(&data).chars().map(|c| match (self, u8::try_from(u32::from(c))) {
+            (InputEncoding::Windows1252, Ok(0x80..=0x9f)) | (InputEncoding::Windows1252, Err(_)) => WINDOWS_1252_HIGH.iter()
+                .position(|&high| high == c)
+                .map_or(b'?', |index| 0x80 + index as u8),
+            (_, Ok(byte)) => byte,
+            (_, Err(_)) => b'?',
+        }).collect();
+        Cow::Owned(encoded)
+    }
+}
 
-        let record = SmartModuleRecord::new(Record::new(INPUT), 0, 0);
-        let result = apply_regex_ops_to_json_record(&record, &ops).unwrap();
-        let result_value: Value = serde_json::from_str(result.as_str()).unwrap();
+/// The record value within the size limit and decoded according to `input_encoding` and `utf8`,
+/// `None` when the record is skipped
+fn checked_value<'a>(record: &'a SmartModuleRecord, spec: &Spec) -> Result<Option<Cow<'a, [u8]>>> {
+    let value = match &spec.size_limit {
+        Some(limit) => limit.check(record.value.as_ref())?,
+        None => Some(record.value.as_ref()),
+    };
+    let value = value.map(|value| match spec.input_encoding.decode(value) {
+        Cow::Borrowed(value) => spec.utf8.decode(value),
+        Cow::Owned(decoded) => Ok(Cow::Owned(decoded)),
+    }).transpose()?;
+    match (value, &spec.codec) {
+        (Some(value), Some(codec)) => Ok(Some(Cow::Owned(codec.decode(&value)?))),
+        (value, _) => Ok(value),
+    }
+}
 
-        let expected_value: Value = serde_json::from_str(EXPECTED).unwrap();
-        assert_eq!(result_value, expected_value);
+/// Output value of a record, encoded back to protobuf or Avro unless the operations left `decoded` unchanged,
+/// or to the `input_encoding`
+fn output_value(spec: &Spec, data: Cow<[u8]>, decoded: &[u8], original: &RecordData) -> Result<RecordData> {
+    let data = match &spec.checksum {
+        Some(checksum) => Cow::Owned(checksum.attach(&data, original.as_ref())?),
+        None => data,
+    };
+    let data = match spec.post_process {
+        Some(post_process) => post_process.apply(data),
+        None => data,
+    };
+    if let Some(schema) = &spec.output_schema {
+        let output = serde_json::from_slice(&data).map_err(|err| eyre!("output record is not JSON, as `{OUTPUT_SCHEMA_PARAM}` requires: {err}"))?;
+        schema.validate(&output).wrap_err_with(|| format!("output record violates `{OUTPUT_SCHEMA_PARAM}`"))?;
+    }
+    match &spec.codec {
+        Some(_) if std::ptr::eq(data.as_ref(), decoded) => Ok(original.clone()),
+        Some(codec) => Ok(codec.encode(&data)?.into()),
+        None => Ok(into_record_data(spec.input_encoding.encode(data), original)),
+    }
+}
+
+/// What to do with a record that cannot be processed, e.g. invalid UTF-8
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum OnError {
+    #[default]
+    Fail,
+    Skip,
+    Passthrough,
+}
+
+/// How records whose JSON root is an array go through the operations
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum ArrayRoot {
+    /// Run the operations once on the whole array
+    #[default]
+    Whole,
+    /// Run the operations on each element on its own and put the array back together
+    Each,
+    /// Emit each element as its own record, run through the operations (array-map build)
+    Explode,
+}
+
+impl ArrayRoot {
+    fn from_params(params: &SmartModuleExtraParams, mode: Mode) -> Result<Self> {
+        let array_root: ArrayRoot = get_keyword_param(params, ARRAY_ROOT_PARAM)?;
+        if array_root == ArrayRoot::Whole {
+            return Ok(array_root);
+        }
+        if !matches!(get_keyword_param(params, FORMAT_PARAM)?, RecordFormat::Text | RecordFormat::Json) {
+            return Err(eyre!("`{ARRAY_ROOT_PARAM}` applies to single JSON documents, it needs `{FORMAT_PARAM}: json` or the default text format"));
+        }
+        match array_root {
+            ArrayRoot::Each if !cfg!(any(feature = "map", feature = "filter-map")) => {
+                return Err(eyre!("`{ARRAY_ROOT_PARAM}: each` rewrites array elements in place and needs the map or filter-map build, use `explode` in the array-map build"));
+            }
+            ArrayRoot::Explode if !cfg!(feature = "array-map") => {
+                return Err(eyre!("`{ARRAY_ROOT_PARAM}: explode` emits a record per element and needs the array-map build, use `each`"));
+            }
+            _ => {}
+        }
+        if mode == Mode::Detect {
+            return Err(eyre!("`{MODE_PARAM}: detect` reports on whole records, remove `{ARRAY_ROOT_PARAM}`"));
+        }
+        Ok(array_root)
+    }
+
+    /// Elements of a record whose JSON root is an array, as written; `None` for other records and with `whole`
+    fn elements<'a>(&self, value: &'a [u8]) -> Option<Vec<&'a [u8]>> {
+        if *self == ArrayRoot::Whole || value.iter().find(|byte| !byte.is_ascii_whitespace()) != Some(&b'[') {
+            return None;
+        }
+        let elements: Vec<&serde_json::value::RawValue> = serde_json::from_slice(value).ok()?;
+        Some(elements.into_iter().map(|element| element.get().as_bytes()).collect())
+    }
+}
+
+/// Format of the records: `csv` lets operations target a single column, `json` applies operations
+/// without a `path` to every string value so the output stays valid JSON, `ndjson` does the same
+/// on each line, and `protobuf` and `avro` operations target fields of the decoded record by `path`
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum RecordFormat {
+    #[default]
+    Text,
+    Csv,
+    Json,
+    Ndjson,
+    Xml,
+    Protobuf,
+    Avro,
+}
+
+/// Serialization format of the `spec` param
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum SpecFormat {
+    #[default]
+    Json,
+    Yaml,
+}
+
+impl SpecFormat {
+    fn parse<T: DeserializeOwned>(&self, raw: &str) -> Result<T> {
+        match self {
+            SpecFormat::Json => Ok(serde_json::from_str(raw)?),
+            // go through a JSON value so enums keep the `- replace: {..}` map shape instead of YAML tags
+            SpecFormat::Yaml => {
+                let value: Value = serde_yaml::from_str(raw)?;
+                Ok(serde_json::from_value(value)?)
+            }
+        }
+    }
+}
+
+/// Settings shared by every operation
+#[derive(Debug, Deserialize)]
+struct OpOptions {
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+    #[serde(default)]
+    path: Option<JsonPath>,
+    /// CSV column name, resolved into `column_index` through the `csv_columns` param
+    #[serde(default)]
+    column: Option<String>,
+    #[serde(default)]
+    column_index: Option<usize>,
+    /// `path` read as an XML path with the `format: xml` param
+    #[serde(skip)]
+    xml_path: Option<XmlPath>,
+    #[serde(default)]
+    flags: Flags,
+    /// Unicode classes and case folding in the regex
+    #[serde(default = "default_enabled")]
+    unicode: bool,
+    /// Unicode normalization of the text before matching, kept in the output
+    #[serde(default)]
+    normalize: Option<Normalization>,
+    /// Encoding of the text the regex runs on once decoded, the output is encoded back
+    #[serde(default)]
+    decode: Option<Decode>,
+    #[serde(default)]
+    engine: Engine,
+    /// Compile limits, over the `regex_limits` param ones
+    #[serde(default)]
+    limits: RegexLimits,
+    #[serde(default)]
+    target: Target,
+    /// Where `insert` and `append` read matches from, the output still goes to the JSON value
+    #[serde(default)]
+    input: Input,
+    #[serde(default)]
+    when: Option<When>,
+    /// Record headers are not exposed to SmartModules, these are only read to reject them at init
+    #[serde(default)]
+    when_header: Option<Value>,
+    #[serde(default)]
+    set_headers: Option<Value>,
+    /// Sample values checked against the operation at init, so an edited spec fails fast
+    #[serde(default)]
+    examples: Vec<Example>,
+    /// Most matches in a record before `on_max_matches` applies, counting stops past it
+    #[serde(default)]
+    max_matches: Option<usize>,
+    #[serde(default)]
+    on_max_matches: OnMaxMatches,
+    #[serde(default)]
+    severity: Severity,
+    /// Fraction of records the operation runs on, picked by a hash of the record offset
+    #[serde(default)]
+    sample_rate: Option<f64>,
+    /// Later operations must not match the output, checked at init
+    #[serde(default, rename = "final")]
+    final_output: bool,
+    /// Regions the operation matches in: each match of this regex, or its first capture group
+    #[serde(default)]
+    scope: Option<Pattern>,
+    /// Set when the operation is part of a stage
+    #[serde(skip)]
+    stage: Option<StageFlow>,
+}
+
+impl Default for OpOptions {
+    fn default() -> Self {
+        OpOptions {
+            name: None,
+            enabled: default_enabled(),
+            path: None,
+            column: None,
+            column_index: None,
+            xml_path: None,
+            flags: Flags::default(),
+            unicode: default_enabled(),
+            normalize: None,
+            decode: None,
+            engine: Engine::default(),
+            limits: RegexLimits::default(),
+            target: Target::default(),
+            input: Input::default(),
+            when: None,
+            when_header: None,
+            set_headers: None,
+            examples: vec![],
+            max_matches: None,
+            on_max_matches: OnMaxMatches::default(),
+            severity: Severity::default(),
+            sample_rate: None,
+            final_output: false,
+            scope: None,
+            stage: None,
+        }
+    }
+}
+
+/// Normal form the text is converted to before an operation matches it, e.g. `nfkc` turns
+/// full-width digits into ASCII ones. Values that are not valid UTF-8 are left as is.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Normalization {
+    Nfc,
+    Nfkc,
+}
+
+impl Normalization {
+    fn apply(self, data: &[u8]) -> Cow<'_, [u8]> {
+        let Ok(text) = std::str::from_utf8(data) else {
+            return Cow::Borrowed(data);
+        };
+        let normalized: String = match self {
+            Normalization::Nfc if is_nfc_quick(text.chars()) == IsNormalized::Yes => return Cow::Borrowed(data),
+            Normalization::Nfkc if is_nfkc_quick(text.chars()) == IsNormalized::Yes => return Cow::Borrowed(data),
+            Normalization::Nfc => text.nfc().collect(),
+            Normalization::Nfkc => text.nfkc().collect(),
+        };
+        if normalized == text {
+            Cow::Borrowed(data)
+        } else {
+            Cow::Owned(normalized.into_bytes())
+        }
+    }
+}
+
+/// Encoding the text holds a document in, e.g. `json_string` for a field carrying an escaped
+/// JSON document. Text that does not decode is left as is.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Decode {
+    JsonString,
+}
+
+impl Decode {
+    /// Run `f` on each string of the decoded text and encode it back when one changed
+    fn map<'h>(self, data: &'h [u8], f: impl Fn(&[u8]) -> Cow<'_, [u8]>) -> Cow<'h, [u8]> {
+        let Decode::JsonString = self;
+        let Ok(mut value) = serde_json::from_slice::<Value>(data) else {
+            return Cow::Borrowed(data);
+        };
+        let mut changed = false;
+        for_each_string_mut(&mut value, &mut |text| {
+            if let Cow::Owned(result) = f(text.as_bytes()) {
+                *text = String::from_utf8_lossy(&result).into_owned();
+                changed = true;
+            }
+        });
+        if changed { Cow::Owned(value.to_string().into_bytes()) } else { Cow::Borrowed(data) }
+    }
+
+    /// The strings of the decoded text, `None` when it does not decode
+    fn texts(self, data: &[u8]) -> Option<Vec<String>> {
+        let Decode::JsonString = self;
+        let mut value = serde_json::from_slice::<Value>(data).ok()?;
+        let mut texts = vec![];
+        for_each_string_mut(&mut value, &mut |text| texts.push(std::mem::take(text)));
+        Some(texts)
+    }
+}
+
+/// Call `f` on every string of the value, the value itself included
+fn for_each_string_mut(value: &mut Value, f: &mut dyn FnMut(&mut String)) {
+    match value {
+        Value::String(text) => f(text),
+        Value::Array(items) => items.iter_mut().for_each(|item| for_each_string_mut(item, f)),
+        Value::Object(fields) => fields.values_mut().for_each(|field| for_each_string_mut(field, f)),
+        _ => {}
+    }
+}
+
+/// Named group of operations in the spec with flow control over the value operations:
+/// `stop_on_no_match` ends the pipeline when none of them matched, `stop_after_first_match`
+/// skips the rest of the stage after the first one that matched
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StageSpec {
+    stage: String,
+    ops: Vec<Value>,
+    #[serde(default)]
+    stop_on_no_match: bool,
+    #[serde(default)]
+    stop_after_first_match: bool,
+}
+
+/// Regex fragments named in `{"definitions": {...}}` spec entries, referenced as `{{name}}`
+/// in the `regex` of operations and of other definitions
+#[derive(Debug, Default)]
+struct Definitions {
+    fragments: BTreeMap<String, String>,
+}
+
+impl Definitions {
+    fn add(&mut self, definitions: serde_json::Map<String, Value>) -> Result<()> {
+        for (name, fragment) in definitions {
+            if !is_definition_name(&name) {
+                return Err(eyre!("definition name `{name}` must be letters, digits and `_`"));
+            }
+            let Value::String(fragment) = fragment else {
+                return Err(eyre!("definition `{name}` must be a regex string"));
+            };
+            if self.fragments.insert(name.clone(), fragment).is_some() {
+                return Err(eyre!("definition `{name}` is defined twice"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace each `{{name}}` with its fragment in a non-capturing group, so `{{ssn}}+` repeats it whole
+    fn expand(&self, regex: &str, expanding: &mut Vec<String>) -> Result<String> {
+        let mut expanded = String::new();
+        let mut rest = regex;
+        while let Some(start) = rest.find("{{") {
+            let reference = rest[start + 2..].find("}}").map(|end| &rest[start + 2..start + 2 + end])
+                .filter(|name| is_definition_name(name));
+            let Some(name) = reference else {
+                expanded.push_str(&rest[..start + 2]);
+                rest = &rest[start + 2..];
+                continue;
+            };
+            let fragment = self.fragments.get(name).ok_or_else(|| eyre!("`{{{{{name}}}}}` is not in the spec definitions"))?;
+            if expanding.iter().any(|outer| outer == name) {
+                return Err(eyre!("definition `{name}` refers to itself"));
+            }
+            expanding.push(name.to_string());
+            let fragment = self.expand(fragment, expanding)?;
+            expanding.pop();
+            expanded.push_str(&rest[..start]);
+            expanded.push_str(&format!("(?:{fragment})"));
+            rest = &rest[start + name.len() + 4..];
+        }
+        expanded.push_str(rest);
+        Ok(expanded)
+    }
+
+    /// Expand the `regex` fields of an operation entry, at any depth so `when` guards are covered
+    fn expand_entry(&self, entry: &mut Value) -> Result<()> {
+        match entry {
+            Value::Object(fields) => {
+                for (field, value) in fields.iter_mut() {
+                    match value {
+                        Value::String(regex) if field == "regex" => *regex = self.expand(regex, &mut vec![])?,
+                        value => self.expand_entry(value)?,
+                    }
+                }
+            }
+            Value::Array(items) => {
+                for item in items {
+                    self.expand_entry(item)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+fn is_definition_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Position of a stage in the resolved operation list, with its flow control
+#[derive(Debug, Clone, PartialEq)]
+struct StageFlow {
+    start: usize,
+    /// Index past the last operation of the stage
+    end: usize,
+    stop_on_no_match: bool,
+    stop_after_first_match: bool,
+}
+
+impl StageFlow {
+    fn controls(&self) -> bool {
+        self.stop_on_no_match || self.stop_after_first_match
+    }
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl OpOptions {
+    /// With the `format: xml` param, `path` selects XML elements or attributes
+    fn resolve_xml_path(&mut self, format: &RecordFormat) -> Result<()> {
+        if *format != RecordFormat::Xml {
+            return Ok(());
+        }
+        if let Some(path) = self.path.take() {
+            self.xml_path = Some(path.to_string().parse()?);
+        }
+        Ok(())
+    }
+
+    /// Check that column targeting is used on CSV records and turn a `column` name into its index
+    fn resolve_column(&mut self, format: &RecordFormat, params: &SmartModuleExtraParams) -> Result<()> {
+        if self.column.is_none() && self.column_index.is_none() {
+            return Ok(());
+        }
+        if *format != RecordFormat::Csv {
+            return Err(eyre!("`column` and `column_index` need the `{FORMAT_PARAM}: csv` param"));
+        }
+        if self.path.is_some() {
+            return Err(eyre!("use either `path` or a column, not both"));
+        }
+
+        if let Some(column) = &self.column {
+            if self.column_index.is_some() {
+                return Err(eyre!("use either `column` or `column_index`, not both"));
+            }
+            let header = params.get(CSV_COLUMNS_PARAM)
+                .ok_or_else(|| eyre!("`column` needs the `{CSV_COLUMNS_PARAM}` param to name the columns"))?;
+            let index = csv_record::column_index(header, column)
+                .ok_or_else(|| eyre!("unknown column `{column}`, expected one of `{header}`"))?;
+            self.column_index = Some(index);
+        }
+        Ok(())
+    }
+}
+
+/// What an operation does with a record that has more than `max_matches` matches
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum OnMaxMatches {
+    /// Fail the record, which is then handled by `on_error`
+    #[default]
+    Fail,
+    /// Leave the record as it is for this operation and log a warning
+    Skip,
+}
+
+/// Enforcement tier of an operation, so one spec can detect, scrub and block
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Severity {
+    /// Rewrite the matches
+    #[default]
+    Redact,
+    /// Leave the matches as they are and log how many there were
+    Warn,
+    /// Drop records the operation matches as received, in the filter-map build
+    Block,
+}
+
+/// Value an operation must rewrite `input` to, compared as JSON when both sides parse as JSON
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Example {
+    input: String,
+    expected: String,
+}
+
+impl Example {
+    fn holds(&self, output: &[u8]) -> bool {
+        let expected = self.expected.as_bytes();
+        match (serde_json::from_slice::<Value>(output), serde_json::from_slice::<Value>(expected)) {
+            (Ok(output), Ok(expected)) => output == expected,
+            _ => output == expected,
+        }
+    }
+}
+
+/// Guard that runs an operation only on matching records: `regex` alone matches the whole record,
+/// with a `path` the selected value must equal `equals`, match `regex`, or just be present
+#[derive(Debug, Deserialize)]
+struct When {
+    #[serde(default)]
+    path: Option<JsonPath>,
+    #[serde(default)]
+    regex: Option<Pattern>,
+    #[serde(default)]
+    equals: Option<Value>,
+}
+
+impl When {
+    fn resolve(&mut self, limits: &RegexLimits) -> Result<()> {
+        if let Some(regex) = self.regex.as_mut() {
+            regex.compile_with(&Flags::default(), Engine::Default, limits)?;
+        }
+        match (&self.path, &self.regex, &self.equals) {
+            (None, None, _) => Err(eyre!("`when` needs a `regex` or a `path`")),
+            (None, Some(_), Some(_)) => Err(eyre!("`when.equals` needs a `path`")),
+            _ => Ok(()),
+        }
+    }
+
+    fn holds(&self, data: &mut Data) -> Result<bool> {
+        let Some(path) = &self.path else {
+            return Ok(self.regex.as_ref().is_some_and(|regex| regex.is_match(data.bytes())));
+        };
+
+        let json = data.json_mut()?;
+        let holds = path.select(json).into_iter().any(|value| {
+            match (&self.equals, &self.regex) {
+                (Some(expected), _) => value == expected,
+                (None, Some(regex)) => value.as_str().is_some_and(|text| regex.is_match(text.as_bytes())),
+                (None, None) => !value.is_null(),
+            }
+        });
+        Ok(holds)
+    }
+}
+
+/// Part of the record an operation applies to
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Target {
+    Key,
+    #[default]
+    Value,
+    Both,
+}
+
+impl Target {
+    fn key(&self) -> bool {
+        matches!(self, Target::Key | Target::Both)
+    }
+
+    fn value(&self) -> bool {
+        matches!(self, Target::Value | Target::Both)
+    }
+}
+
+/// Part of the record an operation reads: `value`, `key` or `header(name)`
+#[derive(Debug, Default, Clone, PartialEq)]
+enum Input {
+    #[default]
+    Value,
+    Key,
+    /// Parsed to reject it at init with an explanation, headers carry no values in SmartModules
+    Header(String),
+}
+
+impl std::str::FromStr for Input {
+    type Err = String;
+
+    fn from_str(raw: &str) -> std::result::Result<Self, String> {
+        match raw {
+            "value" => Ok(Input::Value),
+            "key" => Ok(Input::Key),
+            raw => match raw.strip_prefix("header(").and_then(|rest| rest.strip_suffix(')')) {
+                Some(name) => Ok(Input::Header(name.to_string())),
+                None => Err(format!("unknown input `{raw}`, expected `value`, `key` or `header(name)`")),
+            },
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Input {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let raw = String::deserialize(deserializer)?;
+        raw.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Operation {
+    Replace(Replace),
+    ReplaceFirst(Replace),
+    Extract(Extract),
+    Find(Extract),
+    Mask(Mask),
+    MaskCreditCard(CardMask),
+    Hash(Hash),
+    Placeholder(Placeholder),
+    AnonymizeIp(AnonymizeIp),
+    Encrypt(Encrypt),
+    Decrypt(Encrypt),
+    Translate(Translate),
+    Normalize(Normalize),
+    Case(Case),
+    TrimField(TrimField),
+    Timestamp(Timestamp),
+    Url(Url),
+    Reorder(Reorder),
+    DeleteField(DeleteField),
+    Redact(Redact),
+    SetKey(SetKey),
+    Insert(AddField),
+    Append(AddField),
+    Route(Route),
+    Match(Match),
+    NotMatch(Match),
+    Split(Explode),
+    FindAll(Explode),
+}
+
+#[derive(Debug, Deserialize)]
+struct Replace {
+    #[serde(flatten)]
+    regex: Pattern,
+    with: String,
+    /// Maximum number of matches to replace, `0` replaces all
+    #[serde(default)]
+    limit: usize,
+    /// Capture group name or number whose text is replaced, the rest of the match is kept
+    #[serde(default)]
+    group: Option<CaptureGroup>,
+    /// Write `with` as is, `$` included, instead of expanding capture references
+    #[serde(default)]
+    literal_replacement: bool,
+    /// Replacement per named group, for matches that group took part in; `with` replaces the others
+    #[serde(default)]
+    cases: BTreeMap<String, String>,
+    /// `with` parsed at init when it calls template functions
+    #[serde(skip)]
+    template: Option<Template>,
+    #[serde(flatten)]
+    options: OpOptions,
+}
+
+/// Replace the record with a JSON object built from the named capture groups
+#[derive(Debug, Deserialize)]
+struct Extract {
+    #[serde(flatten)]
+    regex: Pattern,
+    #[serde(flatten)]
+    options: OpOptions,
+}
+
+/// Mask letters and digits of each match, keeping its length and punctuation
+#[derive(Debug, Deserialize)]
+struct Mask {
+    #[serde(flatten)]
+    regex: Pattern,
+    #[serde(default = "default_mask_char")]
+    mask_char: char,
+    #[serde(flatten)]
+    options: OpOptions,
+}
+
+fn default_mask_char() -> char {
+    '*'
+}
+
+/// Mask the digits of card numbers that pass the Luhn checksum, keeping the last ones
+#[derive(Debug, Deserialize)]
+struct CardMask {
+    /// Candidate card numbers, checked against the Luhn checksum before masking
+    #[serde(default = "default_card_candidates")]
+    regex: Pattern,
+    #[serde(default = "default_keep_last")]
+    keep_last: usize,
+    #[serde(default = "default_mask_char")]
+    mask_char: char,
+    #[serde(flatten)]
+    options: OpOptions,
+}
+
+fn default_card_candidates() -> Pattern {
+    Pattern::from(r"\b[0-9](?:[ -]?[0-9]){12,}\b")
+}
+
+fn default_keep_last() -> usize {
+    4
+}
+
+/// Replace each match with the hex digest of the salt followed by the matched text
+#[derive(Debug, Deserialize)]
+struct Hash {
+    #[serde(flatten)]
+    regex: Pattern,
+    #[serde(default)]
+    algorithm: HashAlgorithm,
+    /// Name of the init param holding the salt
+    #[serde(default)]
+    salt: Option<String>,
+    #[serde(skip)]
+    salt_value: String,
+    #[serde(flatten)]
+    options: OpOptions,
+}
+
+/// Replace each distinct match with a `<LABEL_id>` token, so repeated values can be correlated without
+/// being seen: `scope: record` numbers values in order of appearance in the record, `scope: stream`
+/// uses the first 8 hex digits of their salted SHA-256 digest, the same in every record
+#[derive(Debug, Deserialize)]
+struct Placeholder {
+    #[serde(flatten)]
+    regex: Pattern,
+    label: String,
+    #[serde(default)]
+    scope: PlaceholderScope,
+    /// Name of the init param holding the salt of `scope: stream` digests
+    #[serde(default)]
+    salt: Option<String>,
+    #[serde(skip)]
+    salt_value: String,
+    #[serde(flatten)]
+    options: OpOptions,
+}
+
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum PlaceholderScope {
+    #[default]
+    Record,
+    Stream,
+}
+
+thread_local! {
+    /// Values numbered under each `placeholder` label in the record being processed, in order of appearance
+    static PLACEHOLDER_VALUES: RefCell<BTreeMap<String, Vec<Vec<u8>>>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+/// Anonymize the IPv4 and IPv6 addresses among the matches: `truncate` zeroes the bits after
+/// `prefix_v4`/`prefix_v6`, `hash` replaces the bits after the prefix (none by default) with salted
+/// pseudonym bits so addresses sharing a prefix keep sharing it. Matches that are not addresses are kept.
+#[derive(Debug, Deserialize)]
+struct AnonymizeIp {
+    #[serde(default = "default_ip_candidates")]
+    regex: Pattern,
+    #[serde(default)]
+    strategy: IpStrategy,
+    #[serde(default)]
+    prefix_v4: Option<u32>,
+    #[serde(default)]
+    prefix_v6: Option<u32>,
+    /// Name of the init param holding the salt of `hash`
+    #[serde(default)]
+    salt: Option<String>,
+    #[serde(skip)]
+    salt_value: String,
+    #[serde(flatten)]
+    options: OpOptions,
+}
+
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum IpStrategy {
+    #[default]
+    Truncate,
+    Hash,
+}
+
+fn default_ip_candidates() -> Pattern {
+    Pattern::from(format!("{}|{}", BuiltinPattern::Ipv4.regex(), BuiltinPattern::Ipv6.regex()).as_str())
+}
+
+/// Replace each match with its deterministic AES-SIV token, or each token with its plaintext for `decrypt`
+#[derive(Debug, Deserialize)]
+struct Encrypt {
+    #[serde(flatten)]
+    regex: Pattern,
+    /// Name of the init param holding the base64 key
+    key: String,
+    #[serde(skip)]
+    cipher: Option<TokenCipher>,
+    #[serde(flatten)]
+    options: OpOptions,
+}
+
+impl Encrypt {
+    fn cipher(&self) -> &TokenCipher {
+        self.cipher.as_ref().expect("cipher loaded at init")
+    }
+
+    /// Tokens that don't decrypt with the key are left as is
+    fn decrypt(&self, token: &[u8]) -> String {
+        match self.cipher().decrypt(token) {
+            Ok(plaintext) => String::from_utf8_lossy(&plaintext).into_owned(),
+            Err(err) => {
+                logging::log(LogLevel::Warn, &[("key", &self.key)], format_args!("{err}"));
+                String::from_utf8_lossy(token).into_owned()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Sha1,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    fn digest(&self, salt: &[u8], data: &[u8]) -> Vec<u8> {
+        use sha2::Digest;
+
+        match self {
+            HashAlgorithm::Sha256 => sha2::Sha256::new().chain_update(salt).chain_update(data).finalize().to_vec(),
+            HashAlgorithm::Sha1 => sha1::Sha1::new().chain_update(salt).chain_update(data).finalize().to_vec(),
+            HashAlgorithm::Blake3 => blake3::Hasher::new().update(salt).update(data).finalize().as_bytes().to_vec(),
+        }
+    }
+
+    fn hex_digest(&self, salt: &[u8], data: &[u8]) -> String {
+        let digest = self.digest(salt, data);
+        digest.iter().fold(String::with_capacity(digest.len() * 2), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+    }
+}
+
+/// Replace literal tokens from a dictionary param in a single pass over the record
+#[derive(Debug, Deserialize)]
+struct Translate {
+    /// Name of the init param holding the token to replacement map
+    #[serde(default = "default_dictionary")]
+    dictionary: String,
+    #[serde(skip)]
+    automaton: Option<AhoCorasick>,
+    #[serde(skip)]
+    replacements: Vec<String>,
+    #[serde(flatten)]
+    options: OpOptions,
+}
+
+fn default_dictionary() -> String {
+    "dictionary".to_string()
+}
+
+/// Clean up text with built-in presets instead of hand-written regexes, in the listed order.
+/// Values that are not valid UTF-8 are left as is.
+#[derive(Debug, Deserialize)]
+struct Normalize {
+    presets: Vec<NormalizePreset>,
+    #[serde(flatten)]
+    options: OpOptions,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum NormalizePreset {
+    /// Replace each run of whitespace, including newlines, with a single space
+    CollapseWhitespace,
+    /// Remove control characters other than tab, carriage return and newline
+    StripControlChars,
+    /// Remove leading and trailing whitespace
+    Trim,
+}
+
+/// Convert each match to another letter case, e.g. `New York` to `new_york` with `snake`
+#[derive(Debug, Deserialize)]
+struct Case {
+    #[serde(flatten)]
+    regex: Pattern,
+    to: CaseStyle,
+    #[serde(flatten)]
+    options: OpOptions,
+}
+
+/// Cut each match, or the text of its `group`, to `max_chars` characters ending with `marker`,
+/// e.g. to keep free text under the column size of a downstream system. Matches the whole value by default.
+#[derive(Debug, Deserialize)]
+struct TrimField {
+    #[serde(default = "default_trim_regex")]
+    regex: Pattern,
+    max_chars: usize,
+    #[serde(default)]
+    marker: String,
+    #[serde(default)]
+    group: Option<CaptureGroup>,
+    #[serde(flatten)]
+    options: OpOptions,
+}
+
+fn default_trim_regex() -> Pattern {
+    Pattern::from(r"(?s).+")
+}
+
+/// Parse each match as a datetime in `from_format` and write it in UTC in `to_format` (RFC 3339 by default).
+/// Datetimes without an offset are read in `timezone`, a fixed offset such as `+02:00` (UTC by default).
+/// Matches that do not parse are kept.
+#[derive(Debug, Deserialize)]
+struct Timestamp {
+    #[serde(flatten)]
+    regex: Pattern,
+    from_format: TimeFormat,
+    #[serde(default)]
+    to_format: TimeFormat,
+    #[serde(default)]
+    timezone: Option<String>,
+    #[serde(skip)]
+    offset: Option<chrono::FixedOffset>,
+    #[serde(flatten)]
+    options: OpOptions,
+}
+
+/// Move the capture groups of each match around, e.g. `${first} ${last}` from `(?P<last>\w+), (?P<first>\w+)`.
+/// `to` holds only capture references and the text between them, and uses every group once.
+#[derive(Debug, Deserialize)]
+struct Reorder {
+    #[serde(flatten)]
+    regex: Pattern,
+    to: String,
+    #[serde(flatten)]
+    options: OpOptions,
+}
+
+/// Rewrite components of the URLs among the matches with a URL parser rather than a regex, so links stay
+/// valid: drop the `strip` query parameters (every one with `*`), replace the user info with `REDACTED`
+/// and drop the fragment. Matches that do not parse as URLs are kept.
+#[derive(Debug, Deserialize)]
+struct Url {
+    #[serde(default = "default_url_candidates")]
+    regex: Pattern,
+    #[serde(default)]
+    strip: Vec<String>,
+    #[serde(default)]
+    redact_userinfo: bool,
+    #[serde(default)]
+    strip_fragment: bool,
+    #[serde(flatten)]
+    options: OpOptions,
+}
+
+/// Absolute URLs, without trailing punctuation of the surrounding sentence
+fn default_url_candidates() -> Pattern {
+    Pattern::from(r#"\b[A-Za-z][A-Za-z0-9+.-]*://[^\s"'<>]*[^\s"'<>.,;:!?)\]]"#)
+}
+
+/// Datetime layout: a named format or a strftime pattern such as `%d/%m/%Y %H:%M`
+#[derive(Debug, Default, Clone, PartialEq)]
+enum TimeFormat {
+    #[default]
+    Rfc3339,
+    Rfc2822,
+    /// Seconds since the epoch
+    Unix,
+    /// Milliseconds since the epoch
+    UnixMs,
+    Strftime(String),
+}
+
+impl std::str::FromStr for TimeFormat {
+    type Err = String;
+
+    fn from_str(raw: &str) -> std::result::Result<Self, String> {
+        match raw {
+            "rfc3339" => Ok(TimeFormat::Rfc3339),
+            "rfc2822" => Ok(TimeFormat::Rfc2822),
+            "unix" => Ok(TimeFormat::Unix),
+            "unix_ms" => Ok(TimeFormat::UnixMs),
+            raw if chrono::format::StrftimeItems::new(raw).any(|item| item == chrono::format::Item::Error) => {
+                Err(format!("invalid time format `{raw}`, expected `rfc3339`, `rfc2822`, `unix`, `unix_ms` or a strftime pattern"))
+            }
+            raw => Ok(TimeFormat::Strftime(raw.to_string())),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeFormat {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let raw = String::deserialize(deserializer)?;
+        raw.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum CaseStyle {
+    Upper,
+    Lower,
+    /// First letter of each word upper case and the rest lower case, separators kept
+    Title,
+    /// Lower case words joined with `_`
+    Snake,
+    /// Words joined without separator, each but the first capitalized
+    Camel,
+}
+
+/// Remove JSON fields whose name matches `regex` at any depth, or the values selected by `path`.
+/// With both, fields matching `regex` are removed under each selected value.
+#[derive(Debug, Deserialize)]
+struct DeleteField {
+    #[serde(default)]
+    regex: Option<Pattern>,
+    #[serde(flatten)]
+    options: OpOptions,
+}
+
+/// Mask every string value of JSON records, like `mask`, except the values selected by an `allow` path
+/// and the ones whose JSON pointer (e.g. `/user/id`) matches `allow_regex`. Other values are kept.
+#[derive(Debug, Deserialize)]
+struct Redact {
+    #[serde(default)]
+    allow: Vec<JsonPath>,
+    #[serde(default)]
+    allow_regex: Option<Pattern>,
+    #[serde(default = "default_mask_char")]
+    mask_char: char,
+    #[serde(flatten)]
+    options: OpOptions,
+}
+
+/// Add a top-level `field` to JSON records the regex matches: `insert` sets it from the first match,
+/// `append` pushes a value per match to an array. String values expand capture groups, e.g. `$1`.
+#[derive(Debug, Deserialize)]
+struct AddField {
+    #[serde(flatten)]
+    regex: Pattern,
+    field: String,
+    #[serde(default = "default_field_value")]
+    value: Value,
+    #[serde(flatten)]
+    options: OpOptions,
+}
+
+fn default_field_value() -> Value {
+    Value::Bool(true)
+}
+
+/// Write the label of the first `routes` entry whose regex matches into a top-level `field` of JSON
+/// records, or the labels of every matching entry as an array with `all`, so downstream filters
+/// read the label instead of running the regexes again
+#[derive(Debug, Deserialize)]
+struct Route {
+    routes: Vec<RouteRule>,
+    #[serde(default = "default_route_field")]
+    field: String,
+    #[serde(default)]
+    all: bool,
+    /// Label written when no route matches, the field is left out otherwise
+    #[serde(default)]
+    default: Option<String>,
+    #[serde(flatten)]
+    options: OpOptions,
+}
+
+#[derive(Debug, Deserialize)]
+struct RouteRule {
+    label: String,
+    #[serde(flatten)]
+    regex: Pattern,
+}
+
+fn default_route_field() -> String {
+    "route".to_string()
+}
+
+/// Set the output record key from a capture of the record value, read as received before
+/// the other operations so the key can come from a field that is masked afterwards
+#[derive(Debug, Deserialize)]
+struct SetKey {
+    #[serde(flatten)]
+    regex: Pattern,
+    /// Capture group name or number, the whole match when absent
+    #[serde(default)]
+    group: Option<CaptureGroup>,
+    /// Hash the capture into one of this many buckets and use the bucket number instead,
+    /// so keys are anonymized while equal captures keep landing on the same partition
+    #[serde(default)]
+    buckets: Option<u64>,
+    #[serde(default)]
+    algorithm: HashAlgorithm,
+    #[serde(default)]
+    combine: CombineKey,
+    #[serde(default = "default_key_separator")]
+    separator: String,
+    #[serde(flatten)]
+    options: OpOptions,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum CaptureGroup {
+    Index(usize),
+    Name(String),
+}
+
+impl CaptureGroup {
+    fn check(&self, regex: &Pattern) -> Result<()> {
+        match self {
+            CaptureGroup::Index(index) if *index >= regex.captures_len() => {
+                Err(eyre!("`group` {index} is not in the regex, which has {} groups", regex.captures_len() - 1))
+            }
+            CaptureGroup::Name(name) if !regex.capture_names().flatten().any(|n| n == name) => {
+                Err(eyre!("`group` `{name}` is not named in the regex"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn get<'h>(&self, caps: &Captures<'h>) -> Option<regex::bytes::Match<'h>> {
+        match self {
+            CaptureGroup::Index(index) => caps.get(*index),
+            CaptureGroup::Name(name) => caps.name(name),
+        }
+    }
+}
+
+/// How a captured key is combined with the existing record key
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum CombineKey {
+    #[default]
+    Replace,
+    Prepend,
+    Append,
+}
+
+fn default_key_separator() -> String {
+    ":".to_string()
+}
+
+/// Predicate used by the filter build to keep or drop records
+#[derive(Debug, Deserialize)]
+struct Match {
+    #[serde(flatten)]
+    regex: Pattern,
+    #[serde(flatten)]
+    options: OpOptions,
+}
+
+/// Turn a record into one record per piece in the array_map build: `split` emits the text between
+/// matches, `find_all` emits each match (or its capture groups as a JSON object or array).
+/// With an `output`, the pieces are reassembled into a single value in any build instead.
+#[derive(Debug, Deserialize)]
+struct Explode {
+    #[serde(flatten)]
+    regex: Pattern,
+    #[serde(default)]
+    output: Option<PiecesOutput>,
+    /// Between pieces with `output: join`, `,` by default
+    #[serde(default)]
+    separator: Option<String>,
+    #[serde(flatten)]
+    options: OpOptions,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum PiecesOutput {
+    Join,
+    JsonArray,
+}
+
+/// Record contents while running operations, borrowed from the record until an operation changes it
+/// and parsed only when an operation targets a JSON path
+enum Data<'a> {
+    Bytes(Cow<'a, [u8]>),
+    Json(Value),
+    Csv(CsvRecord),
+    Xml(XmlRecord),
+}
+
+impl<'a> Data<'a> {
+    fn bytes(&mut self) -> &[u8] {
+        match self {
+            Data::Json(value) => *self = Data::Bytes(Cow::Owned(value.to_string().into_bytes())),
+            Data::Csv(record) => *self = Data::Bytes(Cow::Owned(record.to_bytes())),
+            Data::Xml(record) => *self = Data::Bytes(Cow::Owned(record.to_bytes())),
+            Data::Bytes(_) => {}
+        }
+        match self {
+            Data::Bytes(bytes) => bytes,
+            _ => unreachable!(),
+        }
+    }
+
+    fn json_mut(&mut self) -> Result<&mut Value> {
+        if !matches!(self, Data::Json(_)) {
+            let value = serde_json::from_slice(self.bytes())
+                .map_err(|err| eyre!("json path operations require a JSON record: {err}"))?;
+            *self = Data::Json(value);
+        }
+        match self {
+            Data::Json(value) => Ok(value),
+            _ => unreachable!(),
+        }
+    }
+
+    fn csv_mut(&mut self) -> Result<&mut CsvRecord> {
+        if !matches!(self, Data::Csv(_)) {
+            *self = Data::Csv(CsvRecord::parse(self.bytes())?);
+        }
+        match self {
+            Data::Csv(record) => Ok(record),
+            _ => unreachable!(),
+        }
+    }
+
+    fn xml_mut(&mut self) -> Result<&mut XmlRecord> {
+        if !matches!(self, Data::Xml(_)) {
+            *self = Data::Xml(XmlRecord::parse(self.bytes())?);
+        }
+        match self {
+            Data::Xml(record) => Ok(record),
+            _ => unreachable!(),
+        }
+    }
+
+    fn into_bytes(mut self) -> Cow<'a, [u8]> {
+        self.bytes();
+        match self {
+            Data::Bytes(bytes) => bytes,
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// How `match`/`not_match` predicates are combined by the filter build
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum FilterMode {
+    #[default]
+    All,
+    Any,
+}
+
+impl Operation {
+    /// Borrows the input when nothing matched
+    pub fn run_regex<'h>(&self, data: &'h [u8]) -> Cow<'h, [u8]> {
+        match self.options().decode {
+            Some(decode) => decode.map(data, |text| self.run_scoped(text)),
+            None => self.run_scoped(data),
+        }
+    }
+
+    fn run_scoped<'h>(&self, data: &'h [u8]) -> Cow<'h, [u8]> {
+        let Some(regions) = self.scope_regions(data) else {
+            return self.run_regex_in(data);
+        };
+        let mut output = Vec::with_capacity(data.len());
+        let (mut last, mut changed) = (0, false);
+        for region in regions {
+            output.extend_from_slice(&data[last..region.start]);
+            let result = self.run_regex_in(&data[region.clone()]);
+            changed |= matches!(result, Cow::Owned(_));
+            output.extend_from_slice(&result);
+            last = region.end;
+        }
+        output.extend_from_slice(&data[last..]);
+        if changed { Cow::Owned(output) } else { Cow::Borrowed(data) }
+    }
+
+    /// Ranges of the `scope` regions in the data, `None` when the operation is not scoped
+    fn scope_regions(&self, data: &[u8]) -> Option<Vec<std::ops::Range<usize>>> {
+        let scope = self.options().scope.as_ref()?;
+        Some(scope.captures_iter(data)
+            .filter_map(|caps| caps.get(1).or_else(|| caps.get(0)))
+            .map(|region| region.range())
+            .collect())
+    }
+
+    fn run_regex_in<'h>(&self, data: &'h [u8]) -> Cow<'h, [u8]> {
+        match self.normalized(data) {
+            Cow::Borrowed(data) => self.run_regex_on(data),
+            Cow::Owned(normalized) => Cow::Owned(self.run_regex_on(&normalized).into_owned()),
+        }
+    }
+
+    /// The data with the `normalize` setting of the operation applied
+    fn normalized<'h>(&self, data: &'h [u8]) -> Cow<'h, [u8]> {
+        match self.options().normalize {
+            Some(normalization) => normalization.apply(data),
+            None => Cow::Borrowed(data),
+        }
+    }
+
+    fn run_regex_on<'h>(&self, data: &'h [u8]) -> Cow<'h, [u8]> {
+        match self {
+            Operation::Replace(r) => r.replace(data, r.limit),
+            Operation::ReplaceFirst(r) => r.replace(data, 1),
+            Operation::Extract(e) => match e.extract(data) {
+                Some(obj) => Cow::Owned(obj.to_string().into_bytes()),
+                None => Cow::Borrowed(data),
+            },
+            Operation::Find(f) => Cow::Owned(f.find(data).to_string().into_bytes()),
+            Operation::Mask(m) => {
+                m.regex.replace_all_with(data, |matched| m.mask(matched))
+            }
+            Operation::MaskCreditCard(c) => c.mask(data),
+            Operation::Hash(h) => {
+                h.regex.replace_all_with(data, |matched| {
+                    h.algorithm.hex_digest(h.salt_value.as_bytes(), matched)
+                })
+            }
+            Operation::Placeholder(p) => p.regex.replace_all_with(data, |matched| p.token(matched)),
+            Operation::AnonymizeIp(a) => a.regex.replace_all_with(data, |matched| a.anonymize(matched)),
+            Operation::Encrypt(e) => {
+                e.regex.replace_all_with(data, |matched| String::from_utf8_lossy(&e.cipher().encrypt(matched)).into_owned())
+            }
+            Operation::Decrypt(e) => e.regex.replace_all_with(data, |token| e.decrypt(token)),
+            Operation::Translate(t) => t.translate(data),
+            Operation::Normalize(n) => n.normalize(data),
+            Operation::Case(c) => c.regex.replace_all_with(data, |matched| c.to.apply(&String::from_utf8_lossy(matched))),
+            Operation::TrimField(t) => t.trim(data),
+            Operation::Timestamp(t) => t.regex.replace_all_with(data, |matched| t.rewrite(matched)),
+            Operation::Url(u) => match u.count(data, 1) {
+                0 => Cow::Borrowed(data),
+                _ => u.regex.replace_all_with(data, |matched| u.rewrite(matched)),
+            },
+            Operation::Reorder(r) => r.regex.replacen_expand(data, 0, &r.to),
+            Operation::DeleteField(_) | Operation::Redact(_) | Operation::SetKey(_) => Cow::Borrowed(data),
+            Operation::Insert(_) | Operation::Append(_) | Operation::Route(_) => Cow::Borrowed(data),
+            Operation::Match(_) | Operation::NotMatch(_) => Cow::Borrowed(data),
+            Operation::Split(_) | Operation::FindAll(_) => match self.reassembled(data) {
+                Some(Value::String(text)) if text.as_bytes() == data => Cow::Borrowed(data),
+                Some(Value::String(text)) => Cow::Owned(text.into_bytes()),
+                Some(value) => Cow::Owned(value.to_string().into_bytes()),
+                None => Cow::Borrowed(data),
+            },
+        }
+    }
+
+    fn explodes(&self) -> bool {
+        matches!(self, Operation::Split(e) | Operation::FindAll(e) if e.output.is_none())
+    }
+
+    /// Pieces of a `split`/`find_all` with an `output` put back together, `None` for other operations
+    fn reassembled(&self, data: &[u8]) -> Option<Value> {
+        let (Operation::Split(e) | Operation::FindAll(e)) = self else {
+            return None;
+        };
+        let items: Vec<Value> = match self {
+            Operation::Split(s) => s.regex.split(data).filter(|piece| !piece.is_empty())
+                .map(|piece| Value::String(String::from_utf8_lossy(piece).into_owned()))
+                .collect(),
+            _ => e.regex.captures_iter(data).map(|caps| e.capture_value(&caps)).collect(),
+        };
+        match e.output? {
+            PiecesOutput::JsonArray => Some(Value::Array(items)),
+            PiecesOutput::Join => {
+                let texts: Vec<String> = items.into_iter().map(|item| match item {
+                    Value::String(text) => text,
+                    item => item.to_string(),
+                }).collect();
+                Some(Value::String(texts.join(e.separator.as_deref().unwrap_or(","))))
+            }
+        }
+    }
+
+    /// Pieces for the operations that turn a record into many, `None` for the others
+    fn explode(&self, data: &[u8]) -> Option<Vec<Vec<u8>>> {
+        let data = &*self.normalized(data);
+        match self {
+            Operation::Split(s) => Some(
+                s.regex.split(data).filter(|piece| !piece.is_empty()).map(<[u8]>::to_vec).collect()
+            ),
+            Operation::FindAll(f) => Some(
+                f.regex.captures_iter(data).map(|caps| f.capture_record(&caps)).collect()
+            ),
+            _ => None,
+        }
+    }
+
+    /// Compile the regex and resolve settings that reference other init params
+    fn resolve(&mut self, params: &SmartModuleExtraParams) -> Result<()> {
+        let supports_fancy = matches!(self,
+            Operation::Replace(_) | Operation::ReplaceFirst(_) | Operation::Mask(_) | Operation::Hash(_)
+                | Operation::Match(_) | Operation::NotMatch(_)
+        );
+        if self.options().engine == Engine::Fancy && !supports_fancy {
+            return Err(eyre!("`engine: fancy` only supports `replace`, `replace_first`, `mask`, `hash`, `match` and `not_match`"));
+        }
+        if let Some(warning) = self.pattern().and_then(Pattern::deprecation) {
+            logging::log(LogLevel::Warn, &[("op", &self.kind())], format_args!("{warning}"));
+        }
+        let format: RecordFormat = get_keyword_param(params, FORMAT_PARAM)?;
+        let default_limits = get_regex_limits(params)?;
+        let deletes_fields = matches!(self, Operation::DeleteField(_));
+        let redacts = matches!(self, Operation::Redact(_));
+        let whole_record = self.explodes() || deletes_fields || redacts;
+        let (mut pattern, options) = self.parts_mut();
+        if options.when_header.is_some() {
+            return Err(eyre!("`when_header` is not supported: SmartModule records do not carry header values"));
+        }
+        if options.set_headers.is_some() {
+            return Err(eyre!("`set_headers` is not supported: SmartModule records do not carry header values, \
+                write captured groups to fields with `insert` instead, e.g. {{\"insert\": {{\"regex\": \"...\", \"field\": \"region\", \"value\": \"$1\"}}}}"));
+        }
+        if let Input::Header(name) = &options.input {
+            return Err(eyre!("`input: header({name})` is not supported: SmartModule records do not carry header values"));
+        }
+        let limits = options.limits.or(&default_limits);
+        if !options.unicode && options.engine == Engine::Fancy {
+            return Err(eyre!("`unicode: false` is not supported with `engine: fancy`"));
+        }
+        options.flags.set_unicode(options.unicode);
+        // with the `x` flag spaces are already ignored
+        if get_flag_param(params, NORMALIZE_PATTERN_WHITESPACE_PARAM)? && !options.flags.ignore_whitespace() {
+            let when = options.when.as_mut().and_then(|when| when.regex.as_mut());
+            for regex in [pattern.as_deref_mut(), when, options.scope.as_mut()].into_iter().flatten() {
+                regex.relax_whitespace();
+            }
+        }
+        if let Some(pattern) = pattern {
+            pattern.compile_with(&options.flags, options.engine, &limits)?;
+        }
+        if let Some(when) = options.when.as_mut() {
+            when.resolve(&limits)?;
+        }
+        if let Some(scope) = options.scope.as_mut() {
+            scope.compile_with(&options.flags, Engine::Default, &limits).wrap_err("invalid `scope`")?;
+        }
+        options.resolve_xml_path(&format)?;
+        options.resolve_column(&format, params)?;
+        if matches!(format, RecordFormat::Protobuf | RecordFormat::Avro) && options.path.is_none() && options.target.value() && !deletes_fields {
+            return Err(eyre!("protobuf and Avro records are rewritten field by field, set a `path` to the string fields"));
+        }
+        if matches!(format, RecordFormat::Json | RecordFormat::Ndjson) && options.path.is_none() && options.target == Target::Value
+            && options.input == Input::Value && !whole_record {
+            options.path = Some(JsonPath::every_value());
+        }
+        if self.explodes() && (self.path().is_some() || self.column().is_some() || self.xml_path().is_some()) {
+            return Err(eyre!("`path` and columns are not supported, records are split on their whole value"));
+        }
+        let rewrites_text = !matches!(self,
+            Operation::DeleteField(_) | Operation::SetKey(_) | Operation::Insert(_) | Operation::Append(_) | Operation::Route(_)
+        );
+        if self.xml_path().is_some() && !rewrites_text {
+            return Err(eyre!("xml paths are only supported by operations that rewrite text"));
+        }
+        if self.options().scope.is_some() && !(rewrites_text && self.rewrites()) {
+            return Err(eyre!("`scope` is only supported by operations that rewrite the matched text in place"));
+        }
+        if self.options().sample_rate.is_some_and(|rate| !(rate > 0.0 && rate <= 1.0)) {
+            return Err(eyre!("`sample_rate` must be above 0 and at most 1"));
+        }
+        match self.options().severity {
+            Severity::Redact => {}
+            _ if !self.rewrites() => return Err(eyre!("`severity` only applies to operations that rewrite records")),
+            Severity::Block if !cfg!(feature = "filter-map") => {
+                return Err(eyre!("`severity: block` drops records and needs the filter-map build"));
+            }
+            Severity::Warn | Severity::Block => {}
+        }
+        if self.options().decode.is_some() && !(rewrites_text && self.rewrites()) {
+            return Err(eyre!("`decode` is only supported by operations that rewrite the matched text in place"));
+        }
+
+        if let Operation::AnonymizeIp(a) = self {
+            a.check()?;
+        }
+        if let Operation::Placeholder(p) = self {
+            p.check()?;
+        }
+        if let Operation::Hash(Hash { salt: Some(name), salt_value, .. })
+            | Operation::Placeholder(Placeholder { salt: Some(name), salt_value, .. })
+            | Operation::AnonymizeIp(AnonymizeIp { salt: Some(name), salt_value, .. }) = self {
+            *salt_value = params.get(name)
+                .ok_or_else(|| SmartModuleInitError::MissingParam(name.to_string()))?
+                .to_string();
+        }
+        if let Operation::Encrypt(e) | Operation::Decrypt(e) = self {
+            let key = params.get(&e.key).ok_or_else(|| SmartModuleInitError::MissingParam(e.key.to_string()))?;
+            e.cipher = Some(TokenCipher::new(key).map_err(|err| eyre!("invalid `{}` param: {err}", e.key))?);
+        }
+        if let Operation::Translate(t) = self {
+            t.load(params)?;
+        }
+        if let Operation::Normalize(n) = self {
+            n.check()?;
+        }
+        if let Operation::DeleteField(d) = self {
+            d.check()?;
+        }
+        if let Operation::Redact(r) = self {
+            r.resolve(&format, &limits)?;
+        }
+        if let Operation::SetKey(k) = self {
+            k.check()?;
+        }
+        if let Operation::MaskCreditCard(c) = self {
+            c.check()?;
+        }
+        if let Operation::Split(e) | Operation::FindAll(e) = self {
+            e.check()?;
+        }
+        if let Operation::Route(r) = self {
+            r.resolve(&limits)?;
+        }
+        if let Operation::TrimField(t) = self {
+            t.check()?;
+        }
+        if let Operation::Timestamp(t) = self {
+            t.resolve()?;
+        }
+        if let Operation::Url(u) = self {
+            u.check()?;
+        }
+        if let Operation::Reorder(r) = self {
+            r.to = interpolate_params(&r.to, params, true)?;
+            r.check()?;
+        }
+        if let Operation::Insert(a) | Operation::Append(a) = self {
+            a.check()?;
+        } else if self.options().input != Input::Value && !matches!(self, Operation::SetKey(_) | Operation::Route(_)) {
+            return Err(eyre!("`input: key` is only supported by `insert`, `append`, `route` and `set_key`, use `target: key` to rewrite the key"));
+        }
+        if let Operation::Replace(r) | Operation::ReplaceFirst(r) = self {
+            if let Some(group) = &r.group {
+                if r.options.engine == Engine::Fancy {
+                    return Err(eyre!("`group` is not supported with `engine: fancy`"));
+                }
+                group.check(&r.regex)?;
+            }
+            r.with = interpolate_params(&r.with, params, !r.literal_replacement)?;
+            r.check_cases(params)?;
+            if !r.literal_replacement {
+                check_capture_references(&r.with, &r.regex)?;
+                r.template = Template::parse(&r.with)?;
+            }
+            if let Some(template) = &r.template {
+                if r.options.engine == Engine::Fancy {
+                    return Err(eyre!("template functions are not supported with `engine: fancy`"));
+                }
+                template.check(&r.regex)?;
+            }
+        }
+        if self.options().max_matches.is_some() && matches!(self, Operation::Normalize(_) | Operation::DeleteField(_) | Operation::Redact(_)) {
+            return Err(eyre!("`max_matches` is only supported by operations that match a regex or dictionary"));
+        }
+        self.check_examples()
+    }
+
+    /// Run the `examples` of the operation on their own, the first mismatch fails init with both outputs
+    fn check_examples(&self) -> Result<()> {
+        let examples = &self.options().examples;
+        let rewrites = !(self.explodes() || matches!(self, Operation::SetKey(_) | Operation::Match(_) | Operation::NotMatch(_)));
+        if !examples.is_empty() && !rewrites {
+            return Err(eyre!("`examples` are only supported by operations that rewrite values"));
+        }
+        for (index, example) in examples.iter().enumerate() {
+            Placeholder::reset();
+            let input = example.input.as_bytes();
+            let output = match self.options().target {
+                Target::Key => self.run_regex(input),
+                _ => apply_regex_ops(input, std::iter::once(self))
+                    .map_err(|err| eyre!("example #{} failed: {err:#}", index + 1))?,
+            };
+            if !example.holds(&output) {
+                return Err(eyre!(
+                    "example #{} failed\n  input:    {}\n  expected: {}\n  actual:   {}",
+                    index + 1, example.input, example.expected, String::from_utf8_lossy(&output)
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the operation to a string value selected by a json path, other values are left as is
+    fn run_value(&self, value: &mut Value) {
+        let Value::String(text) = value else {
+            return;
+        };
+        match self {
+            Operation::Extract(e) => {
+                if let Some(obj) = e.extract(text.as_bytes()) {
+                    *value = obj;
+                }
+            }
+            Operation::Find(f) => *value = f.find(text.as_bytes()),
+            Operation::Split(_) | Operation::FindAll(_) => {
+                if let Some(reassembled) = self.reassembled(text.as_bytes()) {
+                    *value = reassembled;
+                }
+            }
+            _ => {
+                if let Cow::Owned(result) = self.run_regex(text.as_bytes()) {
+                    *text = String::from_utf8(result)
+                        .unwrap_or_else(|err| String::from_utf8_lossy(err.as_bytes()).into_owned());
+                }
+            }
+        }
+    }
+
+    /// Check the record time budget, then the `sample_rate` and the `when` guard of the operation against
+    /// the data it would run on
+    fn applies_to(&self, data: &mut Data) -> Result<bool> {
+        TimeBudget::check()?;
+        if self.options().sample_rate.is_some_and(|rate| !sampled(template::record_offset(), rate)) {
+            return Ok(false);
+        }
+        match &self.options().when {
+            Some(when) => when.holds(data),
+            None => Ok(true),
+        }
+    }
+
+    /// Run the operation on the whole record or on the values selected by its path
+    fn apply(&self, data: &mut Data, key: Option<&[u8]>) -> Result<()> {
+        if !self.applies_to(data)? {
+            return Ok(());
+        }
+        match self.options().severity {
+            Severity::Redact => {}
+            // records it matches never get here, see `blocked`
+            Severity::Block => return Ok(()),
+            Severity::Warn => {
+                let matches = self.count_matches(data, key)?;
+                if matches > 0 {
+                    logging::log(LogLevel::Warn, &[("op", &self.kind())], format_args!("{matches} matches left as is with `severity: warn`"));
+                }
+                return Ok(());
+            }
+        }
+        if let Some(max) = self.options().max_matches {
+            if self.count_matches_up_to(data, key, max.saturating_add(1))? > max {
+                if self.options().on_max_matches == OnMaxMatches::Fail {
+                    return Err(eyre!("more than {max} matches, over `max_matches`"));
+                }
+                logging::log(LogLevel::Warn, &[("op", &self.kind())], format_args!("more than {max} matches, skipping the operation"));
+                return Ok(());
+            }
+        }
+        if let Operation::DeleteField(d) = self {
+            d.delete(data.json_mut()?);
+            return Ok(());
+        }
+        if let Operation::Redact(r) = self {
+            r.redact(data.json_mut()?);
+            return Ok(());
+        }
+        if let Operation::SetKey(_) = self {
+            return Ok(());
+        }
+        if let Operation::Insert(a) | Operation::Append(a) = self {
+            let append = matches!(self, Operation::Append(_));
+            let values: Vec<Value> = match (&a.options.input, self.path()) {
+                (Input::Key, _) => key.map_or(vec![], |key| a.values(key, append)),
+                (_, Some(path)) => path.select(data.json_mut()?).into_iter()
+                    .filter_map(Value::as_str)
+                    .flat_map(|text| a.values(text.as_bytes(), append))
+                    .collect(),
+                (_, None) => a.values(data.bytes(), append),
+            };
+            if !values.is_empty() {
+                a.add(data.json_mut()?, values, append)?;
+            }
+            return Ok(());
+        }
+        if let Operation::Route(r) = self {
+            let labels = match (&r.options.input, self.path()) {
+                (Input::Key, _) => key.map_or(vec![], |key| r.labels(std::iter::once(key))),
+                (_, Some(path)) => {
+                    let selected = path.select(data.json_mut()?);
+                    r.labels(selected.iter().filter_map(|value| value.as_str()).map(str::as_bytes))
+                }
+                (_, None) => r.labels(std::iter::once(data.bytes())),
+            };
+            if let Some(route) = r.route(labels) {
+                r.write(data.json_mut()?, route)?;
+            }
+            return Ok(());
+        }
+        if let Some(xml_path) = self.xml_path() {
+            return data.xml_mut()?.for_each_text_mut(xml_path, &mut |text| {
+                if let Cow::Owned(result) = self.run_regex(text) {
+                    *text = result;
+                }
+            });
+        }
+        match (self.path(), self.column()) {
+            (Some(path), _) => path.for_each_mut(data.json_mut()?, &mut |value| self.run_value(value)),
+            (None, Some(index)) => data.csv_mut()?.for_each_field_mut(index, &mut |field| {
+                if let Cow::Owned(result) = self.run_regex(field) {
+                    *field = result;
+                }
+            }),
+            (None, None) => {
+                let result = match self.run_regex(data.bytes()) {
+                    Cow::Owned(result) => result,
+                    Cow::Borrowed(_) => return Ok(()),
+                };
+                *data = Data::Bytes(Cow::Owned(result));
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of matches the operation acts on, counted on the data before it runs
+    fn count_matches(&self, data: &mut Data, key: Option<&[u8]>) -> Result<usize> {
+        self.count_matches_up_to(data, key, usize::MAX)
+    }
+
+    /// Matches in the record, counting stops once `limit` is reached
+    fn count_matches_up_to(&self, data: &mut Data, key: Option<&[u8]>, limit: usize) -> Result<usize> {
+        if !self.applies_to(data)? {
+            return Ok(0);
+        }
+        if self.options().input == Input::Key {
+            return Ok(key.map_or(0, |key| self.count_up_to(key, limit)));
+        }
+        if let Operation::DeleteField(d) = self {
+            return Ok(d.delete(&mut data.json_mut()?.clone()));
+        }
+        if let Operation::Redact(r) = self {
+            return Ok(r.redact(&mut data.json_mut()?.clone()));
+        }
+        let sum = |counts: &mut dyn Iterator<Item = usize>| {
+            let mut total = 0usize;
+            for count in counts {
+                total = total.saturating_add(count);
+                if total >= limit {
+                    return limit;
+                }
+            }
+            total
+        };
+        if let Some(xml_path) = self.xml_path() {
+            return Ok(sum(&mut data.xml_mut()?.texts(xml_path)?.iter().map(|text| self.count_up_to(text, limit))));
+        }
+        match (self.path(), self.column()) {
+            (Some(path), _) => Ok(sum(&mut path.select(data.json_mut()?).into_iter()
+                .filter_map(Value::as_str)
+                .map(|text| self.count_up_to(text.as_bytes(), limit)))),
+            (None, Some(index)) => Ok(sum(&mut data.csv_mut()?.fields(index).into_iter()
+                .map(|field| self.count_up_to(field, limit)))),
+            (None, None) => Ok(self.count_up_to(data.bytes(), limit)),
+        }
+    }
+
+    fn count_up_to(&self, data: &[u8], limit: usize) -> usize {
+        let Some(texts) = self.options().decode.and_then(|decode| decode.texts(data)) else {
+            return self.count_scoped(data, limit);
+        };
+        let mut count = 0;
+        for text in texts {
+            count += self.count_scoped(text.as_bytes(), limit - count);
+            if count >= limit {
+                break;
+            }
+        }
+        count
+    }
+
+    fn count_scoped(&self, data: &[u8], limit: usize) -> usize {
+        match self.scope_regions(data) {
+            Some(regions) => {
+                let mut count = 0;
+                for region in regions {
+                    count += self.count_in(&data[region], limit - count);
+                    if count >= limit {
+                        break;
+                    }
+                }
+                count
+            }
+            None => self.count_in(data, limit),
+        }
+    }
+
+    fn count_in(&self, data: &[u8], limit: usize) -> usize {
+        let data = &*self.normalized(data);
+        match self {
+            Operation::Replace(r) if r.limit > 0 => r.regex.count(data, limit.min(r.limit)),
+            Operation::Replace(r) => r.regex.count(data, limit),
+            Operation::ReplaceFirst(r) => usize::from(r.regex.is_match(data)),
+            Operation::Extract(e) => usize::from(e.regex.is_match(data)),
+            Operation::Find(f) => f.regex.count(data, limit),
+            Operation::Mask(m) => m.regex.count(data, limit),
+            Operation::MaskCreditCard(c) => c.cards(data).len().min(limit),
+            Operation::Hash(h) => h.regex.count(data, limit),
+            Operation::AnonymizeIp(a) => a.regex.count(data, limit),
+            Operation::Encrypt(e) | Operation::Decrypt(e) => e.regex.count(data, limit),
+            Operation::Translate(t) => t.automaton.as_ref().map_or(0, |automaton| automaton.find_iter(data).take(limit).count()),
+            Operation::Normalize(n) => usize::from(matches!(n.normalize(data), Cow::Owned(_))),
+            Operation::Case(c) => c.regex.count(data, limit),
+            Operation::TrimField(t) => t.count(data, limit),
+            Operation::Timestamp(t) => t.regex.count(data, limit),
+            Operation::Url(u) => u.count(data, limit),
+            Operation::Reorder(r) => r.regex.count(data, limit),
+            Operation::Placeholder(p) => p.regex.count(data, limit),
+            Operation::DeleteField(_) | Operation::Redact(_) => 0,
+            Operation::SetKey(k) => usize::from(k.regex.is_match(data)),
+            Operation::Insert(a) => usize::from(a.regex.is_match(data)),
+            Operation::Route(r) => r.labels(std::iter::once(data)).len(),
+            Operation::Append(a) => a.regex.count(data, limit),
+            Operation::Match(m) | Operation::NotMatch(m) => usize::from(m.regex.is_match(data)),
+            Operation::Split(e) | Operation::FindAll(e) => e.regex.find_iter(data).take(limit).count(),
+        }
+    }
+
+    /// Byte ranges of the matches the operation acts on in the whole record text
+    fn spans(&self, data: &[u8]) -> Vec<[usize; 2]> {
+        match self.scope_regions(data) {
+            Some(regions) => regions.into_iter()
+                .flat_map(|region| {
+                    let start = region.start;
+                    self.spans_in(&data[region]).into_iter().map(move |[from, to]| [start + from, start + to])
+                })
+                .collect(),
+            None => self.spans_in(data),
+        }
+    }
+
+    fn spans_in(&self, data: &[u8]) -> Vec<[usize; 2]> {
+        let limit = match self {
+            Operation::Replace(r) if r.limit > 0 => r.limit,
+            Operation::ReplaceFirst(_) | Operation::Extract(_) | Operation::SetKey(_) | Operation::Insert(_) => 1,
+            _ => usize::MAX,
+        };
+        let spans = match self {
+            Operation::Translate(t) => t.automaton.as_ref()
+                .map_or(vec![], |automaton| automaton.find_iter(data).map(|m| [m.start(), m.end()]).collect()),
+            Operation::DeleteField(_) | Operation::Redact(_) => vec![],
+            Operation::MaskCreditCard(c) => c.cards(data).into_iter().map(|card| [card.start, card.end]).collect(),
+            Operation::TrimField(t) => t.regex.captures_iter(data)
+                .filter(|caps| t.target(caps).is_some_and(|target| t.exceeds(target.as_bytes())))
+                .filter_map(|caps| caps.get(0).map(|m| [m.start(), m.end()]))
+                .collect(),
+            Operation::Url(u) => u.regex.captures_iter(data)
+                .filter_map(|caps| caps.get(0).filter(|m| u.changes(m.as_bytes())).map(|m| [m.start(), m.end()]))
+                .collect(),
+            _ => self.pattern().map_or(vec![], |pattern| pattern.spans(data)),
+        };
+        spans.into_iter().take(limit).collect()
+    }
+
+    /// Key captured by a `set_key` operation from the record value, or from the received key
+    /// with `input: key`, `None` for other operations and when nothing matched
+    fn captured_key(&self, value: &[u8], key: Option<&[u8]>) -> Result<Option<Vec<u8>>> {
+        let Operation::SetKey(k) = self else {
+            return Ok(None);
+        };
+        let mut data = Data::Bytes(Cow::Borrowed(value));
+        if !self.applies_to(&mut data)? {
+            return Ok(None);
+        }
+        if self.options().input == Input::Key {
+            return Ok(key.and_then(|key| k.capture(key)));
+        }
+        match self.path() {
+            Some(path) => Ok(path.select(data.json_mut()?).into_iter()
+                .filter_map(Value::as_str)
+                .find_map(|text| k.capture(text.as_bytes()))),
+            None => Ok(k.capture(data.bytes())),
+        }
+    }
+
+    /// Locate a record error at the operation, numbered like the unnamed `stats_field` counts
+    fn describe(&self, index: usize) -> String {
+        match &self.options().name {
+            Some(name) => format!("operation #{index} `{}: {name}`", self.kind()),
+            None => format!("operation #{index} `{}`", self.kind()),
+        }
+    }
+
+    /// Text the operation writes in place of a match, when it does not depend on the matched text
+    fn output_samples(&self) -> Vec<String> {
+        match self {
+            Operation::Replace(r) | Operation::ReplaceFirst(r) if r.template.is_none() => {
+                std::iter::once(&r.with).chain(r.cases.values())
+                    .map(|with| match r.literal_replacement {
+                        true => with.clone(),
+                        false => without_capture_references(with),
+                    })
+                    .filter(|literal| !literal.is_empty())
+                    .collect()
+            }
+            Operation::Mask(m) => vec![m.mask_char.to_string().repeat(8)],
+            Operation::MaskCreditCard(c) => vec![format!("{}1234", c.mask_char.to_string().repeat(12))],
+            Operation::Hash(h) => vec![h.algorithm.hex_digest(h.salt_value.as_bytes(), b"")],
+            Operation::Encrypt(e) => vec![String::from_utf8_lossy(&e.cipher().encrypt(b"")).into_owned()],
+            Operation::Translate(t) => t.replacements.iter().filter(|replacement| !replacement.is_empty()).cloned().collect(),
+            Operation::Placeholder(p) => vec![format!("<{}_1>", p.label)],
+            Operation::Reorder(r) => [without_capture_references(&r.to)].into_iter().filter(|literal| !literal.is_empty()).collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Whether the operation changes the text it matches, as opposed to reading or routing it
+    fn rewrites(&self) -> bool {
+        !(self.explodes() || matches!(self,
+            Operation::Extract(_) | Operation::Find(_) | Operation::SetKey(_) | Operation::Insert(_)
+                | Operation::Append(_) | Operation::Route(_) | Operation::Match(_) | Operation::NotMatch(_)
+        ))
+    }
+
+    /// Whether both operations can read the same part of a record
+    fn shares_scope(&self, other: &Operation) -> bool {
+        let (options, other) = (self.options(), other.options());
+        let whole = |path: &Option<JsonPath>| path.as_ref().is_none_or(|path| *path == JsonPath::every_value());
+        (options.target.key() && other.target.key() || options.target.value() && other.target.value())
+            && (whole(&options.path) || whole(&other.path) || options.path == other.path)
+            && (options.column_index.is_none() || other.column_index.is_none() || options.column_index == other.column_index)
+    }
+
+    /// `describe` followed by the compiled regex and the settings that differ from the defaults
+    fn summary(&self, index: usize) -> String {
+        let options = self.options();
+        let mut summary = self.describe(index);
+        if let Some(pattern) = self.pattern() {
+            summary.push_str(&format!(" regex=`{}`", pattern.source()));
+        }
+        let flags = options.flags.inline();
+        if !flags.is_empty() {
+            summary.push_str(&format!(" flags={flags}"));
+        }
+        if options.engine == Engine::Fancy {
+            summary.push_str(" engine=fancy");
+        }
+        if options.target != Target::Value {
+            summary.push_str(&format!(" target={}", format!("{:?}", options.target).to_lowercase()));
+        }
+        match &options.input {
+            Input::Value => {}
+            Input::Key => summary.push_str(" input=key"),
+            Input::Header(name) => summary.push_str(&format!(" input=header({name})")),
+        }
+        if let Some(path) = &options.path {
+            summary.push_str(&format!(" path={path}"));
+        }
+        if let Some(column) = &options.column {
+            summary.push_str(&format!(" column={column}"));
+        }
+        summary
+    }
+
+    /// Operation name as written in the spec
+    fn kind(&self) -> &'static str {
+        match self {
+            Operation::Replace(_) => "replace",
+            Operation::ReplaceFirst(_) => "replace_first",
+            Operation::Extract(_) => "extract",
+            Operation::Find(_) => "find",
+            Operation::Mask(_) => "mask",
+            Operation::MaskCreditCard(_) => "mask_credit_card",
+            Operation::Hash(_) => "hash",
+            Operation::AnonymizeIp(_) => "anonymize_ip",
+            Operation::Encrypt(_) => "encrypt",
+            Operation::Decrypt(_) => "decrypt",
+            Operation::Translate(_) => "translate",
+            Operation::Normalize(_) => "normalize",
+            Operation::Case(_) => "case",
+            Operation::TrimField(_) => "trim_field",
+            Operation::Timestamp(_) => "timestamp",
+            Operation::Url(_) => "url",
+            Operation::Reorder(_) => "reorder",
+            Operation::Placeholder(_) => "placeholder",
+            Operation::DeleteField(_) => "delete_field",
+            Operation::Redact(_) => "redact",
+            Operation::SetKey(_) => "set_key",
+            Operation::Insert(_) => "insert",
+            Operation::Append(_) => "append",
+            Operation::Route(_) => "route",
+            Operation::Match(_) => "match",
+            Operation::NotMatch(_) => "not_match",
+            Operation::Split(_) => "split",
+            Operation::FindAll(_) => "find_all",
+        }
+    }
+
+    fn path(&self) -> Option<&JsonPath> {
+        self.options().path.as_ref()
+    }
+
+    fn column(&self) -> Option<usize> {
+        self.options().column_index
+    }
+
+    fn xml_path(&self) -> Option<&XmlPath> {
+        self.options().xml_path.as_ref()
+    }
+
+    fn options(&self) -> &OpOptions {
+        match self {
+            Operation::Replace(r) | Operation::ReplaceFirst(r) => &r.options,
+            Operation::Extract(e) | Operation::Find(e) => &e.options,
+            Operation::Mask(m) => &m.options,
+            Operation::MaskCreditCard(c) => &c.options,
+            Operation::Hash(h) => &h.options,
+            Operation::AnonymizeIp(a) => &a.options,
+            Operation::Encrypt(e) | Operation::Decrypt(e) => &e.options,
+            Operation::Translate(t) => &t.options,
+            Operation::Normalize(n) => &n.options,
+            Operation::Case(c) => &c.options,
+            Operation::TrimField(t) => &t.options,
+            Operation::Timestamp(t) => &t.options,
+            Operation::Url(u) => &u.options,
+            Operation::Reorder(r) => &r.options,
+            Operation::Placeholder(p) => &p.options,
+            Operation::DeleteField(d) => &d.options,
+            Operation::Redact(r) => &r.options,
+            Operation::SetKey(k) => &k.options,
+            Operation::Insert(a) | Operation::Append(a) => &a.options,
+            Operation::Route(r) => &r.options,
+            Operation::Match(m) | Operation::NotMatch(m) => &m.options,
+            Operation::Split(e) | Operation::FindAll(e) => &e.options,
+        }
+    }
+
+    fn pattern(&self) -> Option<&Pattern> {
+        match self {
+            Operation::Replace(r) | Operation::ReplaceFirst(r) => Some(&r.regex),
+            Operation::Extract(e) | Operation::Find(e) => Some(&e.regex),
+            Operation::Mask(m) => Some(&m.regex),
+            Operation::MaskCreditCard(c) => Some(&c.regex),
+            Operation::Hash(h) => Some(&h.regex),
+            Operation::AnonymizeIp(a) => Some(&a.regex),
+            Operation::Encrypt(e) | Operation::Decrypt(e) => Some(&e.regex),
+            Operation::Translate(_) | Operation::Normalize(_) => None,
+            Operation::Case(c) => Some(&c.regex),
+            Operation::TrimField(t) => Some(&t.regex),
+            Operation::Timestamp(t) => Some(&t.regex),
+            Operation::Url(u) => Some(&u.regex),
+            Operation::Reorder(r) => Some(&r.regex),
+            Operation::Placeholder(p) => Some(&p.regex),
+            Operation::DeleteField(d) => d.regex.as_ref(),
+            Operation::Redact(_) => None,
+            Operation::SetKey(k) => Some(&k.regex),
+            Operation::Insert(a) | Operation::Append(a) => Some(&a.regex),
+            Operation::Route(_) => None,
+            Operation::Match(m) | Operation::NotMatch(m) => Some(&m.regex),
+            Operation::Split(e) | Operation::FindAll(e) => Some(&e.regex),
+        }
+    }
+
+    fn parts_mut(&mut self) -> (Option<&mut Pattern>, &mut OpOptions) {
+        match self {
+            Operation::Replace(r) | Operation::ReplaceFirst(r) => (Some(&mut r.regex), &mut r.options),
+            Operation::Extract(e) | Operation::Find(e) => (Some(&mut e.regex), &mut e.options),
+            Operation::Mask(m) => (Some(&mut m.regex), &mut m.options),
+            Operation::MaskCreditCard(c) => (Some(&mut c.regex), &mut c.options),
+            Operation::Hash(h) => (Some(&mut h.regex), &mut h.options),
+            Operation::AnonymizeIp(a) => (Some(&mut a.regex), &mut a.options),
+            Operation::Encrypt(e) | Operation::Decrypt(e) => (Some(&mut e.regex), &mut e.options),
+            Operation::Translate(t) => (None, &mut t.options),
+            Operation::Normalize(n) => (None, &mut n.options),
+            Operation::Case(c) => (Some(&mut c.regex), &mut c.options),
+            Operation::TrimField(t) => (Some(&mut t.regex), &mut t.options),
+            Operation::Timestamp(t) => (Some(&mut t.regex), &mut t.options),
+            Operation::Url(u) => (Some(&mut u.regex), &mut u.options),
+            Operation::Reorder(r) => (Some(&mut r.regex), &mut r.options),
+            Operation::Placeholder(p) => (Some(&mut p.regex), &mut p.options),
+            Operation::DeleteField(d) => (d.regex.as_mut(), &mut d.options),
+            Operation::Redact(r) => (None, &mut r.options),
+            Operation::SetKey(k) => (Some(&mut k.regex), &mut k.options),
+            Operation::Insert(a) | Operation::Append(a) => (Some(&mut a.regex), &mut a.options),
+            Operation::Route(r) => (None, &mut r.options),
+            Operation::Match(m) | Operation::NotMatch(m) => (Some(&mut m.regex), &mut m.options),
+            Operation::Split(e) | Operation::FindAll(e) => (Some(&mut e.regex), &mut e.options),
+        }
+    }
+
+    /// Evaluate `match`/`not_match` predicates, `None` for operations that don't filter.
+    /// With `target: both`, `match` holds when either the key or the value matches.
+    fn is_match(&self, value: &mut Data, key: Option<&mut Data>) -> Result<Option<bool>> {
+        let (m, negate) = match self {
+            Operation::Match(m) => (m, false),
+            Operation::NotMatch(m) => (m, true),
+            _ => return Ok(None),
+        };
+        if !self.applies_to(value)? {
+            return Ok(None);
+        }
+
+        let target = m.options.target;
+        let mut matched = target.value() && m.matches(value)?;
+        if let (false, true, Some(key)) = (matched, target.key(), key) {
+            matched = m.matches(key)?;
+        }
+        Ok(Some(matched != negate))
+    }
+}
+
+impl Replace {
+    fn replace<'h>(&self, data: &'h [u8], limit: usize) -> Cow<'h, [u8]> {
+        if !self.cases.is_empty() {
+            return self.regex.replacen(data, limit, |caps: &Captures| self.replace_case(caps));
+        }
+        match (&self.group, &self.template) {
+            (Some(group), _) => self.regex.replacen(data, limit, |caps: &Captures| self.replace_group(group, caps)),
+            (None, Some(template)) => self.regex.replacen(data, limit, |caps: &Captures| template.render(caps)),
+            (None, None) if self.literal_replacement => self.regex.replacen_literal(data, limit, &self.with),
+            (None, None) => self.regex.replacen_expand(data, limit, &self.with),
+        }
+    }
+
+    /// Check that `cases` keys are named groups of the regex, and resolve params in their replacements
+    fn check_cases(&mut self, params: &SmartModuleExtraParams) -> Result<()> {
+        if self.cases.is_empty() {
+            return Ok(());
+        }
+        if self.group.is_some() || self.options.engine == Engine::Fancy {
+            return Err(eyre!("`cases` is not supported with `group` or `engine: fancy`"));
+        }
+        let templated = |with: &str| Ok::<_, eyre::Report>(Template::parse(with)?.is_some());
+        if !self.literal_replacement && templated(&self.with)? {
+            return Err(eyre!("`cases` replacements only expand capture references, template functions are not supported"));
+        }
+        for (name, with) in self.cases.iter_mut() {
+            if !self.regex.has_group_name(name) {
+                return Err(eyre!("`cases` key `{name}` is not a named group of the regex"));
+            }
+            *with = interpolate_params(with, params, !self.literal_replacement)?;
+            if self.literal_replacement {
+                continue;
+            }
+            if templated(with)? {
+                return Err(eyre!("`cases` replacements only expand capture references, template functions are not supported"));
+            }
+            check_capture_references(with, &self.regex)?;
+        }
+        Ok(())
+    }
+
+    /// The replacement of the first group in the regex that is a `cases` key and took part in the match,
+    /// `with` when none did
+    fn replace_case(&self, caps: &Captures) -> Vec<u8> {
+        let with = self.regex.capture_names().flatten()
+            .find_map(|name| caps.name(name).and(self.cases.get(name)))
+            .unwrap_or(&self.with);
+        if self.literal_replacement {
+            return with.as_bytes().to_vec();
+        }
+        let mut out = vec![];
+        caps.expand(with.as_bytes(), &mut out);
+        out
+    }
+
+    /// The match with only the text of `group` replaced, as is when the group did not participate
+    fn replace_group(&self, group: &CaptureGroup, caps: &Captures) -> Vec<u8> {
+        let whole = &caps[0];
+        let start = caps.get(0).map_or(0, |m| m.start());
+        let Some(target) = group.get(caps) else {
+            return whole.to_vec();
+        };
+        let mut out = whole[..target.start() - start].to_vec();
+        match &self.template {
+            Some(template) => out.extend(template.render(caps)),
+            None if self.literal_replacement => out.extend_from_slice(self.with.as_bytes()),
+            None => caps.expand(self.with.as_bytes(), &mut out),
+        }
+        out.extend_from_slice(&whole[target.end() - start..]);
+        out
+    }
+}
+
+impl Extract {
+    /// Collect named groups of the first match, `null` for groups that did not participate
+    fn extract(&self, data: &[u8]) -> Option<Value> {
+        let caps = self.regex.captures(data)?;
+        Some(named_captures(&self.regex, &caps))
+    }
+
+    /// Summary of every match for `find`: `{"count": N, "matches": [{"span": [start, end], "groups": {..}}]}`,
+    /// with byte spans and the named groups when the regex has any
+    fn find(&self, data: &[u8]) -> Value {
+        let named = self.regex.capture_names().flatten().next().is_some();
+        let matches: Vec<Value> = self.regex.captures_iter(data)
+            .map(|caps| {
+                let whole = caps.get(0).expect("group 0 always participates");
+                let mut found = serde_json::json!({"span": [whole.start(), whole.end()]});
+                if named {
+                    found["groups"] = named_captures(&self.regex, &caps);
+                }
+                found
+            })
+            .collect();
+        serde_json::json!({"count": matches.len(), "matches": matches})
+    }
+}
+
+fn named_captures(regex: &Pattern, caps: &Captures) -> Value {
+    let mut obj = serde_json::Map::new();
+    for name in regex.capture_names().flatten() {
+        obj.insert(name.to_string(), capture_value(caps.name(name)));
+    }
+    Value::Object(obj)
+}
+
+fn capture_value(group: Option<regex::bytes::Match>) -> Value {
+    group
+        .map(|m| Value::String(String::from_utf8_lossy(m.as_bytes()).into_owned()))
+        .unwrap_or(Value::Null)
+}
+
+impl Explode {
+    fn check(&self) -> Result<()> {
+        if self.separator.is_some() && self.output != Some(PiecesOutput::Join) {
+            return Err(eyre!("`separator` needs `output: join`"));
+        }
+        Ok(())
+    }
+
+    /// The whole match without groups, named groups as an object, otherwise groups as an array
+    fn capture_record(&self, caps: &Captures) -> Vec<u8> {
+        if caps.len() == 1 {
+            caps[0].to_vec()
+        } else {
+            self.capture_value(caps).to_string().into_bytes()
+        }
+    }
+
+    fn capture_value(&self, caps: &Captures) -> Value {
+        if caps.len() == 1 {
+            Value::String(String::from_utf8_lossy(&caps[0]).into_owned())
+        } else if self.regex.capture_names().flatten().next().is_some() {
+            named_captures(&self.regex, caps)
+        } else {
+            Value::Array(caps.iter().skip(1).map(capture_value).collect())
+        }
+    }
+}
+
+impl Translate {
+    /// Build the automaton from the dictionary param, preferring the longest token at each position
+    fn load(&mut self, params: &SmartModuleExtraParams) -> Result<()> {
+        let raw = params.get(&self.dictionary)
+            .ok_or_else(|| SmartModuleInitError::MissingParam(self.dictionary.clone()))?;
+        let format: SpecFormat = get_keyword_param(params, SPEC_FORMAT_PARAM)?;
+        let dictionary: BTreeMap<String, String> = format.parse(raw)
+            .map_err(|err| eyre!("cannot parse `{}` param: {err}, expected tokens mapped to replacements", self.dictionary))?;
+        if dictionary.contains_key("") {
+            return Err(eyre!("`{}` param contains an empty token", self.dictionary));
+        }
+
+        let automaton = AhoCorasick::builder()
+            .match_kind(MatchKind::LeftmostLongest)
+            .ascii_case_insensitive(self.options.flags.case_insensitive())
+            .build(dictionary.keys())
+            .map_err(|err| eyre!("cannot build `{}` dictionary: {err}", self.dictionary))?;
+        self.automaton = Some(automaton);
+        self.replacements = dictionary.into_values().collect();
+        Ok(())
+    }
+
+    /// Borrows the input when no token matched
+    fn translate<'h>(&self, data: &'h [u8]) -> Cow<'h, [u8]> {
+        let automaton = self.automaton.as_ref().expect("dictionary loaded at init");
+        if !automaton.is_match(data) {
+            return Cow::Borrowed(data);
+        }
+        Cow::Owned(automaton.replace_all_bytes(data, &self.replacements))
+    }
+}
+
+impl Normalize {
+    fn check(&self) -> Result<()> {
+        if self.presets.is_empty() {
+            return Err(eyre!("`presets` must list at least one of `collapse_whitespace`, `strip_control_chars` or `trim`"));
+        }
+        Ok(())
+    }
+
+    /// Borrows the input when no preset changed it
+    fn normalize<'h>(&self, data: &'h [u8]) -> Cow<'h, [u8]> {
+        let Ok(text) = std::str::from_utf8(data) else {
+            return Cow::Borrowed(data);
+        };
+        let mut result: Option<String> = None;
+        for preset in &self.presets {
+            if let Some(changed) = preset.apply(result.as_deref().unwrap_or(text)) {
+                result = Some(changed);
+            }
+        }
+        match result {
+            Some(result) => Cow::Owned(result.into_bytes()),
+            None => Cow::Borrowed(data),
+        }
+    }
+}
+
+impl NormalizePreset {
+    /// `None` when the text is already normalized
+    fn apply(self, text: &str) -> Option<String> {
+        match self {
+            NormalizePreset::CollapseWhitespace => {
+                let mut previous_space = false;
+                let collapsed = text.chars().all(|c| {
+                    let single_space = !c.is_whitespace() || (c == ' ' && !previous_space);
+                    previous_space = c.is_whitespace();
+                    single_space
+                });
+                if collapsed {
+                    return None;
+                }
+                let mut result = String::with_capacity(text.len());
+                let mut previous_space = false;
+                for c in text.chars() {
+                    if !c.is_whitespace() {
+                        result.push(c);
+                    } else if !previous_space {
+                        result.push(' ');
+                    }
+                    previous_space = c.is_whitespace();
+                }
+                Some(result)
+            }
+            NormalizePreset::StripControlChars => {
+                let stripped = |c: char| c.is_control() && !matches!(c, '\t' | '\r' | '\n');
+                text.contains(stripped).then(|| text.chars().filter(|c| !stripped(*c)).collect())
+            }
+            NormalizePreset::Trim => {
+                let trimmed = text.trim();
+                (trimmed.len() != text.len()).then(|| trimmed.to_string())
+            }
+        }
+    }
+}
+
+impl CaseStyle {
+    fn apply(self, text: &str) -> String {
+        match self {
+            CaseStyle::Upper => text.to_uppercase(),
+            CaseStyle::Lower => text.to_lowercase(),
+            CaseStyle::Title => {
+                let mut previous_alphanumeric = false;
+                text.chars().flat_map(|c| {
+                    let word_start = !previous_alphanumeric;
+                    previous_alphanumeric = c.is_alphanumeric();
+                    match word_start {
+                        true => c.to_uppercase().collect::<Vec<_>>(),
+                        false => c.to_lowercase().collect(),
+                    }
+                }).collect()
+            }
+            CaseStyle::Snake => case_words(text).iter().map(|word| word.to_lowercase()).collect::<Vec<_>>().join("_"),
+            CaseStyle::Camel => case_words(text).iter().enumerate().map(|(index, word)| match index {
+                0 => word.to_lowercase(),
+                _ => CaseStyle::Title.apply(word),
+            }).collect(),
+        }
+    }
+}
+
+/// Words split on anything but letters and digits, and on case changes: `userID` and `user-id` are `user`, `ID`/`id`,
+/// and an acronym ends before a capitalized word, as in `HTTPServer`
+fn case_words(text: &str) -> Vec<&str> {
+    let mut words = vec![];
+    let mut start = None;
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    for (index, &(offset, c)) in chars.iter().enumerate() {
+        if !c.is_alphanumeric() {
+            if let Some(start) = start.take() {
+                words.push(&text[start..offset]);
+            }
+            continue;
+        }
+        let previous = index.checked_sub(1).map(|index| chars[index].1);
+        let next = chars.get(index + 1).map(|&(_, c)| c);
+        let boundary = c.is_uppercase() && previous.is_some_and(|previous| {
+            previous.is_lowercase() || previous.is_numeric()
+                || (previous.is_uppercase() && next.is_some_and(char::is_lowercase))
+        });
+        match start {
+            Some(word) if boundary => {
+                words.push(&text[word..offset]);
+                start = Some(offset);
+            }
+            Some(_) => {}
+            None => start = Some(offset),
+        }
+    }
+    if let Some(start) = start {
+        words.push(&text[start..]);
+    }
+    words
+}
+
+impl AddField {
+    fn check(&self) -> Result<()> {
+        if self.options.target != Target::Value || self.options.column.is_some() {
+            return Err(eyre!("fields are added to JSON record values, `target` must be `value` and `column` is not supported"));
+        }
+        if self.options.input == Input::Key && self.options.path.is_some() {
+            return Err(eyre!("`path` selects value fields, it is not supported with `input: key`"));
+        }
+        Ok(())
+    }
+
+    /// Field values for the matches in `data`, the first one only unless `all`
+    fn values(&self, data: &[u8], all: bool) -> Vec<Value> {
+        let limit = if all { usize::MAX } else { 1 };
+        self.regex.captures_iter(data).take(limit).map(|caps| match &self.value {
+            Value::String(template) => {
+                let mut expanded = vec![];
+                caps.expand(template.as_bytes(), &mut expanded);
+                Value::String(String::from_utf8_lossy(&expanded).into_owned())
+            }
+            value => value.clone(),
+        }).collect()
+    }
+
+    fn add(&self, record: &mut Value, values: Vec<Value>, append: bool) -> Result<()> {
+        let Value::Object(fields) = record else {
+            return Err(eyre!("cannot add field `{}` to a record that is not a JSON object", self.field));
+        };
+        if !append {
+            fields.extend(values.into_iter().take(1).map(|value| (self.field.clone(), value)));
+            return Ok(());
+        }
+        let entry = fields.entry(self.field.clone()).or_insert_with(|| Value::Array(vec![]));
+        if !entry.is_array() {
+            *entry = Value::Array(vec![entry.take()]);
+        }
+        if let Value::Array(items) = entry {
+            items.extend(values);
+        }
+        Ok(())
+    }
+}
+
+impl Route {
+    fn resolve(&mut self, limits: &RegexLimits) -> Result<()> {
+        if self.options.target != Target::Value || self.options.column.is_some() {
+            return Err(eyre!("routes are written to JSON record values, `target` must be `value` and `column` is not supported"));
+        }
+        if self.options.input == Input::Key && self.options.path.is_some() {
+            return Err(eyre!("`path` selects value fields, it is not supported with `input: key`"));
+        }
+        if self.routes.is_empty() {
+            return Err(eyre!("`route` needs at least one entry in `routes`"));
+        }
+        for rule in &mut self.routes {
+            rule.regex.compile_with(&self.options.flags, Engine::Default, limits)
+                .wrap_err_with(|| format!("invalid regex of route `{}`", rule.label))?;
+        }
+        Ok(())
+    }
+
+    /// Labels of the routes matching any of the texts, in `routes` order, the first one only unless `all`
+    fn labels<'a>(&self, texts: impl Iterator<Item = &'a [u8]> + Clone) -> Vec<&str> {
+        let limit = if self.all { usize::MAX } else { 1 };
+        self.routes.iter()
+            .filter(|rule| texts.clone().any(|text| rule.regex.is_match(text)))
+            .map(|rule| rule.label.as_str())
+            .take(limit)
+            .collect()
+    }
+
+    /// Value of the route field, `None` when no route matched and there is no `default`
+    fn route(&self, labels: Vec<&str>) -> Option<Value> {
+        let labels = match (labels.is_empty(), &self.default) {
+            (true, None) => return None,
+            (true, Some(default)) => vec![default.as_str()],
+            (false, _) => labels,
+        };
+        let mut labels = labels.into_iter().map(|label| Value::String(label.to_string()));
+        match self.all {
+            true => Some(Value::Array(labels.collect())),
+            false => labels.next(),
+        }
+    }
+
+    fn write(&self, record: &mut Value, route: Value) -> Result<()> {
+        let Value::Object(fields) = record else {
+            return Err(eyre!("cannot add field `{}` to a record that is not a JSON object", self.field));
+        };
+        fields.insert(self.field.clone(), route);
+        Ok(())
+    }
+}
+
+impl SetKey {
+    fn check(&self) -> Result<()> {
+        if self.options.target != Target::Value {
+            return Err(eyre!("`set_key` reads the record value, `target` must be `value`"));
+        }
+        if self.options.input == Input::Key && self.options.path.is_some() {
+            return Err(eyre!("`path` selects value fields, it is not supported with `input: key`"));
+        }
+        if self.buckets == Some(0) {
+            return Err(eyre!("`buckets` must be at least 1"));
+        }
+        match &self.group {
+            Some(group) => group.check(&self.regex),
+            None => Ok(()),
+        }
+    }
+
+    fn capture(&self, data: &[u8]) -> Option<Vec<u8>> {
+        let caps = self.regex.captures(data)?;
+        let group = match &self.group {
+            None => caps.get(0),
+            Some(group) => group.get(&caps),
+        };
+        let captured = group?.as_bytes();
+        match self.buckets {
+            Some(buckets) => Some(self.bucket(captured, buckets).to_string().into_bytes()),
+            None => Some(captured.to_vec()),
+        }
+    }
+
+    /// The first 8 bytes of the digest modulo `buckets`, stable across restarts and deployments
+    fn bucket(&self, captured: &[u8], buckets: u64) -> u64 {
+        let digest = self.algorithm.digest(b"", captured);
+        let prefix: [u8; 8] = digest[..8].try_into().expect("digests are at least 8 bytes");
+        u64::from_be_bytes(prefix) % buckets
+    }
+
+    fn combine(&self, key: Option<&RecordData>, captured: Vec<u8>) -> Vec<u8> {
+        let separator = self.separator.as_bytes();
+        match (self.combine, key) {
+            (CombineKey::Replace, _) | (_, None) => captured,
+            (CombineKey::Prepend, Some(key)) => [&captured, separator, key.as_ref()].concat(),
+            (CombineKey::Append, Some(key)) => [key.as_ref(), separator, &captured].concat(),
+        }
+    }
+}
+
+impl DeleteField {
+    fn check(&self) -> Result<()> {
+        match (&self.regex, &self.options.path) {
+            (None, None) => Err(eyre!("`delete_field` needs a `regex` on field names or a `path`")),
+            (None, Some(path)) if path.is_root() => Err(eyre!("`path` must select fields, not the whole record")),
+            _ if self.options.column_index.is_some() => Err(eyre!("`delete_field` only applies to JSON records")),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns how many fields were removed
+    fn delete(&self, value: &mut Value) -> usize {
+        match (&self.regex, &self.options.path) {
+            (Some(regex), Some(path)) => {
+                let mut removed = 0;
+                path.for_each_mut(value, &mut |selected| removed += remove_fields(regex, selected));
+                removed
+            }
+            (Some(regex), None) => remove_fields(regex, value),
+            (None, Some(path)) => path.remove(value),
+            (None, None) => 0,
+        }
+    }
+}
+
+fn remove_fields(regex: &Pattern, value: &mut Value) -> usize {
+    match value {
+        Value::Object(map) => {
+            let before = map.len();
+            map.retain(|name, _| !regex.is_match(name.as_bytes()));
+            let removed = before - map.len();
+            removed + map.values_mut().map(|child| remove_fields(regex, child)).sum::<usize>()
+        }
+        Value::Array(items) => items.iter_mut().map(|child| remove_fields(regex, child)).sum(),
+        _ => 0,
+    }
+}
+
+impl TrimField {
+    fn check(&self) -> Result<()> {
+        if self.max_chars == 0 {
+            return Err(eyre!("`max_chars` must be at least 1"));
+        }
+        if self.marker.chars().count() >= self.max_chars {
+            return Err(eyre!("`marker` must be shorter than `max_chars`, the marker counts toward the limit"));
+        }
+        match &self.group {
+            Some(group) => group.check(&self.regex),
+            None => Ok(()),
+        }
+    }
+
+    /// Text the operation cuts in a match: the whole match or its `group`
+    fn target<'h>(&self, caps: &Captures<'h>) -> Option<regex::bytes::Match<'h>> {
+        match &self.group {
+            Some(group) => group.get(caps),
+            None => caps.get(0),
+        }
+    }
+
+    fn exceeds(&self, text: &[u8]) -> bool {
+        String::from_utf8_lossy(text).chars().nth(self.max_chars).is_some()
+    }
+
+    fn trim<'h>(&self, data: &'h [u8]) -> Cow<'h, [u8]> {
+        if self.count(data, 1) == 0 {
+            return Cow::Borrowed(data);
+        }
+        self.regex.replace_all(data, |caps: &Captures| {
+            let whole = caps.get(0).expect("group 0 is always set");
+            match self.target(caps).filter(|target| self.exceeds(target.as_bytes())) {
+                Some(target) => [
+                    &data[whole.start()..target.start()],
+                    &self.trimmed(target.as_bytes()),
+                    &data[target.end()..whole.end()],
+                ].concat(),
+                None => whole.as_bytes().to_vec(),
+            }
+        })
+    }
+
+    /// The first characters of the text followed by the marker, `max_chars` characters in all
+    fn trimmed(&self, text: &[u8]) -> Vec<u8> {
+        let text = String::from_utf8_lossy(text);
+        let keep = self.max_chars - self.marker.chars().count();
+        let end = text.char_indices().nth(keep).map_or(text.len(), |(index, _)| index);
+        [text[..end].as_bytes(), self.marker.as_bytes()].concat()
+    }
+
+    /// Matches over the limit, the ones the operation changes
+    fn count(&self, data: &[u8], limit: usize) -> usize {
+        self.regex.captures_iter(data)
+            .filter(|caps| self.target(caps).is_some_and(|target| self.exceeds(target.as_bytes())))
+            .take(limit)
+            .count()
+    }
+}
+
+impl AnonymizeIp {
+    fn check(&self) -> Result<()> {
+        if self.prefix_v4.is_some_and(|prefix| prefix > 32) || self.prefix_v6.is_some_and(|prefix| prefix > 128) {
+            return Err(eyre!("`prefix_v4` must be at most 32 and `prefix_v6` at most 128"));
+        }
+        match (self.strategy, &self.salt) {
+            (IpStrategy::Truncate, Some(_)) => Err(eyre!("`salt` is only used by `strategy: hash`")),
+            (IpStrategy::Hash, None) => Err(eyre!("`strategy: hash` needs a `salt` param, unsalted IPv4 pseudonyms are reversed by hashing every address")),
+            _ => Ok(()),
+        }
+    }
+
+    fn anonymize(&self, matched: &[u8]) -> String {
+        let text = String::from_utf8_lossy(matched);
+        let default_prefix = |truncated| if self.strategy == IpStrategy::Truncate { truncated } else { 0 };
+        match text.parse::<std::net::IpAddr>() {
+            Ok(std::net::IpAddr::V4(ip)) => {
+                let bits = self.anonymize_bits(u32::from(ip).into(), 32, self.prefix_v4.unwrap_or(default_prefix(24)));
+                std::net::Ipv4Addr::from(bits as u32).to_string()
+            }
+            Ok(std::net::IpAddr::V6(ip)) => {
+                let bits = self.anonymize_bits(u128::from(ip), 128, self.prefix_v6.unwrap_or(default_prefix(48)));
+                std::net::Ipv6Addr::from(bits).to_string()
+            }
+            Err(_) => text.into_owned(),
+        }
+    }
+
+    /// The `width` bits of an address with the ones after `prefix` zeroed or pseudonymized
+    fn anonymize_bits(&self, bits: u128, width: u32, prefix: u32) -> u128 {
+        let host_mask = if prefix == width { 0 } else { (u128::MAX >> (128 - width)) >> prefix };
+        match self.strategy {
+            IpStrategy::Truncate => bits & !host_mask,
+            IpStrategy::Hash => {
+                // each bit is flipped by a digest of the bits before it, as in Crypto-PAn
+                let mut anonymized = bits;
+                for position in prefix..width {
+                    let before = if position == 0 { 0 } else { bits >> (width - position) };
+                    let input = [&[width as u8, position as u8][..], &before.to_be_bytes()].concat();
+                    let flip = HashAlgorithm::Sha256.digest(self.salt_value.as_bytes(), &input)[0] & 1;
+                    anonymized ^= u128::from(flip) << (width - 1 - position);
+                }
+                anonymized
+            }
+        }
+    }
+}
+
+impl Timestamp {
+    fn resolve(&mut self) -> Result<()> {
+        self.offset = match self.timezone.as_deref() {
+            None | Some("UTC" | "Z") => None,
+            Some(timezone) => Some(timezone.parse().map_err(|_| {
+                eyre!("invalid `timezone` `{timezone}`, expected `UTC` or a fixed offset such as `+02:00`, named zones are not supported")
+            })?),
+        };
+        Ok(())
+    }
+
+    fn parse(&self, text: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+        use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+
+        let local = |naive: NaiveDateTime| match self.offset {
+            Some(offset) => offset.from_local_datetime(&naive).single().map(|time| time.with_timezone(&Utc)),
+            None => Some(naive.and_utc()),
+        };
+        match &self.from_format {
+            TimeFormat::Rfc3339 => DateTime::parse_from_rfc3339(text).ok().map(|time| time.with_timezone(&Utc)),
+            TimeFormat::Rfc2822 => DateTime::parse_from_rfc2822(text).ok().map(|time| time.with_timezone(&Utc)),
+            TimeFormat::Unix => DateTime::from_timestamp(text.parse().ok()?, 0),
+            TimeFormat::UnixMs => DateTime::from_timestamp_millis(text.parse().ok()?),
+            TimeFormat::Strftime(format) => match DateTime::parse_from_str(text, format) {
+                Ok(time) => Some(time.with_timezone(&Utc)),
+                Err(_) => local(NaiveDateTime::parse_from_str(text, format).ok()?),
+            },
+        }
+    }
+
+    fn rewrite(&self, matched: &[u8]) -> String {
+        let text = String::from_utf8_lossy(matched);
+        let Some(time) = self.parse(&text) else {
+            return text.into_owned();
+        };
+        match &self.to_format {
+            TimeFormat::Rfc3339 => time.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true),
+            TimeFormat::Rfc2822 => time.to_rfc2822(),
+            TimeFormat::Unix => time.timestamp().to_string(),
+            TimeFormat::UnixMs => time.timestamp_millis().to_string(),
+            TimeFormat::Strftime(format) => time.format(format).to_string(),
+        }
+    }
+}
+
+impl Placeholder {
+    fn check(&self) -> Result<()> {
+        if self.label.is_empty() || !self.label.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(eyre!("`label` must be letters, digits and `_`, e.g. `SSN`"));
+        }
+        if self.salt.is_some() && self.scope == PlaceholderScope::Record {
+            return Err(eyre!("`salt` only applies to `scope: stream`, `scope: record` numbers values"));
+        }
+        Ok(())
+    }
+
+    /// Forget the values numbered in the previous record
+    fn reset() {
+        PLACEHOLDER_VALUES.with(|values| values.borrow_mut().clear());
+    }
+
+    fn token(&self, matched: &[u8]) -> String {
+        let id = match self.scope {
+            PlaceholderScope::Record => PLACEHOLDER_VALUES.with(|values| {
+                let mut values = values.borrow_mut();
+                let seen = values.entry(self.label.clone()).or_default();
+                let position = seen.iter().position(|value| value == matched).unwrap_or_else(|| {
+                    seen.push(matched.to_vec());
+                    seen.len() - 1
+                });
+                (position + 1).to_string()
+            }),
+            PlaceholderScope::Stream => HashAlgorithm::Sha256.hex_digest(self.salt_value.as_bytes(), matched)[..8].to_string(),
+        };
+        format!("<{}_{id}>", self.label)
+    }
+}
+
+impl Reorder {
+    /// Check that `to` only references capture groups of the regex, each of them once
+    fn check(&self) -> Result<()> {
+        let groups = self.regex.captures_len() - 1;
+        if groups < 2 {
+            return Err(eyre!("`reorder` needs a regex with at least two capture groups to reorder"));
+        }
+        let mut uses = vec![0; groups];
+        let mut rest = self.to.as_str();
+        while let Some(start) = rest.find('$') {
+            rest = &rest[start + 1..];
+            let reference = match rest.strip_prefix('{').and_then(|inner| inner.split_once('}')) {
+                Some((reference, after)) => {
+                    rest = after;
+                    reference
+                }
+                None if rest.starts_with('$') => {
+                    rest = &rest[1..];
+                    continue;
+                }
+                None => {
+                    let end = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+                    let reference = &rest[..end];
+                    rest = &rest[end..];
+                    reference
+                }
+            };
+            let index = match reference.parse::<usize>() {
+                Ok(index) => Some(index),
+                Err(_) => self.regex.capture_names().position(|name| name == Some(reference)),
+            };
+            match index {
+                Some(index @ 1..) if index <= groups => uses[index - 1] += 1,
+                _ => return Err(eyre!("`to` references `{reference}`, expected a capture group of the regex, use `replace` for templates or group 0")),
+            }
+        }
+        let group_name = |index: usize| match self.regex.capture_names().nth(index).flatten() {
+            Some(name) => format!("`{name}`"),
+            None => format!("{index}"),
+        };
+        if let Some(index) = uses.iter().position(|&n| n != 1) {
+            let what = if uses[index] == 0 { "leaves out" } else { "repeats" };
+            return Err(eyre!("`to` {what} capture group {}, `reorder` uses each group once, use `replace` to drop or copy text", group_name(index + 1)));
+        }
+        Ok(())
+    }
+}
+
+impl Url {
+    fn check(&self) -> Result<()> {
+        if self.strip.is_empty() && !self.redact_userinfo && !self.strip_fragment {
+            return Err(eyre!("`url` needs `strip`, `redact_userinfo` or `strip_fragment`"));
+        }
+        Ok(())
+    }
+
+    fn rewrite(&self, matched: &[u8]) -> String {
+        let text = String::from_utf8_lossy(matched);
+        let Some(mut url) = UrlParts::parse(&text) else {
+            return text.into_owned();
+        };
+        url.strip_params(|name| self.strip.iter().any(|strip| strip == "*" || strip == name));
+        if self.redact_userinfo && url.userinfo.is_some() {
+            url.userinfo = Some("REDACTED");
+        }
+        if self.strip_fragment {
+            url.fragment = None;
+        }
+        url.to_string()
+    }
+
+    /// Whether the match is a URL with components the operation rewrites
+    fn changes(&self, matched: &[u8]) -> bool {
+        self.rewrite(matched).as_bytes() != matched
+    }
+
+    fn count(&self, data: &[u8], limit: usize) -> usize {
+        self.regex.captures_iter(data).filter(|caps| self.changes(&caps[0])).take(limit).count()
+    }
+}
+
+impl Mask {
+    fn mask(&self, matched: &[u8]) -> String {
+        mask_chars(&String::from_utf8_lossy(matched), self.mask_char)
+    }
+}
+
+/// Replace alphanumeric characters with `mask_char`, keeping separators so the shape of the value shows
+fn mask_chars(text: &str, mask_char: char) -> String {
+    text.chars().map(|c| if c.is_alphanumeric() { mask_char } else { c }).collect()
+}
+
+impl Redact {
+    fn resolve(&mut self, format: &RecordFormat, limits: &RegexLimits) -> Result<()> {
+        if !matches!(format, RecordFormat::Text | RecordFormat::Json | RecordFormat::Ndjson) {
+            return Err(eyre!("`redact` only applies to JSON records"));
+        }
+        if self.options.path.is_some() {
+            return Err(eyre!("`redact` applies to the whole record, select the values to keep with `allow`"));
+        }
+        if let Some(regex) = self.allow_regex.as_mut() {
+            regex.compile_with(&Flags::default(), Engine::Default, limits).wrap_err("invalid `allow_regex`")?;
+        }
+        Ok(())
+    }
+
+    /// Returns how many values were masked
+    fn redact(&self, value: &mut Value) -> usize {
+        // allowed values are told apart by address, the document is not reshaped while masking
+        let allowed: HashSet<*const Value> = self.allow.iter()
+            .flat_map(|path| path.select(value))
+            .map(|selected| selected as *const Value)
+            .collect();
+        self.redact_value(value, &mut String::new(), &allowed)
+    }
+
+    fn redact_value(&self, value: &mut Value, pointer: &mut String, allowed: &HashSet<*const Value>) -> usize {
+        if allowed.contains(&(value as *const Value)) {
+            return 0;
+        }
+        let redact_child = |pointer: &mut String, token: &str, child: &mut Value| {
+            let len = pointer.len();
+            pointer.push('/');
+            pointer.push_str(&token.replace('~', "~0").replace('/', "~1"));
+            let masked = self.redact_value(child, pointer, allowed);
+            pointer.truncate(len);
+            masked
+        };
+        match value {
+            Value::String(text) => {
+                if self.allow_regex.as_ref().is_some_and(|regex| regex.is_match(pointer.as_bytes())) {
+                    return 0;
+                }
+                let masked = mask_chars(text, self.mask_char);
+                if masked == *text {
+                    return 0;
+                }
+                *text = masked;
+                1
+            }
+            Value::Object(map) => map.iter_mut().map(|(name, child)| redact_child(pointer, name, child)).sum(),
+            Value::Array(items) => items.iter_mut().enumerate()
+                .map(|(index, child)| redact_child(pointer, &index.to_string(), child))
+                .sum(),
+            _ => 0,
+        }
+    }
+}
+
+impl CardMask {
+    fn check(&self) -> Result<()> {
+        if self.keep_last >= *CARD_DIGITS.start() {
+            return Err(eyre!("`keep_last` must be below {}, the shortest card number", CARD_DIGITS.start()));
+        }
+        Ok(())
+    }
+
+    /// Ranges of the card numbers among the candidate matches. A candidate that fails the checksum is
+    /// searched for cards between its digit groups, e.g. a card next to a quantity in a run of digits.
+    fn cards(&self, data: &[u8]) -> Vec<std::ops::Range<usize>> {
+        let mut cards = vec![];
+        for candidate in self.regex.find_iter(data) {
+            let text = candidate.as_bytes();
+            if is_card_number(text) {
+                cards.push(candidate.range());
+                continue;
+            }
+            let mut starts = vec![0];
+            let mut ends = vec![];
+            for (i, pair) in text.windows(2).enumerate() {
+                match (pair[0].is_ascii_digit(), pair[1].is_ascii_digit()) {
+                    (true, false) => ends.push(i + 1),
+                    (false, true) => starts.push(i + 1),
+                    _ => {}
+                }
+            }
+            ends.push(text.len());
+            // digits before each position, so only ends a card length away are checked
+            let digits_before: Vec<usize> = std::iter::once(0)
+                .chain(text.iter().scan(0, |count, c| {
+                    *count += usize::from(c.is_ascii_digit());
+                    Some(*count)
+                }))
+                .collect();
+            let mut from = 0;
+            for start in starts.into_iter().filter(|&start| text[start].is_ascii_digit()) {
+                if start < from {
+                    continue;
+                }
+                let card_end = ends[ends.partition_point(|&end| end <= start)..].iter()
+                    .skip_while(|&&end| (digits_before[end] - digits_before[start]) < *CARD_DIGITS.start())
+                    .take_while(|&&end| (digits_before[end] - digits_before[start]) <= *CARD_DIGITS.end())
+                    .filter(|&&end| is_card_number(&text[start..end]))
+                    .last();
+                if let Some(&end) = card_end {
+                    cards.push(candidate.start() + start..candidate.start() + end);
+                    from = end;
+                }
+            }
+        }
+        cards
+    }
+
+    fn mask<'h>(&self, data: &'h [u8]) -> Cow<'h, [u8]> {
+        let cards = self.cards(data);
+        if cards.is_empty() {
+            return Cow::Borrowed(data);
+        }
+        let mut output = Vec::with_capacity(data.len());
+        let mut last = 0;
+        for card in cards {
+            output.extend_from_slice(&data[last..card.start]);
+            let mut masked = data[card.clone()].iter().filter(|c| c.is_ascii_digit()).count().saturating_sub(self.keep_last);
+            output.extend(String::from_utf8_lossy(&data[card.clone()]).chars()
+                .map(|c| match c {
+                    '0'..='9' if masked > 0 => {
+                        masked -= 1;
+                        self.mask_char
+                    }
+                    c => c,
+                })
+                .collect::<String>()
+                .bytes());
+            last = card.end;
+        }
+        output.extend_from_slice(&data[last..]);
+        Cow::Owned(output)
+    }
+}
+
+/// Number of digits of a card number
+const CARD_DIGITS: std::ops::RangeInclusive<usize> = 13..=19;
+
+/// Whether the digits of `text`, ignoring separators, are a card number with a valid Luhn checksum
+fn is_card_number(text: &[u8]) -> bool {
+    let digits: Vec<u32> = text.iter().filter(|c| c.is_ascii_digit()).map(|c| u32::from(c - b'0')).collect();
+    if !CARD_DIGITS.contains(&digits.len()) {
+        return false;
+    }
+    let sum: u32 = digits.iter().rev().enumerate()
+        .map(|(i, &digit)| match (i % 2, digit * 2) {
+            (0, _) => digit,
+            (_, doubled) if doubled > 9 => doubled - 9,
+            (_, doubled) => doubled,
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+impl Match {
+    /// With a path or a column, the predicate holds when any selected value matches
+    fn matches(&self, data: &mut Data) -> Result<bool> {
+        if let Some(xml_path) = &self.options.xml_path {
+            return Ok(data.xml_mut()?.texts(xml_path)?.iter().any(|text| self.is_match(text)));
+        }
+        match (&self.options.path, self.options.column_index) {
+            (Some(path), _) => Ok(path.select(data.json_mut()?).into_iter()
+                .any(|value| value.as_str().is_some_and(|text| self.is_match(text.as_bytes())))),
+            (None, Some(index)) => Ok(data.csv_mut()?.fields(index).into_iter()
+                .any(|field| self.is_match(field))),
+            (None, None) => Ok(self.is_match(data.bytes())),
+        }
+    }
+
+    fn is_match(&self, data: &[u8]) -> bool {
+        match self.options.normalize {
+            Some(normalization) => self.regex.is_match(&normalization.apply(data)),
+            None => self.regex.is_match(data),
+        }
+    }
+}
+
+/// Parse a keyword param (e.g. `spec_format: yaml`), falling back to the default when absent
+fn get_keyword_param<T: DeserializeOwned + Default>(params: &SmartModuleExtraParams, name: &str) -> Result<T> {
+    match params.get(name) {
+        Some(raw) => serde_json::from_value(Value::String(raw.trim().to_string()))
+            .map_err(|err| eyre!("invalid `{name}` param `{raw}`: {err}")),
+        None => Ok(T::default()),
+    }
+}
+
+/// Parse a `true`/`false` param, `false` when absent
+fn get_flag_param(params: &SmartModuleExtraParams, name: &str) -> Result<bool> {
+    match params.get(name).map(|raw| raw.trim()) {
+        None | Some("false") => Ok(false),
+        Some("true") => Ok(true),
+        Some(raw) => Err(eyre!("invalid `{name}` param `{raw}`: expected `true` or `false`")),
+    }
+}
+
+/// Substitute `{{param:name}}` references with the value of the init param `name`. With `escape`, `$` is
+/// doubled so the value is inserted as is by the regex crate expansion, literal replacements take it unchanged
+fn interpolate_params(text: &str, params: &SmartModuleExtraParams, escape: bool) -> Result<String> {
+    let mut result = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("{{param:") {
+        result.push_str(&rest[..start]);
+        let reference = &rest[start + "{{param:".len()..];
+        let end = reference.find("}}")
+            .ok_or_else(|| eyre!("unterminated `{{{{param:` reference in `{text}`"))?;
+        let name = &reference[..end];
+        let value = params.get(name).ok_or_else(|| SmartModuleInitError::MissingParam(name.to_string()))?;
+        match escape {
+            true => result.push_str(&value.replace('$', "$$")),
+            false => result.push_str(value),
+        }
+        rest = &reference[end + 2..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Parse input paramters
+fn get_params(params: SmartModuleExtraParams) -> Result<Vec<Operation>> {
+    let format: SpecFormat = get_keyword_param(&params, SPEC_FORMAT_PARAM)?;
+    let sources = spec_sources(&params)?;
+    match parse_operations(&format, &sources, &params) {
+        Ok(operations) => {
+            Ok(operations)
+        }
+        Err(err) => {
+            logging::log(LogLevel::Error, &[], format_args!("unable to parse spec from params: {err}"));
+            Err(err)
+        }
+    }
+}
+
+/// The decoded `spec` param followed by `spec.1`, `spec.2`... which are appended to it,
+/// or the lists packed in `compiled_spec`. None at all when `spec` is absent and `allow_empty_spec` is set
+fn spec_sources(params: &SmartModuleExtraParams) -> Result<Vec<(String, Cow<'_, str>)>> {
+    if let Some(raw) = params.get(COMPILED_SPEC_PARAM) {
+        if [PARAM_NAME, SPEC_REF_PARAM, SPEC_ENCODING_PARAM].iter().any(|name| params.get(name).is_some()) {
+            return Err(eyre!("`{COMPILED_SPEC_PARAM}` replaces the `{PARAM_NAME}`, `{SPEC_REF_PARAM}` and `{SPEC_ENCODING_PARAM}` params, set either"));
+        }
+        // the compiled lists already include any `spec.N`, more would be silently dropped
+        if params.get(&format!("{PARAM_NAME}.1")).is_some() {
+            return Err(eyre!("`{COMPILED_SPEC_PARAM}` replaces the `{PARAM_NAME}.N` params, compile them into it"));
+        }
+        let compiled = CompiledSpec::decode(raw)?;
+        return Ok(compiled.sources.into_iter().map(|(param, lists)| (param, Cow::Owned(lists))).collect());
+    }
+    let encoding: SpecEncoding = get_keyword_param(params, SPEC_ENCODING_PARAM)?;
+    let mut sources = if let Some(reference) = params.get(SPEC_REF_PARAM) {
+        if params.get(PARAM_NAME).is_some() {
+            return Err(eyre!("`{SPEC_REF_PARAM}` replaces the `{PARAM_NAME}` param, set either"));
+        }
+        let reference = SpecRef::parse(reference)?;
+        let spec = match (reference.read(params)?, &encoding) {
+            (spec, SpecEncoding::Plain) => spec,
+            (spec, encoding) => Cow::Owned(encoding.decode(&spec)?.into_owned()),
+        };
+        vec![(reference.label(), spec)]
+    } else {
+        let Some(raw_spec) = params.get(PARAM_NAME) else {
+            return default_sources(params, EMBEDDED_SPEC);
+        };
+        vec![(PARAM_NAME.to_string(), encoding.decode(raw_spec)?)]
+    };
+    // `spec.1`, `spec.2`... layer on top of the referenced spec as they do on `spec`
+    for n in 1.. {
+        let name = format!("{PARAM_NAME}.{n}");
+        let Some(raw_spec) = params.get(&name) else {
+            break;
+        };
+        sources.push((name, encoding.decode(raw_spec)?));
+    }
+    Ok(sources)
+}
+
+/// Where the `spec_ref` param points: `param:NAME`, a param the deployment fills in, e.g. from a secret,
+/// or `file:PATH`, read where the runtime has a filesystem (library API and CLI)
+enum SpecRef<'a> {
+    Param(&'a str),
+    File(&'a str),
+}
+
+impl<'a> SpecRef<'a> {
+    fn parse(reference: &'a str) -> Result<Self> {
+        match reference.split_once(':') {
+            Some(("param", name)) if !name.is_empty() => Ok(SpecRef::Param(name)),
+            Some(("file", path)) if !path.is_empty() => Ok(SpecRef::File(path)),
+            Some(("http" | "https", _)) => Err(eyre!(
+                "`{SPEC_REF_PARAM}` cannot fetch `{reference}`, SmartModules have no network access\n  hint: fetch the spec when deploying and pass it in a param referenced with `param:NAME`"
+            )),
+            _ => Err(eyre!("invalid `{SPEC_REF_PARAM}` `{reference}`, expected `param:NAME` or `file:PATH`")),
+        }
+    }
+
+    /// The spec text as referenced, before `spec_encoding`
+    fn read(&self, params: &'a SmartModuleExtraParams) -> Result<Cow<'a, str>> {
+        match self {
+            SpecRef::Param(name) => match params.get(name) {
+                Some(spec) => Ok(Cow::Borrowed(spec)),
+                None => Err(SmartModuleInitError::MissingParam(name.to_string()).into()),
+            },
+            SpecRef::File(_) if cfg!(all(target_arch = "wasm32", target_os = "unknown")) => Err(eyre!(
+                "`{SPEC_REF_PARAM}: file:` needs a filesystem, which the SmartModule runtime does not have\n  hint: pass the spec in a param referenced with `param:NAME`"
+            )),
+            SpecRef::File(path) => std::fs::read_to_string(path)
+                .map(Cow::Owned)
+                .map_err(|err| eyre!("cannot read `{SPEC_REF_PARAM}` file `{path}`: {err}")),
+        }
+    }
+
+    /// Name errors in the referenced spec point at
+    fn label(&self) -> String {
+        match self {
+            SpecRef::Param(name) => name.to_string(),
+            SpecRef::File(_) => SPEC_REF_PARAM.to_string(),
+        }
+    }
+}
+
+/// Operations to run without a `spec` param: the spec embedded at build time, else none with
+/// `allow_empty_spec`
+fn default_sources(params: &SmartModuleExtraParams, embedded: Option<&'static str>) -> Result<Vec<(String, Cow<'static, str>)>> {
+    if let Some(embedded) = embedded {
+        return Ok(vec![(format!("{PARAM_NAME} (embedded)"), Cow::Borrowed(embedded))]);
+    }
+    if get_flag_param(params, ALLOW_EMPTY_SPEC_PARAM)? {
+        return Ok(vec![]);
+    }
+    Err(SmartModuleInitError::MissingParam(PARAM_NAME.to_string()).into())
+}
+
+/// Unresolved operations, each list labelled with the param it comes from
+type OperationLists = Vec<(String, Vec<Value>)>;
+
+/// Operation lists of each tenant, from `spec` params mapping tenant ids to lists
+fn get_tenant_lists(params: &SmartModuleExtraParams) -> Result<BTreeMap<String, OperationLists>> {
+    let format: SpecFormat = get_keyword_param(params, SPEC_FORMAT_PARAM)?;
+    let mut tenants: BTreeMap<String, OperationLists> = BTreeMap::new();
+    for (param, raw_spec) in spec_sources(params)? {
+        let lists: BTreeMap<String, Vec<Value>> = format.parse(&raw_spec).map_err(|err| {
+            eyre!("cannot parse `{param}` param: {err}\n  hint: with `{TENANT_SELECTOR_PARAM}`, expected tenant ids mapped to lists of operations, e.g. {{\"acme\": [{{\"mask\": {{\"pattern\": \"ssn\"}}}}]}}")
+        })?;
+        for (tenant, list) in lists {
+            tenants.entry(tenant.clone()).or_default().push((format!("{param}.{tenant}"), list));
+        }
+    }
+    Ok(tenants)
+}
+
+/// Parse and resolve each operation on its own so errors point at the offending entry,
+/// disabled operations are validated but left out
+fn parse_operations(format: &SpecFormat, sources: &[(String, Cow<str>)], params: &SmartModuleExtraParams) -> Result<Vec<Operation>> {
+    let mut lists = vec![];
+    for (param, raw_spec) in sources {
+        let list: Vec<Value> = format.parse(raw_spec).map_err(|err| {
+            eyre!("cannot parse `{param}` param: {err}\n  hint: expected a list of operations, e.g. [{{\"replace\": {{\"regex\": \"\\\\d+\", \"with\": \"*\"}}}}]")
+        })?;
+        lists.push((param.clone(), list));
+    }
+    resolve_operations(format, lists, params)
+}
+
+/// Resolve the operation lists of each param in order
+fn resolve_operations(format: &SpecFormat, lists: OperationLists, params: &SmartModuleExtraParams) -> Result<Vec<Operation>> {
+    let mut entries: Vec<Value> = vec![];
+    let mut origins = vec![];
+    let mut stages = vec![];
+    let mut definitions = Definitions::default();
+    for (param, list) in &lists {
+        for (index, entry) in list.iter().cloned().enumerate() {
+            let single_key = entry.as_object().is_some_and(|fields| fields.len() == 1);
+            if let Some(Value::Object(fragments)) = entry.get("definitions").filter(|_| single_key) {
+                definitions.add(fragments.clone()).map_err(|err| spec_error(param, index, "definitions", err))?;
+                continue;
+            }
+            if !entry.get("stage").is_some_and(Value::is_string) {
+                origins.push((param.as_str(), index, None));
+                entries.push(entry);
+                continue;
+            }
+            // stages are flattened so `overrides` reach their operations by name
+            let stage: StageSpec = serde_json::from_value(entry).map_err(|err| spec_error(param, index, "stage", err))?;
+            origins.extend((0..stage.ops.len()).map(|_| (param.as_str(), index, Some(stages.len()))));
+            entries.extend(stage.ops);
+            stages.push((stage.stage, StageFlow {
+                start: 0,
+                end: 0,
+                stop_on_no_match: stage.stop_on_no_match,
+                stop_after_first_match: stage.stop_after_first_match,
+            }));
+        }
+    }
+    if let Some(raw_overrides) = params.get(OVERRIDES_PARAM) {
+        apply_overrides(format, raw_overrides, &mut entries)?;
+    }
+
+    let mut ops = vec![];
+    let mut op_stages = vec![];
+    for (mut entry, (param, index, stage)) in entries.into_iter().zip(origins) {
+        let name = match &entry {
+            Value::Object(map) if map.len() == 1 => map.keys().next().cloned().unwrap_or_default(),
+            _ => return Err(spec_error(param, index, "?", "expected an object with a single operation name as key")),
+        };
+        let name = match stage {
+            Some(id) => format!("{}/{name}", stages[id].0),
+            None => name,
+        };
+        definitions.expand_entry(&mut entry).map_err(|err| spec_error(param, index, &name, err))?;
+        let mut op: Operation = serde_json::from_value(entry).map_err(|err| spec_error(param, index, &name, err))?;
+        if op.options().enabled {
+            let name = match &op.options().name {
+                Some(op_name) => format!("{name}: {op_name}"),
+                None => name,
+            };
+            op.resolve(params).map_err(|err| spec_error(param, index, &name, err))?;
+            ops.push(op);
+            op_stages.push(stage);
+        }
+    }
+
+    for (id, (_, mut flow)) in stages.into_iter().enumerate() {
+        let Some(start) = op_stages.iter().position(|stage| *stage == Some(id)) else {
+            continue;
+        };
+        flow.start = start;
+        flow.end = start + op_stages[start..].iter().take_while(|stage| **stage == Some(id)).count();
+        for op in &mut ops[flow.start..flow.end] {
+            op.parts_mut().1.stage = Some(flow.clone());
+        }
+    }
+    check_rematches(&ops, get_flag_param(params, STRICT_PARAM)?)?;
+    Ok(ops)
+}
+
+/// Check that each `$1`, `$name` and `${name}` reference of a replacement is a group of the regex,
+/// the regex crate expands other references to an empty string. Template calls are checked by the template.
+fn check_capture_references(with: &str, regex: &Pattern) -> Result<()> {
+    let mut rest = with;
+    while let Some(start) = rest.find('$') {
+        rest = &rest[start + 1..];
+        let (reference, braced) = match rest.strip_prefix('{').and_then(|inner| inner.split_once('}')) {
+            Some((reference, after)) => {
+                rest = after;
+                (reference, true)
+            }
+            None if rest.starts_with('$') => {
+                rest = &rest[1..];
+                continue;
+            }
+            None => {
+                let end = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+                let reference = &rest[..end];
+                rest = &rest[end..];
+                (reference, false)
+            }
+        };
+        if reference.is_empty() || reference.contains(['|', ':']) || (braced && reference.starts_with("__")) {
+            continue;
+        }
+        let exists = match reference.parse::<usize>() {
+            Ok(index) => regex.has_group_index(index),
+            Err(_) => regex.has_group_name(reference),
+        };
+        if exists {
+            continue;
+        }
+        let hint = match reference.find(|c: char| !c.is_ascii_digit()) {
+            Some(end) if !braced && end > 0 => format!(", write `${{{}}}{}` for group {} followed by text", &reference[..end], &reference[end..], &reference[..end]),
+            _ => String::new(),
+        };
+        return Err(eyre!("`with` references group `{reference}` that is not in the regex{hint}"));
+    }
+    Ok(())
+}
+
+/// Replacement text with `$1`, `$name` and `${name}` references left out and `$$` unescaped
+fn without_capture_references(with: &str) -> String {
+    let mut literal = String::new();
+    let mut chars = with.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('$', Some('$')) => {
+                literal.push('$');
+                chars.next();
+            }
+            ('$', Some('{')) => {
+                chars.by_ref().find(|&c| c == '}');
+            }
+            ('$', Some(&next)) if next.is_alphanumeric() || next == '_' => {
+                while chars.next_if(|&c| c.is_alphanumeric() || c == '_').is_some() {}
+            }
+            _ => literal.push(c),
+        }
+    }
+    literal
+}
+
+/// Warn when a later operation matches text an earlier one wrote, so records are not rewritten twice.
+/// It fails init instead when the earlier operation is `final` or with the `strict` param.
+fn check_rematches(ops: &[Operation], strict: bool) -> Result<()> {
+    for (index, op) in ops.iter().enumerate() {
+        for sample in op.output_samples() {
+            let later = ops.iter().enumerate().skip(index + 1)
+                .filter(|(_, later)| later.rewrites() && op.shares_scope(later));
+            for (later_index, later) in later {
+                if !later.pattern().is_some_and(|pattern| pattern.is_match(sample.as_bytes())) {
+                    continue;
+                }
+                let message = format!("{} matches the output of {}, e.g. `{sample}`",
+                    later.describe(later_index), op.describe(index));
+                if strict || op.options().final_output {
+                    return Err(eyre!("{message}\n  hint: narrow the regex of the later operation or reorder them"));
+                }
+                logging::log(LogLevel::Warn, &[], format_args!("{message}"));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Merge the fields of each `overrides` entry into the operation with the same `name`
+fn apply_overrides(format: &SpecFormat, raw_overrides: &str, entries: &mut [Value]) -> Result<()> {
+    let overrides: serde_json::Map<String, Value> = format.parse(raw_overrides).map_err(|err| {
+        eyre!("cannot parse `{OVERRIDES_PARAM}` param: {err}\n  hint: expected operation names mapped to fields, e.g. {{\"address\": {{\"enabled\": false}}}}")
+    })?;
+
+    for (name, fields) in overrides {
+        let Value::Object(fields) = fields else {
+            return Err(eyre!("invalid `{OVERRIDES_PARAM}` entry `{name}`: expected an object of operation fields"));
+        };
+        let mut targets = entries.iter_mut()
+            .filter_map(|entry| entry.as_object_mut()?.values_mut().next()?.as_object_mut())
+            .filter(|body| body.get("name").and_then(Value::as_str) == Some(name.as_str()));
+        let body = targets.next()
+            .ok_or_else(|| eyre!("invalid `{OVERRIDES_PARAM}` entry `{name}`: no operation with this name"))?;
+        body.extend(fields);
+        if targets.next().is_some() {
+            return Err(eyre!("invalid `{OVERRIDES_PARAM}` entry `{name}`: several operations share this name"));
+        }
+    }
+    Ok(())
+}
+
+fn spec_error(param: &str, index: usize, name: &str, err: impl std::fmt::Display) -> eyre::Error {
+    let message = err.to_string();
+    let hint = if message.contains("unknown variant") {
+        Some("check the spelling of the operation name")
+    } else if message.contains("exceeds size limit") || message.contains("nest limit") {
+        Some("simplify the regex or raise the compile `limits` of the operation or the `regex_limits` param")
+    } else if message.contains("invalid regex") {
+        Some("backslashes must be escaped in JSON and quoted YAML strings, e.g. \"\\\\d{3}\"")
+    } else if message.contains("missing field") || message.contains("missing `regex`") {
+        Some("see the README for the fields each operation requires")
+    } else if message.contains("unknown regex flag") {
+        Some("flags are a string of `imsxU` characters, e.g. \"i\"")
+    } else {
+        None
+    };
+
+    match hint {
+        Some(hint) => eyre!("invalid `{param}` operation #{index} `{name}`: {message}\n  hint: {hint}"),
+        None => eyre!("invalid `{param}` operation #{index} `{name}`: {message}"),
+    }
+}
+
+/// Collect the operations and the record handling params
+fn get_spec(params: SmartModuleExtraParams) -> Result<Spec> {
+    match params.get(TENANT_SELECTOR_PARAM) {
+        Some(selector) => {
+            let selector = selector.clone();
+            get_tenant_spec(params, &selector).inspect_err(|err| {
+                logging::log(LogLevel::Error, &[], format_args!("unable to parse spec from params: {err}"));
+            })
+        }
+        None => build_spec(params, |params| get_params(params.clone())),
+    }
+}
+
+/// Spec without operations of its own, holding one spec per tenant of the `spec` map
+fn get_tenant_spec(params: SmartModuleExtraParams, selector: &str) -> Result<Spec> {
+    if [CONTROL_KEY_PARAM, DICTIONARY_KEY_PARAM, OVERRIDES_PARAM].iter().any(|name| params.get(name).is_some()) {
+        return Err(eyre!("`{TENANT_SELECTOR_PARAM}` is not supported with `{CONTROL_KEY_PARAM}`, `{DICTIONARY_KEY_PARAM}` or `{OVERRIDES_PARAM}`"));
+    }
+    let selector = TenantSelector::from_param(selector, &get_regex_limits(&params)?)?;
+    let format: SpecFormat = get_keyword_param(&params, SPEC_FORMAT_PARAM)?;
+    let mut specs = BTreeMap::new();
+    for (tenant, lists) in get_tenant_lists(&params)? {
+        let spec = build_spec(params.clone(), |params| resolve_operations(&format, lists, params))?;
+        specs.insert(tenant, spec);
+    }
+    let mut spec = build_spec(params, |_| Ok(vec![]))?;
+    spec.tenants = Some(Tenants { selector, specs });
+    Ok(spec)
+}
+
+/// Spec with the operations of a control record in place of the `spec` params
+fn reload_spec(control: &Control, params: SmartModuleExtraParams, raw_spec: &str) -> Result<Spec> {
+    let label = control.key.as_deref().unwrap_or(CONTROL_KEY_PARAM);
+    let mut spec = build_spec(params, |params| {
+        let format: SpecFormat = get_keyword_param(params, SPEC_FORMAT_PARAM)?;
+        parse_operations(&format, &[(label.to_string(), Cow::Borrowed(raw_spec))], params)
+    })?;
+    if let Some(control) = spec.control.as_mut() {
+        control.raw_spec = Some(raw_spec.to_string());
+    }
+    Ok(spec)
+}
+
+/// Params of `control` with the dictionaries of a dictionary record, which only replaces
+/// dictionaries that `translate` operations of the active spec read
+fn dictionary_params(spec: &Spec, control: &Control, raw: &str) -> Result<SmartModuleExtraParams> {
+    let format: SpecFormat = get_keyword_param(&control.params, SPEC_FORMAT_PARAM)?;
+    let dictionaries: BTreeMap<String, Value> = format.parse(raw).map_err(|err| {
+        eyre!("cannot parse dictionary record: {err}\n  hint: expected dictionary param names mapped to dictionaries, e.g. {{\"dictionary\": {{\"Hardy\": \"H.\"}}}}")
+    })?;
+    let mut params = control.params.clone();
+    for (name, dictionary) in dictionaries {
+        if !spec.ops.iter().any(|op| matches!(op, Operation::Translate(t) if t.dictionary == name)) {
+            return Err(eyre!("dictionary record sets `{name}`, which no `translate` operation reads"));
+        }
+        let raw = match dictionary {
+            Value::String(raw) => raw,
+            dictionary => dictionary.to_string(),
+        };
+        params.insert(name, raw);
+    }
+    Ok(params)
+}
+
+fn build_spec(params: SmartModuleExtraParams, get_ops: impl FnOnce(&SmartModuleExtraParams) -> Result<Vec<Operation>>) -> Result<Spec> {
+    let filter_mode = get_keyword_param(&params, FILTER_MODE_PARAM)?;
+    let on_error = get_keyword_param(&params, ON_ERROR_PARAM)?;
+    if on_error == OnError::Skip && cfg!(feature = "map") {
+        return Err(eyre!("`{ON_ERROR_PARAM}: skip` cannot drop records in the map build, use `fail` or `passthrough`"));
+    }
+
+    let stats_field: Option<String> = get_keyword_param(&params, STATS_FIELD_PARAM)?;
+    let audit = get_flag_param(&params, AUDIT_PARAM)?;
+    if audit && !cfg!(feature = "array-map") {
+        return Err(eyre!("`{AUDIT_PARAM}` emits an extra record per input and needs the array-map build"));
+    }
+    let size_limit = SizeLimit::from_params(&params)?;
+    let time_budget = TimeBudget::from_params(&params)?;
+    let regex_limits = get_regex_limits(&params)?;
+    let utf8 = get_keyword_param(&params, UTF8_PARAM)?;
+    let input_encoding = get_keyword_param(&params, INPUT_ENCODING_PARAM)?;
+    let mode = get_keyword_param(&params, MODE_PARAM)?;
+    if mode == Mode::Detect && !cfg!(feature = "map") {
+        return Err(eyre!("`{MODE_PARAM}: detect` wraps each record and needs the map build"));
+    }
+    if mode == Mode::Detect && stats_field.is_some() {
+        return Err(eyre!("`{MODE_PARAM}: detect` already reports matches, remove `{STATS_FIELD_PARAM}`"));
+    }
+    let window_records = match params.get(WINDOW_RECORDS_PARAM) {
+        Some(_) if !cfg!(feature = "aggregate") => {
+            return Err(eyre!("`{WINDOW_RECORDS_PARAM}` sets the windows of match totals and needs the aggregate build"));
+        }
+        Some(raw) => Some(raw.trim().parse().ok().filter(|records| *records > 0)
+            .ok_or_else(|| eyre!("invalid `{WINDOW_RECORDS_PARAM}` param `{raw}`: expected a positive number of records"))?),
+        None => None,
+    };
+    let drop_regex = match params.get(DROP_REGEX_PARAM) {
+        Some(_) if !cfg!(feature = "filter-map") => {
+            return Err(eyre!("`{DROP_REGEX_PARAM}` drops output records and needs the filter-map build"));
+        }
+        Some(raw) => {
+            let mut regex = Pattern::from(raw.as_str());
+            regex.compile_with(&Flags::default(), Engine::Default, &regex_limits)
+                .map_err(|err| eyre!("invalid `{DROP_REGEX_PARAM}` param: {err}"))?;
+            Some(regex)
+        }
+        None => None,
+    };
+    let codec = match get_keyword_param(&params, FORMAT_PARAM)? {
+        #[cfg(feature = "protobuf")]
+        RecordFormat::Protobuf => Some(RecordCodec::Protobuf(get_proto_codec(&params)?)),
+        #[cfg(not(feature = "protobuf"))]
+        RecordFormat::Protobuf => {
+            return Err(eyre!("`{FORMAT_PARAM}: protobuf` requires the `protobuf` cargo feature"));
+        }
+        #[cfg(feature = "avro")]
+        RecordFormat::Avro => {
+            let schema = params.get(AVRO_SCHEMA_PARAM).ok_or_else(|| SmartModuleInitError::MissingParam(AVRO_SCHEMA_PARAM.to_string()))?;
+            Some(RecordCodec::Avro(AvroCodec::new(schema).map_err(|err| eyre!("invalid `{AVRO_SCHEMA_PARAM}` param: {err}"))?))
+        }
+        #[cfg(not(feature = "avro"))]
+        RecordFormat::Avro => {
+            return Err(eyre!("`{FORMAT_PARAM}: avro` requires the `avro` cargo feature"));
+        }
+        _ => None,
+    };
+    if codec.is_some() && input_encoding != InputEncoding::Utf8 {
+        return Err(eyre!("protobuf and Avro records are binary, remove `{INPUT_ENCODING_PARAM}`"));
+    }
+    if codec.is_some() && (utf8 != Utf8Mode::default() || size_limit.as_ref().is_some_and(|limit| limit.on_oversize == OnOversize::Truncate)) {
+        return Err(eyre!("protobuf and Avro records cannot be decoded as UTF-8 or truncated, remove `{UTF8_PARAM}` and `{ON_OVERSIZE_PARAM}: truncate`"));
+    }
+    let output_schema = match params.get(OUTPUT_SCHEMA_PARAM) {
+        Some(_) if cfg!(any(feature = "filter", feature = "aggregate")) => {
+            return Err(eyre!("`{OUTPUT_SCHEMA_PARAM}` checks rewritten records and needs the map, filter-map or array-map build"));
+        }
+        Some(_) if mode == Mode::Detect => {
+            return Err(eyre!("`{MODE_PARAM}: detect` outputs match reports, remove `{OUTPUT_SCHEMA_PARAM}`"));
+        }
+        Some(raw) => {
+            let format: SpecFormat = get_keyword_param(&params, SPEC_FORMAT_PARAM)?;
+            let schema: Value = format.parse(raw).map_err(|err| eyre!("cannot parse `{OUTPUT_SCHEMA_PARAM}` param: {err}"))?;
+            Some(JsonSchema::new(&schema).map_err(|err| eyre!("invalid `{OUTPUT_SCHEMA_PARAM}` param: {err}"))?)
+        }
+        None => None,
+    };
+    let lines = match get_keyword_param(&params, FORMAT_PARAM)? {
+        RecordFormat::Ndjson if cfg!(any(feature = "filter", feature = "array-map", feature = "aggregate")) => {
+            return Err(eyre!("`{FORMAT_PARAM}: ndjson` rewrites records line by line and needs the map or filter-map build"));
+        }
+        RecordFormat::Ndjson if mode == Mode::Detect => {
+            return Err(eyre!("`{MODE_PARAM}: detect` is not supported with `{FORMAT_PARAM}: ndjson`"));
+        }
+        RecordFormat::Ndjson => Some(get_keyword_param(&params, ON_LINE_ERROR_PARAM)?),
+        _ if params.get(ON_LINE_ERROR_PARAM).is_some() => {
+            return Err(eyre!("`{ON_LINE_ERROR_PARAM}` needs the `{FORMAT_PARAM}: ndjson` param"));
+        }
+        _ => None,
+    };
+    let post_process = match get_keyword_param(&params, POST_PROCESS_PARAM)? {
+        Some(_) if cfg!(any(feature = "filter", feature = "aggregate")) => {
+            return Err(eyre!("`{POST_PROCESS_PARAM}` reformats rewritten records and needs the map, filter-map or array-map build"));
+        }
+        Some(_) if mode == Mode::Detect => {
+            return Err(eyre!("`{MODE_PARAM}: detect` outputs match reports, remove `{POST_PROCESS_PARAM}`"));
+        }
+        Some(_) if matches!(get_keyword_param(&params, FORMAT_PARAM)?, RecordFormat::Ndjson | RecordFormat::Protobuf | RecordFormat::Avro) => {
+            return Err(eyre!("`{POST_PROCESS_PARAM}` reformats single JSON documents, it is not supported with `{FORMAT_PARAM}: ndjson`, `protobuf` or `avro`"));
+        }
+        post_process => post_process,
+    };
+    let checksum = Checksum::from_params(&params, mode)?;
+    let spans = SpanReport::from_params(&params, mode)?;
+    let array_root = ArrayRoot::from_params(&params, mode)?;
+    let ops = get_ops(&params)?;
+    if let Some(spans) = &spans {
+        spans.check(&ops)?;
+    }
+    if cfg!(any(feature = "filter", feature = "array-map"))
+        && ops.iter().any(|op| op.options().stage.as_ref().is_some_and(StageFlow::controls)) {
+        return Err(eyre!("stage flow control is only supported by the map and aggregate builds"));
+    }
+    // records the prefilter forwards would miss the per-record field
+    let prefilter = match (&stats_field, &spans) {
+        (None, None) => build_prefilter(&ops, &regex_limits),
+        _ => None,
+    };
+
+    let control_key = |name: &str| match params.get(name) {
+        Some(key) if key.is_empty() => Err(eyre!("`{name}` must not be empty")),
+        key => Ok(key.cloned()),
+    };
+    let (key, dictionary_key) = (control_key(CONTROL_KEY_PARAM)?, control_key(DICTIONARY_KEY_PARAM)?);
+    if key.is_some() && key == dictionary_key {
+        return Err(eyre!("`{CONTROL_KEY_PARAM}` and `{DICTIONARY_KEY_PARAM}` must differ"));
+    }
+    let control = (key.is_some() || dictionary_key.is_some())
+        .then(|| Control { key, dictionary_key, params: params.clone(), raw_spec: None });
+
+    Ok(Spec {
+        ops, filter_mode, on_error, stats_field, prefilter, audit, size_limit, time_budget, window_records, utf8, input_encoding, mode, control, drop_regex,
+        codec, output_schema, lines, array_root, post_process, checksum, spans, tenants: None,
+    })
+}
+
+/// Message type of protobuf records from the base64 `proto_descriptor` and `proto_message` params
+#[cfg(feature = "protobuf")]
+fn get_proto_codec(params: &SmartModuleExtraParams) -> Result<ProtoCodec> {
+    use base64::Engine;
+
+    let param = |name: &str| params.get(name).ok_or_else(|| SmartModuleInitError::MissingParam(name.to_string()));
+    let compact: String = param(PROTO_DESCRIPTOR_PARAM)?.split_whitespace().collect();
+    let descriptor = base64::engine::general_purpose::STANDARD.decode(compact)
+        .map_err(|err| eyre!("cannot decode base64 `{PROTO_DESCRIPTOR_PARAM}` param: {err}"))?;
+    ProtoCodec::new(&descriptor, param(PROTO_MESSAGE_PARAM)?)
+}
+
+/// Spec-level compile limits from the `regex_limits` param, e.g. `{"size_limit": 1048576}`
+fn get_regex_limits(params: &SmartModuleExtraParams) -> Result<RegexLimits> {
+    match params.get(REGEX_LIMITS_PARAM) {
+        Some(raw) => serde_json::from_str(raw)
+            .map_err(|err| eyre!("invalid `{REGEX_LIMITS_PARAM}` param: {err}")),
+        None => Ok(RegexLimits::default()),
+    }
+}
+
+/// `None` when an operation could change a record without a regex match, e.g. path operations
+/// re-serialize JSON, `translate` has no regex and a `split`/`find_all` `output` reassembles records without a match,
+/// or when the combined set exceeds the compile limits
+fn build_prefilter(ops: &[Operation], limits: &RegexLimits) -> Option<RegexSet> {
+    let mut sources = vec![];
+    for op in ops.iter().filter(|op| op.options().target.value()) {
+        let options = op.options();
+        let rewrites = matches!(op, Operation::DeleteField(_) | Operation::Redact(_) | Operation::Find(_)) || options.input != Input::Value
+            || options.path.is_some() || options.column_index.is_some() || options.xml_path.is_some()
+            || options.engine == Engine::Fancy || options.decode.is_some() || options.normalize.is_some()
+            || matches!(op, Operation::Split(e) | Operation::FindAll(e) if e.output.is_some());
+        match op.pattern() {
+            Some(pattern) if !rewrites => {
+                sources.push(format!("{}{}", options.flags.inline(), pattern.source()));
+            }
+            _ => return None,
+        }
+    }
+    if sources.is_empty() {
+        return None;
+    }
+    let mut builder = RegexSetBuilder::new(sources);
+    limits.apply_to_set(&mut builder);
+    builder.build().ok()
+}
+
+/// Traverse the regex list, compute regex, and collect output
+fn apply_regex_ops_to_json_record<'a>(value: &'a [u8], key: Option<&[u8]>, ops: &[Operation]) -> Result<Cow<'a, [u8]>> {
+    let mut data = Data::Bytes(Cow::Borrowed(value));
+    run_value_ops(ops, &mut data, key, |_, op, data| op.apply(data, key))?;
+    Ok(data.into_bytes())
+}
+
+/// Call `step` with each value operation in order, following the flow control of stages.
+/// Whether an operation matched is only checked, before `step` runs it, in stages that need it.
+fn run_value_ops(ops: &[Operation], data: &mut Data, key: Option<&[u8]>, mut step: impl FnMut(usize, &Operation, &mut Data) -> Result<()>) -> Result<()> {
+    let mut index = 0;
+    let mut stage_matched = false;
+    while let Some(op) = ops.get(index) {
+        let flow = op.options().stage.as_ref();
+        if flow.is_some_and(|flow| flow.start == index) {
+            stage_matched = false;
+        }
+        let mut next = index + 1;
+        if op.options().target.value() {
+            let matched = match flow {
+                Some(flow) if flow.controls() => op.count_matches(data, key).wrap_err_with(|| op.describe(index))? > 0,
+                _ => false,
+            };
+            step(index, op, data).wrap_err_with(|| op.describe(index))?;
+            stage_matched |= matched;
+            if let Some(flow) = flow.filter(|flow| matched && flow.stop_after_first_match) {
+                next = flow.end;
+            }
+        }
+        if flow.is_some_and(|flow| next >= flow.end && flow.stop_on_no_match && !stage_matched) {
+            break;
+        }
+        index = next;
+    }
+    Ok(())
+}
+
+/// Run the operations targeting the key, records without a key are left as is, then let `set_key` operations
+/// replace or extend it with what they capture from `value` (the received key with `input: key`),
+/// before the value operations run
+fn apply_regex_ops_to_key(record: &SmartModuleRecord, value: &[u8], ops: &[Operation]) -> Result<Option<RecordData>> {
+    let mut key_ops = ops.iter().filter(|op| op.options().target.key()).peekable();
+    let mut key = match &record.key {
+        Some(key) if key_ops.peek().is_some() => Some(into_record_data(apply_regex_ops(key.as_ref(), key_ops)?, key)),
+        key => key.clone(),
+    };
+
+    for (index, op) in ops.iter().enumerate() {
+        if let (Operation::SetKey(k), Some(captured)) = (op, op.captured_key(value, record_key(record)).wrap_err_with(|| op.describe(index))?) {
+            key = Some(k.combine(key.as_ref(), captured).into());
+        }
+    }
+    Ok(key)
+}
+
+fn apply_regex_ops<'a, 'o>(bytes: &'a [u8], ops: impl Iterator<Item = &'o Operation>) -> Result<Cow<'a, [u8]>> {
+    let mut data = Data::Bytes(Cow::Borrowed(bytes));
+
+    for op in ops {
+        op.apply(&mut data, None)?;
+    }
+
+    Ok(data.into_bytes())
+}
+
+/// Run the value operations and add their match counts to the record under `field`,
+/// keyed by operation name or by position for unnamed operations
+fn apply_regex_ops_with_stats<'a>(bytes: &'a [u8], key: Option<&[u8]>, ops: &[Operation], field: &str) -> Result<Cow<'a, [u8]>> {
+    let (mut data, counts) = apply_regex_ops_counting(bytes, key, ops)?;
+    let stats = counts.into_iter().map(|(label, count)| (label, count.into())).collect();
+
+    let Value::Object(record) = data.json_mut()? else {
+        return Err(eyre!("`{STATS_FIELD_PARAM}` requires JSON object records"));
+    };
+    record.insert(field.to_string(), Value::Object(stats));
+    Ok(data.into_bytes())
+}
+
+/// Run the value operations and add the `[op_index, start, end]` ranges each one rewrote to the record
+/// under the field of `report`. Operations that left the text unchanged have no ranges.
+fn apply_regex_ops_with_spans<'a>(bytes: &'a [u8], key: Option<&[u8]>, ops: &[Operation], report: &SpanReport) -> Result<Cow<'a, [u8]>> {
+    let mut data = Data::Bytes(Cow::Borrowed(bytes));
+    let mut spans = vec![];
+
+    run_value_ops(ops, &mut data, key, |index, op, data| {
+        let input = data.bytes().to_vec();
+        let matches = op.spans(&input);
+        op.apply(data, key)?;
+        let output = data.bytes();
+        if output == input.as_slice() {
+            return Ok(());
+        }
+        let ranges = match report.coordinates {
+            // operations without matches, such as `normalize`, report the range they changed
+            SpanCoordinates::Input if matches.is_empty() => vec![changed_range(&input, output)[0]],
+            SpanCoordinates::Input => matches,
+            SpanCoordinates::Output => output_spans(op, &input, output, &matches),
+        };
+        spans.extend(ranges.into_iter().map(|[start, end]| serde_json::json!([index, start, end])));
+        Ok(())
+    })?;
+
+    let Ok(Value::Object(record)) = data.json_mut() else {
+        return Err(eyre!("`{SPANS_FIELD_PARAM}` requires JSON object records"));
+    };
+    record.insert(report.field.clone(), Value::Array(spans));
+    Ok(data.into_bytes())
+}
+
+/// Ranges of the replacements in the output of an operation, rebuilt by rewriting each match on its own.
+/// When that does not give the actual output, e.g. the regex looks around the match, the whole changed
+/// range is reported instead.
+fn output_spans(op: &Operation, input: &[u8], output: &[u8], matches: &[[usize; 2]]) -> Vec<[usize; 2]> {
+    let mut rebuilt = Vec::with_capacity(output.len());
+    let mut spans = vec![];
+    let mut last = 0;
+    for &[start, end] in matches {
+        rebuilt.extend_from_slice(&input[last..start]);
+        let replacement = op.run_regex(&input[start..end]);
+        spans.push([rebuilt.len(), rebuilt.len() + replacement.len()]);
+        rebuilt.extend_from_slice(&replacement);
+        last = end;
+    }
+    rebuilt.extend_from_slice(&input[last..]);
+    if rebuilt == output {
+        return spans;
+    }
+    vec![changed_range(input, output)[1]]
+}
+
+/// The range that differs between the input and output of an operation, in each of them
+fn changed_range(input: &[u8], output: &[u8]) -> [[usize; 2]; 2] {
+    let prefix = input.iter().zip(output).take_while(|(a, b)| a == b).count();
+    let suffix = input[prefix..].iter().rev().zip(output[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+    [[prefix, input.len() - suffix], [prefix, output.len() - suffix]]
+}
+
+/// Run the operations on the value, counting the matches of each one before it runs,
+/// keyed by operation `name` or `#index`
+fn apply_regex_ops_counting<'a>(bytes: &'a [u8], key: Option<&[u8]>, ops: &[Operation]) -> Result<(Data<'a>, Vec<(String, usize)>)> {
+    let mut data = Data::Bytes(Cow::Borrowed(bytes));
+    let mut counts = vec![];
+
+    run_value_ops(ops, &mut data, key, |index, op, data| {
+        let label = op.options().name.clone().unwrap_or_else(|| format!("#{index}"));
+        counts.push((label, op.count_matches(data, key)?));
+        op.apply(data, key)
+    })?;
+    Ok((data, counts))
+}
+
+/// Running match totals kept in the accumulator of the aggregate build
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+struct MatchTotals {
+    records: u64,
+    /// Records with at least one match
+    matched_records: u64,
+    /// Matches per operation, keyed like `stats_field` counts
+    matches: BTreeMap<String, u64>,
+}
+
+fn aggregate_record(accumulator: &[u8], record: &SmartModuleRecord, spec: &Spec) -> Result<MatchTotals> {
+    let mut totals: MatchTotals = match accumulator {
+        [] => MatchTotals::default(),
+        accumulator => serde_json::from_slice(accumulator).map_err(|err| eyre!("invalid aggregate accumulator: {err}"))?,
+    };
+    if spec.window_records.is_some_and(|window| totals.records >= window) {
+        totals = MatchTotals::default();
+    }
+    begin_record(spec, record);
+    let spec = tenant_spec(spec, record)?;
+    let Some(value) = checked_value(record, spec)? else {
+        return Ok(totals);
+    };
+
+    let (_, counts) = apply_regex_ops_counting(&value, record_key(record), &spec.ops)?;
+    totals.records += 1;
+    if counts.iter().any(|(_, count)| *count > 0) {
+        totals.matched_records += 1;
+    }
+    for (label, count) in counts {
+        *totals.matches.entry(label).or_default() += count as u64;
+    }
+    Ok(totals)
+}
+
+/// Key of the record as received, read by operations with `input: key`
+fn record_key(record: &SmartModuleRecord) -> Option<&[u8]> {
+    record.key.as_ref().map(|key| key.as_ref())
+}
+
+/// Reuse the original buffer when no operation changed the data, borrowed data can also be
+/// a truncated prefix of the original or a copy decoded with `utf8: lossy`
+fn into_record_data(data: Cow<[u8]>, original: &RecordData) -> RecordData {
+    match data {
+        Cow::Borrowed(data) if std::ptr::eq(data, original.as_ref()) => original.clone(),
+        Cow::Borrowed(data) => data.to_vec().into(),
+        Cow::Owned(data) => data.into(),
+    }
+}
+
+/// Matches of one operation on the value of a record, without the matched text
+#[derive(Debug, Serialize)]
+struct AuditEntry {
+    #[serde(skip)]
+    index: usize,
+    op: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    matches: usize,
+    /// Byte ranges in the record value, for operations on the whole value before any split
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    spans: Vec<[usize; 2]>,
+}
+
+/// Count the matches of an operation about to run, adding them to its entry
+fn audit_op(entries: &mut Vec<AuditEntry>, index: usize, op: &Operation, data: &mut Data, key: Option<&[u8]>, whole_value: bool) -> Result<()> {
+    let matches = op.count_matches(data, key)?;
+    if matches == 0 {
+        return Ok(());
+    }
+    let spans = match (whole_value, op.path(), op.column()) {
+        (true, None, None) if op.xml_path().is_none() && op.options().input == Input::Value => op.spans(data.bytes()),
+        _ => vec![],
+    };
+
+    match entries.iter_mut().find(|entry| entry.index == index) {
+        Some(entry) => {
+            entry.matches += matches;
+            entry.spans.extend(spans);
+        }
+        None => entries.push(AuditEntry { index, op: op.kind(), name: op.options().name.clone(), matches, spans }),
+    }
+    Ok(())
+}
+
+/// Run the value operations on a copy of the value, recording their matches like `audit` does,
+/// and wrap the unchanged value with them: `{"value": "...", "detections": [...]}`.
+/// Spans are relative to the text each operation ran on, after the operations before it.
+fn detect_record(value: &[u8], key: Option<&[u8]>, ops: &[Operation]) -> Result<Vec<u8>> {
+    let mut data = Data::Bytes(Cow::Borrowed(value));
+    let mut detections = vec![];
+    run_value_ops(ops, &mut data, key, |index, op, data| {
+        audit_op(&mut detections, index, op, data, key, !op.explodes())?;
+        op.apply(data, key)
+    })?;
+
+    let wrapper = serde_json::json!({"value": String::from_utf8_lossy(value), "detections": detections});
+    Ok(wrapper.to_string().into_bytes())
+}
+
+/// Run the value operations, splitting the record into many at `split`/`find_all` operations
+fn array_map_record(record: &SmartModuleRecord, spec: &Spec) -> Result<Vec<(Option<RecordData>, RecordData)>> {
+    begin_record(spec, record);
+    let spec = tenant_spec(spec, record)?;
+    let Some(value) = checked_value(record, spec)? else {
+        return Ok(vec![]);
+    };
+    // `each` is rejected at init in the array-map build
+    let result = match spec.array_root.elements(&value) {
+        Some(elements) => elements.into_iter().enumerate()
+            .map(|(index, element)| array_map_value(record, element, spec).wrap_err_with(|| format!("array element {index}")))
+            .collect::<Result<Vec<_>>>()
+            .map(|records| records.into_iter().flatten().collect()),
+        None => array_map_value(record, &value, spec),
+    };
+    result.or_else(|err| Ok(timeout_fallback(record, spec, err)?.into_iter().collect()))
+}
+
+fn array_map_value(record: &SmartModuleRecord, value: &[u8], spec: &Spec) -> Result<Vec<(Option<RecordData>, RecordData)>> {
+    let key = apply_regex_ops_to_key(record, value, &spec.ops)?;
+    let mut values = vec![Data::Bytes(Cow::Borrowed(value))];
+    let mut audit = vec![];
+    let mut whole_value = true;
+
+    for (index, op) in spec.ops.iter().enumerate().filter(|(_, op)| op.options().target.value()) {
+        if op.explodes() {
+            let mut pieces = vec![];
+            for mut data in values {
+                if op.applies_to(&mut data)? {
+                    let exploded = op.explode(data.bytes()).unwrap_or_default();
+                    pieces.extend(exploded.into_iter().map(|piece| Data::Bytes(Cow::Owned(piece))));
+                } else {
+                    pieces.push(data);
+                }
+            }
+            values = pieces;
+            whole_value = false;
+        } else {
+            for data in values.iter_mut() {
+                if spec.audit {
+                    audit_op(&mut audit, index, op, data, record_key(record), whole_value)?;
+                }
+                op.apply(data, record_key(record))?;
+            }
+        }
+    }
+
+    let mut output: Vec<_> = values.into_iter()
+        .map(|data| Ok((key.clone(), output_value(spec, data.into_bytes(), value, &record.value)?)))
+        .collect::<Result<_>>()?;
+    if spec.audit {
+        let audit = serde_json::json!({"offset": record.offset(), "operations": audit});
+        output.push((record.key.clone(), audit.to_string().into()));
+    }
+    Ok(output)
+}
+
+/// Decide whether the record passes the `match`/`not_match` predicates
+fn filter_json_record(record: &SmartModuleRecord, value: &[u8], ops: &[Operation], mode: &FilterMode) -> Result<bool> {
+    let mut data = Data::Bytes(Cow::Borrowed(value));
+    let mut key = record.key.as_ref().map(|key| Data::Bytes(Cow::Borrowed(key.as_ref())));
+
+    for (index, op) in ops.iter().enumerate() {
+        match (op.is_match(&mut data, key.as_mut()).wrap_err_with(|| op.describe(index))?, mode) {
+            (Some(false), FilterMode::All) => return Ok(false),
+            (Some(true), FilterMode::Any) => return Ok(true),
+            _ => {}
+        }
+    }
+
+    Ok(*mode == FilterMode::All)
+}
+
+fn map_record(record: &SmartModuleRecord, spec: &Spec) -> Result<(Option<RecordData>, RecordData)> {
+    begin_record(spec, record);
+    let spec = tenant_spec(spec, record)?;
+    // skipping oversized or timed out records is rejected at init in the map build
+    let value = checked_value(record, spec)?.wrap_err("oversized record cannot be skipped by a map")?;
+    map_value(record, value, spec)
+        .or_else(|err| timeout_fallback(record, spec, err)?.wrap_err("timed out record cannot be skipped by a map"))
+}
+
+/// Map the record, dropping it when its value is skipped, or empty or matching `drop_regex` after the operations
+fn filter_map_record(record: &SmartModuleRecord, spec: &Spec) -> Result<Option<(Option<RecordData>, RecordData)>> {
+    begin_record(spec, record);
+    let spec = tenant_spec(spec, record)?;
+    let Some(value) = checked_value(record, spec)? else {
+        return Ok(None);
+    };
+    if blocked(record, &value, spec)? {
+        return Ok(None);
+    }
+    let (key, value) = match map_value(record, value, spec) {
+        Ok(mapped) => mapped,
+        Err(err) => return timeout_fallback(record, spec, err),
+    };
+    let dropped = value.as_ref().is_empty() || spec.drop_regex.as_ref().is_some_and(|regex| regex.is_match(value.as_ref()));
+    Ok((!dropped).then_some((key, value)))
+}
+
+/// Whether the record at `offset` is among the `rate` fraction of records sampled, the same
+/// records for every operation with that rate
+fn sampled(offset: i64, rate: f64) -> bool {
+    // splitmix64 finalizer, so consecutive offsets spread over the whole range
+    let mut hash = (offset as u64).wrapping_add(0x9e37_79b9_7f4a_7c15);
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^= hash >> 31;
+    (hash as f64) < rate * u64::MAX as f64
+}
+
+/// Whether a `severity: block` operation matches the record as received
+fn blocked(record: &SmartModuleRecord, value: &[u8], spec: &Spec) -> Result<bool> {
+    for (index, op) in spec.ops.iter().enumerate().filter(|(_, op)| op.options().severity == Severity::Block) {
+        let mut data = Data::Bytes(Cow::Borrowed(value));
+        if op.count_matches_up_to(&mut data, record_key(record), 1).wrap_err_with(|| op.describe(index))? > 0 {
+            logging::log(LogLevel::Info, &[("op", &op.kind())], format_args!("record at offset {} dropped with `severity: block`", record.offset()));
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn map_value(record: &SmartModuleRecord, value: Cow<[u8]>, spec: &Spec) -> Result<(Option<RecordData>, RecordData)> {
+    if spec.mode == Mode::Detect {
+        return Ok((record.key.clone(), detect_record(&value, record_key(record), &spec.ops)?.into()));
+    }
+    let key = apply_regex_ops_to_key(record, &value, &spec.ops)?;
+    if let Some(elements) = spec.array_root.elements(&value) {
+        let result = apply_value_ops_to_elements(&value, &elements, record, spec)?;
+        return Ok((key, output_value(spec, result, &value, &record.value)?));
+    }
+    if spec.prefilter.as_ref().is_some_and(|set| !set.is_match(&value)) {
+        return Ok((key, output_value(spec, Cow::Borrowed(&value), &value, &record.value)?));
+    }
+    let result = match spec.lines {
+        Some(on_line_error) => apply_regex_ops_to_lines(&value, record, spec, on_line_error)?,
+        None => apply_value_ops(&value, record, spec)?,
+    };
+    Ok((key, output_value(spec, result, &value, &record.value)?))
+}
+
+fn apply_value_ops<'a>(value: &'a [u8], record: &SmartModuleRecord, spec: &Spec) -> Result<Cow<'a, [u8]>> {
+    match (&spec.stats_field, &spec.spans) {
+        (Some(field), _) => apply_regex_ops_with_stats(value, record_key(record), &spec.ops, field),
+        (None, Some(report)) => apply_regex_ops_with_spans(value, record_key(record), &spec.ops, report),
+        (None, None) => apply_regex_ops_to_json_record(value, record_key(record), &spec.ops),
+    }
+}
+
+/// Run the value operations on each element of a JSON array record on its own, so `when` guards and
+/// stages see one element at a time. The array is rebuilt only when an element changed.
+fn apply_value_ops_to_elements<'a>(value: &'a [u8], elements: &[&[u8]], record: &SmartModuleRecord, spec: &Spec) -> Result<Cow<'a, [u8]>> {
+    let mut outputs = Vec::with_capacity(elements.len());
+    let mut changed = false;
+    for (index, element) in elements.iter().enumerate() {
+        let output = apply_value_ops(element, record, spec).wrap_err_with(|| format!("array element {index}"))?;
+        changed |= output.as_ref() != *element;
+        outputs.push(output);
+    }
+    if !changed {
+        return Ok(Cow::Borrowed(value));
+    }
+    let mut array = b"[".to_vec();
+    for (index, output) in outputs.iter().enumerate() {
+        if index > 0 {
+            array.push(b',');
+        }
+        array.extend_from_slice(output);
+    }
+    array.push(b']');
+    Ok(Cow::Owned(array))
+}
+
+/// Run the value operations on each line of an NDJSON record, keeping line endings and blank lines.
+/// Lines that fail are dropped with `skip`, kept as they were with `passthrough`, or fail the record.
+fn apply_regex_ops_to_lines<'a>(value: &'a [u8], record: &SmartModuleRecord, spec: &Spec, on_line_error: OnError) -> Result<Cow<'a, [u8]>> {
+    let mut output = Vec::with_capacity(value.len());
+    let mut changed = false;
+    for (number, line) in value.split_inclusive(|byte| *byte == b'\n').enumerate() {
+        let text = line.strip_suffix(b"\n").unwrap_or(line);
+        let text = text.strip_suffix(b"\r").unwrap_or(text);
+        if text.iter().all(u8::is_ascii_whitespace) {
+            output.extend_from_slice(line);
+            continue;
+        }
+        match (apply_value_ops(text, record, spec), on_line_error) {
+            (Ok(Cow::Borrowed(_)), _) => output.extend_from_slice(line),
+            (Ok(Cow::Owned(result)), _) => {
+                output.extend_from_slice(&result);
+                output.extend_from_slice(&line[text.len()..]);
+                changed = true;
+            }
+            (Err(err), _) if err.is::<ProcessingTimeout>() => return Err(err),
+            (Err(err), OnError::Fail) => return Err(err.wrap_err(format!("line {}", number + 1))),
+            (Err(err), on_line_error) => {
+                let (offset, line_number) = (record.offset(), number + 1);
+                logging::log(LogLevel::Warn, &[("offset", &offset), ("line", &line_number), ("action", &format!("{on_line_error:?}").to_lowercase())], format_args!("{err:#}"));
+                if on_line_error == OnError::Passthrough {
+                    output.extend_from_slice(line);
+                } else {
+                    changed = true;
+                }
+            }
+        }
+    }
+    Ok(if changed { Cow::Owned(output) } else { Cow::Borrowed(value) })
+}
+
+fn active_spec() -> Result<Arc<Spec>> {
+    SPEC.read().unwrap_or_else(PoisonError::into_inner).clone().wrap_err("regex operations not initialized")
+}
+
+/// The spec carried by `record` when it is a control record, `None` for other records
+fn control_spec(spec: &Spec, record: &SmartModuleRecord) -> Result<Option<Spec>> {
+    let Some(control) = &spec.control else {
+        return Ok(None);
+    };
+    let record_key = record.key.as_ref().map(|key| key.as_ref());
+    let keyed = |key: &Option<String>| key.as_ref().is_some_and(|key| record_key == Some(key.as_bytes()));
+    if !keyed(&control.key) && !keyed(&control.dictionary_key) {
+        return Ok(None);
+    }
+    let value = std::str::from_utf8(record.value.as_ref())
+        .map_err(|err| eyre!("control record is not valid UTF-8: {err}"))?;
+    if keyed(&control.key) {
+        return reload_spec(control, control.params.clone(), value).map(Some);
+    }
+    let params = dictionary_params(spec, control, value)?;
+    match &control.raw_spec {
+        Some(raw_spec) => reload_spec(control, params, raw_spec).map(Some),
+        None => build_spec(params, |params| get_params(params.clone())).map(Some),
+    }
+}
+
+/// Log a record the `on_error` policy skipped or passed through, with a snippet of its value at debug level
+fn log_record_error(record: &SmartModuleRecord, on_error: OnError, err: &eyre::Report) {
+    let offset = record.offset();
+    let action = format!("{on_error:?}").to_lowercase();
+    let payload = logging::enabled(LogLevel::Debug).then(|| logging::snippet(record.value.as_ref()));
+    let mut fields: Vec<(&str, &dyn std::fmt::Display)> = vec![("offset", &offset), ("action", &action)];
+    if let Some(payload) = &payload {
+        fields.push(("payload", payload));
+    }
+    logging::log(LogLevel::Warn, &fields, format_args!("{err:#}"));
+}
+
+/// Make the spec of a control record the active one, `true` when `record` was a control record.
+/// The active spec is kept when the new one is invalid.
+fn reload(spec: &Spec, record: &SmartModuleRecord) -> Result<bool> {
+    let Some(reloaded) = control_spec(spec, record)? else {
+        return Ok(false);
+    };
+    *SPEC.write().unwrap_or_else(PoisonError::into_inner) = Some(Arc::new(reloaded));
+    logging::log(LogLevel::Info, &[("offset", &record.offset())], format_args!("regex operations reloaded from the control record"));
+    Ok(true)
+}
+
+#[cfg_attr(feature = "map", smartmodule(map))]
+pub fn map(record: &SmartModuleRecord) -> Result<(Option<RecordData>, RecordData)> {
+    let spec = active_spec()?;
+
+    // control records are forwarded as is, the map build cannot drop them
+    let result = reload(&spec, record).and_then(|reloaded| match reloaded {
+        true => Ok((record.key.clone(), record.value.clone())),
+        false => map_record(record, &spec),
+    });
+    match result {
+        Err(err) if spec.on_error == OnError::Passthrough => {
+            log_record_error(record, spec.on_error, &err);
+            Ok((record.key.clone(), record.value.clone()))
+        }
+        result => result,
+    }
+}
+
+#[cfg_attr(feature = "filter", smartmodule(filter))]
+pub fn filter(record: &SmartModuleRecord) -> Result<bool> {
+    let spec = active_spec()?;
+
+    let result = match reload(&spec, record) {
+        Ok(true) => Ok(false),
+        Ok(false) => {
+            begin_record(&spec, record);
+            tenant_spec(&spec, record).and_then(|spec| match checked_value(record, spec)? {
+                Some(value) => filter_json_record(record, &value, &spec.ops, &spec.filter_mode)
+                    .or_else(|err| Ok(timeout_fallback(record, spec, err)?.is_some())),
+                None => Ok(false),
+            })
+        }
+        Err(err) => Err(err),
+    };
+    match (result, spec.on_error) {
+        (Err(err), OnError::Skip | OnError::Passthrough) => {
+            log_record_error(record, spec.on_error, &err);
+            Ok(spec.on_error == OnError::Passthrough)
+        }
+        (result, _) => result,
+    }
+}
+
+#[cfg_attr(feature = "filter-map", smartmodule(filter_map))]
+pub fn filter_map(record: &SmartModuleRecord) -> Result<Option<(Option<RecordData>, RecordData)>> {
+    let spec = active_spec()?;
+
+    let result = reload(&spec, record).and_then(|reloaded| match reloaded {
+        true => Ok(None),
+        false => filter_map_record(record, &spec),
+    });
+    match (result, spec.on_error) {
+        (Err(err), OnError::Skip | OnError::Passthrough) => {
+            log_record_error(record, spec.on_error, &err);
+            Ok((spec.on_error == OnError::Passthrough).then(|| (record.key.clone(), record.value.clone())))
+        }
+        (result, _) => result,
+    }
+}
+
+#[cfg_attr(feature = "array-map", smartmodule(array_map))]
+pub fn array_map(record: &SmartModuleRecord) -> Result<Vec<(Option<RecordData>, RecordData)>> {
+    let spec = active_spec()?;
+
+    let result = reload(&spec, record).and_then(|reloaded| match reloaded {
+        true => Ok(vec![]),
+        false => array_map_record(record, &spec),
+    });
+    match (result, spec.on_error) {
+        (Err(err), OnError::Skip | OnError::Passthrough) => {
+            log_record_error(record, spec.on_error, &err);
+            match spec.on_error {
+                OnError::Passthrough => Ok(vec![(record.key.clone(), record.value.clone())]),
+                _ => Ok(vec![]),
+            }
+        }
+        (result, _) => result,
+    }
+}
+
+#[cfg_attr(feature = "aggregate", smartmodule(aggregate))]
+pub fn aggregate(accumulator: RecordData, current: &SmartModuleRecord) -> Result<RecordData> {
+    let spec = active_spec()?;
+
+    let result = reload(&spec, current).and_then(|reloaded| match reloaded {
+        true => Ok(None),
+        false => aggregate_record(accumulator.as_ref(), current, &spec).map(Some),
+    });
+    match result {
+        Ok(Some(totals)) => Ok(serde_json::to_vec(&totals)?.into()),
+        Ok(None) => Ok(accumulator),
+        Err(err) if spec.on_error != OnError::Fail => {
+            log_record_error(current, spec.on_error, &err);
+            Ok(accumulator)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Params as compared across `init` calls, the params type only exposes lookups
+/// and its debug form lists them in key order
+fn init_fingerprint(params: &SmartModuleExtraParams) -> String {
+    format!("{params:?}")
+}
+
+/// Whether `init` builds the spec: the first time and with `force_reinit`, not when it runs again
+/// with the same params, which keeps the active spec and any reload, and an error with other params
+fn needs_init(initialized: Option<&str>, params: &SmartModuleExtraParams) -> Result<bool> {
+    match initialized {
+        None => Ok(true),
+        Some(_) if get_flag_param(params, FORCE_REINIT_PARAM)? => Ok(true),
+        Some(initialized) if initialized == init_fingerprint(params) => Ok(false),
+        Some(_) => Err(eyre!("regex operations already initialized with other params, set `{FORCE_REINIT_PARAM}: true` to replace them")),
+    }
+}
+
+#[smartmodule(init)]
+fn init(params: SmartModuleExtraParams) -> Result<()> {
+    logging::set_level(get_keyword_param(&params, LOG_LEVEL_PARAM)?);
+    let mut initialized = INIT_PARAMS.write().unwrap_or_else(PoisonError::into_inner);
+    if !needs_init(initialized.as_deref(), &params)? {
+        logging::log(LogLevel::Info, &[], format_args!("init repeated with the same params, keeping the active operations"));
+        return Ok(());
+    }
+    let fingerprint = init_fingerprint(&params);
+    let describe = get_flag_param(&params, DESCRIBE_PARAM)?;
+    let spec = get_spec(params)?;
+    logging::log(LogLevel::Info, &[("operations", &spec.operation_count())], format_args!("regex operations initialized"));
+    if describe {
+        for line in spec.describe() {
+            logging::log(LogLevel::Warn, &[], format_args!("{line}"));
+        }
+    }
+
+    *SPEC.write().unwrap_or_else(PoisonError::into_inner) = Some(Arc::new(spec));
+    *initialized = Some(fingerprint);
+    Ok(())
+}
+
+/// Replay the control records of the topic history, so the last spec sent survives restarts
+#[smartmodule(look_back)]
+pub fn look_back(record: &SmartModuleRecord) -> Result<()> {
+    let spec = active_spec()?;
+
+    match reload(&spec, record) {
+        Err(err) if spec.on_error != OnError::Fail => {
+            logging::log(LogLevel::Warn, &[("offset", &record.offset())], format_args!("ignoring control record: {err:#}"));
+            Ok(())
+        }
+        result => result.map(|_| ()),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fluvio_smartmodule::Record;
+    
+    static INPUT: &str = r#"{
+        "description": "Independence High School",
+        "class": "2025-A",
+        "students": [
+          {
+              "first": "Abby",
+              "last": "Hardy",
+              "address": "285 LA PALA DR APT 2343, SAN JOSE CA 95127",
+              "ssn": "123-45-6789"
+          },
+          {
+              "first": "Bob",
+              "last": "Newmal",
+              "address": "21 E TRIMBLE RD, Santa Clara CA 95347",
+              "ssn": "987-65-4321"
+          },
+          {
+              "first": "Cindy",
+              "last": "Hall",
+              "address": "1601 PRIME PL, Milpitas CA 95344",
+              "ssn": "999-88-7777"
+          }
+        ]
+    }"#;
+
+    fn run(op: &Operation, input: &str) -> String {
+        String::from_utf8(op.run_regex(input.as_bytes()).into_owned()).unwrap()
+    }
+
+    /// Parse and resolve a JSON spec the same way `init` does
+    fn ops_from_json(spec: &str) -> Vec<Operation> {
+        let mut params = SmartModuleExtraParams::default();
+        params.insert(PARAM_NAME.to_owned(), spec.to_owned());
+        get_params(params).unwrap()
+    }
+
+    /// Build a spec from a JSON spec and extra params the same way `init` does
+    fn spec_with(ops: &str, extra: &[(&str, &str)]) -> Result<Spec> {
+        let mut params = SmartModuleExtraParams::default();
+        params.insert(PARAM_NAME.to_owned(), ops.to_owned());
+        for (name, value) in extra {
+            params.insert(name.to_string(), value.to_string());
+        }
+        get_spec(params)
+    }
+
+    /// Map a text record with `spec` and return its value
+    fn map_str(spec: &Spec, value: &str) -> Result<String> {
+        let (_, value) = map_record(&SmartModuleRecord::new(Record::new(value.to_owned()), 0, 0), spec)?;
+        Ok(String::from_utf8(value.as_ref().to_vec())?)
+    }
+
+    #[test]
+    fn run_regex_test() {
+        // Replace exact
+        let input = r"123-45-6789".to_owned();
+        let regex = r"\d{3}-\d{2}-\d{4}";
+        let op = Operation::Replace(Replace {
+            regex: Pattern::new(regex).unwrap(),
+            with: "***-**-****".to_owned(),
+            limit: 0,
+            group: None,
+            literal_replacement: false,
+            cases: BTreeMap::new(),
+            template: None,
+            options: OpOptions::default(),
+        });
+        let expected = "***-**-****".to_owned();
+
+        let result = run(&op, &input);
+        assert_eq!(result, expected);
+
+        // Replace subset
+        let input = r"Alice Jackson, ssn 123-45-6789, location: NY".to_owned();
+        let regex = r"\d{3}-\d{2}-\d{4}";
+        let op = Operation::Replace(Replace {
+            regex: Pattern::new(regex).unwrap(),
+            with: "***-**-****".to_owned(),
+            limit: 0,
+            group: None,
+            literal_replacement: false,
+            cases: BTreeMap::new(),
+            template: None,
+            options: OpOptions::default(),
+        });
+        let expected = "Alice Jackson, ssn ***-**-****, location: NY".to_owned();
+
+        let result = run(&op, &input);
+        assert_eq!(result, expected);
+
+        // Replace multiple
+        let input = r"Alice, ssn 123-45-6789, Jack, ssn 987-65-4321".to_owned();
+        let regex = r"\d{3}-\d{2}-\d{4}";
+        let op = Operation::Replace(Replace {
+            regex: Pattern::new(regex).unwrap(),
+            with: "***-**-****".to_owned(),
+            limit: 0,
+            group: None,
+            literal_replacement: false,
+            cases: BTreeMap::new(),
+            template: None,
+            options: OpOptions::default(),
+        });
+        let expected = "Alice, ssn ***-**-****, Jack, ssn ***-**-****".to_owned();
+
+        let result = run(&op, &input);
+        assert_eq!(result, expected);
+
+        // Replace address
+        let input = r#""address": "285 LA PALA DR APT 2343, SAN JOSE CA 95127""#.to_owned();
+        let regex = r#"(?P<first>"address":\s+\")([\w\d\s]+),"#;
+        let op = Operation::Replace(Replace {
+            regex: Pattern::new(regex).unwrap(),
+            with: "${first}...".to_owned(),
+            limit: 0,
+            group: None,
+            literal_replacement: false,
+            cases: BTreeMap::new(),
+            template: None,
+            options: OpOptions::default(),
+        });
+        let expected = r#""address": "... SAN JOSE CA 95127""#.to_owned();
+
+        let result = run(&op, &input);
+        assert_eq!(result, expected);
+
+        // Replace none
+        let input = r"not a match".to_owned();
+        let regex = r"\d{3}-\d{2}-\d{4}";
+        let op = Operation::Replace(Replace {
+            regex: Pattern::new(regex).unwrap(),
+            with: "***-**-****".to_owned(),
+            limit: 0,
+            group: None,
+            literal_replacement: false,
+            cases: BTreeMap::new(),
+            template: None,
+            options: OpOptions::default(),
+        });
+        let expected = r"not a match".to_owned();
+
+        let result = run(&op, &input);
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn apply_regex_ops_to_json_record_tests() {
+        static EXPECTED: &str = r#"{
+            "description": "Independence High School",
+            "class": "2025-A",
+            "students": [
+              {
+                  "first": "Abby",
+                  "last": "Hardy",
+                  "ssn": "***-**-****",
+                  "address": "... SAN JOSE CA 95127"
+              },
+              {
+                  "first": "Bob",
+                  "last": "Newmal",
+                  "ssn": "***-**-****",
+                  "address": "... Santa Clara CA 95347"
+              },
+              {
+                  "first": "Cindy",
+                  "last": "Hall",
+                  "ssn": "***-**-****",
+                  "address": "... Milpitas CA 95344"
+              }
+            ]
+        }"#;
+        let ops = vec![
+            Operation::Replace(Replace {
+                regex: Pattern::new(r"\d{3}-\d{2}-\d{4}").unwrap(),
+                with: "***-**-****".to_owned(),
+                limit: 0,
+                group: None,
+                literal_replacement: false,
+                cases: BTreeMap::new(),
+                template: None,
+                options: OpOptions::default(),
+            }),
+            Operation::Replace(Replace {
+                regex: Pattern::new(r#"(?P<first>"address":\s+\")([\w\d\s]+),"#).unwrap(),
+                with: "${first}...".to_owned(),
+                limit: 0,
+                group: None,
+                literal_replacement: false,
+                cases: BTreeMap::new(),
+                template: None,
+                options: OpOptions::default(),
+            })
+        ];
+
+        let record = SmartModuleRecord::new(Record::new(INPUT), 0, 0);
+        let result = apply_regex_ops_to_json_record(record.value.as_ref(), None, &ops).unwrap();
+        let result_value: Value = serde_json::from_slice(&result).unwrap();
+
+        let expected_value: Value = serde_json::from_str(EXPECTED).unwrap();
+        assert_eq!(result_value, expected_value);
+    }
+
+    #[test]
+    fn get_params_yaml_test() {
+        let spec = r#"
+- replace:
+    regex: "\\d{3}-\\d{2}-\\d{4}"
+    with: "***-**-****"
+- extract:
+    regex: 'zip (?P<zip>\d{5})'
+"#;
+        let mut params = SmartModuleExtraParams::default();
+        params.insert(PARAM_NAME.to_owned(), spec.to_owned());
+        params.insert(SPEC_FORMAT_PARAM.to_owned(), "yaml".to_owned());
+
+        let ops = get_params(params).unwrap();
+        assert_eq!(ops.len(), 2);
+        assert_eq!(run(&ops[0], "ssn 123-45-6789"), "ssn ***-**-****");
+        assert_eq!(run(&ops[1], "zip 95127"), r#"{"zip":"95127"}"#);
+
+        // Unknown formats are rejected
+        let mut params = SmartModuleExtraParams::default();
+        params.insert(PARAM_NAME.to_owned(), "[]".to_owned());
+        params.insert(SPEC_FORMAT_PARAM.to_owned(), "toml".to_owned());
+        assert!(get_params(params).is_err());
+    }
+
+    #[test]
+    fn apply_json_path_ops_test() {
+        let ops = ops_from_json(r#"[
+            {"replace": {"regex": "\\d", "with": "*", "path": "$.students[*].ssn"}},
+            {"replace": {"regex": "^\\w+", "with": "REDACTED", "path": "$.students[*].address"}},
+            {"extract": {"regex": "(?P<year>\\d{4})-(?P<section>\\w)", "path": "$.class"}},
+            {"replace": {"regex": "High", "with": "Middle"}}
+        ]"#);
+
+        let record = SmartModuleRecord::new(Record::new(INPUT), 0, 0);
+        let result = apply_regex_ops_to_json_record(record.value.as_ref(), None, &ops).unwrap();
+        let result: Value = serde_json::from_slice(&result).unwrap();
+
+        assert_eq!(result["description"], "Independence Middle School");
+        assert_eq!(result["class"], serde_json::json!({"year": "2025", "section": "A"}));
+        assert_eq!(result["students"][0]["ssn"], "***-**-****");
+        assert_eq!(result["students"][0]["first"], "Abby");
+        assert_eq!(result["students"][2]["address"], "REDACTED PRIME PL, Milpitas CA 95344");
+
+        // path operations need a JSON record
+        let record = SmartModuleRecord::new(Record::new("not json"), 0, 0);
+        assert!(apply_regex_ops_to_json_record(record.value.as_ref(), None, &ops).is_err());
+
+        // filter predicates only look at the selected values
+        let ops = ops_from_json(r#"[
+            {"match": {"regex": "Newmal", "path": "$.students[*].last"}},
+            {"not_match": {"regex": "Newmal", "path": "$.description"}}
+        ]"#);
+        let record = SmartModuleRecord::new(Record::new(INPUT), 0, 0);
+        assert!(filter_json_record(&record, record.value.as_ref(), &ops, &FilterMode::All).unwrap());
+    }
+
+    #[test]
+    fn filter_json_record_test() {
+        let ops = vec![
+            Operation::Match(Match {
+                regex: Pattern::new(r"\d{3}-\d{2}-\d{4}").unwrap(),
+                options: OpOptions::default(),
+            }),
+            Operation::NotMatch(Match {
+                regex: Pattern::new(r"Newmal").unwrap(),
+                options: OpOptions::default(),
+            }),
+            // non-predicate operations are ignored by the filter
+            Operation::Replace(Replace {
+                regex: Pattern::new(r"\d{3}-\d{2}-\d{4}").unwrap(),
+                with: "***-**-****".to_owned(),
+                limit: 0,
+                group: None,
+                literal_replacement: false,
+                cases: BTreeMap::new(),
+                template: None,
+                options: OpOptions::default(),
+            }),
+        ];
+        let pii = SmartModuleRecord::new(Record::new("Abby, ssn 123-45-6789"), 0, 0);
+        let excluded = SmartModuleRecord::new(Record::new("Newmal, ssn 987-65-4321"), 0, 0);
+        let clean = SmartModuleRecord::new(Record::new("Cindy"), 0, 0);
+
+        assert!(filter_json_record(&pii, pii.value.as_ref(), &ops, &FilterMode::All).unwrap());
+        assert!(!filter_json_record(&excluded, excluded.value.as_ref(), &ops, &FilterMode::All).unwrap());
+        assert!(!filter_json_record(&clean, clean.value.as_ref(), &ops, &FilterMode::All).unwrap());
+
+        assert!(filter_json_record(&pii, pii.value.as_ref(), &ops, &FilterMode::Any).unwrap());
+        assert!(filter_json_record(&excluded, excluded.value.as_ref(), &ops, &FilterMode::Any).unwrap());
+        assert!(filter_json_record(&clean, clean.value.as_ref(), &ops, &FilterMode::Any).unwrap());
+        let newmal_only = SmartModuleRecord::new(Record::new("Newmal"), 0, 0);
+        assert!(!filter_json_record(&newmal_only, newmal_only.value.as_ref(), &ops, &FilterMode::Any).unwrap());
+    }
+
+    #[test]
+    fn mask_test() {
+        let op = Operation::Mask(Mask {
+            regex: Pattern::new(r"\d{3,4}-\d{2}-\d{4,5}").unwrap(),
+            mask_char: default_mask_char(),
+            options: OpOptions::default(),
+        });
+        let input = r"ssn 123-45-6789, id 1234-56-78901";
+        assert_eq!(run(&op, input), r"ssn ***-**-****, id ****-**-*****");
+
+        let op = ops_from_json(r##"[{"mask": {"regex": "[\\w.]+@[\\w.]+", "mask_char": "#"}}]"##).remove(0);
+        assert_eq!(run(&op, "mail abby.h@school.edu now"), "mail ####.#@######.### now");
+    }
+
+    #[test]
+    fn mask_credit_card_test() {
+        let op = ops_from_json(r#"[{"mask_credit_card": {}}]"#).remove(0);
+        assert_eq!(
+            run(&op, "card 4111 1111 1111 1111, amex 3782-822463-10005, order 4111111111111112"),
+            "card **** **** **** 1111, amex ****-******-*0005, order 4111111111111112"
+        );
+        assert!(matches!(op.run_regex(b"tracking 1Z 1234567890123"), Cow::Borrowed(_)));
+        assert_eq!(op.count_up_to(b"4111111111111111 4111111111111112 4222222222222", usize::MAX), 2);
+        assert_eq!(op.spans(b"x 4111111111111112 4222222222222"), vec![[19, 32]]);
+        // cards sharing a candidate with other digit groups
+        assert_eq!(run(&op, "4111 1111 1111 1111 7 items"), "**** **** **** 1111 7 items");
+        assert_eq!(run(&op, "qty 7 4111 1111 1111 1111 4111 1111 1111 1111"), "qty 7 **** **** **** 1111 **** **** **** 1111");
+        // the longest run that passes the checksum is masked, here including `8 3`
+        assert_eq!(run(&op, "ip 10.0.0.42 8 3 4111 1111 1111 1111"), "ip 10.0.0.42 * * **** **** **** 1111");
+        assert!(matches!(op.run_regex("1 ".repeat(10_000).as_bytes()), Cow::Borrowed(_)));
+
+        let op = ops_from_json(r##"[{"mask_credit_card": {"keep_last": 0, "mask_char": "#"}}]"##).remove(0);
+        assert_eq!(run(&op, "4222222222222"), "#############");
+        let op = ops_from_json(r#"[{"mask_credit_card": {"regex": "\\d{16}", "keep_last": 6}}]"#).remove(0);
+        assert_eq!(run(&op, "a4111111111111111b"), "a**********111111b");
+
+        let mut params = SmartModuleExtraParams::default();
+        params.insert(PARAM_NAME.to_owned(), r#"[{"mask_credit_card": {"keep_last": 13}}]"#.to_owned());
+        assert!(get_params(params).is_err());
+    }
+
+    #[test]
+    fn normalize_test() {
+        let op = ops_from_json(r#"[{"normalize": {"presets": ["strip_control_chars", "collapse_whitespace", "trim"]}}]"#).remove(0);
+        assert_eq!(run(&op, " Abby\u{7}\u{0}  Hardy\t\r\n lives\u{a0}\u{3000}here\u{1b} "), "Abby Hardy lives here");
+        assert!(matches!(op.run_regex(b"Abby Hardy"), Cow::Borrowed(_)));
+        assert!(matches!(op.run_regex(b" \xff "), Cow::Borrowed(_)));
+        assert_eq!(op.count_up_to(b" a", usize::MAX), 1);
+
+        // presets run in the listed order
+        let op = ops_from_json(r#"[{"normalize": {"presets": ["collapse_whitespace", "strip_control_chars"]}}]"#).remove(0);
+        assert_eq!(run(&op, "a \u{0} b\nc"), "a  b c");
+        let op = ops_from_json(r#"[{"normalize": {"presets": ["strip_control_chars"]}}]"#).remove(0);
+        assert_eq!(run(&op, "a\u{85}\u{0}\tb\r\n"), "a\tb\r\n");
+
+        let mut params = SmartModuleExtraParams::default();
+        params.insert(PARAM_NAME.to_owned(), r#"[{"normalize": {"presets": []}}]"#.to_owned());
+        assert!(get_params(params).is_err());
+        let mut params = SmartModuleExtraParams::default();
+        params.insert(PARAM_NAME.to_owned(), r#"[{"normalize": {"presets": ["lowercase"]}}]"#.to_owned());
+        assert!(get_params(params).is_err());
+    }
+
+    #[test]
+    fn case_test() {
+        let op = ops_from_json(r#"[{"case": {"regex": "[A-Z][a-z]+ [A-Z][a-z]+", "to": "upper"}}]"#).remove(0);
+        assert_eq!(run(&op, "name: abby, city: New York"), "name: abby, city: NEW YORK");
+        assert_eq!(op.count_up_to(b"New York, San Jose", usize::MAX), 2);
+
+        let cases = [
+            ("lower", "Abby HARDY", "abby hardy"),
+            ("title", "abby o'NEIL-hardy", "Abby O'Neil-Hardy"),
+            ("snake", "New York", "new_york"),
+            ("snake", "userID HTTPServer v2Api", "user_id_http_server_v2_api"),
+            ("camel", "first_name", "firstName"),
+            ("camel", "HTTP server-URL", "httpServerUrl"),
+        ];
+        for (to, input, expected) in cases {
+            let op = ops_from_json(&format!(r#"[{{"case": {{"regex": ".+", "to": "{to}"}}}}]"#)).remove(0);
+            assert_eq!(run(&op, input), expected, "{to}");
+        }
+
+        let mut params = SmartModuleExtraParams::default();
+        params.insert(PARAM_NAME.to_owned(), r#"[{"case": {"regex": ".+", "to": "kebab"}}]"#.to_owned());
+        assert!(get_params(params).is_err());
+    }
+
+    #[test]
+    fn rematch_test() {
+        let parse = |spec: &str, strict: bool| {
+            let mut params = SmartModuleExtraParams::default();
+            params.insert(PARAM_NAME.to_owned(), spec.to_owned());
+            if strict {
+                params.insert(STRICT_PARAM.to_owned(), "true".to_owned());
+            }
+            get_params(params)
+        };
+        let spec = r#"[{"replace": {"regex": "\\d{3}-\\d{2}-\\d{4}", "with": "SSN-000"}}, {"mask": {"regex": "\\d+"}}]"#;
+        assert!(parse(spec, false).is_ok());
+        let err = parse(spec, true).unwrap_err().to_string();
+        assert!(err.contains("operation #1 `mask` matches the output of operation #0 `replace`, e.g. `SSN-000`"), "{err}");
+
+        let spec = r#"[{"replace": {"regex": "\\d{3}-\\d{2}-\\d{4}", "with": "SSN-000", "final": true}}, {"mask": {"regex": "\\d+"}}]"#;
+        assert!(parse(spec, false).is_err());
+        let spec = r#"[{"hash": {"regex": "\\w+@\\w+"}}, {"mask": {"regex": "[0-9a-f]{64}", "name": "digests"}}]"#;
+        assert!(parse(spec, true).unwrap_err().to_string().contains("operation #1 `mask: digests`"));
+
+        // other fields, capture references and operations that don't rewrite are left out
+        assert!(parse(r#"[{"replace": {"regex": "x", "with": "1", "path": "$.a"}}, {"mask": {"regex": "\\d", "path": "$.b"}}]"#, true).is_ok());
+        assert!(parse(r#"[{"replace": {"regex": "(\\d+)", "with": "<$1>"}}, {"mask": {"regex": "\\d"}}]"#, true).is_ok());
+        assert!(parse(r#"[{"replace": {"regex": "x", "with": "1"}}, {"match": {"regex": "\\d"}}]"#, true).is_ok());
+        assert_eq!(without_capture_references("${area}-$1x $$5"), "- $5");
+    }
+
+    #[test]
+    fn hash_test() {
+        let spec = r#"[
+            {"hash": {"regex": "[\\w.]+@[\\w.]+", "salt": "email_salt"}},
+            {"hash": {"regex": "\\d{3}-\\d{2}-\\d{4}", "algorithm": "sha1"}},
+            {"hash": {"regex": "Newmal", "algorithm": "blake3"}}
+        ]"#;
+        let mut params = SmartModuleExtraParams::default();
+        params.insert(PARAM_NAME.to_owned(), spec.to_owned());
+        params.insert("email_salt".to_owned(), "s3cr3t".to_owned());
+        let ops = get_params(params).unwrap();
+
+        // sha256("s3cr3t" + "abby@school.edu"), stable across records
+        let email = "05d6d53769be21dda2aac52ed0f3f8873edd0e06f13fca6a6fd2922f7a296c6f";
+        assert_eq!(run(&ops[0], "abby@school.edu"), email);
+        assert_eq!(run(&ops[0], "to: abby@school.edu"), format!("to: {email}"));
+
+        assert_eq!(run(&ops[1], "ssn 123-45-6789"), "ssn 6032e9a846950908c6e9957d6cbf04ee5913c801");
+        assert_eq!(run(&ops[2], "Newmal").len(), 64);
+
+        // salt param must be supplied
+        let mut params = SmartModuleExtraParams::default();
+        params.insert(PARAM_NAME.to_owned(), spec.to_owned());
+        assert!(get_params(params).is_err());
+    }
+
+    #[test]
+    fn encrypt_test() {
+        let parse = |spec: &str, key: Option<&str>| {
+            let mut params = SmartModuleExtraParams::default();
+            params.insert(PARAM_NAME.to_owned(), spec.to_owned());
+            if let Some(key) = key {
+                params.insert("ssn_key".to_owned(), key.to_owned());
+            }
+            get_params(params)
+        };
+        let key = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8gISIjJCUmJygpKissLS4vMDEyMzQ1Njc4OTo7PD0+Pw==";
+        let ops = parse(r#"[
+            {"encrypt": {"regex": "\\d{3}-\\d{2}-\\d{4}", "key": "ssn_key", "path": "$.ssn"}},
+            {"decrypt": {"regex": ".+", "key": "ssn_key", "path": "$.ssn"}}
+        ]"#, Some(key)).unwrap();
+        let run = |op: &Operation, input: &str| {
+            String::from_utf8(apply_regex_ops(input.as_bytes(), [op].into_iter()).unwrap().into_owned()).unwrap()
+        };
+
+        let encrypted = run(&ops[0], r#"{"ssn":"123-45-6789"}"#);
+        assert_ne!(encrypted, r#"{"ssn":"123-45-6789"}"#);
+        assert_eq!(run(&ops[0], r#"{"ssn":"123-45-6789"}"#), encrypted);
+        assert_eq!(run(&ops[1], &encrypted), r#"{"ssn":"123-45-6789"}"#);
+        // values that are not tokens of this key are left as is
+        assert_eq!(run(&ops[1], r#"{"ssn":"***-**-****"}"#), r#"{"ssn":"***-**-****"}"#);
+
+        let spec = r#"[{"encrypt": {"regex": "\\d+", "key": "ssn_key"}}]"#;
+        assert!(parse(spec, None).unwrap_err().to_string().contains("ssn_key"));
+        assert!(parse(spec, Some("c2hvcnQ=")).unwrap_err().to_string().contains("invalid `ssn_key` param: AES-SIV keys are 32 or 64 bytes"));
+    }
+
+    #[test]
+    fn flags_spec_test() {
+        let ops = ops_from_json(r#"[{"replace": {"regex": "hardy", "with": "H.", "flags": "i"}}]"#);
+        assert_eq!(run(&ops[0], "Abby HARDY"), "Abby H.");
+
+        let mut params = SmartModuleExtraParams::default();
+        params.insert(PARAM_NAME.to_owned(), r#"[{"replace": {"regex": "hardy", "with": "H.", "flags": "iz"}}]"#.to_owned());
+        let err = get_params(params).unwrap_err();
+        assert!(format!("{err:?}").contains("unknown regex flag `z`"));
+    }
+
+    #[test]
+    fn target_key_test() {
+        let ops = ops_from_json(r#"[
+            {"replace": {"regex": "\\d{3}-\\d{2}-\\d{4}", "with": "***-**-****", "target": "both"}},
+            {"replace": {"regex": "^student:", "with": "", "target": "key"}},
+            {"replace": {"regex": "ssn", "with": "SSN"}}
+        ]"#);
+        let record = SmartModuleRecord::new(
+            Record::new_key_value("student:123-45-6789", "ssn 123-45-6789 for student:abby"), 0, 0
+        );
+
+        let key = apply_regex_ops_to_key(&record, record.value.as_ref(), &ops).unwrap().unwrap();
+        assert_eq!(key.as_ref(), b"***-**-****");
+        let value = apply_regex_ops_to_json_record(record.value.as_ref(), None, &ops).unwrap();
+        assert_eq!(value, "SSN ***-**-**** for student:abby".as_bytes());
+
+        // records without a key keep none
+        let record = SmartModuleRecord::new(Record::new("ssn 123-45-6789"), 0, 0);
+        assert!(apply_regex_ops_to_key(&record, record.value.as_ref(), &ops).unwrap().is_none());
+
+        // filter predicates on the key
+        let ops = ops_from_json(r#"[{"match": {"regex": "^student:", "target": "key"}}]"#);
+        let student = SmartModuleRecord::new(Record::new_key_value("student:abby", "{}"), 0, 0);
+        let teacher = SmartModuleRecord::new(Record::new_key_value("teacher:bob", "student:"), 0, 0);
+        assert!(filter_json_record(&student, student.value.as_ref(), &ops, &FilterMode::All).unwrap());
+        assert!(!filter_json_record(&teacher, teacher.value.as_ref(), &ops, &FilterMode::All).unwrap());
+    }
+
+    #[test]
+    fn builtin_pattern_spec_test() {
+        let ops = ops_from_json(r#"[
+            {"replace": {"pattern": "email", "with": "<redacted>"}},
+            {"mask": {"pattern": "ssn"}}
+        ]"#);
+        let record = SmartModuleRecord::new(Record::new("abby@school.edu, ssn 123-45-6789"), 0, 0);
+        let result = apply_regex_ops_to_json_record(record.value.as_ref(), None, &ops).unwrap();
+        assert_eq!(result, "<redacted>, ssn ***-**-****".as_bytes());
+
+        // a pinned version keeps matching as released
+        let ops = ops_from_json(r#"[{"mask": {"pattern": "ipv6@v1"}}, {"replace": {"pattern": "ipv6@v2", "with": "<ip>"}}]"#);
+        assert_eq!(run(&ops[0], "fe80::12345"), "****::****5");
+        assert_eq!(run(&ops[1], "fe80::12345"), "<ip>12345");
+    }
+
+    #[test]
+    fn bytes_pipeline_test() {
+        let ops = ops_from_json(r#"[
+            {"replace": {"regex": "\\d{3}-\\d{2}-\\d{4}", "with": "***-**-****"}},
+            {"replace": {"regex": "Newmal", "with": "N."}}
+        ]"#);
+
+        // no match borrows the record value
+        let record = SmartModuleRecord::new(Record::new("nothing to redact"), 0, 0);
+        let result = apply_regex_ops_to_json_record(record.value.as_ref(), None, &ops).unwrap();
+        assert!(matches!(result, Cow::Borrowed(_)));
+
+        // non UTF-8 bytes around the match are preserved
+        let record = SmartModuleRecord::new(Record::new(b"\xff ssn 123-45-6789 \xfe".to_vec()), 0, 0);
+        let result = apply_regex_ops_to_json_record(record.value.as_ref(), None, &ops).unwrap();
+        assert_eq!(result, b"\xff ssn ***-**-**** \xfe".as_slice());
+    }
+
+    #[test]
+    fn on_error_test() {
+        let spec = spec_with(r#"[{"mask": {"regex": ".+", "path": "$.ssn"}}]"#, &[(ON_ERROR_PARAM, "passthrough")]).unwrap();
+        assert_eq!(spec.on_error, OnError::Passthrough);
+
+        let invalid = SmartModuleRecord::new(Record::new("ssn: 123-45-6789"), 0, 0);
+        assert!(map_record(&invalid, &spec).is_err());
+
+        // `skip` needs a build that can drop records
+        assert_eq!(spec_with("[]", &[(ON_ERROR_PARAM, "skip")]).is_err(), cfg!(feature = "map"));
+        assert!(spec_with("[]", &[(ON_ERROR_PARAM, "ignore")]).is_err());
+
+    }
+
+    #[test]
+    fn replace_limit_test() {
+        let ops = ops_from_json(r##"[
+            {"replace_first": {"regex": "\\d", "with": "#"}},
+            {"replace": {"regex": "\\d", "with": "*", "limit": 2}},
+            {"replace": {"regex": "\\d", "with": "*", "limit": 0}}
+        ]"##);
+        let input = "1234";
+        assert_eq!(run(&ops[0], input), "#234");
+        assert_eq!(run(&ops[1], input), "**34");
+        assert_eq!(run(&ops[2], input), "****");
+    }
+
+    #[test]
+    fn replace_group_test() {
+        let ops = ops_from_json(r##"[
+            {"replace": {"regex": "password=(\\w+)", "with": "***", "group": 1}},
+            {"replace_first": {"regex": "(?P<user>\\w+)@(?P<domain>[\\w.]+)", "with": "${user|upper}", "group": "user"}},
+            {"replace": {"regex": "id:(\\d+)?(x)", "with": "[$2]", "group": 1}}
+        ]"##);
+        assert_eq!(run(&ops[0], "password=hunter2 user=abby password=x"), "password=*** user=abby password=***");
+        assert_eq!(run(&ops[1], "abby@school.edu bob@school.edu"), "ABBY@school.edu bob@school.edu");
+        // a group that did not participate leaves the match as is
+        assert_eq!(run(&ops[2], "id:42x id:x"), "id:[x]x id:x");
+
+        let error = |spec: &str| {
+            let mut params = SmartModuleExtraParams::default();
+            params.insert(PARAM_NAME.to_owned(), spec.to_owned());
+            get_params(params).unwrap_err().to_string()
+        };
+        assert!(error(r#"[{"replace": {"regex": "(a)", "with": "b", "group": 2}}]"#).contains("`group` 2 is not in the regex"));
+        assert!(error(r#"[{"replace": {"regex": "(a)", "with": "b", "group": "x"}}]"#).contains("`group` `x` is not named"));
+    }
+
+    #[test]
+    fn capture_references_test() {
+        let check = |regex: &str, with: &str| check_capture_references(with, &Pattern::new(regex).unwrap()).map_err(|err| err.to_string());
+        assert!(check(r"(?P<user>\w+)@(\w+)", "$1 ${2} $user ${user}@$$domain ${user|upper} $").is_ok());
+        assert_eq!(check(r"(\w+)", "$2").unwrap_err(), "`with` references group `2` that is not in the regex");
+        assert_eq!(check(r"(?P<user>\w+)", "${usr}").unwrap_err(), "`with` references group `usr` that is not in the regex");
+        assert_eq!(check(r"(\w+)", "$1st").unwrap_err(),
+            "`with` references group `1st` that is not in the regex, write `${1}st` for group 1 followed by text");
+
+        let mut params = SmartModuleExtraParams::default();
+        params.insert(PARAM_NAME.to_owned(), r#"[{"replace": {"regex": "(?P<first>\\w+) (?P<last>\\w+)", "with": "$last, $frist"}}]"#.to_owned());
+        let err = get_params(params).unwrap_err().to_string();
+        assert!(err.contains("operation #0 `replace`: `with` references group `frist`"), "{err}");
+    }
+
+    #[test]
+    fn anonymize_ip_test() {
+        let parse = |spec: &str| {
+            let mut params = SmartModuleExtraParams::default();
+            params.insert(PARAM_NAME.to_owned(), spec.to_owned());
+            params.insert("ip_salt".to_owned(), "pepper".to_owned());
+            get_params(params).map_err(|err| err.to_string())
+        };
+        let ops = parse(r#"[{"anonymize_ip": {}}, {"anonymize_ip": {"prefix_v4": 16, "prefix_v6": 32}}]"#).unwrap();
+        let input = "from 192.168.17.42 and 2001:db8:85a3::8a2e:370:7334 at 10:30:00";
+        assert_eq!(run(&ops[0], input), "from 192.168.17.0 and 2001:db8:85a3:: at 10:30:00");
+        assert_eq!(run(&ops[1], input), "from 192.168.0.0 and 2001:db8:: at 10:30:00");
+
+        let ops = parse(r#"[{"anonymize_ip": {"strategy": "hash", "salt": "ip_salt"}}, {"anonymize_ip": {"strategy": "hash", "salt": "ip_salt", "prefix_v4": 8}}]"#).unwrap();
+        let anonymize = |op: &Operation, ip: &str| run(op, ip).parse::<std::net::IpAddr>().unwrap();
+        let (a, b) = (anonymize(&ops[0], "10.1.2.3"), anonymize(&ops[0], "10.1.2.200"));
+        let (std::net::IpAddr::V4(a), std::net::IpAddr::V4(b)) = (a, b) else { panic!("expected IPv4 pseudonyms") };
+        assert_ne!(a.octets(), [10, 1, 2, 3]);
+        // addresses sharing a 24 bit prefix keep sharing one, and pseudonyms are stable
+        assert_eq!(a.octets()[..3], b.octets()[..3]);
+        assert_ne!(a, b);
+        assert_eq!(run(&ops[0], "10.1.2.3"), a.to_string());
+        assert!(run(&ops[1], "10.1.2.3").starts_with("10."));
+        assert!(anonymize(&ops[0], "fe80::1").is_ipv6());
+
+        assert!(parse(r#"[{"anonymize_ip": {"strategy": "hash"}}]"#).unwrap_err().contains("needs a `salt` param"));
+        assert!(parse(r#"[{"anonymize_ip": {"salt": "ip_salt"}}]"#).unwrap_err().contains("only used by `strategy: hash`"));
+        assert!(parse(r#"[{"anonymize_ip": {"prefix_v4": 33}}]"#).is_err());
+    }
+
+    #[test]
+    fn timestamp_test() {
+        let ops = ops_from_json(r#"[
+            {"timestamp": {"regex": "\\d{2}/\\d{2}/\\d{4} \\d{2}:\\d{2}", "from_format": "%d/%m/%Y %H:%M", "timezone": "+02:00"}},
+            {"timestamp": {"regex": "\\b\\d{10}\\b", "from_format": "unix", "to_format": "%Y-%m-%d %H:%M:%S"}},
+            {"timestamp": {"regex": "at \\S+", "from_format": "at %Y-%m-%dT%H:%M:%S%z", "to_format": "unix_ms"}}
+        ]"#);
+        assert_eq!(run(&ops[0], "login 31/12/2023 23:30, 31/02/2023 10:00"), "login 2023-12-31T21:30:00Z, 31/02/2023 10:00");
+        assert_eq!(run(&ops[1], "ts=1700000000"), "ts=2023-11-14 22:13:20");
+        assert_eq!(run(&ops[2], "seen at 2023-11-14T23:13:20+0100"), "seen 1700000000000");
+
+        let parse = |spec: &str| {
+            let mut params = SmartModuleExtraParams::default();
+            params.insert(PARAM_NAME.to_owned(), spec.to_owned());
+            get_params(params).map_err(|err| err.to_string())
+        };
+        assert!(parse(r#"[{"timestamp": {"regex": "x", "from_format": "%Q"}}]"#).unwrap_err().contains("invalid time format `%Q`"));
+        let err = parse(r#"[{"timestamp": {"regex": "x", "from_format": "rfc3339", "timezone": "Europe/Paris"}}]"#).unwrap_err();
+        assert!(err.contains("named zones are not supported"), "{err}");
+    }
+
+    #[test]
+    fn literal_replacement_test() {
+        let ops = ops_from_json(r#"[
+            {"replace": {"regex": "price: \\d+", "with": "price: $1.00 (${usd})", "literal_replacement": true}},
+            {"replace_first": {"regex": "(\\w+)=(\\d+)", "group": 2, "with": "$$", "literal_replacement": true}}
+        ]"#);
+        assert_eq!(run(&ops[0], "price: 12, price: 7"), "price: $1.00 (${usd}), price: $1.00 (${usd})");
+        assert_eq!(run(&ops[1], "a=1 b=2"), "a=$$ b=2");
+        assert_eq!(ops[0].output_samples(), ["price: $1.00 (${usd})"]);
+
+        // without the flag, `$1` would be rejected as a group missing from the regex
+        let mut params = SmartModuleExtraParams::default();
+        params.insert(PARAM_NAME.to_owned(), r#"[{"replace": {"regex": "price: \d+", "with": "$1.00"}}]"#.to_owned());
+        assert!(get_params(params).is_err());
+
+        // param values are inserted unescaped, as they are written out
+        let spec = spec_with(r#"[{"replace": {"regex": "\\d+", "with": "{{param:price}}", "literal_replacement": true}}]"#, &[("price", "$5")]).unwrap();
+        assert_eq!(map_str(&spec, "total 12").unwrap(), "total $5");
+        let spec = spec_with(r#"[{"replace": {"regex": "\\d+", "with": "{{param:price}}"}}]"#, &[("price", "$5")]).unwrap();
+        assert_eq!(map_str(&spec, "total 12").unwrap(), "total $5");
+    }
+
+    #[test]
+    fn normalize_pattern_whitespace_test() {
+        let parse = |spec: &str, normalize: bool| {
+            let mut params = SmartModuleExtraParams::default();
+            params.insert(PARAM_NAME.to_owned(), spec.to_owned());
+            params.insert(NORMALIZE_PATTERN_WHITESPACE_PARAM.to_owned(), normalize.to_string());
+            get_params(params).unwrap()
+        };
+        let spec = r#"[{"replace": {"regex": "user (\\w+) logged in", "with": "user <$1> logged in", "when": {"regex": "INFO user"}}}]"#;
+        let ops = parse(spec, true);
+        assert_eq!(ops[0].pattern().unwrap().source(), r"user\s+(\w+)\s+logged\s+in");
+        assert_eq!(run(&ops[0], "INFO\tuser abby\t logged  in"), "INFO\tuser <abby> logged in");
+        assert_eq!(run(&parse(spec, false)[0], "INFO\tuser abby\t logged  in"), "INFO\tuser abby\t logged  in");
+
+        // with the `x` flag spaces are ignored rather than relaxed
+        let ops = parse(r#"[{"mask": {"regex": "\\d{3} - \\d{2}", "flags": "x"}}]"#, true);
+        assert_eq!(ops[0].pattern().unwrap().source(), r"\d{3} - \d{2}");
+        assert_eq!(run(&ops[0], "123-45"), "***-**");
+    }
+
+    #[test]
+    fn definitions_test() {
+        let parse = |spec: &str, spec_1: Option<&str>| {
+            let mut params = SmartModuleExtraParams::default();
+            params.insert(PARAM_NAME.to_owned(), spec.to_owned());
+            if let Some(spec_1) = spec_1 {
+                params.insert(format!("{PARAM_NAME}.1"), spec_1.to_owned());
+            }
+            get_params(params)
+        };
+        let ops = parse(r#"[
+            {"definitions": {"area": "\\d{3}", "ssn": "{{area}}-\\d{2}-\\d{4}"}},
+            {"mask": {"regex": "ssn {{ssn}}", "when": {"regex": "{{area}}"}}},
+            {"replace": {"regex": "({{area}}){2}", "with": "<$1>"}}
+        ]"#, Some(r#"[{"hash": {"regex": "\\b{{ssn}}\\b"}}]"#)).unwrap();
+        assert_eq!(ops[0].pattern().unwrap().source(), r"ssn (?:(?:\d{3})-\d{2}-\d{4})");
+        assert_eq!(run(&ops[0], "ssn 123-45-6789 ssn 12-345-6789"), "*** ***-**-**** ssn 12-345-6789");
+        assert_eq!(run(&ops[1], "123456"), "<456>");
+        assert_eq!(ops[2].pattern().unwrap().source(), r"\b(?:(?:\d{3})-\d{2}-\d{4})\b");
+
+        let error = |spec: &str| parse(spec, None).unwrap_err().to_string();
+        assert!(error(r#"[{"mask": {"regex": "{{zip}}"}}]"#).contains("`{{zip}}` is not in the spec definitions"));
+        assert!(error(r#"[{"definitions": {"a": "{{b}}", "b": "x{{a}}"}}, {"mask": {"regex": "{{a}}"}}]"#).contains("refers to itself"));
+        assert!(error(r#"[{"definitions": {"a": "x"}}, {"definitions": {"a": "y"}}]"#).contains("`a` is defined twice"));
+        assert!(error(r#"[{"definitions": {"a-b": "x"}}]"#).contains("invalid `spec` operation #0 `definitions`"));
+        // `{{` that is not a reference is left to the regex parser
+        assert!(parse(r#"[{"mask": {"regex": "a{{ b"}}]"#, None).is_err());
+    }
+
+    #[test]
+    fn replace_param_test() {
+        let parse = |spec: &str, tenant: &str| {
+            let mut params = SmartModuleExtraParams::default();
+            params.insert(PARAM_NAME.to_owned(), spec.to_owned());
+            params.insert("tenant_tag".to_owned(), tenant.to_owned());
+            get_params(params)
+        };
+        let ops = parse(r#"[
+            {"replace": {"regex": "id:(\\d+)", "with": "{{param:tenant_tag}}-${1}"}},
+            {"replace": {"regex": "(?P<user>\\w+)@", "with": "${user|upper}@{{param:tenant_tag}}."}}
+        ]"#, "acme$1").unwrap();
+        assert_eq!(run(&ops[0], "id:42 id:7"), "acme$1-42 acme$1-7");
+        assert_eq!(run(&ops[1], "abby@school.edu"), "ABBY@acme$1.school.edu");
+
+        let err = parse(r#"[{"replace": {"regex": "a", "with": "{{param:region}}"}}]"#, "acme").unwrap_err();
+        assert!(err.to_string().contains("region"), "{err}");
+        let err = parse(r#"[{"replace": {"regex": "a", "with": "{{param:tenant_tag"}}]"#, "acme").unwrap_err();
+        assert!(err.to_string().contains("unterminated `{{param:` reference"), "{err}");
+    }
+
+    #[test]
+    fn spec_validation_test() {
+        let error = |spec: &str| {
+            let mut params = SmartModuleExtraParams::default();
+            params.insert(PARAM_NAME.to_owned(), spec.to_owned());
+            get_params(params).unwrap_err().to_string()
+        };
+
+        let err = error(r#"[{"replace": {"regex": "a", "with": "b"}}, {"replace": {"regex": "\\d"}}]"#);
+        assert!(err.starts_with("invalid `spec` operation #1 `replace`: missing field `with`"), "{err}");
+        assert!(err.contains("hint:"), "{err}");
+
+        let err = error(r#"[{"replace_all": {"regex": "a", "with": "b"}}]"#);
+        assert!(err.starts_with("invalid `spec` operation #0 `replace_all`: unknown variant `replace_all`"), "{err}");
+
+        let err = error(r#"[{"mask": {"regex": "(\\d"}}]"#);
+        assert!(err.starts_with("invalid `spec` operation #0 `mask`: invalid regex `(\\d`"), "{err}");
+        assert!(err.contains("backslashes must be escaped"), "{err}");
+
+        let err = error(r#"[{"mask": {"regex": "a"}, "replace": {"regex": "a", "with": "b"}}]"#);
+        assert!(err.starts_with("invalid `spec` operation #0 `?`"), "{err}");
+
+        let err = error(r#"{"replace": {"regex": "a", "with": "b"}}"#);
+        assert!(err.starts_with("cannot parse `spec` param"), "{err}");
+    }
+
+    #[test]
+    fn array_map_record_test() {
+        let spec = |json: &str| Spec {
+            ops: ops_from_json(json),
+            filter_mode: FilterMode::default(),
+            on_error: OnError::default(),
+            stats_field: None,
+            prefilter: None,
+            audit: false,
+            size_limit: None,
+            time_budget: None,
+            window_records: None,
+            utf8: Utf8Mode::default(),
+            input_encoding: InputEncoding::default(),
+            mode: Mode::default(),
+            control: None,
+            drop_regex: None,
+            codec: None,
+            output_schema: None,
+            lines: None,
+            array_root: ArrayRoot::default(),
+            post_process: None,
+            checksum: None,
+            spans: None,
+            tenants: None,
+        };
+        let values = |spec: &Spec, record: &SmartModuleRecord| -> Vec<String> {
+            array_map_record(record, spec).unwrap().into_iter()
+                .map(|(_, value)| String::from_utf8(value.as_ref().to_vec()).unwrap())
+                .collect()
+        };
+        let record = SmartModuleRecord::new(
+            Record::new_key_value("batch-1", "ts=1 ssn=123-45-6789\n\nts=2 ssn=987-65-4321\n"), 0, 0
+        );
+
+        // ops before the split apply to the whole record, after it to each piece
+        let split = spec(r#"[
+            {"replace": {"regex": "ts=", "with": "time="}},
+            {"split": {"regex": "\n"}},
+            {"mask": {"regex": "\\d{3}-\\d{2}-\\d{4}"}}
+        ]"#);
+        assert_eq!(values(&split, &record), vec!["time=1 ssn=***-**-****", "time=2 ssn=***-**-****"]);
+        let output = array_map_record(&record, &split).unwrap();
+        assert_eq!(output[1].0.as_ref().unwrap().as_ref(), b"batch-1");
+
+        let find_all = spec(r#"[{"find_all": {"regex": "ssn=\\S+"}}]"#);
+        assert_eq!(values(&find_all, &record), vec!["ssn=123-45-6789", "ssn=987-65-4321"]);
+
+        let named = spec(r#"[{"find_all": {"regex": "ts=(?P<ts>\\d+) ssn=(?P<ssn>\\S+)"}}]"#);
+        assert_eq!(values(&named, &record), vec![
+            r#"{"ssn":"123-45-6789","ts":"1"}"#, r#"{"ssn":"987-65-4321","ts":"2"}"#
+        ]);
+
+        let tuples = spec(r#"[{"find_all": {"regex": "ts=(\\d+) ssn=(\\S+)"}}]"#);
+        assert_eq!(values(&tuples, &record), vec![r#"["1","123-45-6789"]"#, r#"["2","987-65-4321"]"#]);
+
+        // no match emits no records, other builds ignore these operations
+        let record = SmartModuleRecord::new(Record::new("nothing here"), 0, 0);
+        assert!(values(&find_all, &record).is_empty());
+        assert_eq!(run(&find_all.ops[0], "ssn=1"), "ssn=1");
+    }
+
+    #[test]
+    fn when_test() {
+        let ops = ops_from_json(r#"[
+            {"mask": {"pattern": "ssn", "when": {"path": "$.type", "equals": "student"}}},
+            {"replace": {"regex": "Hall", "with": "H.", "when": {"regex": "\"type\":\\s*\"teacher\""}}},
+            {"replace": {"regex": "Cindy", "with": "C.", "when": {"path": "$.nickname"}}}
+        ]"#);
+        let apply = |input: &str| -> Value {
+            let record = SmartModuleRecord::new(Record::new(input), 0, 0);
+            serde_json::from_slice(&apply_regex_ops_to_json_record(record.value.as_ref(), None, &ops).unwrap()).unwrap()
+        };
+
+        let student = apply(r#"{"type": "student", "name": "Cindy Hall", "ssn": "123-45-6789"}"#);
+        assert_eq!(student, serde_json::json!({"type": "student", "name": "Cindy Hall", "ssn": "***-**-****"}));
+
+        let teacher = apply(r#"{"type": "teacher", "name": "Cindy Hall", "ssn": "123-45-6789", "nickname": "Cin"}"#);
+        assert_eq!(teacher["ssn"], "123-45-6789");
+        assert_eq!(teacher["name"], "C. H.");
+
+        let mut params = SmartModuleExtraParams::default();
+        params.insert(PARAM_NAME.to_owned(), r#"[{"mask": {"pattern": "ssn", "when": {"equals": "x"}}}]"#.to_owned());
+        assert!(get_params(params).is_err());
+
+        for spec in [
+            r#"[{"mask": {"pattern": "ssn", "when_header": {"content-type": "application/json"}}}]"#,
+            r#"[{"mask": {"pattern": "ssn", "set_headers": {"x-redacted": "true"}}}]"#,
+        ] {
+            let mut params = SmartModuleExtraParams::default();
+            params.insert(PARAM_NAME.to_owned(), spec.to_owned());
+            assert!(get_params(params).is_err());
+        }
+        let mut params = SmartModuleExtraParams::default();
+        params.insert(PARAM_NAME.to_owned(), r#"[{"extract": {"regex": "(?P<region>\\w+)/", "set_headers": {"x-region": "$region"}}}]"#.to_owned());
+        assert!(get_params(params).unwrap_err().to_string().contains("with `insert` instead"));
+    }
+
+    #[test]
+    fn spec_encoding_test() {
+        use base64::Engine;
+        use std::io::Write;
+
+        let spec = r#"[{"mask": {"pattern": "ssn"}}]"#;
+        let base64 = base64::engine::general_purpose::STANDARD;
+        let mut gzip = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        gzip.write_all(spec.as_bytes()).unwrap();
+        let gzipped = base64.encode(gzip.finish().unwrap());
+
+        for (encoding, raw) in [("plain", spec.to_owned()), ("base64", base64.encode(spec)), ("gzip+base64", gzipped)] {
+            let mut params = SmartModuleExtraParams::default();
+            params.insert(PARAM_NAME.to_owned(), raw);
+            params.insert(SPEC_ENCODING_PARAM.to_owned(), encoding.to_owned());
+            let ops = get_params(params).unwrap();
+            assert_eq!(run(&ops[0], "123-45-6789"), "***-**-****", "{encoding}");
+        }
+
+        let mut params = SmartModuleExtraParams::default();
+        params.insert(PARAM_NAME.to_owned(), "not base64!".to_owned());
+        params.insert(SPEC_ENCODING_PARAM.to_owned(), "base64".to_owned());
+        assert!(get_params(params).is_err());
+    }
+
+    #[test]
+    fn overrides_test() {
+        let spec = r#"[
+            {"mask": {"name": "ssn", "pattern": "ssn"}},
+            {"replace": {"name": "address", "regex": "\\d+ \\w+ St", "with": "[address]"}},
+            {"replace": {"regex": "Abby", "with": "A.", "enabled": false}}
+        ]"#;
+        let input = "Abby, 123-45-6789, 1 Main St";
+        let run_all = |ops: &[Operation]| {
+            String::from_utf8(apply_regex_ops(input.as_bytes(), ops.iter()).unwrap().into_owned()).unwrap()
+        };
+
+        let ops = ops_from_json(spec);
+        assert_eq!(ops.len(), 2);
+        assert_eq!(run_all(&ops), "Abby, ***-**-****, [address]");
+
+        let with_overrides = |overrides: &str| {
+            let mut params = SmartModuleExtraParams::default();
+            params.insert(PARAM_NAME.to_owned(), spec.to_owned());
+            params.insert(OVERRIDES_PARAM.to_owned(), overrides.to_owned());
+            get_params(params)
+        };
+        let ops = with_overrides(r##"{"address": {"enabled": false}, "ssn": {"mask_char": "#"}}"##).unwrap();
+        assert_eq!(run_all(&ops), "Abby, ###-##-####, 1 Main St");
+
+        let err = with_overrides(r#"{"phone": {"enabled": false}}"#).unwrap_err();
+        assert!(err.to_string().contains("no operation with this name"), "{err}");
+        assert!(with_overrides(r#"{"ssn": false}"#).is_err());
+        let err = with_overrides(r#"{"address": {"regex": "("}}"#).unwrap_err();
+        assert!(err.to_string().contains("operation #1 `replace: address`"), "{err}");
+    }
+
+    #[test]
+    fn translate_test() {
+        let translate = |spec: &str, dictionary: &str| {
+            let mut params = SmartModuleExtraParams::default();
+            params.insert(PARAM_NAME.to_owned(), spec.to_owned());
+            params.insert("dictionary".to_owned(), dictionary.to_owned());
+            get_params(params)
+        };
+        let dictionary = r#"{"St": "Street", "Ave": "Avenue", "Street": "Street", "CA": "California"}"#;
+
+        let ops = translate(r#"[{"translate": {}}]"#, dictionary).unwrap();
+        assert_eq!(run(&ops[0], "1 Main St, Oakland CA"), "1 Main Street, Oakland California");
+        assert_eq!(run(&ops[0], "1 Main Street"), "1 Main Street");
+        assert!(matches!(ops[0].run_regex(b"no tokens"), Cow::Borrowed(_)));
+
+        let ops = translate(r#"[{"translate": {"flags": "i", "path": "$.city"}}]"#, dictionary).unwrap();
+        let mut data = Data::Bytes(Cow::Borrowed(br#"{"city": "oakland ca", "state": "CA"}"#));
+        ops[0].apply(&mut data, None).unwrap();
+        assert_eq!(data.into_bytes().as_ref(), br#"{"city":"oakland California","state":"CA"}"#);
+
+        let mut params = SmartModuleExtraParams::default();
+        params.insert(PARAM_NAME.to_owned(), r#"[{"translate": {"dictionary": "states"}}]"#.to_owned());
+        let err = get_params(params).unwrap_err();
+        assert!(err.to_string().contains("states"), "{err}");
+        assert!(translate(r#"[{"translate": {}}]"#, r#"{"": "x"}"#).is_err());
+        assert!(translate(r#"[{"translate": {}}]"#, r#"["St"]"#).is_err());
+    }
+
+    #[test]
+    fn stats_field_test() {
+        let spec = Spec {
+            ops: ops_from_json(r##"[
+                {"mask": {"name": "ssn", "pattern": "ssn"}},
+                {"replace_first": {"regex": "Abby", "with": "A.", "path": "$.first"}},
+                {"replace": {"regex": "\\d", "with": "#", "limit": 2, "target": "key"}}
+            ]"##),
+            filter_mode: FilterMode::default(),
+            on_error: OnError::default(),
+            stats_field: Some("_stats".to_string()),
+            prefilter: None,
+            audit: false,
+            size_limit: None,
+            time_budget: None,
+            window_records: None,
+            utf8: Utf8Mode::default(),
+            input_encoding: InputEncoding::default(),
+            mode: Mode::default(),
+            control: None,
+            drop_regex: None,
+            codec: None,
+            output_schema: None,
+            lines: None,
+            array_root: ArrayRoot::default(),
+            post_process: None,
+            checksum: None,
+            spans: None,
+            tenants: None,
+        };
+        let record = SmartModuleRecord::new(
+            Record::new_key_value("id-123", r#"{"first": "Abby", "ssn": ["123-45-6789", "234-56-7890"]}"#), 0, 0
+        );
+        let (key, value) = map_record(&record, &spec).unwrap();
+        assert_eq!(key.unwrap().as_ref(), b"id-##3");
+        assert_eq!(
+            serde_json::from_slice::<Value>(value.as_ref()).unwrap(),
+            serde_json::json!({"first": "A.", "ssn": ["***-**-****", "***-**-****"], "_stats": {"ssn": 2, "#1": 1}})
+        );
+
+        let record = SmartModuleRecord::new(Record::new("123-45-6789"), 0, 0);
+        assert!(map_record(&record, &spec).is_err());
+    }
+
+    #[test]
+    fn csv_column_test() {
+        let csv_params = |spec: &str| {
+            let mut params = SmartModuleExtraParams::default();
+            params.insert(PARAM_NAME.to_owned(), spec.to_owned());
+            params.insert(FORMAT_PARAM.to_owned(), "csv".to_owned());
+            params.insert(CSV_COLUMNS_PARAM.to_owned(), "first,address,ssn".to_owned());
+            get_params(params)
+        };
+        let ops = csv_params(r#"[
+            {"replace": {"regex": "^\\d+ ", "with": "", "column": "address"}},
+            {"mask": {"pattern": "ssn", "column_index": 2}},
+            {"replace": {"regex": ",", "with": ";"}}
+        ]"#).unwrap();
+        let input = b"Abby,\"1 Main St, Oakland\",123-45-6789";
+        let result = apply_regex_ops(input, ops.iter()).unwrap();
+        assert_eq!(result.as_ref(), b"Abby;\"Main St; Oakland\";***-**-****");
+
+        let ops = csv_params(r#"[{"match": {"regex": "Oakland", "column": "first"}}]"#).unwrap();
+        let mut data = Data::Bytes(Cow::Borrowed(input));
+        assert_eq!(ops[0].is_match(&mut data, None).unwrap(), Some(false));
+
+        let err = csv_params(r#"[{"mask": {"regex": "x", "column": "phone"}}]"#).unwrap_err();
+        assert!(err.to_string().contains("unknown column `phone`"), "{err}");
+        assert!(csv_params(r#"[{"mask": {"regex": "x", "column": "ssn", "path": "$.ssn"}}]"#).is_err());
+
+        let mut params = SmartModuleExtraParams::default();
+        params.insert(PARAM_NAME.to_owned(), r#"[{"mask": {"regex": "x", "column_index": 0}}]"#.to_owned());
+        let err = get_params(params).unwrap_err();
+        assert!(err.to_string().contains("`format: csv`"), "{err}");
+    }
+
+    #[test]
+    fn replace_template_test() {
+        let ops = ops_from_json(r#"[
+            {"replace": {"regex": "(?P<first>\\w+) (?P<last>\\w+)", "with": "${first|upper} ${last|truncate(1)}."}},
+            {"replace_first": {"regex": "(\\d+)", "with": "<$1>"}}
+        ]"#);
+        assert_eq!(run(&ops[0], "abby hardy, bob smith"), "ABBY h., BOB s.");
+        assert_eq!(run(&ops[1], "12 34"), "<12> 34");
+
+        let mut params = SmartModuleExtraParams::default();
+        params.insert(PARAM_NAME.to_owned(), r#"[{"replace": {"regex": "(\\w+)", "with": "${name|upper}"}}]"#.to_owned());
+        let err = get_params(params).unwrap_err();
+        assert!(err.to_string().contains("group `name`"), "{err}");
+    }
+
+    #[test]
+    fn fancy_engine_test() {
+        let parse = |spec: &str| {
+            let mut params = SmartModuleExtraParams::default();
+            params.insert(PARAM_NAME.to_owned(), spec.to_owned());
+            get_params(params)
+        };
+        let mask = parse(r#"[{"mask": {"regex": "(?<=ssn:)\\d+", "engine": "fancy", "flags": "i"}}]"#);
+        if cfg!(feature = "fancy-regex") {
+            assert_eq!(run(&mask.unwrap()[0], "SSN:123 id:456"), "SSN:*** id:456");
+            let ops = ops_from_json(r#"[{"replace": {"regex": "(\\w)\\1", "with": "<$1$1>", "engine": "fancy"}}]"#);
+            assert_eq!(run(&ops[0], "abba"), "a<bb>a");
+            let ops = ops_from_json(r#"[{"replace": {"regex": "(\\w)\\1", "with": "$1", "engine": "fancy", "literal_replacement": true}}]"#);
+            assert_eq!(run(&ops[0], "abba"), "a$1a");
+        } else {
+            assert!(mask.is_err());
+        }
+
+        let err = parse(r#"[{"extract": {"regex": "(?P<a>x)", "engine": "fancy"}}]"#).unwrap_err();
+        assert!(err.to_string().contains("only supports"), "{err}");
+        assert!(parse(r#"[{"replace": {"regex": "(\\w)", "with": "${1|upper}", "engine": "fancy"}}]"#).is_err());
+        assert!(parse(r#"[{"mask": {"regex": "(?<=ssn:)\\d+"}}]"#).is_err());
+        if cfg!(feature = "fancy-regex") {
+            assert!(parse(r#"[{"replace": {"regex": "(\\w)\\1", "with": "$2", "engine": "fancy"}}]"#).is_err());
+        }
+    }
+
+    #[test]
+    fn prefilter_test() {
+        let spec = |json: &str| spec_with(json, &[]).unwrap();
+
+        let masked = spec(r#"[{"mask": {"pattern": "ssn"}}, {"replace": {"regex": "abby", "with": "A.", "flags": "i"}}]"#);
+        let prefilter = masked.prefilter.as_ref().unwrap();
+        assert!(prefilter.is_match(b"ABBY"));
+        assert!(!prefilter.is_match(b"no pii here"));
+
+        assert_eq!(map_str(&masked, "no pii here").unwrap(), "no pii here");
+        assert_eq!(map_str(&masked, "Abby 123-45-6789").unwrap(), "A. ***-**-****");
+
+        // path operations re-serialize JSON even when nothing matches
+        assert!(spec(r#"[{"mask": {"pattern": "ssn", "path": "$.ssn"}}]"#).prefilter.is_none());
+        assert!(spec_with(r#"[{"mask": {"pattern": "ssn"}}]"#, &[(STATS_FIELD_PARAM, "_stats")]).unwrap().prefilter.is_none());
+    }
+
+    #[test]
+    fn delete_field_test() {
+        let delete = |spec: &str| {
+            let ops = ops_from_json(spec);
+            let input = br#"{"first": "Abby", "ssn": "123-45-6789", "guardian": {"name": "Ann", "ssn_last4": "3333"}}"#;
+            let result = apply_regex_ops(input, ops.iter()).unwrap();
+            serde_json::from_slice::<Value>(&result).unwrap()
+        };
+
+        assert_eq!(
+            delete(r#"[{"delete_field": {"regex": "^ssn"}}]"#),
+            serde_json::json!({"first": "Abby", "guardian": {"name": "Ann"}})
+        );
+        assert_eq!(
+            delete(r#"[{"delete_field": {"path": "$.guardian.name"}}]"#),
+            serde_json::json!({"first": "Abby", "ssn": "123-45-6789", "guardian": {"ssn_last4": "3333"}})
+        );
+        assert_eq!(
+            delete(r#"[{"delete_field": {"regex": "^ssn", "path": "$.guardian"}}]"#),
+            serde_json::json!({"first": "Abby", "ssn": "123-45-6789", "guardian": {"name": "Ann"}})
+        );
+
+        assert!(spec_with(r#"[{"delete_field": {}}]"#, &[]).is_err());
+        assert!(spec_with(r#"[{"delete_field": {"path": "$"}}]"#, &[]).is_err());
+        // field names are not visible to a raw bytes prefilter
+        assert!(spec_with(r#"[{"delete_field": {"regex": "^ssn$"}}]"#, &[]).unwrap().prefilter.is_none());
+    }
+
+    #[test]
+    fn redact_test() {
+        let redact = |spec: &str| {
+            let ops = ops_from_json(spec);
+            let input = br#"{"id": "u-42", "age": 7, "name": "Abby", "tags": ["vip", "a/b"], "trace": {"trace_id": "ab12", "host": "db1"}}"#;
+            let result = apply_regex_ops(input, ops.iter()).unwrap();
+            serde_json::from_slice::<Value>(&result).unwrap()
+        };
+
+        assert_eq!(
+            redact(r#"[{"redact": {}}]"#),
+            serde_json::json!({"id": "*-**", "age": 7, "name": "****", "tags": ["***", "*/*"], "trace": {"trace_id": "****", "host": "***"}})
+        );
+        assert_eq!(
+            redact(r#"[{"redact": {"allow": ["/id", "$.trace"], "mask_char": "x"}}]"#),
+            serde_json::json!({"id": "u-42", "age": 7, "name": "xxxx", "tags": ["xxx", "x/x"], "trace": {"trace_id": "ab12", "host": "db1"}})
+        );
+        assert_eq!(
+            redact(r#"[{"redact": {"allow_regex": "_id$|^/tags/1$"}}]"#),
+            serde_json::json!({"id": "*-**", "age": 7, "name": "****", "tags": ["***", "a/b"], "trace": {"trace_id": "ab12", "host": "***"}})
+        );
+
+        let parse = |spec: &str, format: &str| spec_with(spec, &[(FORMAT_PARAM, format)]).map_err(|err| err.to_string());
+        let spec = parse(r#"[{"redact": {"allow": ["/id"]}}]"#, "json").unwrap();
+        assert_eq!(map_str(&spec, r#"{"id":"u-42","ssn":"123-45-6789"}"#).unwrap(), r#"{"id":"u-42","ssn":"***-**-****"}"#);
+        assert!(spec.prefilter.is_none());
+        assert!(parse(r#"[{"redact": {}}]"#, "csv").unwrap_err().contains("`redact` only applies to JSON records"));
+        assert!(parse(r#"[{"redact": {"path": "$.user"}}]"#, "json").unwrap_err().contains("select the values to keep with `allow`"));
+        assert!(parse(r#"[{"redact": {"allow_regex": "("}}]"#, "json").unwrap_err().contains("invalid `allow_regex`"));
+    }
+
+    #[test]
+    fn json_format_test() {
+        let json_ops = |spec: &str| {
+            let mut params = SmartModuleExtraParams::default();
+            params.insert(PARAM_NAME.to_owned(), spec.to_owned());
+            params.insert(FORMAT_PARAM.to_owned(), "json".to_owned());
+            get_params(params)
+        };
+        let ops = json_ops(r#"[
+            {"replace": {"regex": "Abby", "with": "\"A\" {redacted}"}},
+            {"mask": {"pattern": "ssn", "path": "/students/0/ssn"}}
+        ]"#).unwrap();
+        let input = br#"{"name": "Abby", "Abby": 1, "students": [{"ssn": "123-45-6789", "note": "Abby's"}]}"#;
+        let result = apply_regex_ops(input, ops.iter()).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Value>(&result).unwrap(),
+            serde_json::json!({
+                "name": "\"A\" {redacted}",
+                "Abby": 1,
+                "students": [{"ssn": "***-**-****", "note": "\"A\" {redacted}'s"}]
+            })
+        );
+        assert!(apply_regex_ops(b"Abby", ops.iter()).is_err());
+
+        let ops = json_ops(r#"[{"split": {"regex": ","}}]"#).unwrap();
+        assert!(ops[0].path().is_none());
+    }
+
+    #[test]
+    fn audit_test() {
+        let spec = Spec {
+            ops: ops_from_json(r#"[
+                {"mask": {"name": "ssn", "pattern": "ssn"}},
+                {"replace": {"regex": "id-\\d", "with": "id", "target": "key"}},
+                {"split": {"regex": ";"}},
+                {"replace_first": {"regex": "Abby", "with": "A."}}
+            ]"#),
+            filter_mode: FilterMode::default(),
+            on_error: OnError::default(),
+            stats_field: None,
+            prefilter: None,
+            audit: true,
+            size_limit: None,
+            time_budget: None,
+            window_records: None,
+            utf8: Utf8Mode::default(),
+            input_encoding: InputEncoding::default(),
+            mode: Mode::default(),
+            control: None,
+            drop_regex: None,
+            codec: None,
+            output_schema: None,
+            lines: None,
+            array_root: ArrayRoot::default(),
+            post_process: None,
+            checksum: None,
+            spans: None,
+            tenants: None,
+        };
+        let record = SmartModuleRecord::new(
+            Record::new_key_value("id-1", "Abby 123-45-6789;Abby Abby;234-56-7890"), 7, 0
+        );
+        let output = array_map_record(&record, &spec).unwrap();
+        assert_eq!(output.len(), 4);
+        assert_eq!(output[0].1.as_ref(), b"A. ***-**-****");
+        let (key, audit) = &output[3];
+        assert_eq!(key.as_ref().unwrap().as_ref(), b"id-1");
+        assert_eq!(
+            serde_json::from_slice::<Value>(audit.as_ref()).unwrap(),
+            serde_json::json!({"offset": 7, "operations": [
+                {"op": "mask", "name": "ssn", "matches": 2, "spans": [[5, 16], [27, 38]]},
+                {"op": "replace_first", "matches": 2}
+            ]})
+        );
+
+        assert_eq!(spec_with(r#"[{"mask": {"pattern": "ssn"}}]"#, &[(AUDIT_PARAM, "true")]).is_ok(), cfg!(feature = "array-map"));
+        assert!(spec_with(r#"[{"mask": {"pattern": "ssn"}}]"#, &[(AUDIT_PARAM, "yes")]).is_err());
+    }
+
+    #[test]
+    fn size_limit_test() {
+        let spec = |on_oversize: OnOversize| Spec {
+            ops: ops_from_json(r#"[{"mask": {"regex": "\\d"}}]"#),
+            filter_mode: FilterMode::default(),
+            on_error: OnError::default(),
+            stats_field: None,
+            prefilter: None,
+            audit: false,
+            size_limit: Some(SizeLimit { max_bytes: 9, on_oversize }),
+            time_budget: None,
+            window_records: None,
+            utf8: Utf8Mode::default(),
+            input_encoding: InputEncoding::default(),
+            mode: Mode::default(),
+            control: None,
+            drop_regex: None,
+            codec: None,
+            output_schema: None,
+            lines: None,
+            array_root: ArrayRoot::default(),
+            post_process: None,
+            checksum: None,
+            spans: None,
+            tenants: None,
+        };
+        let small = SmartModuleRecord::new(Record::new("id 1234"), 0, 0);
+        let large = SmartModuleRecord::new(Record::new("id 1234 é 5678"), 0, 0);
+
+        let (_, value) = map_record(&small, &spec(OnOversize::Fail)).unwrap();
+        assert_eq!(value.as_ref(), b"id ****");
+        let err = map_record(&large, &spec(OnOversize::Fail)).unwrap_err();
+        assert!(err.to_string().contains("exceeds `max_record_bytes` of 9"), "{err}");
+
+        // the cut falls inside `é`
+        let (_, value) = map_record(&large, &spec(OnOversize::Truncate)).unwrap();
+        assert_eq!(value.as_ref(), b"id **** ");
+        let truncated = spec(OnOversize::Truncate);
+        let record = SmartModuleRecord::new(Record::new("no digits here"), 0, 0);
+        assert_eq!(map_record(&record, &truncated).unwrap().1.as_ref(), b"no digits");
+
+        assert!(array_map_record(&large, &spec(OnOversize::Skip)).unwrap().is_empty());
+
+        let mut params = SmartModuleExtraParams::default();
+        params.insert(MAX_RECORD_BYTES_PARAM.to_owned(), "1k".to_owned());
+        assert!(SizeLimit::from_params(&params).is_err());
+        params.insert(ON_OVERSIZE_PARAM.to_owned(), "skip".to_owned());
+        params.insert(MAX_RECORD_BYTES_PARAM.to_owned(), "1024".to_owned());
+        assert_eq!(SizeLimit::from_params(&params).is_ok(), !cfg!(feature = "map"));
+    }
+
+    #[test]
+    fn time_budget_test() {
+        let spec = |max_processing_ms: &str, on_timeout: &str, extra: &[(&str, &str)]| {
+            let params = [&[(MAX_PROCESSING_MS_PARAM, max_processing_ms), (ON_TIMEOUT_PARAM, on_timeout)][..], extra].concat();
+            spec_with(r#"[{"mask": {"pattern": "ssn"}}]"#, &params)
+        };
+        let record = r#"{"ssn":"123-45-6789"}"#;
+
+        assert_eq!(map_str(&spec("60000", "fail", &[]).unwrap(), record).unwrap(), r#"{"ssn":"***-**-****"}"#);
+        // a zero budget is spent before the first operation runs
+        let err = map_str(&spec("0", "fail", &[]).unwrap(), record).unwrap_err();
+        assert!(format!("{err:#}").contains("exceeded `max_processing_ms` of 0ms"), "{err:#}");
+        assert_eq!(map_str(&spec("0", "passthrough", &[]).unwrap(), record).unwrap(), r#"{"ssn":"123-45-6789"}"#);
+        assert_eq!(map_str(&spec("0", "annotate", &[]).unwrap(), record).unwrap(), r#"{"_processing_timeout_ms":0,"ssn":"123-45-6789"}"#);
+        assert_eq!(map_str(&spec("0", "annotate", &[]).unwrap(), "ssn 123-45-6789").unwrap(), "ssn 123-45-6789");
+
+        // the whole record falls back, a timed out line is not handled as a line error
+        let lines = spec("0", "passthrough", &[(FORMAT_PARAM, "ndjson"), (ON_LINE_ERROR_PARAM, "skip")]).unwrap();
+        let ndjson = "{\"ssn\":\"123-45-6789\"}\n{\"id\":1}\n";
+        assert_eq!(map_str(&lines, ndjson).unwrap(), ndjson);
+        let record = SmartModuleRecord::new(Record::new(record), 0, 0);
+        assert_eq!(array_map_record(&record, &spec("0", "passthrough", &[]).unwrap()).unwrap().len(), 1);
+
+        assert_eq!(spec("0", "drop", &[]).is_ok(), !cfg!(feature = "map"));
+        assert!(spec("1s", "fail", &[]).unwrap_err().to_string().contains("invalid `max_processing_ms`"));
+    }
+
+    #[test]
+    fn array_root_test() {
+        let spec = |array_root: &str, format: &str| {
+            let ops = r#"[{"mask": {"pattern": "ssn", "when": {"path": "$.type", "equals": "student"}}}]"#;
+            spec_with(ops, &[(ARRAY_ROOT_PARAM, array_root), (FORMAT_PARAM, format)])
+        };
+        let input = r#"[{"type":"student","ssn":"123-45-6789"}, {"type": "teacher", "ssn": "987-65-4321"}]"#;
+        let record = SmartModuleRecord::new(Record::new(input), 0, 0);
+
+        // the guard cannot see `$.type` of the whole array
+        assert!(map_str(&spec("whole", "json").unwrap(), input).unwrap().contains("123-45-6789"));
+        // each element is guarded on its own
+        let each = Spec { array_root: ArrayRoot::Each, ..spec("whole", "json").unwrap() };
+        let (_, value) = map_record(&record, &each).unwrap();
+        assert_eq!(value.as_ref(), br#"[{"ssn":"***-**-****","type":"student"},{"ssn":"987-65-4321","type":"teacher"}]"#);
+        let object = SmartModuleRecord::new(Record::new(r#"{"type":"student","ssn":"123-45-6789"}"#), 0, 0);
+        assert_eq!(map_record(&object, &each).unwrap().1.as_ref(), br#"{"ssn":"***-**-****","type":"student"}"#);
+        let untouched = SmartModuleRecord::new(Record::new(r#"[{"type":"teacher"}, 1]"#), 0, 0);
+        assert_eq!(map_record(&untouched, &each).unwrap().1.as_ref(), untouched.value.as_ref());
+
+        let explode = Spec { array_root: ArrayRoot::Explode, ..spec("whole", "json").unwrap() };
+        let records = array_map_record(&record, &explode).unwrap();
+        let values: Vec<&[u8]> = records.iter().map(|(_, value)| value.as_ref()).collect();
+        assert_eq!(values, [&br#"{"ssn":"***-**-****","type":"student"}"#[..], br#"{"ssn":"987-65-4321","type":"teacher"}"#]);
+
+        assert_eq!(spec("each", "json").is_ok(), cfg!(any(feature = "map", feature = "filter-map")));
+        assert_eq!(spec("explode", "json").is_ok(), cfg!(feature = "array-map"));
+        let err = spec("explode", "xml").unwrap_err();
+        assert!(err.to_string().contains("applies to single JSON documents"), "{err}");
+    }
+
+    #[test]
+    fn merged_specs_test() {
+        let mut params = SmartModuleExtraParams::default();
+        params.insert(PARAM_NAME.to_owned(), r#"[{"mask": {"name": "ssn", "pattern": "ssn"}}]"#.to_owned());
+        params.insert("spec.1".to_owned(), r#"[{"replace": {"regex": "Abby", "with": "A."}}]"#.to_owned());
+        params.insert("spec.2".to_owned(), r#"[{"replace": {"regex": "A\\.", "with": "B."}}]"#.to_owned());
+        // not consecutive, ignored
+        params.insert("spec.4".to_owned(), r#"[{"replace": {"regex": "B", "with": "C"}}]"#.to_owned());
+        params.insert(OVERRIDES_PARAM.to_owned(), r#"{"ssn": {"mask_char": "x"}}"#.to_owned());
+        let ops = get_params(params.clone()).unwrap();
+        assert_eq!(ops.len(), 3);
+        let result = apply_regex_ops(b"Abby 123-45-6789", ops.iter()).unwrap();
+        assert_eq!(result.as_ref(), b"B. xxx-xx-xxxx");
+
+        params.insert("spec.2".to_owned(), r#"[{"replace": {"regex": "a"}}]"#.to_owned());
+        let err = get_params(params.clone()).unwrap_err();
+        assert!(err.to_string().starts_with("invalid `spec.2` operation #0 `replace`"), "{err}");
+        params.insert("spec.1".to_owned(), "{}".to_owned());
+        let err = get_params(params).unwrap_err();
+        assert!(err.to_string().starts_with("cannot parse `spec.1` param"), "{err}");
+    }
+
+    #[test]
+    fn unicode_options_test() {
+        let run = |spec: &str, input: &str| {
+            let ops = ops_from_json(spec);
+            String::from_utf8(apply_regex_ops_to_json_record(input.as_bytes(), None, &ops).unwrap().into_owned()).unwrap()
+        };
+
+        // full-width digits only match the builtin once normalized
+        let ssn = "ssn １２３-４５-６７８９";
+        assert_eq!(run(r#"[{"mask": {"pattern": "ssn"}}]"#, ssn), ssn);
+        assert_eq!(run(r#"[{"mask": {"pattern": "ssn", "normalize": "nfkc"}}]"#, ssn), "ssn ***-**-****");
+        assert_eq!(run(r#"[{"mask": {"regex": "\\d", "normalize": "nfkc"}}]"#, "ﬁ ２"), "fi *");
+        assert_eq!(run(r#"[{"replace": {"regex": "é", "with": "e", "normalize": "nfc"}}]"#, "cafe\u{301}"), "cafe");
+
+        // ASCII-only classes and case folding
+        assert_eq!(run(r#"[{"mask": {"regex": "\\d+", "unicode": false}}]"#, "1 ٢"), "* ٢");
+        assert_eq!(run(r#"[{"mask": {"regex": "k", "flags": "i"}}]"#, "K \u{212a}"), "* *");
+        assert_eq!(run(r#"[{"mask": {"regex": "k", "flags": "i", "unicode": false}}]"#, "K \u{212a}"), "* \u{212a}");
+        let ops = ops_from_json(r#"[{"match": {"regex": "\\d", "normalize": "nfkc"}}]"#);
+        let record = SmartModuleRecord::new(Record::new("５"), 0, 0);
+        assert!(filter_json_record(&record, "５".as_bytes(), &ops, &FilterMode::All).unwrap());
+        // the raw bytes prefilter would miss the normalized match
+        let spec = spec_with(r#"[{"mask": {"pattern": "ssn", "normalize": "nfkc"}}]"#, &[]).unwrap();
+        assert!(spec.prefilter.is_none());
+        assert_eq!(map_str(&spec, ssn).unwrap(), "ssn ***-**-****");
+    }
+
+    #[test]
+    fn split_output_test() {
+        let run = |spec: &str, input: &str| {
+            let ops = ops_from_json(spec);
+            String::from_utf8(apply_regex_ops_to_json_record(input.as_bytes(), None, &ops).unwrap().into_owned()).unwrap()
+        };
+
+        assert_eq!(run(r#"[{"split": {"regex": "\\s*;\\s*", "output": "json_array"}}]"#, "a; b;;c"), r#"["a","b","c"]"#);
+        assert_eq!(run(r#"[{"split": {"regex": ";", "output": "join", "separator": "|"}}]"#, "a;b"), "a|b");
+        assert_eq!(run(r#"[{"split": {"regex": ";", "output": "join"}}, {"mask": {"regex": "b"}}]"#, "a;b"), "a,*");
+        assert_eq!(
+            run(r#"[{"find_all": {"regex": "(?P<k>\\w)=(?P<v>\\d)", "output": "json_array"}}]"#, "a=1 b=2"),
+            r#"[{"k":"a","v":"1"},{"k":"b","v":"2"}]"#
+        );
+        assert_eq!(
+            run(r#"[{"split": {"regex": ";", "output": "json_array", "path": "$.tags"}}]"#, r#"{"tags": "x;y", "id": "1;2"}"#),
+            r#"{"id":"1;2","tags":["x","y"]}"#
+        );
+        // records without a match are reassembled too, so they skip the prefilter
+        let spec = spec_with(r#"[{"split": {"regex": ";", "output": "json_array"}}]"#, &[]).unwrap();
+        assert!(spec.prefilter.is_none());
+        assert_eq!(map_str(&spec, "a").unwrap(), r#"["a"]"#);
+        let spec = spec_with(r#"[{"find_all": {"regex": "\\d", "output": "json_array"}}]"#, &[]).unwrap();
+        assert_eq!(map_str(&spec, "none").unwrap(), "[]");
+        let spec = spec_with(r#"[{"find_all": {"regex": "\\d", "output": "join"}}]"#, &[]).unwrap();
+        assert_eq!(map_str(&spec, "none").unwrap(), "");
+
+        let mut params = SmartModuleExtraParams::default();
+        params.insert(PARAM_NAME.to_owned(), r#"[{"split": {"regex": ";", "separator": "|"}}]"#.to_owned());
+        assert!(get_params(params).is_err());
+    }
+
+    #[cfg(feature = "protobuf")]
+    #[test]
+    fn protobuf_format_test() {
+        use base64::Engine;
+
+        let descriptor = base64::engine::general_purpose::STANDARD.encode(proto_record::tests::student_descriptor());
+        let parse = |ops: &str, extra: &[(&str, &str)]| {
+            let params = [&[(FORMAT_PARAM, "protobuf"), (PROTO_MESSAGE_PARAM, "school.Student"), (PROTO_DESCRIPTOR_PARAM, descriptor.as_str())][..], extra].concat();
+            spec_with(ops, &params)
+        };
+        let spec = parse(r#"[
+            {"mask": {"pattern": "ssn", "path": "$.ssn"}},
+            {"mask": {"pattern": "email", "path": "$.contacts[*].email"}},
+            {"set_key": {"regex": "\\w+", "path": "$.name"}}
+        ]"#, &[]).unwrap();
+        let codec = spec.codec.as_ref().unwrap();
+
+        let value = codec.encode(br#"{"name":"Abby","ssn":"123-45-6789","id":42,"contacts":[{"email":"abby@school.edu"}]}"#).unwrap();
+        let (key, output) = map_record(&SmartModuleRecord::new(Record::new(value), 0, 0), &spec).unwrap();
+        assert_eq!(key.unwrap().as_ref(), b"Abby");
+        assert_eq!(
+            codec.decode(output.as_ref()).unwrap(),
+            br#"{"name":"Abby","ssn":"***-**-****","id":42,"contacts":[{"email":"****@******.***"}]}"#
+        );
+
+        assert!(map_record(&SmartModuleRecord::new(Record::new("\u{ff}"), 0, 0), &spec).is_err());
+
+        let err = parse(r#"[{"mask": {"pattern": "ssn"}}]"#, &[]).unwrap_err();
+        assert!(err.to_string().contains("set a `path`"), "{err}");
+        assert!(parse(r#"[{"delete_field": {"regex": "^ssn$"}}]"#, &[]).is_ok());
+        assert!(parse("[]", &[(UTF8_PARAM, "lossy")]).is_err());
+        let missing = spec_with("[]", &[(FORMAT_PARAM, "protobuf")]);
+        assert!(missing.unwrap_err().to_string().contains(PROTO_DESCRIPTOR_PARAM));
+    }
+
+    #[cfg(feature = "avro")]
+    #[test]
+    fn avro_format_test() {
+        let parse = |ops: &str, schema: &str| spec_with(ops, &[(FORMAT_PARAM, "avro"), (AVRO_SCHEMA_PARAM, schema)]);
+        let spec = parse(r#"[
+            {"mask": {"pattern": "ssn", "path": "$.ssn"}},
+            {"mask": {"pattern": "email", "path": "$.contacts[*].email"}}
+        ]"#, avro_record::tests::STUDENT_SCHEMA).unwrap();
+        let codec = spec.codec.as_ref().unwrap();
+        let map = |json: &[u8]| {
+            let value = codec.encode(json).unwrap();
+            let (_, output) = map_record(&SmartModuleRecord::new(Record::new(value.clone()), 0, 0), &spec).unwrap();
+            (value, output.as_ref().to_vec())
+        };
+
+        let (_, output) = map(br#"{"name":"Abby","ssn":"123-45-6789","id":42,"contacts":[{"email":"abby@school.edu"}]}"#);
+        assert_eq!(
+            codec.decode(&output).unwrap(),
+            br#"{"contacts":[{"email":"****@******.***"}],"id":42,"name":"Abby","ssn":"***-**-****"}"#
+        );
+        // records without matches are forwarded as received
+        let (input, output) = map(br#"{"name":"Cindy","ssn":null,"id":7,"contacts":[]}"#);
+        assert_eq!(output, input);
+
+        assert!(map_record(&SmartModuleRecord::new(Record::new("\u{ff}"), 0, 0), &spec).is_err());
+        let err = parse(r#"[{"mask": {"pattern": "ssn"}}]"#, avro_record::tests::STUDENT_SCHEMA).unwrap_err();
+        assert!(err.to_string().contains("set a `path`"), "{err}");
+        assert!(parse("[]", r#"{"type": "recrod"}"#).unwrap_err().to_string().contains(AVRO_SCHEMA_PARAM));
+        let missing = spec_with("[]", &[(FORMAT_PARAM, "avro")]);
+        assert!(missing.unwrap_err().to_string().contains(AVRO_SCHEMA_PARAM));
+        let latin1 = [(INPUT_ENCODING_PARAM, "latin1"), (FORMAT_PARAM, "avro"), (AVRO_SCHEMA_PARAM, r#"{"type": "string"}"#)];
+        assert!(spec_with("[]", &latin1).unwrap_err().to_string().contains("remove `input_encoding`"));
+    }
+
+    #[test]
+    fn xml_format_test() {
+        let ops = |spec: &str| {
+            let mut params = SmartModuleExtraParams::default();
+            params.insert(PARAM_NAME.to_owned(), spec.to_owned());
+            params.insert(FORMAT_PARAM.to_owned(), "xml".to_owned());
+            get_params(params)
+        };
+        let input = r#"<order id="A-1"><customer name="Abby"><ssn>123-45-6789</ssn></customer><note>ssn 123-45-6789</note></order>"#;
+
+        let spec = ops(r#"[
+            {"mask": {"pattern": "ssn", "path": "/order/customer/ssn"}},
+            {"replace": {"regex": "^A", "with": "X", "path": "//@name"}},
+            {"replace": {"regex": "order", "with": "ORDER"}}
+        ]"#).unwrap();
+        let output = apply_regex_ops_to_json_record(input.as_bytes(), None, &spec).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&output).unwrap(),
+            r#"<ORDER id="A-1"><customer name="Xbby"><ssn>***-**-****</ssn></customer><note>ssn 123-45-6789</note></ORDER>"#
+        );
+
+        let mut data = Data::Bytes(Cow::Borrowed(input.as_bytes()));
+        assert_eq!(spec[0].count_matches(&mut data, None).unwrap(), 1);
+        let filter = ops(r#"[{"match": {"regex": "^\\d", "path": "//ssn"}}]"#).unwrap();
+        assert!(filter_json_record(&SmartModuleRecord::new(Record::new(input), 0, 0), input.as_bytes(), &filter, &FilterMode::All).unwrap());
+
+        assert!(ops(r#"[{"mask": {"regex": "a", "path": "$.order"}}]"#).is_err());
+        assert!(ops(r#"[{"set_key": {"regex": "a", "path": "/order/@id"}}]"#).is_err());
+        assert!(apply_regex_ops_to_json_record(b"<order>", None, &spec).is_ok());
+        assert!(apply_regex_ops_to_json_record(b"<a></b>", None, &spec).is_err());
+    }
+
+    #[test]
+    fn control_record_test() {
+        let spec = spec_with(r#"[{"mask": {"name": "ssn", "pattern": "ssn"}}]"#, &[
+            (CONTROL_KEY_PARAM, "__regex_map_spec__"),
+            (OVERRIDES_PARAM, r##"{"ssn": {"mask_char": "#"}}"##),
+            (ON_ERROR_PARAM, "passthrough"),
+        ]).unwrap();
+        let control = |value: &str| SmartModuleRecord::new(Record::new_key_value("__regex_map_spec__", value), 0, 0);
+        let value = |spec: &Spec, text: &str| map_str(spec, text).unwrap();
+
+        let other = SmartModuleRecord::new(Record::new_key_value("user", r#"[{"mask": {"regex": "a"}}]"#), 0, 0);
+        assert!(control_spec(&spec, &other).unwrap().is_none());
+        assert_eq!(value(&spec, "ssn 123-45-6789 abby"), "ssn ###-##-#### abby");
+
+        // the new operations replace the old ones, with the init settings and overrides
+        let reloaded = control_spec(&spec, &control(r#"[{"mask": {"name": "ssn", "regex": "abby"}}]"#)).unwrap().unwrap();
+        assert_eq!(value(&reloaded, "ssn 123-45-6789 abby"), "ssn 123-45-6789 ####");
+        assert_eq!(reloaded.on_error, OnError::Passthrough);
+        assert!(control_spec(&reloaded, &control(r#"[{"mask": {"name": "ssn", "regex": "b"}}]"#)).unwrap().is_some());
+        // `overrides` must still name operations of the new spec
+        assert!(control_spec(&reloaded, &control("[]")).is_err());
+
+        let err = control_spec(&spec, &control(r#"[{"mask": {"name": "ssn", "regex": "("}}]"#)).unwrap_err();
+        assert!(err.to_string().contains("`__regex_map_spec__` operation #0 `mask: ssn`"), "{err}");
+        assert!(control_spec(&spec, &SmartModuleRecord::new(Record::new_key_value("__regex_map_spec__", vec![0xff]), 0, 0)).is_err());
+
+        // without `control_key` every record is data
+        assert!(control_spec(&spec_with("[]", &[]).unwrap(), &control("[]")).unwrap().is_none());
+        assert!(spec_with("[]", &[(CONTROL_KEY_PARAM, "")]).is_err());
+    }
+
+    #[test]
+    fn dictionary_record_test() {
+        let spec = spec_with(r#"[{"translate": {"dictionary": "allowlist"}}]"#, &[
+            ("allowlist", r#"{"Abby": "A."}"#),
+            (DICTIONARY_KEY_PARAM, "__regex_map_dictionary__"),
+            (CONTROL_KEY_PARAM, "__regex_map_spec__"),
+        ]).unwrap();
+        let dictionary = |value: &str| SmartModuleRecord::new(Record::new_key_value("__regex_map_dictionary__", value), 0, 0);
+        let control = |value: &str| SmartModuleRecord::new(Record::new_key_value("__regex_map_spec__", value), 0, 0);
+        let value = |spec: &Spec, text: &str| map_str(spec, text).unwrap();
+        assert_eq!(value(&spec, "Abby Hardy"), "A. Hardy");
+
+        // the dictionary replaces the init param one, and survives a spec reload
+        let reloaded = control_spec(&spec, &dictionary(r#"{"allowlist": {"Hardy": "H."}}"#)).unwrap().unwrap();
+        assert_eq!(value(&reloaded, "Abby Hardy"), "Abby H.");
+        let reloaded = control_spec(&reloaded, &control(r#"[{"translate": {"dictionary": "allowlist", "flags": "i"}}]"#)).unwrap().unwrap();
+        assert_eq!(value(&reloaded, "abby hardy"), "abby H.");
+        // and a spec reload survives the next dictionary
+        let reloaded = control_spec(&reloaded, &dictionary(r#"{"allowlist": {"Abby": "A."}}"#)).unwrap().unwrap();
+        assert_eq!(value(&reloaded, "abby hardy"), "A. hardy");
+
+        assert!(control_spec(&spec, &dictionary(r#"{"spec": "[]"}"#)).unwrap_err().to_string().contains("no `translate` operation reads"));
+        assert!(control_spec(&spec, &dictionary(r#"{"allowlist": ["Abby"]}"#)).is_err());
+        assert!(control_spec(&spec, &dictionary("[]")).unwrap_err().to_string().contains("cannot parse dictionary record"));
+
+        let err = spec_with("[]", &[(DICTIONARY_KEY_PARAM, "__regex_map__"), (CONTROL_KEY_PARAM, "__regex_map__")]).unwrap_err();
+        assert!(err.to_string().contains("must differ"));
+    }
+
+    #[test]
+    fn stages_test() {
+        let run = |spec: &str, input: &str| {
+            let ops = ops_from_json(spec);
+            String::from_utf8(apply_regex_ops_to_json_record(input.as_bytes(), None, &ops).unwrap().into_owned()).unwrap()
+        };
+        let spec = r#"[
+            {"stage": "normalize", "ops": [{"replace": {"regex": "\\s+", "with": " "}}]},
+            {"stage": "classify", "stop_after_first_match": true, "stop_on_no_match": true, "ops": [
+                {"replace": {"regex": "^ERROR", "with": "E"}},
+                {"replace": {"regex": "^(WARN|ERROR)", "with": "W"}}
+            ]},
+            {"mask": {"regex": "\\d+"}}
+        ]"#;
+        // only the first matching operation of the stage runs
+        assert_eq!(run(spec, "ERROR   code 42"), "E code **");
+        assert_eq!(run(spec, "WARN code 42"), "W code **");
+        // nothing in the stage matched, the pipeline stops
+        assert_eq!(run(spec, "INFO  code 42"), "INFO code 42");
+
+        // a disabled operation shrinks its stage, overrides reach staged operations
+        let mut params = SmartModuleExtraParams::default();
+        params.insert(PARAM_NAME.to_owned(), spec.replace(r#""with": "E""#, r#""with": "E", "name": "error""#));
+        params.insert(OVERRIDES_PARAM.to_owned(), r#"{"error": {"enabled": false}}"#.to_owned());
+        let ops = get_params(params).unwrap();
+        assert_eq!(ops[1].options().stage, Some(StageFlow { start: 1, end: 2, stop_on_no_match: true, stop_after_first_match: true }));
+        assert_eq!(ops[2].options().stage, None);
+
+        let mut params = SmartModuleExtraParams::default();
+        params.insert(PARAM_NAME.to_owned(), r#"[{"stage": "s", "ops": [], "stop": true}]"#.to_owned());
+        assert!(get_params(params).unwrap_err().to_string().contains("unknown field `stop`"));
+        let mut params = SmartModuleExtraParams::default();
+        params.insert(PARAM_NAME.to_owned(), r#"[{"stage": "s", "ops": [{"mask": {"regex": "("}}]}]"#.to_owned());
+        assert!(get_params(params).unwrap_err().to_string().contains("operation #0 `s/mask`"));
+    }
+
+    #[test]
+    fn detect_mode_test() {
+        let spec = Spec {
+            ops: ops_from_json(r#"[
+                {"mask": {"name": "ssn", "pattern": "ssn"}},
+                {"replace": {"regex": "Abby", "with": "A.", "target": "both"}},
+                {"delete_field": {"regex": "^dob$"}}
+            ]"#),
+            filter_mode: FilterMode::default(),
+            on_error: OnError::default(),
+            stats_field: None,
+            prefilter: None,
+            audit: false,
+            size_limit: None,
+            time_budget: None,
+            window_records: None,
+            utf8: Utf8Mode::default(),
+            input_encoding: InputEncoding::default(),
+            mode: Mode::Detect,
+            control: None,
+            drop_regex: None,
+            codec: None,
+            output_schema: None,
+            lines: None,
+            array_root: ArrayRoot::default(),
+            post_process: None,
+            checksum: None,
+            spans: None,
+            tenants: None,
+        };
+        let record = SmartModuleRecord::new(
+            Record::new_key_value("Abby", r#"{"name": "Abby", "ssn": "123-45-6789", "dob": "2000-01-01"}"#), 0, 0
+        );
+
+        let (key, value) = map_record(&record, &spec).unwrap();
+        assert_eq!(key.unwrap().as_ref(), b"Abby");
+        let output: Value = serde_json::from_slice(value.as_ref()).unwrap();
+        assert_eq!(output, serde_json::json!({
+            "value": r#"{"name": "Abby", "ssn": "123-45-6789", "dob": "2000-01-01"}"#,
+            "detections": [
+                {"op": "mask", "name": "ssn", "matches": 1, "spans": [[25, 36]]},
+                {"op": "replace", "matches": 1, "spans": [[10, 14]]},
+                {"op": "delete_field", "matches": 1},
+            ],
+        }));
+
+        assert_eq!(spec_with("[]", &[(MODE_PARAM, "detect")]).is_ok(), cfg!(feature = "map"));
+    }
+
+    #[test]
+    fn utf8_mode_test() {
+        let spec = |utf8: Utf8Mode| Spec {
+            ops: ops_from_json(r#"[{"mask": {"regex": "\\d+"}}]"#),
+            filter_mode: FilterMode::default(),
+            on_error: OnError::default(),
+            stats_field: None,
+            prefilter: None,
+            audit: false,
+            size_limit: None,
+            time_budget: None,
+            window_records: None,
+            utf8,
+            input_encoding: InputEncoding::default(),
+            mode: Mode::default(),
+            control: None,
+            drop_regex: None,
+            codec: None,
+            output_schema: None,
+            lines: None,
+            array_root: ArrayRoot::default(),
+            post_process: None,
+            checksum: None,
+            spans: None,
+            tenants: None,
+        };
+        let record = SmartModuleRecord::new(Record::new(&b"id \xff 1234 \xf0\x9f\x98"[..]), 0, 0);
+
+        let (_, value) = map_record(&record, &spec(Utf8Mode::Bytes)).unwrap();
+        assert_eq!(value.as_ref(), b"id \xff **** \xf0\x9f\x98");
+        let (_, value) = map_record(&record, &spec(Utf8Mode::Lossy)).unwrap();
+        assert_eq!(std::str::from_utf8(value.as_ref()).unwrap(), "id \u{fffd} **** \u{fffd}");
+        assert!(map_record(&record, &spec(Utf8Mode::Strict)).unwrap_err().to_string().contains("not valid UTF-8"));
+
+        // an unchanged lossy copy of the same length is not mistaken for the original
+        let record = SmartModuleRecord::new(Record::new(&b"\xf0\x9f\x98"[..]), 0, 0);
+        let (_, value) = map_record(&record, &spec(Utf8Mode::Lossy)).unwrap();
+        assert_eq!(value.as_ref(), "\u{fffd}".as_bytes());
+    }
+
+    #[test]
+    fn input_encoding_test() {
+        let spec = |encoding: &str, ops: &str| spec_with(ops, &[(INPUT_ENCODING_PARAM, encoding)]);
+        let map = |spec: &Spec, value: &[u8]| map_record(&SmartModuleRecord::new(Record::new(value), 0, 0), spec).unwrap().1.as_ref().to_vec();
+
+        // `é` is 0xe9 in both, matched as a character and written back in the record encoding
+        let latin1 = spec("latin1", r#"[{"replace": {"regex": "Renée \\d+", "with": "Renée ***"}}]"#).unwrap();
+        assert_eq!(map(&latin1, b"Ren\xe9e 42 \x80"), b"Ren\xe9e *** \x80");
+        let windows = spec("windows-1252", r#"[{"replace": {"regex": "€(\\d+)", "with": "EUR $1 … é 中"}}]"#).unwrap();
+        assert_eq!(map(&windows, b"\x8012 \x93ok\x94"), b"EUR 12 \x85 \xe9 ? \x93ok\x94");
+        // records the operations leave unchanged are forwarded as received
+        assert_eq!(map(&windows, b"\x93no price\x94"), b"\x93no price\x94");
+
+        assert!(spec("ebcdic", "[]").is_err());
+    }
+
+    #[test]
+    fn record_error_test() {
+        let spec = |raw: &str| spec_with(raw, &[]).unwrap();
+        let record = SmartModuleRecord::new(Record::new("not json"), 0, 0);
+
+        let err = map_str(&spec(r#"[
+            {"mask": {"pattern": "ssn"}},
+            {"replace": {"name": "first", "regex": "a", "with": "b", "path": "$.name"}}
+        ]"#), "not json").unwrap_err();
+        assert!(format!("{err:#}").starts_with("operation #1 `replace: first`: "), "{err:#}");
+
+        let filter = spec(r#"[{"match": {"regex": "a", "path": "$.name"}}]"#);
+        let err = filter_json_record(&record, b"not json", &filter.ops, &filter.filter_mode).unwrap_err();
+        assert_eq!(err.to_string(), "operation #0 `match`");
+
+        let mut params = SmartModuleExtraParams::default();
+        params.insert(LOG_LEVEL_PARAM.to_owned(), "verbose".to_owned());
+        assert!(get_keyword_param::<LogLevel>(&params, LOG_LEVEL_PARAM).unwrap_err().to_string().contains("unknown variant"));
+    }
+
+    #[test]
+    fn output_schema_test() {
+        let parse = |schema: &str| {
+            spec_with(r#"[{"replace": {"regex": "(\\d{3})-\\d{2}-(\\d{4})", "with": "$1-**-$2"}}]"#, &[(OUTPUT_SCHEMA_PARAM, schema)])
+        };
+        let schema = r#"{"type": "object", "required": ["ssn"], "properties": {"ssn": {"type": "string", "pattern": "^\\d{3}-\\*\\*-\\d{4}$"}}}"#;
+        let spec = parse(schema).unwrap();
+        let map = |value: &str| map_str(&spec, value);
+        assert_eq!(map(r#"{"ssn": "123-45-6789"}"#).unwrap(), r#"{"ssn": "123-**-6789"}"#);
+
+        // unchanged records are checked too
+        assert!(map(r#"{"ssn": "123-**-6789"}"#).is_ok());
+
+        let err = map(r#"{"ssn": "123 45 6789"}"#).unwrap_err();
+        assert_eq!(format!("{err:#}"), r"output record violates `output_schema`: `/ssn` does not match pattern `^\d{3}-\*\*-\d{4}$`");
+        assert!(map("123-45-6789").unwrap_err().to_string().contains("output record is not JSON"));
+
+        let err = parse(r#"{"properties": {"ssn": {"format": "ssn"}}}"#).unwrap_err();
+        assert!(err.to_string().contains("unsupported keyword `format` at `#/properties/ssn`"), "{err}");
+        assert!(parse("{").is_err());
+    }
+
+    #[test]
+    fn reinit_test() {
+        let params = |spec: &str, force: Option<&str>| {
+            let mut params = SmartModuleExtraParams::default();
+            params.insert(PARAM_NAME.to_owned(), spec.to_owned());
+            if let Some(force) = force {
+                params.insert(FORCE_REINIT_PARAM.to_owned(), force.to_owned());
+            }
+            params
+        };
+        let first = params(r#"[{"mask": {"pattern": "ssn"}}]"#, None);
+        let initialized = init_fingerprint(&first);
+
+        assert!(needs_init(None, &first).unwrap());
+        assert!(!needs_init(Some(&initialized), &first).unwrap());
+        assert!(!needs_init(Some(&initialized), &params(r#"[{"mask": {"pattern": "ssn"}}]"#, None)).unwrap());
+
+        let other = params(r#"[{"mask": {"pattern": "email"}}]"#, None);
+        assert!(needs_init(Some(&initialized), &other).unwrap_err().to_string().contains("set `force_reinit: true`"));
+        assert!(needs_init(Some(&initialized), &params(r#"[{"mask": {"pattern": "email"}}]"#, Some("true"))).unwrap());
+        assert!(needs_init(Some(&initialized), &params("[]", Some("yes"))).is_err());
+    }
+
+    #[test]
+    fn filter_map_test() {
+        let spec = Spec {
+            ops: ops_from_json(r#"[{"replace": {"regex": "^ssn \\d{3}-\\d{2}-\\d{4}$", "with": ""}}, {"mask": {"pattern": "email"}}]"#),
+            filter_mode: FilterMode::default(),
+            on_error: OnError::default(),
+            stats_field: None,
+            prefilter: None,
+            audit: false,
+            size_limit: Some(SizeLimit { max_bytes: 30, on_oversize: OnOversize::Skip }),
+            time_budget: None,
+            window_records: None,
+            utf8: Utf8Mode::default(),
+            input_encoding: InputEncoding::default(),
+            mode: Mode::default(),
+            control: None,
+            drop_regex: Some(Pattern::new(r"^[*@.]*$").unwrap()),
+            codec: None,
+            output_schema: None,
+            lines: None,
+            array_root: ArrayRoot::default(),
+            post_process: None,
+            checksum: None,
+            spans: None,
+            tenants: None,
+        };
+        let value = |text: &str| {
+            let record = SmartModuleRecord::new(Record::new_key_value("k", text), 0, 0);
+            filter_map_record(&record, &spec).unwrap()
+                .map(|(_, value)| String::from_utf8(value.as_ref().to_vec()).unwrap())
+        };
+
+        assert_eq!(value("ssn 123-45-6789, id 7").as_deref(), Some("ssn 123-45-6789, id 7"));
+        assert_eq!(value("ssn 123-45-6789"), None);
+        assert_eq!(value("abby@school.edu"), None);
+        assert_eq!(value("mail abby@school.edu").as_deref(), Some("mail ****@******.***"));
+        assert_eq!(value("a very long record over the size limit"), None);
+
+        assert!(spec_with("[]", &[(DROP_REGEX_PARAM, "(")]).is_err());
+    }
+
+    #[test]
+    fn aggregate_test() {
+        let spec = Spec {
+            ops: ops_from_json(r#"[{"mask": {"name": "ssn", "pattern": "ssn"}}, {"mask": {"pattern": "email"}}]"#),
+            filter_mode: FilterMode::default(),
+            on_error: OnError::default(),
+            stats_field: None,
+            prefilter: None,
+            audit: false,
+            size_limit: None,
+            time_budget: None,
+            window_records: Some(2),
+            utf8: Utf8Mode::default(),
+            input_encoding: InputEncoding::default(),
+            mode: Mode::default(),
+            control: None,
+            drop_regex: None,
+            codec: None,
+            output_schema: None,
+            lines: None,
+            array_root: ArrayRoot::default(),
+            post_process: None,
+            checksum: None,
+            spans: None,
+            tenants: None,
+        };
+        let aggregate = |accumulator: &[u8], value: &str| {
+            let record = SmartModuleRecord::new(Record::new(value), 0, 0);
+            aggregate_record(accumulator, &record, &spec).unwrap()
+        };
+
+        let totals = aggregate(b"", "ssn 123-45-6789, 234-56-7890");
+        assert_eq!(totals, MatchTotals {
+            records: 1,
+            matched_records: 1,
+            matches: BTreeMap::from([("ssn".to_string(), 2), ("#1".to_string(), 0)]),
+        });
+        let totals = aggregate(&serde_json::to_vec(&totals).unwrap(), "abby@mail.com");
+        assert_eq!((totals.records, totals.matched_records, totals.matches["#1"]), (2, 2, 1));
+
+        // the window is full, totals restart
+        let totals = aggregate(&serde_json::to_vec(&totals).unwrap(), "nothing here");
+        assert_eq!((totals.records, totals.matched_records, totals.matches["ssn"]), (1, 0, 0));
+
+        let record = SmartModuleRecord::new(Record::new("x"), 0, 0);
+        assert!(aggregate_record(b"not json", &record, &spec).is_err());
+
+        assert!(spec_with("[]", &[(WINDOW_RECORDS_PARAM, "0")]).is_err());
+    }
+
+    #[test]
+    fn add_field_test() {
+        let ops = ops_from_json(r#"[
+            {"insert": {"regex": "\\d{3}-\\d{2}-\\d{4}", "field": "pii_detected"}},
+            {"insert": {"regex": "^(\\w+)/", "field": "region", "value": "$1", "path": "$.office"}},
+            {"append": {"regex": "\\w+@\\w+\\.com", "field": "emails", "value": "$0"}},
+            {"mask": {"regex": "\\d{3}-\\d{2}-\\d{4}"}}
+        ]"#);
+        let run = |input: &str| {
+            let output = apply_regex_ops_to_json_record(input.as_bytes(), None, &ops).unwrap();
+            serde_json::from_slice::<Value>(&output).unwrap()
+        };
+
+        assert_eq!(
+            run(r#"{"office": "emea/paris", "note": "abby@a.com, ssn 123-45-6789, cc bob@b.com", "emails": "x@y.com"}"#),
+            serde_json::json!({
+                "office": "emea/paris",
+                "note": "abby@a.com, ssn ***-**-****, cc bob@b.com",
+                "pii_detected": true,
+                "region": "emea",
+                // the record is matched as re-serialized by the previous operations, keys sorted
+                "emails": ["x@y.com", "x@y.com", "abby@a.com", "bob@b.com"],
+            })
+        );
+        let input = r#"{"office":"paris"}"#;
+        assert_eq!(apply_regex_ops_to_json_record(input.as_bytes(), None, &ops).unwrap().as_ref(), input.as_bytes());
+        assert!(apply_regex_ops_to_json_record(b"[\"123-45-6789\"]", None, &ops).is_err());
+
+        let mut params = SmartModuleExtraParams::default();
+        params.insert(PARAM_NAME.to_owned(), r#"[{"insert": {"regex": "a", "field": "f", "target": "key"}}]"#.to_owned());
+        assert!(get_params(params).is_err());
+    }
+
+    #[test]
+    fn route_test() {
+        let spec = |all: bool, default: &str| format!(r#"[{{"route": {{"routes": [
+            {{"label": "payments", "regex": "\\bcard\\b"}},
+            {{"label": "auth", "regex": "(?i)login"}},
+            {{"label": "ssn", "pattern": "ssn"}}
+        ], "all": {all}{default}}}}}]"#);
+        let run = |spec: &str, input: &str| {
+            let ops = ops_from_json(spec);
+            String::from_utf8(apply_regex_ops_to_json_record(input.as_bytes(), None, &ops).unwrap().into_owned()).unwrap()
+        };
+
+        let first = spec(false, "");
+        assert_eq!(run(&first, r#"{"msg":"LOGIN with card 123-45-6789"}"#), r#"{"msg":"LOGIN with card 123-45-6789","route":"payments"}"#);
+        assert_eq!(run(&spec(true, ""), r#"{"msg":"LOGIN with card 123-45-6789"}"#),
+            r#"{"msg":"LOGIN with card 123-45-6789","route":["payments","auth","ssn"]}"#);
+        // records no route matches are forwarded as is, or get the `default` label
+        assert_eq!(run(&first, r#"{"msg": "hello"}"#), r#"{"msg": "hello"}"#);
+        assert_eq!(run(&spec(false, r#", "default": "other", "field": "_to""#), r#"{"msg":"hello"}"#), r#"{"_to":"other","msg":"hello"}"#);
+        assert_eq!(run(&spec(true, r#", "default": "other""#), r#"{"msg":"hello"}"#), r#"{"msg":"hello","route":["other"]}"#);
+
+        let ops = ops_from_json(r#"[{"route": {"routes": [{"label": "acme", "regex": "^acme-"}], "input": "key"}}]"#);
+        let output = apply_regex_ops_to_json_record(br#"{"id":1}"#, Some(b"acme-7"), &ops).unwrap();
+        assert_eq!(output.as_ref(), br#"{"id":1,"route":"acme"}"#);
+        let ops = ops_from_json(r#"[{"route": {"routes": [{"label": "eu", "regex": "^emea/"}], "path": "$.office"}}]"#);
+        let output = apply_regex_ops_to_json_record(br#"{"office":"emea/paris","note":"us/nyc"}"#, None, &ops).unwrap();
+        assert_eq!(output.as_ref(), br#"{"note":"us/nyc","office":"emea/paris","route":"eu"}"#);
+
+        let parse = |spec: &str| {
+            let mut params = SmartModuleExtraParams::default();
+            params.insert(PARAM_NAME.to_owned(), spec.to_owned());
+            get_params(params).map_err(|err| err.to_string())
+        };
+        assert!(parse(r#"[{"route": {"routes": []}}]"#).unwrap_err().contains("at least one entry in `routes`"));
+        assert!(parse(r#"[{"route": {"routes": [{"label": "x", "regex": "("}]}}]"#).unwrap_err().contains("invalid regex of route `x`"));
+        assert!(parse(r#"[{"route": {"routes": [{"label": "x", "regex": "a"}], "target": "key"}}]"#).is_err());
+    }
+
+    #[test]
+    fn trim_field_test() {
+        let ops = ops_from_json(r#"[
+            {"trim_field": {"path": "$.note", "max_chars": 8, "marker": "..."}},
+            {"trim_field": {"regex": "tag=(?P<tag>\\w+)", "group": "tag", "max_chars": 3}}
+        ]"#);
+        assert_eq!(run(&ops[0], "héllo wörld"), "héllo...");
+        assert_eq!(run(&ops[1], "tag=abcdef tag=ab"), "tag=abc tag=ab");
+        assert_eq!(ops[1].count_up_to(b"tag=abcdef tag=ab", usize::MAX), 1);
+        let output = apply_regex_ops_to_json_record(br#"{"note":"short","id":"abcdefghijkl"}"#, None, &ops).unwrap();
+        assert_eq!(output.as_ref(), br#"{"id":"abcdefghijkl","note":"short"}"#);
+        let output = apply_regex_ops_to_json_record(br#"{"note":"the quick brown fox"}"#, None, &ops).unwrap();
+        assert_eq!(output.as_ref(), br#"{"note":"the q..."}"#);
+
+        let parse = |spec: &str| {
+            let mut params = SmartModuleExtraParams::default();
+            params.insert(PARAM_NAME.to_owned(), spec.to_owned());
+            get_params(params).map_err(|err| err.to_string())
+        };
+        assert!(parse(r#"[{"trim_field": {"max_chars": 0}}]"#).unwrap_err().contains("`max_chars` must be at least 1"));
+        assert!(parse(r#"[{"trim_field": {"max_chars": 3, "marker": "..."}}]"#).unwrap_err().contains("`marker` must be shorter"));
+        assert!(parse(r#"[{"trim_field": {"regex": "(a)", "group": 2, "max_chars": 3}}]"#).is_err());
+    }
+
+    #[test]
+    fn record_variables_test() {
+        let spec = spec_with(r#"[{"replace": {"regex": "^id", "with": "${__offset}@${__timestamp|truncate(3)}"}}]"#, &[]).unwrap();
+        let record = SmartModuleRecord::new(Record::new("id 7"), 42, 1_700_000_000_000);
+        let (_, value) = map_record(&record, &spec).unwrap();
+        assert_eq!(value.as_ref(), b"42@170 7");
+
+        let parse = |with: &str| {
+            let mut params = SmartModuleExtraParams::default();
+            params.insert(PARAM_NAME.to_owned(), format!(r#"[{{"replace": {{"regex": "a", "with": "{with}"}}}}]"#));
+            get_params(params).map_err(|err| err.to_string())
+        };
+        assert!(parse("${__partition}").unwrap_err().contains("do not carry their partition"));
+        assert!(parse("${__key}").unwrap_err().contains("unknown record variable"));
+    }
+
+    #[test]
+    fn allow_empty_spec_test() {
+        let record = SmartModuleRecord::new(Record::new(r#"{"ssn":"123-45-6789"}"#), 0, 0);
+        let mut params = SmartModuleExtraParams::default();
+        assert!(get_spec(params.clone()).unwrap_err().to_string().contains("spec"));
+        params.insert(ALLOW_EMPTY_SPEC_PARAM.to_owned(), "true".to_owned());
+        let spec = get_spec(params.clone()).unwrap();
+        let (_, value) = map_record(&record, &spec).unwrap();
+        assert_eq!(value.as_ref(), br#"{"ssn":"123-45-6789"}"#);
+
+        // a `spec` param still takes over when set
+        params.insert(PARAM_NAME.to_owned(), r#"[{"mask": {"pattern": "ssn"}}]"#.to_owned());
+        let (_, value) = map_record(&record, &get_spec(params).unwrap()).unwrap();
+        assert_eq!(value.as_ref(), br#"{"ssn":"***-**-****"}"#);
+    }
+
+    #[test]
+    fn spec_ref_test() {
+        let record = SmartModuleRecord::new(Record::new("ssn 123-45-6789"), 0, 0);
+        let masked = |params: SmartModuleExtraParams| get_spec(params).map(|spec| map_record(&record, &spec).unwrap().1.as_ref().to_vec());
+
+        let mut params = SmartModuleExtraParams::default();
+        params.insert(SPEC_REF_PARAM.to_owned(), "param:redaction_spec".to_owned());
+        params.insert("redaction_spec".to_owned(), r#"[{"mask": {"pattern": "ssn"}}]"#.to_owned());
+        assert_eq!(masked(params.clone()).unwrap(), b"ssn ***-**-****");
+        // `spec.N` lists still apply after the referenced spec
+        let mut layered = params.clone();
+        layered.insert("spec.1".to_owned(), r#"[{"replace": {"regex": "^ssn", "with": "SSN"}}]"#.to_owned());
+        assert_eq!(masked(layered).unwrap(), b"SSN ***-**-****");
+
+        // `spec_encoding` applies to the referenced spec
+        params.insert(SPEC_ENCODING_PARAM.to_owned(), "base64".to_owned());
+        params.insert("redaction_spec".to_owned(), "W3sibWFzayI6IHsicGF0dGVybiI6ICJzc24ifX1d".to_owned());
+        assert_eq!(masked(params.clone()).unwrap(), b"ssn ***-**-****");
+        params.insert(PARAM_NAME.to_owned(), "[]".to_owned());
+        assert!(masked(params).unwrap_err().to_string().contains("replaces the `spec` param"));
+
+        let path = std::env::temp_dir().join(format!("regex-map-spec-ref-{}.json", std::process::id()));
+        std::fs::write(&path, r#"[{"mask": {"regex": "\\d"}}]"#).unwrap();
+        let mut params = SmartModuleExtraParams::default();
+        params.insert(SPEC_REF_PARAM.to_owned(), format!("file:{}", path.display()));
+        assert_eq!(masked(params.clone()).unwrap(), b"ssn ***-**-****");
+        std::fs::remove_file(&path).unwrap();
+        assert!(masked(params).unwrap_err().to_string().contains("cannot read `spec_ref` file"));
+
+        let error = |reference: &str| {
+            let mut params = SmartModuleExtraParams::default();
+            params.insert(SPEC_REF_PARAM.to_owned(), reference.to_owned());
+            get_spec(params).unwrap_err().to_string()
+        };
+        assert!(error("param:missing").contains("missing"));
+        assert!(error("https://example.com/spec.json").contains("no network access"));
+        assert!(error("spec.json").contains("expected `param:NAME` or `file:PATH`"));
+    }
+
+    #[test]
+    fn embedded_spec_test() {
+        let mut params = SmartModuleExtraParams::default();
+        let embedded = r#"[{"mask": {"pattern": "ssn"}}]"#;
+        let sources = default_sources(&params, Some(embedded)).unwrap();
+        assert_eq!(sources, [("spec (embedded)".to_string(), Cow::Borrowed(embedded))]);
+        assert!(default_sources(&params, None).is_err());
+        params.insert(ALLOW_EMPTY_SPEC_PARAM.to_owned(), "true".to_owned());
+        assert!(default_sources(&params, None).unwrap().is_empty());
+        // the embedded spec still wins over `allow_empty_spec`, and a `spec` param over both
+        assert_eq!(default_sources(&params, Some(embedded)).unwrap().len(), 1);
+        params.insert(PARAM_NAME.to_owned(), r#"[{"mask": {"regex": "\\d"}}]"#.to_owned());
+        assert_eq!(spec_sources(&params).unwrap()[0].0, PARAM_NAME);
+    }
+
+    #[test]
+    fn input_test() {
+        let spec = spec_with(r#"[
+            {"insert": {"regex": "^tenant-(\\w+)", "field": "tenant", "value": "$1", "input": "key"}},
+            {"mask": {"regex": "acme"}}
+        ]"#, &[]).unwrap();
+        let map = |key: Option<&str>, value: &str| {
+            let record = match key {
+                Some(key) => Record::new_key_value(key.to_owned(), value.to_owned()),
+                None => Record::new(value.to_owned()),
+            };
+            let (key, value) = map_record(&SmartModuleRecord::new(record, 0, 0), &spec).unwrap();
+            (key.map(|key| key.as_ref().to_vec()), serde_json::from_slice::<Value>(value.as_ref()).unwrap())
+        };
+
+        // the key is read, not rewritten, and the inserted field goes through the next operations
+        let (key, value) = map(Some("tenant-acme"), r#"{"id": 7}"#);
+        assert_eq!(key.unwrap(), b"tenant-acme");
+        assert_eq!(value, serde_json::json!({"id": 7, "tenant": "****"}));
+        assert_eq!(map(None, r#"{"id": 7}"#).1, serde_json::json!({"id": 7}));
+        assert_eq!(map(Some("other"), r#"{"id": 7}"#).1, serde_json::json!({"id": 7}));
+
+        let error = |spec: &str| spec_with(spec, &[]).unwrap_err().to_string();
+        assert!(error(r#"[{"insert": {"regex": "a", "field": "f", "input": "header(tenant)"}}]"#).contains("`input: header(tenant)` is not supported"));
+        assert!(error(r#"[{"mask": {"regex": "a", "input": "key"}}]"#).contains("use `target: key`"));
+        assert!(error(r#"[{"insert": {"regex": "a", "field": "f", "input": "key", "path": "$.a"}}]"#).contains("not supported with `input: key`"));
+        assert!(error(r#"[{"insert": {"regex": "a", "field": "f", "input": "headers"}}]"#).contains("unknown input `headers`"));
+    }
+
+    #[test]
+    fn ndjson_test() {
+        let parse = |on_line_error: Option<&str>| {
+            let params: Vec<_> = [(FORMAT_PARAM, "ndjson")].into_iter()
+                .chain(on_line_error.map(|on_line_error| (ON_LINE_ERROR_PARAM, on_line_error)))
+                .collect();
+            spec_with(r#"[{"mask": {"regex": "\\d{3}-\\d{2}-\\d{4}", "path": "$.ssn"}}]"#, &params)
+        };
+        let input = "{\"ssn\":\"123-45-6789\"}\r\n\n{\"id\":1}\nnot json\n{\"ssn\":\"987-65-4321\"}";
+
+        let skip = parse(Some("skip")).unwrap();
+        assert_eq!(map_str(&skip, input).unwrap(), "{\"ssn\":\"***-**-****\"}\r\n\n{\"id\":1}\n{\"ssn\":\"***-**-****\"}");
+        let passthrough = parse(Some("passthrough")).unwrap();
+        assert_eq!(map_str(&passthrough, input).unwrap(), "{\"ssn\":\"***-**-****\"}\r\n\n{\"id\":1}\nnot json\n{\"ssn\":\"***-**-****\"}");
+        assert_eq!(map_str(&passthrough, "{\"id\":1}\n").unwrap(), "{\"id\":1}\n");
+
+        let fail = parse(None).unwrap();
+        assert!(format!("{:#}", map_str(&fail, input).unwrap_err()).starts_with("line 4"));
+        assert!(map_str(&fail, "{\"ssn\":\"123-45-6789\"}\n{\"id\":1}").is_ok());
+
+        let err = spec_with(r#"[{"mask": {"regex": "a"}}]"#, &[(ON_LINE_ERROR_PARAM, "skip")]).unwrap_err();
+        assert!(err.to_string().contains("needs the `format: ndjson` param"));
+        assert!(parse(Some("drop")).is_err());
+    }
+
+    #[test]
+    fn describe_test() {
+        let spec = spec_with(r#"[
+            {"definitions": {"digits": "\\d+"}},
+            {"mask": {"regex": "\\d{3}-\\d{2}-\\d{4}", "name": "ssn", "path": "$.ssn"}},
+            {"replace": {"regex": "id-{{digits}}", "with": "id", "flags": "i", "target": "both"}},
+            {"insert": {"regex": "^t-(\\w+)", "field": "tenant", "value": "$1", "input": "key", "unicode": false}},
+            {"mask": {"regex": "x", "enabled": false}}
+        ]"#, &[]).unwrap();
+        assert_eq!(spec.describe(), [
+            r"operation #1 `mask: ssn` regex=`\d{3}-\d{2}-\d{4}` path=$.ssn",
+            r"operation #2 `replace` regex=`id-(?:\d+)` flags=(?i) target=both",
+            r"operation #3 `insert` regex=`^t-(\w+)` flags=(?-u) input=key",
+        ]);
+    }
+
+    #[test]
+    fn examples_test() {
+        let parse = |spec: &str| spec_with(spec, &[]);
+        assert!(parse(r##"[
+            {"mask": {"pattern": "ssn", "examples": [{"input": "ssn 123-45-6789", "expected": "ssn ***-**-****"}]}},
+            {"replace": {"regex": "\\d+", "with": "#", "path": "$.id", "examples": [{"input": "{\"id\": \"42\", \"n\": 1}", "expected": "{\"n\": 1, \"id\": \"#\"}"}]}},
+            {"replace": {"regex": "^\\w+", "with": "user", "target": "key", "examples": [{"input": "abby-1", "expected": "user-1"}]}}
+        ]"##).is_ok());
+
+        let err = parse(r#"[{"replace": {"regex": "^(\\w+)@", "with": "$1 at ", "name": "email", "examples": [
+            {"input": "abby@school.edu", "expected": "abby at school.edu"},
+            {"input": "abby.hardy@school.edu", "expected": "abby.hardy at school.edu"}
+        ]}}]"#).unwrap_err().to_string();
+        assert!(err.contains("`replace: email`"), "{err}");
+        assert!(err.contains("example #2 failed\n  input:    abby.hardy@school.edu\n  expected: abby.hardy at school.edu\n  actual:   abby.hardy@school.edu"), "{err}");
+
+        let err = parse(r#"[{"mask": {"regex": "a", "path": "$.a", "examples": [{"input": "not json", "expected": ""}]}}]"#).unwrap_err();
+        assert!(err.to_string().contains("example #1 failed: "), "{err}");
+        assert!(parse(r#"[{"match": {"regex": "a", "examples": [{"input": "a", "expected": "a"}]}}]"#).is_err());
+        assert!(parse(r#"[{"mask": {"regex": "a", "examples": [{"input": "a", "output": "*"}]}}]"#).is_err());
+    }
+
+    #[test]
+    fn post_process_param_test() {
+        let parse = |post_process: &str, format: &str| {
+            spec_with(r#"[{"mask": {"pattern": "ssn"}}]"#, &[(POST_PROCESS_PARAM, post_process), (FORMAT_PARAM, format)])
+        };
+        let spec = parse("minify_json", "text").unwrap();
+        let map = |value: &str| map_str(&spec, value).unwrap();
+        assert_eq!(map("{\n  \"ssn\": \"123-45-6789\",\n  \"id\": 7\n}"), r#"{"ssn":"***-**-****","id":7}"#);
+        // records no operation matched are reformatted too, and text records are left as is
+        assert_eq!(map("{\"id\": 7}"), r#"{"id":7}"#);
+        assert_eq!(map("ssn 123-45-6789"), "ssn ***-**-****");
+
+        assert!(parse("pretty_json", "ndjson").unwrap_err().to_string().contains("not supported with `format: ndjson`"));
+        assert!(parse("gzip", "text").is_err());
+    }
+
+    #[test]
+    fn tenants_test() {
+        let parse = |spec: &str, selector: &str| spec_with(spec, &[(TENANT_SELECTOR_PARAM, selector)]);
+        let spec = parse(r#"{
+            "acme": [{"mask": {"pattern": "ssn"}}],
+            "globex": [{"hash": {"regex": "\\d{3}-\\d{2}-\\d{4}", "algorithm": "blake3"}}, {"mask": {"pattern": "email"}}]
+        }"#, "/tenant").unwrap();
+        assert_eq!(spec.operation_count(), 3);
+        assert!(spec.describe()[0].starts_with("tenant `acme` operation #1 `mask` regex="));
+        assert_eq!(map_str(&spec, r#"{"tenant":"acme","ssn":"123-45-6789","email":"abby@acme.com"}"#).unwrap(),
+            r#"{"tenant":"acme","ssn":"***-**-****","email":"abby@acme.com"}"#);
+        let globex = map_str(&spec, r#"{"tenant":"globex","ssn":"123-45-6789","email":"abby@acme.com"}"#).unwrap();
+        assert!(!globex.contains("123-45-6789") && globex.contains("****@****.***"), "{globex}");
+        assert!(map_str(&spec, r#"{"tenant":"initech","ssn":"123-45-6789"}"#).unwrap_err().to_string().contains("no operations for tenant `initech`"));
+        assert!(map_str(&spec, "ssn 123-45-6789").unwrap_err().to_string().contains("found no tenant"));
+
+        // the `*` tenant takes records of other tenants, and a regex selector reads its first group
+        let spec = parse(r#"{"acme": [{"mask": {"regex": "\\d"}}], "*": []}"#, r"^(\w+):").unwrap();
+        assert_eq!(map_str(&spec, "acme: 123").unwrap(), "acme: ***");
+        assert_eq!(map_str(&spec, "globex: 123").unwrap(), "globex: 123");
+        assert_eq!(map_str(&spec, "123").unwrap(), "123");
+
+        let err = parse(r#"{"acme": [{"mask": {"regex": "("}}]}"#, "/tenant").unwrap_err().to_string();
+        assert!(err.contains("invalid `spec.acme` operation #0 `mask`"), "{err}");
+        assert!(parse(r#"[{"mask": {"pattern": "ssn"}}]"#, "/tenant").unwrap_err().to_string().contains("expected tenant ids mapped to lists"));
+        assert!(parse(r#"{"acme": []}"#, "(").is_err());
+        assert!(spec_with(r#"{"acme": []}"#, &[(TENANT_SELECTOR_PARAM, "/tenant"), (CONTROL_KEY_PARAM, "spec")]).is_err());
+    }
+
+    #[test]
+    fn spans_test() {
+        let parse = |spec: &str, extra: &[(&str, &str)]| spec_with(spec, &[&[(SPANS_FIELD_PARAM, "_spans")][..], extra].concat());
+        let ops = r#"[{"replace": {"regex": "\\d{3}-\\d{2}-\\d{4}", "with": "<ssn>"}}, {"mask": {"regex": "Abby"}}, {"mask": {"regex": "Hardy"}}]"#;
+        let record = r#"{"name":"Abby","ssn":"123-45-6789"}"#;
+        let spec = parse(ops, &[]).unwrap();
+        assert_eq!(map_str(&spec, record).unwrap(), r#"{"_spans":[[0,22,33],[1,9,13]],"name":"****","ssn":"<ssn>"}"#);
+        // records no operation matched get an empty list
+        assert_eq!(map_str(&spec, r#"{"id":7}"#).unwrap(), r#"{"_spans":[],"id":7}"#);
+        assert!(map_str(&spec, "ssn 123-45-6789").unwrap_err().to_string().contains("requires JSON object records"));
+
+        let spec = parse(ops, &[(SPANS_COORDINATES_PARAM, "output")]).unwrap();
+        assert_eq!(map_str(&spec, record).unwrap(), r#"{"_spans":[[0,22,27],[1,9,13]],"name":"****","ssn":"<ssn>"}"#);
+        // matches that rewrite differently on their own are reported as the whole changed range
+        let spec = parse(r#"[{"replace": {"regex": "\\Ba", "with": "AA"}}]"#, &[(SPANS_COORDINATES_PARAM, "output")]).unwrap();
+        assert_eq!(map_str(&spec, r#"{"id":"banana"}"#).unwrap(), r#"{"_spans":[[0,8,16]],"id":"bAAnAAnAA"}"#);
+        let spec = parse(r#"[{"normalize": {"presets": ["collapse_whitespace"]}}]"#, &[]).unwrap();
+        assert_eq!(map_str(&spec, r#"{"name":"Abby   Hardy"}"#).unwrap(), r#"{"_spans":[[0,14,16]],"name":"Abby Hardy"}"#);
+
+        let err = parse(r#"[{"mask": {"regex": "\\d", "path": "$.ssn"}}]"#, &[]).unwrap_err().to_string();
+        assert!(err.contains("operation #0 `mask` reads part of it"), "{err}");
+        assert!(parse(ops, &[(FORMAT_PARAM, "json")]).is_err());
+        assert!(parse(ops, &[(STATS_FIELD_PARAM, "_stats")]).is_err());
+        assert!(parse(ops, &[(SPANS_COORDINATES_PARAM, "after")]).is_err());
+    }
+
+    #[test]
+    fn checksum_test() {
+        let parse = |extra: &[(&str, &str)]| spec_with(r#"[{"mask": {"pattern": "ssn"}}]"#, extra);
+        let record = r#"{"id":7,"ssn":"123-45-6789"}"#;
+        let spec = parse(&[(CHECKSUM_FIELD_PARAM, "_checksum")]).unwrap();
+        assert_eq!(map_str(&spec, record).unwrap(),
+            r#"{"_checksum":"99297c6b6980e5280e7d7643fee19eef6a39427e479a74301c5760ac1f99d4dc","id":7,"ssn":"***-**-****"}"#);
+        // records the prefilter forwards get the checksum too
+        assert_eq!(map_str(&spec, r#"{"id":7}"#).unwrap().len(), r#"{"_checksum":"","id":7}"#.len() + 64);
+        assert!(map_str(&spec, "ssn 123-45-6789").unwrap_err().to_string().contains("requires JSON object records"));
+
+        let spec = parse(&[(CHECKSUM_FIELD_PARAM, "_checksum"), (CHECKSUM_ALGORITHM_PARAM, "crc32")]).unwrap();
+        assert_eq!(map_str(&spec, record).unwrap(), r#"{"_checksum":"e83859e7","id":7,"ssn":"***-**-****"}"#);
+
+        assert!(parse(&[(CHECKSUM_ALGORITHM_PARAM, "crc32")]).is_err());
+        assert!(parse(&[(CHECKSUM_FIELD_PARAM, "_checksum"), (CHECKSUM_ALGORITHM_PARAM, "md5")]).is_err());
+        assert!(parse(&[(CHECKSUM_FIELD_PARAM, "_checksum"), (FORMAT_PARAM, "ndjson")]).is_err());
+    }
+
+    #[test]
+    fn scope_test() {
+        let op = ops_from_json(r#"[{"mask": {"regex": "\\d+", "scope": "\"comments\":\\s*\"([^\"]*)\""}}]"#).remove(0);
+        let record = r#"{"id": "123-45", "comments": "call 555-1234 or 555-9876", "phone": "555-0000"}"#;
+        assert_eq!(run(&op, record), r#"{"id": "123-45", "comments": "call ***-**** or ***-****", "phone": "555-0000"}"#);
+        assert_eq!(op.count_up_to(record.as_bytes(), usize::MAX), 4);
+        assert_eq!(op.count_up_to(record.as_bytes(), 3), 3);
+        assert_eq!(op.spans(br#"{"comments": "a 12"}"#), vec![[16, 18]]);
+        assert!(matches!(op.run_regex(br#"{"id": "123"}"#), Cow::Borrowed(_)));
+
+        // without groups the whole scope match is the region, and `replace_first` applies once per region
+        let op = ops_from_json(r##"[{"replace_first": {"regex": "\\d", "with": "#", "scope": "\\[[^\\]]*\\]"}}]"##).remove(0);
+        assert_eq!(run(&op, "12 [34] [56]"), "12 [#4] [#6]");
+
+        let mut params = SmartModuleExtraParams::default();
+        params.insert(PARAM_NAME.to_owned(), r#"[{"match": {"regex": "\\d", "scope": "\\[.*\\]"}}]"#.to_owned());
+        assert!(get_params(params).unwrap_err().to_string().contains("`scope` is only supported"));
+        let mut params = SmartModuleExtraParams::default();
+        params.insert(PARAM_NAME.to_owned(), r#"[{"mask": {"regex": "\\d", "scope": "("}}]"#.to_owned());
+        assert!(get_params(params).unwrap_err().to_string().contains("invalid `scope`"));
+    }
+
+    #[test]
+    fn url_test() {
+        let parse = |spec: &str| {
+            let mut params = SmartModuleExtraParams::default();
+            params.insert(PARAM_NAME.to_owned(), spec.to_owned());
+            get_params(params).map_err(|err| err.to_string())
+        };
+        let ops = parse(r#"[{"url": {"strip": ["token", "utm_source"], "redact_userinfo": true, "strip_fragment": true}}]"#).unwrap();
+        assert_eq!(run(&ops[0], "see https://abby:pw@example.com/a?token=x&page=2#top."),
+            "see https://REDACTED@example.com/a?page=2.");
+        assert_eq!(run(&ops[0], "(http://example.com/?utm_source=mail)"), "(http://example.com/)");
+        assert_eq!(ops[0].spans(b"http://a.com/ http://b.com/#x"), vec![[14, 29]]);
+        assert!(matches!(ops[0].run_regex(b"http://example.com/?page=2"), Cow::Borrowed(_)));
+
+        let ops = parse(r#"[{"url": {"strip": ["*"], "path": "$.link"}}]"#).unwrap();
+        let output = apply_regex_ops_to_json_record(br#"{"link":"https://example.com/p?a=1&b=2#s"}"#, None, &ops).unwrap();
+        assert_eq!(output.as_ref(), br#"{"link":"https://example.com/p#s"}"#);
+
+        assert!(parse(r#"[{"url": {}}]"#).unwrap_err().contains("needs `strip`"));
+    }
+
+    #[test]
+    fn placeholder_test() {
+        let map = |spec: &Spec, value: &str| map_str(spec, value).unwrap();
+        let record_scope = spec_with(r#"[
+            {"placeholder": {"pattern": "ssn", "label": "SSN"}},
+            {"placeholder": {"pattern": "email", "label": "EMAIL"}}
+        ]"#, &[(FORMAT_PARAM, "json")]).unwrap();
+        let input = r#"{"a":"123-45-6789 abby@school.edu","b":"234-56-7890, again 123-45-6789"}"#;
+        // numbering runs across the string values of the record, per label
+        assert_eq!(map(&record_scope, input), r#"{"a":"<SSN_1> <EMAIL_1>","b":"<SSN_2>, again <SSN_1>"}"#);
+        // and restarts with each record
+        assert_eq!(map(&record_scope, r#"{"a":"234-56-7890"}"#), r#"{"a":"<SSN_1>"}"#);
+
+        let stream_scope = spec_with(r#"[{"placeholder": {"pattern": "ssn", "label": "SSN", "scope": "stream", "salt": "ssn_salt"}}]"#, &[("ssn_salt", "s3cr3t")]).unwrap();
+        let first = map(&stream_scope, "ssn 123-45-6789 and 234-56-7890");
+        let token = &first[4..18];
+        assert!(token.starts_with("<SSN_") && token.ends_with('>'), "{first}");
+        assert_eq!(map(&stream_scope, "again 123-45-6789"), format!("again {token}"));
+        assert_ne!(&first[23..], token);
+
+        assert!(spec_with(r#"[{"placeholder": {"pattern": "ssn", "label": "S S N"}}]"#, &[]).unwrap_err().to_string().contains("`label` must be"));
+        assert!(spec_with(r#"[{"placeholder": {"pattern": "ssn", "label": "SSN", "salt": "ssn_salt"}}]"#, &[("ssn_salt", "x")]).is_err());
+    }
+
+    #[test]
+    fn reorder_test() {
+        let parse = |spec: &str| {
+            let mut params = SmartModuleExtraParams::default();
+            params.insert(PARAM_NAME.to_owned(), spec.to_owned());
+            get_params(params).map_err(|err| err.to_string())
+        };
+        let ops = parse(r#"[{"reorder": {"regex": "(?P<last>[A-Z]\\w+), (?P<first>[A-Z]\\w+)", "to": "${first} ${last}"}}]"#).unwrap();
+        assert_eq!(run(&ops[0], "by Doe, John and Roe, Jane"), "by John Doe and Jane Roe");
+        // matches do not overlap: `Roe` pairs with `Doe` only, and the output is not matched again
+        assert_eq!(run(&ops[0], "Doe, Roe, Poe"), "Roe Doe, Poe");
+        assert_eq!(run(&ops[0], "Doe, John, Smith, Jane"), "John Doe, Jane Smith");
+
+        let ops = parse(r#"[{"reorder": {"regex": "\\b(\\d{2})/(\\d{2})/(\\d{4})\\b", "to": "$3-$2-$1"}}]"#).unwrap();
+        assert_eq!(run(&ops[0], "01/02/2024 to 31/12/2024"), "2024-02-01 to 2024-12-31");
+        // adjacent dates are reordered one after the other, and of overlapping candidates the leftmost full one wins
+        assert_eq!(run(&ops[0], "01/02/2024/03/04/2025"), "2024-02-01/2025-04-03");
+        assert_eq!(run(&ops[0], "01/02/03/04/2025"), "01/02/2025-04-03");
+
+        let error = parse(r#"[{"reorder": {"regex": "(?P<last>\\w+), (?P<first>\\w+)", "to": "${first}"}}]"#).unwrap_err();
+        assert!(error.contains("leaves out capture group `last`"), "{error}");
+        let error = parse(r#"[{"reorder": {"regex": "(\\w+) (\\w+)", "to": "$2 $1 $2"}}]"#).unwrap_err();
+        assert!(error.contains("repeats capture group 2"), "{error}");
+        let error = parse(r#"[{"reorder": {"regex": "(\\w+) (\\w+)", "to": "${upper(2)} $1"}}]"#).unwrap_err();
+        assert!(error.contains("use `replace` for templates"), "{error}");
+        assert!(parse(r#"[{"reorder": {"regex": "(\\w+)", "to": "$1"}}]"#).unwrap_err().contains("at least two capture groups"));
+    }
+
+    #[test]
+    fn replace_cases_test() {
+        let parse = |spec: &str| {
+            let mut params = SmartModuleExtraParams::default();
+            params.insert(PARAM_NAME.to_owned(), spec.to_owned());
+            get_params(params).map_err(|err| err.to_string())
+        };
+        let ops = parse(r#"[{"replace": {"regex": "\\b(?:(?P<visa>4\\d{15})|(?P<amex>3[47]\\d{13})|\\d{16})\\b", "with": "<CARD>", "cases": {"visa": "<VISA>", "amex": "<AMEX ${amex}>"}}}]"#).unwrap();
+        assert_eq!(
+            run(&ops[0], "4111111111111111 378282246310005 5555555555554444"),
+            "<VISA> <AMEX 378282246310005> <CARD>"
+        );
+
+        let ops = parse(r#"[{"replace": {"regex": "(?P<a>x)|(?P<b>y)", "with": "?", "cases": {"a": "$b"}, "literal_replacement": true}}]"#).unwrap();
+        assert_eq!(run(&ops[0], "xyz"), "$b?z");
+
+        let error = parse(r#"[{"replace": {"regex": "(?P<a>x)|y", "with": "?", "cases": {"b": "!"}}}]"#).unwrap_err();
+        assert!(error.contains("`cases` key `b` is not a named group"), "{error}");
+        let error = parse(r#"[{"replace": {"regex": "(?P<a>x)|y", "with": "?", "cases": {"a": "${a|upper}"}}}]"#).unwrap_err();
+        assert!(error.contains("template functions are not supported"), "{error}");
+        let error = parse(r#"[{"replace": {"regex": "(?P<a>x)|y", "with": "?", "group": "a", "cases": {"a": "!"}}}]"#).unwrap_err();
+        assert!(error.contains("not supported with `group`"), "{error}");
+    }
+
+    #[test]
+    fn severity_test() {
+        let with_ops = |ops: Vec<Operation>| Spec { ops, ..spec_with("[]", &[(STATS_FIELD_PARAM, "_matches")]).unwrap() };
+        let mut ops = ops_from_json(r#"[
+            {"mask": {"pattern": "email", "name": "email", "severity": "warn"}},
+            {"mask": {"pattern": "ssn", "name": "ssn"}},
+            {"mask": {"pattern": "credit_card", "name": "card"}}
+        ]"#);
+        // `block` is only accepted at init in the filter-map build
+        ops[2].parts_mut().1.severity = Severity::Block;
+        let spec = with_ops(ops);
+        let value = |text: &str| {
+            let record = SmartModuleRecord::new(Record::new(text), 0, 0);
+            filter_map_record(&record, &spec).unwrap()
+                .map(|(_, value)| String::from_utf8(value.as_ref().to_vec()).unwrap())
+        };
+        assert_eq!(value(r#"{"to":"abby@school.edu","ssn":"123-45-6789"}"#).as_deref(),
+            Some(r#"{"_matches":{"card":0,"email":1,"ssn":1},"ssn":"***-**-****","to":"abby@school.edu"}"#));
+        assert_eq!(value(r#"{"card":"4111111111111111","ssn":"123-45-6789"}"#), None);
+
+        let parse = |spec: &str| {
+            let mut params = SmartModuleExtraParams::default();
+            params.insert(PARAM_NAME.to_owned(), spec.to_owned());
+            get_params(params).map_err(|err| err.to_string())
+        };
+        assert_eq!(parse(r#"[{"mask": {"pattern": "ssn", "severity": "block"}}]"#).is_ok(), cfg!(feature = "filter-map"));
+        assert!(parse(r#"[{"match": {"pattern": "ssn", "severity": "warn"}}]"#).unwrap_err().contains("only applies to operations that rewrite"));
+        assert!(parse(r#"[{"mask": {"pattern": "ssn", "severity": "drop"}}]"#).is_err());
+    }
+
+    #[test]
+    fn sample_rate_test() {
+        let spec = spec_with(r#"[{"mask": {"regex": "\\d", "sample_rate": 0.1}}, {"mask": {"regex": "[a-z]"}}]"#, &[]).unwrap();
+        let map = |offset: i64| {
+            let record = SmartModuleRecord::new(Record::new("ab 12"), offset, 0);
+            String::from_utf8(map_record(&record, &spec).unwrap().1.as_ref().to_vec()).unwrap()
+        };
+        let outputs: Vec<String> = (0..1000).map(map).collect();
+        let hits = outputs.iter().filter(|output| *output == "** **").count();
+        assert!((70..130).contains(&hits), "{hits} sampled");
+        // unsampled records still run the other operations, and the choice only depends on the offset
+        assert!(outputs.iter().all(|output| output == "** **" || output == "** 12"));
+        assert_eq!((0..1000).map(map).collect::<Vec<_>>(), outputs);
+        assert!(sampled(7, 1.0));
+
+        let mut params = SmartModuleExtraParams::default();
+        params.insert(PARAM_NAME.to_owned(), r#"[{"mask": {"regex": "\\d", "sample_rate": 0}}]"#.to_owned());
+        assert!(get_params(params).unwrap_err().to_string().contains("`sample_rate` must be above 0"));
+    }
+
+    #[test]
+    fn decode_test() {
+        let op = ops_from_json(r#"[{"replace": {"regex": "\"(\\w+)\"", "with": "'$1'", "decode": "json_string"}}]"#).remove(0);
+        assert_eq!(run(&op, r#"{"note":"say \"hi\"","n":[1,"\"x\""]}"#), r#"{"n":[1,"'x'"],"note":"say 'hi'"}"#);
+        assert_eq!(run(&op, r#""say \"hi\"""#), r#""say 'hi'""#);
+        assert_eq!(op.count_up_to(br#"{"a":"\"x\" \"y\"","b":"\"z\""}"#, usize::MAX), 3);
+        assert!(matches!(op.run_regex(br#"{"note":"hi"}"#), Cow::Borrowed(_)));
+        assert!(matches!(op.run_regex(b"not json \"hi\""), Cow::Borrowed(_)));
+
+        // a field holding an escaped JSON document is decoded, rewritten and escaped again
+        let ops = ops_from_json(r#"[{"replace": {"regex": "\"(\\w+)\"", "with": "'$1'", "path": "$.payload", "decode": "json_string"}}]"#);
+        let output = apply_regex_ops_to_json_record(br#"{"payload":"{\"note\":\"say \\\"hi\\\"\"}"}"#, None, &ops).unwrap();
+        assert_eq!(output.as_ref(), br#"{"payload":"{\"note\":\"say 'hi'\"}"}"#);
+
+        let mut params = SmartModuleExtraParams::default();
+        params.insert(PARAM_NAME.to_owned(), r#"[{"match": {"regex": "\\d", "decode": "json_string"}}]"#.to_owned());
+        assert!(get_params(params).unwrap_err().to_string().contains("`decode` is only supported"));
+    }
+
+    #[test]
+    fn max_matches_test() {
+        let parse = |spec: &str| spec_with(spec, &[]);
+        let spec = parse(r#"[{"mask": {"regex": "\\d", "max_matches": 3}}, {"replace": {"regex": "a", "with": "b"}}]"#).unwrap();
+        assert_eq!(map_str(&spec, "a 123").unwrap(), "b ***");
+        let err = map_str(&spec, "a 1234").unwrap_err();
+        assert_eq!(format!("{err:#}"), "operation #0 `mask`: more than 3 matches, over `max_matches`");
+
+        // counted across the values a path selects
+        let spec = parse(r#"[{"mask": {"regex": "\\d", "path": "$..n", "max_matches": 3, "on_max_matches": "skip"}}, {"replace": {"regex": "a", "with": "b"}}]"#).unwrap();
+        assert_eq!(map_str(&spec, r#"{"n": "12", "m": {"n": "a"}}"#).unwrap(), r#"{"m":{"n":"b"},"n":"**"}"#);
+        assert_eq!(map_str(&spec, r#"{"n": "12", "m": {"n": "34"}}"#).unwrap(), r#"{"m":{"n":"34"},"n":"12"}"#);
+
+        let spec = parse(r#"[{"mask": {"regex": "\\d", "max_matches": 0, "on_max_matches": "skip"}}]"#).unwrap();
+        assert_eq!(map_str(&spec, "a 1").unwrap(), "a 1");
+
+        assert!(parse(r#"[{"delete_field": {"path": "$.a", "max_matches": 1}}]"#).is_err());
+        assert!(parse(r#"[{"mask": {"regex": "a", "max_matches": 1, "on_max_matches": "truncate"}}]"#).is_err());
+    }
+
+    #[test]
+    fn regex_limits_test() {
+        let parse = |spec: &str, limits: Option<&str>| {
+            let params: Vec<_> = limits.map(|limits| (REGEX_LIMITS_PARAM, limits)).into_iter().collect();
+            spec_with(spec, &params)
+        };
+        let spec = r#"[{"mask": {"regex": "\\w{50}"}}]"#;
+        assert!(parse(spec, None).is_ok());
+        let err = parse(spec, Some(r#"{"size_limit": 1000}"#)).unwrap_err().to_string();
+        assert!(err.contains("exceeds size limit") && err.contains("hint"), "{err}");
+
+        let spec = r#"[{"mask": {"regex": "\\w{50}", "limits": {"size_limit": 10000000}}}]"#;
+        parse(spec, Some(r#"{"size_limit": 1000}"#)).unwrap();
+        let spec = r#"[{"mask": {"regex": "a", "when": {"regex": "((b))"}}}]"#;
+        assert!(parse(spec, Some(r#"{"nest_limit": 1}"#)).is_err());
+        assert!(parse(spec, Some("{\"size\": 1}")).is_err());
+    }
+
+    #[test]
+    fn set_key_test() {
+        let spec = |json: &str| Spec {
+            ops: ops_from_json(json),
+            filter_mode: FilterMode::default(),
+            on_error: OnError::default(),
+            stats_field: None,
+            prefilter: None,
+            audit: false,
+            size_limit: None,
+            time_budget: None,
+            window_records: None,
+            utf8: Utf8Mode::default(),
+            input_encoding: InputEncoding::default(),
+            mode: Mode::default(),
+            control: None,
+            drop_regex: None,
+            codec: None,
+            output_schema: None,
+            lines: None,
+            array_root: ArrayRoot::default(),
+            post_process: None,
+            checksum: None,
+            spans: None,
+            tenants: None,
+        };
+        let record = SmartModuleRecord::new(
+            Record::new_key_value("orders", r#"{"user": "u-42", "ssn": "123-45-6789"}"#), 0, 0
+        );
+        let map = |spec: &Spec| {
+            let (key, value) = map_record(&record, spec).unwrap();
+            (String::from_utf8(key.unwrap().as_ref().to_vec()).unwrap(), String::from_utf8(value.as_ref().to_vec()).unwrap())
+        };
+
+        // the key is read before the value is masked
+        let (key, value) = map(&spec(r#"[
+            {"mask": {"regex": "u-\\d+|\\d{3}-\\d{2}-\\d{4}"}},
+            {"set_key": {"regex": "\"user\": \"(?P<user>[^\"]+)\"", "group": "user"}}
+        ]"#));
+        assert_eq!(key, "u-42");
+        assert_eq!(value, r#"{"user": "*-**", "ssn": "***-**-****"}"#);
+
+        let (key, _) = map(&spec(r#"[{"set_key": {"regex": "\\d+-(\\d+)", "group": 1, "combine": "append", "separator": "/", "path": "$.ssn"}}]"#));
+        assert_eq!(key, "orders/45");
+        let (key, _) = map(&spec(r#"[{"set_key": {"regex": "u-\\d+", "combine": "prepend"}}]"#));
+        assert_eq!(key, "u-42:orders");
+        let (key, _) = map(&spec(r#"[{"set_key": {"regex": "nope"}}]"#));
+        assert_eq!(key, "orders");
+
+        let parse = |json: &str| {
+            let mut params = SmartModuleExtraParams::default();
+            params.insert(PARAM_NAME.to_owned(), json.to_owned());
+            get_params(params)
+        };
+        assert!(parse(r#"[{"set_key": {"regex": "(a)", "group": 2}}]"#).is_err());
+        assert!(parse(r#"[{"set_key": {"regex": "(a)", "group": "b"}}]"#).is_err());
+        assert!(parse(r#"[{"set_key": {"regex": "a", "target": "key"}}]"#).is_err());
+    }
+
+    #[test]
+    fn key_buckets_test() {
+        let parse = |json: &str| spec_with(json, &[(FORMAT_PARAM, "json")]);
+        let key = |spec: &Spec, key: &str, value: &str| {
+            let record = SmartModuleRecord::new(Record::new_key_value(key.to_owned(), value.to_owned()), 0, 0);
+            let (key, _) = map_record(&record, spec).unwrap();
+            String::from_utf8(key.unwrap().as_ref().to_vec()).unwrap()
+        };
+        let expected = |text: &str, buckets: u64| {
+            let digest = HashAlgorithm::Sha256.digest(b"", text.as_bytes());
+            (u64::from_be_bytes(digest[..8].try_into().unwrap()) % buckets).to_string()
+        };
+
+        // the tenant part of the received key is hashed, so records of a tenant share a bucket
+        let spec = parse(r#"[{"set_key": {"regex": "^tenant-(\\w+)", "group": 1, "input": "key", "buckets": 16}}]"#).unwrap();
+        assert_eq!(key(&spec, "tenant-acme/7", "{}"), expected("acme", 16));
+        assert_eq!(key(&spec, "tenant-acme/8", "{}"), key(&spec, "tenant-acme/7", "{}"));
+        assert_eq!(key(&spec, "other", "{}"), "other");
+
+        let spec = parse(r#"[{"set_key": {"regex": "[\\w.]+@[\\w.]+", "path": "$.email", "buckets": 1000, "algorithm": "blake3"}}]"#).unwrap();
+        let bucket = key(&spec, "k", r#"{"email": "abby@school.edu"}"#);
+        assert!(bucket.parse::<u64>().unwrap() < 1000);
+
+        assert!(parse(r#"[{"set_key": {"regex": "a", "buckets": 0}}]"#).is_err());
+        assert!(parse(r#"[{"set_key": {"regex": "a", "input": "key", "path": "$.a"}}]"#).is_err());
+    }
+
+    #[test]
+    fn extract_test() {
+        let op = Operation::Extract(Extract {
+            regex: Pattern::new(r"ssn (?P<ssn>\d{3}-\d{2}-\d{4}), zip (?P<zip>\d{5})(?P<ext>-\d{4})?").unwrap(),
+            options: OpOptions::default(),
+        });
+
+        // Named groups become fields, unmatched optional groups are null
+        let input = r"Alice Jackson, ssn 123-45-6789, zip 95127, location: CA";
+        let result: Value = serde_json::from_str(&run(&op, input)).unwrap();
+        let expected = serde_json::json!({"ssn": "123-45-6789", "zip": "95127", "ext": null});
+        assert_eq!(result, expected);
+
+        // No match leaves the record untouched
+        let input = r"not a match";
+        assert_eq!(run(&op, input), input);
+    }
+
+    #[test]
+    fn find_test() {
+        let ops = ops_from_json(r#"[
+            {"find": {"regex": "(?P<area>\\d{3})-\\d{2}-\\d{4}"}},
+            {"find": {"pattern": "email", "path": "$.contact"}}
+        ]"#);
+
+        let result: Value = serde_json::from_str(&run(&ops[0], "ssn 123-45-6789, 987-65-4321")).unwrap();
+        let expected = serde_json::json!({"count": 2, "matches": [
+            {"span": [4, 15], "groups": {"area": "123"}},
+            {"span": [17, 28], "groups": {"area": "987"}}
+        ]});
+        assert_eq!(result, expected);
+        assert_eq!(run(&ops[0], "nothing"), r#"{"count":0,"matches":[]}"#);
+
+        let result = apply_regex_ops(br#"{"id": 7, "contact": "abby@school.edu"}"#, ops[1..].iter()).unwrap();
+        let expected = serde_json::json!({"id": 7, "contact": {"count": 1, "matches": [{"span": [0, 15]}]}});
+        assert_eq!(serde_json::from_slice::<Value>(&result).unwrap(), expected);
     }
 
 }
\ No newline at end of file
@@ -0,0 +1,84 @@
+use std::fmt::Arguments;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use serde::Deserialize;
+
+/// Most verbose level written to stderr, set by the `log_level` param
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq, PartialOrd)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    Off,
+    Error,
+    #[default]
+    Warn,
+    Info,
+    /// Adds a snippet of the payload to record errors
+    Debug,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Warn as u8);
+
+/// Longest payload snippet logged, in bytes
+const SNIPPET_BYTES: usize = 64;
+
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: LogLevel) -> bool {
+    level != LogLevel::Off && level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// Write one `level=... key=value ... msg="..."` line, `fields` values are quoted when they contain spaces
+pub fn log(level: LogLevel, fields: &[(&str, &dyn std::fmt::Display)], message: Arguments) {
+    if enabled(level) {
+        eprintln!("{}", line(level, fields, message));
+    }
+}
+
+fn line(level: LogLevel, fields: &[(&str, &dyn std::fmt::Display)], message: Arguments) -> String {
+    let mut line = format!("level={level:?}").to_lowercase();
+    for (key, value) in fields {
+        line.push_str(&format!(" {key}={}", quoted(&value.to_string())));
+    }
+    line.push_str(&format!(" msg={}", quoted(&message.to_string())));
+    line
+}
+
+fn quoted(value: &str) -> String {
+    match value.contains([' ', '"', '=']) || value.is_empty() {
+        true => format!("{value:?}"),
+        false => value.escape_debug().to_string(),
+    }
+}
+
+/// First bytes of a payload, non-printable bytes escaped and `...` marking a cut
+pub fn snippet(data: &[u8]) -> String {
+    let cut = data.len() > SNIPPET_BYTES;
+    let mut snippet = data[..data.len().min(SNIPPET_BYTES)].escape_ascii().to_string();
+    if cut {
+        snippet.push_str("...");
+    }
+    snippet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logging_test() {
+        let offset = 12;
+        assert_eq!(
+            line(LogLevel::Warn, &[("offset", &offset), ("action", &"skip")], format_args!("operation #1 `mask`: failed")),
+            r#"level=warn offset=12 action=skip msg="operation #1 `mask`: failed""#
+        );
+        assert_eq!(line(LogLevel::Info, &[("op", &"a=b")], format_args!("")), r#"level=info op="a=b" msg="""#);
+
+        assert_eq!(snippet(b"ssn\n\xff"), r"ssn\n\xff");
+        assert_eq!(snippet(&[b'a'; 100]), format!("{}...", "a".repeat(SNIPPET_BYTES)));
+
+        assert!(LogLevel::Error < LogLevel::Debug);
+        assert_eq!(serde_json::from_str::<LogLevel>(r#""debug""#).unwrap(), LogLevel::Debug);
+    }
+}
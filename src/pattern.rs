@@ -0,0 +1,553 @@
+use std::borrow::Cow;
+use std::ops::Deref;
+
+use regex::bytes::{Regex, RegexBuilder, RegexSetBuilder};
+use serde::{Deserialize, Deserializer};
+use serde::de::{self, MapAccess, Visitor};
+use serde::de::value::MapAccessDeserializer;
+
+use fluvio_smartmodule::{Result, eyre};
+
+use crate::builtin::PatternRef;
+
+/// Regex source from the spec, compiled at init once per-operation settings such as `flags` are known.
+/// Flattened into operations, it reads either a `regex` or a built-in `pattern` name, elsewhere
+/// it is a plain regex string.
+#[derive(Debug)]
+pub struct Pattern {
+    source: String,
+    /// Built-in pattern the source comes from
+    builtin: Option<PatternRef>,
+    regex: Option<Regex>,
+    #[cfg(feature = "fancy-regex")]
+    fancy: Option<fancy_regex::Regex>,
+}
+
+/// Regex engine of an operation, `fancy` adds lookaround and backreferences at the cost of speed
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Engine {
+    #[default]
+    Default,
+    Fancy,
+}
+
+impl Pattern {
+    /// Compile a pattern with default settings
+    #[cfg(test)]
+    pub fn new(source: &str) -> Result<Self> {
+        let mut pattern = Pattern::from_source(source.to_string());
+        pattern.compile(&Flags::default())?;
+        Ok(pattern)
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Init warning when the source is a deprecated version of a built-in pattern
+    pub fn deprecation(&self) -> Option<String> {
+        self.builtin.as_ref().and_then(PatternRef::deprecation)
+    }
+
+    /// Let each run of literal spaces outside character classes match any run of whitespace,
+    /// escaped spaces (`\ `) still match a single space
+    pub fn relax_whitespace(&mut self) {
+        self.source = relax_whitespace(&self.source);
+    }
+
+    fn from_source(source: String) -> Self {
+        Pattern {
+            source,
+            builtin: None,
+            regex: None,
+            #[cfg(feature = "fancy-regex")]
+            fancy: None,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn compile(&mut self, flags: &Flags) -> Result<()> {
+        self.compile_with(flags, Engine::Default, &RegexLimits::default())
+    }
+
+    pub fn compile_with(&mut self, flags: &Flags, engine: Engine, limits: &RegexLimits) -> Result<()> {
+        match engine {
+            Engine::Default => {
+                let mut builder = flags.builder(&self.source);
+                limits.apply(&mut builder);
+                let regex = builder.build()
+                    .map_err(|err| eyre!("invalid regex `{}`: {err}", self.source))?;
+                self.regex = Some(regex);
+            }
+            #[cfg(feature = "fancy-regex")]
+            Engine::Fancy => {
+                let mut builder = fancy_regex::RegexBuilder::new(&format!("{}{}", flags.inline(), self.source));
+                if let Some(size_limit) = limits.size_limit {
+                    builder.delegate_size_limit(size_limit);
+                }
+                if let Some(dfa_size_limit) = limits.dfa_size_limit {
+                    builder.delegate_dfa_size_limit(dfa_size_limit);
+                }
+                let fancy = builder.build()
+                    .map_err(|err| eyre!("invalid regex `{}`: {err}", self.source))?;
+                self.fancy = Some(fancy);
+            }
+            #[cfg(not(feature = "fancy-regex"))]
+            Engine::Fancy => {
+                return Err(eyre!("`engine: fancy` requires the `fancy-regex` cargo feature"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the data contains a match, with either engine
+    pub fn is_match(&self, data: &[u8]) -> bool {
+        #[cfg(feature = "fancy-regex")]
+        if let Some(fancy) = &self.fancy {
+            return fancy_text(data).is_some_and(|text| fancy.is_match(text).unwrap_or_else(|err| {
+                crate::logging::log(crate::logging::LogLevel::Warn, &[("regex", &self.source)], format_args!("regex failed: {err}"));
+                false
+            }));
+        }
+        self.deref().is_match(data)
+    }
+
+    /// Number of matches, with either engine, counting stops at `limit`
+    pub fn count(&self, data: &[u8], limit: usize) -> usize {
+        #[cfg(feature = "fancy-regex")]
+        if let Some(fancy) = &self.fancy {
+            return fancy_text(data).map_or(0, |text| fancy.find_iter(text).filter_map(|m| m.ok()).take(limit).count());
+        }
+        self.find_iter(data).take(limit).count()
+    }
+
+    /// Byte ranges of the matches, with either engine
+    pub fn spans(&self, data: &[u8]) -> Vec<[usize; 2]> {
+        #[cfg(feature = "fancy-regex")]
+        if let Some(fancy) = &self.fancy {
+            return fancy_text(data).map_or(vec![], |text| {
+                fancy.find_iter(text).filter_map(|m| m.ok()).map(|m| [m.start(), m.end()]).collect()
+            });
+        }
+        self.find_iter(data).map(|m| [m.start(), m.end()]).collect()
+    }
+
+    /// Whether the regex has the capture group `index`, with either engine
+    pub fn has_group_index(&self, index: usize) -> bool {
+        #[cfg(feature = "fancy-regex")]
+        if let Some(fancy) = &self.fancy {
+            return index < fancy.captures_len();
+        }
+        index < self.captures_len()
+    }
+
+    /// Whether the regex has a capture group named `name`, with either engine
+    pub fn has_group_name(&self, name: &str) -> bool {
+        #[cfg(feature = "fancy-regex")]
+        if let Some(fancy) = &self.fancy {
+            return fancy.capture_names().flatten().any(|n| n == name);
+        }
+        self.capture_names().flatten().any(|n| n == name)
+    }
+
+    /// Replace up to `limit` matches (`0` for all), expanding `$name` references in `with`
+    pub fn replacen_expand<'h>(&self, data: &'h [u8], limit: usize, with: &str) -> Cow<'h, [u8]> {
+        #[cfg(feature = "fancy-regex")]
+        if let Some(fancy) = &self.fancy {
+            return self.fancy_replacen(fancy, data, limit, with);
+        }
+        self.replacen(data, limit, with.as_bytes())
+    }
+
+    /// Replace up to `limit` matches (`0` for all) with `with` as is, `$` included
+    pub fn replacen_literal<'h>(&self, data: &'h [u8], limit: usize, with: &str) -> Cow<'h, [u8]> {
+        #[cfg(feature = "fancy-regex")]
+        if let Some(fancy) = &self.fancy {
+            return self.fancy_replacen(fancy, data, limit, fancy_regex::NoExpand(with));
+        }
+        self.replacen(data, limit, regex::bytes::NoExpand(with.as_bytes()))
+    }
+
+    /// Replace every match with the output of `f` on the matched text
+    pub fn replace_all_with<'h>(&self, data: &'h [u8], f: impl Fn(&[u8]) -> String) -> Cow<'h, [u8]> {
+        #[cfg(feature = "fancy-regex")]
+        if let Some(fancy) = &self.fancy {
+            return self.fancy_replacen(fancy, data, 0, |caps: &fancy_regex::Captures| f(caps[0].as_bytes()));
+        }
+        self.replace_all(data, |caps: &regex::bytes::Captures| f(&caps[0]))
+    }
+
+    /// The fancy engine only reads UTF-8 text, other data and matching errors leave the input as is
+    #[cfg(feature = "fancy-regex")]
+    fn fancy_replacen<'h>(
+        &self,
+        fancy: &fancy_regex::Regex,
+        data: &'h [u8],
+        limit: usize,
+        with: impl fancy_regex::Replacer,
+    ) -> Cow<'h, [u8]> {
+        let Some(text) = fancy_text(data) else {
+            return Cow::Borrowed(data);
+        };
+        match fancy.try_replacen(text, limit, with) {
+            Ok(Cow::Owned(result)) => Cow::Owned(result.into_bytes()),
+            Ok(Cow::Borrowed(_)) => Cow::Borrowed(data),
+            Err(err) => {
+                crate::logging::log(crate::logging::LogLevel::Warn, &[("regex", &self.source)], format_args!("regex failed: {err}"));
+                Cow::Borrowed(data)
+            }
+        }
+    }
+}
+
+/// Rewrite literal spaces to `\s+`, or to a single `\s` before a quantifier so `a +b` and `a *b` keep their meaning
+fn relax_whitespace(source: &str) -> String {
+    let mut relaxed = String::with_capacity(source.len());
+    let mut chars = source.chars().peekable();
+    let mut class_depth = 0;
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                relaxed.push(c);
+                relaxed.extend(chars.next());
+                continue;
+            }
+            '[' => {
+                class_depth += 1;
+                relaxed.push(c);
+                // a `]` right after the opening bracket is a literal
+                if chars.peek() == Some(&'^') {
+                    relaxed.extend(chars.next());
+                }
+                if chars.peek() == Some(&']') {
+                    relaxed.extend(chars.next());
+                }
+                continue;
+            }
+            ']' if class_depth > 0 => class_depth -= 1,
+            ' ' if class_depth == 0 => {
+                let mut run = 1;
+                while chars.peek() == Some(&' ') {
+                    chars.next();
+                    run += 1;
+                }
+                if matches!(chars.peek(), Some('*' | '+' | '?' | '{')) {
+                    if run > 1 {
+                        relaxed.push_str(r"\s+");
+                    }
+                    relaxed.push_str(r"\s");
+                } else {
+                    relaxed.push_str(r"\s+");
+                }
+                continue;
+            }
+            _ => {}
+        }
+        relaxed.push(c);
+    }
+    relaxed
+}
+
+#[cfg(feature = "fancy-regex")]
+fn fancy_text(data: &[u8]) -> Option<&str> {
+    std::str::from_utf8(data).ok()
+}
+
+impl Deref for Pattern {
+    type Target = Regex;
+
+    fn deref(&self) -> &Regex {
+        self.regex.as_ref().expect("regex compiled at init")
+    }
+}
+
+/// Default regex of an operation, compiled at init like the ones from the spec
+impl From<&str> for Pattern {
+    fn from(source: &str) -> Self {
+        Pattern::from_source(source.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct PatternVisitor;
+
+        impl<'de> Visitor<'de> for PatternVisitor {
+            type Value = Pattern;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a regex string, or `regex` or `pattern` fields")
+            }
+
+            fn visit_str<E: de::Error>(self, source: &str) -> std::result::Result<Pattern, E> {
+                Ok(Pattern::from_source(source.to_string()))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> std::result::Result<Pattern, A::Error> {
+                let spec = PatternSpec::deserialize(MapAccessDeserializer::new(map))?;
+                Pattern::try_from(spec).map_err(de::Error::custom)
+            }
+        }
+
+        deserializer.deserialize_any(PatternVisitor)
+    }
+}
+
+#[derive(Deserialize)]
+struct PatternSpec {
+    #[serde(default)]
+    regex: Option<String>,
+    #[serde(default)]
+    pattern: Option<PatternRef>,
+}
+
+impl TryFrom<PatternSpec> for Pattern {
+    type Error = String;
+
+    fn try_from(spec: PatternSpec) -> std::result::Result<Self, String> {
+        let source = match (spec.regex, spec.pattern) {
+            (Some(regex), None) => regex,
+            (None, Some(builtin)) => builtin.regex().to_string(),
+            (Some(_), Some(_)) => return Err("use either `regex` or `pattern`, not both".to_string()),
+            (None, None) => return Err("missing `regex` or `pattern`".to_string()),
+        };
+        Ok(Pattern { builtin: spec.pattern, ..Pattern::from_source(source) })
+    }
+}
+
+/// Bounds on the compiled size of a regex, over which the spec is rejected at init.
+/// The fancy engine applies `size_limit` and `dfa_size_limit` to the parts it delegates.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RegexLimits {
+    /// Bytes of compiled program
+    #[serde(default)]
+    pub size_limit: Option<usize>,
+    /// Bytes of lazy DFA cache
+    #[serde(default)]
+    pub dfa_size_limit: Option<usize>,
+    /// Nesting depth of groups and repetitions
+    #[serde(default)]
+    pub nest_limit: Option<u32>,
+}
+
+impl RegexLimits {
+    /// Limits set here, falling back to `defaults` for the others
+    pub fn or(&self, defaults: &RegexLimits) -> RegexLimits {
+        RegexLimits {
+            size_limit: self.size_limit.or(defaults.size_limit),
+            dfa_size_limit: self.dfa_size_limit.or(defaults.dfa_size_limit),
+            nest_limit: self.nest_limit.or(defaults.nest_limit),
+        }
+    }
+
+    fn apply(&self, builder: &mut RegexBuilder) {
+        if let Some(size_limit) = self.size_limit {
+            builder.size_limit(size_limit);
+        }
+        if let Some(dfa_size_limit) = self.dfa_size_limit {
+            builder.dfa_size_limit(dfa_size_limit);
+        }
+        if let Some(nest_limit) = self.nest_limit {
+            builder.nest_limit(nest_limit);
+        }
+    }
+
+    pub fn apply_to_set(&self, builder: &mut RegexSetBuilder) {
+        if let Some(size_limit) = self.size_limit {
+            builder.size_limit(size_limit);
+        }
+        if let Some(dfa_size_limit) = self.dfa_size_limit {
+            builder.dfa_size_limit(dfa_size_limit);
+        }
+        if let Some(nest_limit) = self.nest_limit {
+            builder.nest_limit(nest_limit);
+        }
+    }
+}
+
+/// Regex flags applied at compile time: `i`, `m`, `s`, `x` and `U`
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Flags {
+    case_insensitive: bool,
+    multi_line: bool,
+    dot_matches_new_line: bool,
+    ignore_whitespace: bool,
+    swap_greed: bool,
+    /// Set from the `unicode: false` operation setting rather than a flag character
+    ascii_only: bool,
+}
+
+impl Flags {
+    pub fn case_insensitive(&self) -> bool {
+        self.case_insensitive
+    }
+
+    pub fn ignore_whitespace(&self) -> bool {
+        self.ignore_whitespace
+    }
+
+    /// Unicode classes and case folding, on by default; off, `\w`, `\d` and `i` only cover ASCII
+    pub fn set_unicode(&mut self, unicode: bool) {
+        self.ascii_only = !unicode;
+    }
+
+    fn builder(&self, source: &str) -> RegexBuilder {
+        let mut builder = RegexBuilder::new(source);
+        builder
+            .case_insensitive(self.case_insensitive)
+            .multi_line(self.multi_line)
+            .dot_matches_new_line(self.dot_matches_new_line)
+            .ignore_whitespace(self.ignore_whitespace)
+            .swap_greed(self.swap_greed)
+            .unicode(!self.ascii_only);
+        builder
+    }
+
+    /// Flags as an inline group prefix, e.g. `(?is)`, for engines without a builder option per flag
+    pub fn inline(&self) -> String {
+        let flags: String = [
+            (self.case_insensitive, 'i'),
+            (self.multi_line, 'm'),
+            (self.dot_matches_new_line, 's'),
+            (self.ignore_whitespace, 'x'),
+            (self.swap_greed, 'U'),
+        ].iter().filter(|(set, _)| *set).map(|(_, flag)| *flag).collect();
+        let flags = if self.ascii_only { format!("{flags}-u") } else { flags };
+
+        if flags.is_empty() { flags } else { format!("(?{flags})") }
+    }
+}
+
+impl std::str::FromStr for Flags {
+    type Err = eyre::Error;
+
+    fn from_str(raw: &str) -> Result<Self> {
+        let mut flags = Flags::default();
+        for c in raw.chars() {
+            match c {
+                'i' => flags.case_insensitive = true,
+                'm' => flags.multi_line = true,
+                's' => flags.dot_matches_new_line = true,
+                'x' => flags.ignore_whitespace = true,
+                'U' => flags.swap_greed = true,
+                _ => return Err(eyre!("unknown regex flag `{c}` in `{raw}`, expected any of `imsxU`")),
+            }
+        }
+        Ok(flags)
+    }
+}
+
+impl<'de> Deserialize<'de> for Flags {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let raw = String::deserialize(deserializer)?;
+        raw.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_test() {
+        let mut pattern: Pattern = serde_json::from_str(r#"{"regex": "^abby.hardy$"}"#).unwrap();
+        let text = b"ABBY\nHARDY";
+
+        pattern.compile(&Flags::default()).unwrap();
+        assert!(!pattern.is_match(text));
+
+        pattern.compile(&"is".parse().unwrap()).unwrap();
+        assert!(pattern.is_match(text));
+
+        let mut pattern = Pattern::new("^abby . hardy # comment").unwrap();
+        assert!(!pattern.is_match(b"ABBY-HARDY"));
+        pattern.compile(&"ix".parse().unwrap()).unwrap();
+        assert!(pattern.is_match(b"ABBY-HARDY"));
+
+        let mut pattern = Pattern::new("a+").unwrap();
+        pattern.compile(&"U".parse().unwrap()).unwrap();
+        assert_eq!(pattern.find(b"aaa").unwrap().as_bytes(), b"a");
+
+        let err = "iq".parse::<Flags>().unwrap_err();
+        assert!(err.to_string().contains("unknown regex flag `q`"));
+        assert!(serde_json::from_str::<Flags>(r#""z""#).is_err());
+        assert!(Pattern::new("(unclosed").is_err());
+    }
+
+    #[test]
+    fn relax_whitespace_test() {
+        assert_eq!(relax_whitespace("user  logged in"), r"user\s+logged\s+in");
+        assert_eq!(relax_whitespace(r"a +b a *b a  ?b"), r"a\s+b\s+a\s*b\s+a\s+\s?b");
+        assert_eq!(relax_whitespace(r"[ -]x[] ]y[^ ] \ z"), r"[ -]x[] ]y[^ ]\s+\ z");
+        assert_eq!(relax_whitespace(r"[[:alpha:] ] x"), r"[[:alpha:] ]\s+x");
+
+        let mut pattern = Pattern::from_source("level: error".to_string());
+        pattern.relax_whitespace();
+        pattern.compile(&Flags::default()).unwrap();
+        assert!(pattern.is_match(b"level:\terror"));
+        assert!(pattern.is_match(b"level:  \n error"));
+        assert!(!pattern.is_match(b"level:error"));
+    }
+
+    #[test]
+    fn engine_test() {
+        let mut pattern = Pattern::from_source(r"(?<=ssn:)\d+".to_string());
+        let compiled = pattern.compile_with(&"i".parse().unwrap(), Engine::Fancy, &RegexLimits::default());
+        if cfg!(feature = "fancy-regex") {
+            compiled.unwrap();
+            assert!(pattern.is_match(b"SSN:123"));
+            assert_eq!(pattern.count(b"ssn:1 id:2 ssn:3", usize::MAX), 2);
+            assert_eq!(pattern.count(b"ssn:1 id:2 ssn:3", 1), 1);
+            assert_eq!(pattern.spans(b"ssn:1 id:2 ssn:3"), vec![[4, 5], [15, 16]]);
+            assert_eq!(pattern.replacen_expand(b"ssn:1 id:2 ssn:3", 1, "*").as_ref(), b"ssn:* id:2 ssn:3");
+            assert_eq!(pattern.replace_all_with(b"ssn:12", |m| "#".repeat(m.len())).as_ref(), b"ssn:##");
+            assert!(matches!(pattern.replacen_expand(b"id:2", 0, "*"), Cow::Borrowed(_)));
+            assert!(!pattern.is_match(b"ssn:\xff1"));
+        } else {
+            assert!(compiled.unwrap_err().to_string().contains("fancy-regex"));
+        }
+        assert!(Pattern::new(r"(?<=ssn:)\d+").is_err());
+        assert_eq!("imU".parse::<Flags>().unwrap().inline(), "(?imU)");
+
+        let mut flags: Flags = "i".parse().unwrap();
+        flags.set_unicode(false);
+        assert_eq!(flags.inline(), "(?i-u)");
+        let mut pattern = Pattern::from_source(r"\d+".to_string());
+        pattern.compile_with(&flags, Engine::Default, &RegexLimits::default()).unwrap();
+        assert!(!pattern.is_match("١٢٣".as_bytes()));
+        pattern.compile(&Flags::default()).unwrap();
+        assert!(pattern.is_match("١٢٣".as_bytes()));
+    }
+
+    #[test]
+    fn limits_test() {
+        let limits: RegexLimits = serde_json::from_str(r#"{"size_limit": 1000, "nest_limit": 2}"#).unwrap();
+        let mut pattern = Pattern::from_source(r"\w{100}".to_string());
+        let err = pattern.compile_with(&Flags::default(), Engine::Default, &limits).unwrap_err();
+        assert!(err.to_string().contains("size limit"));
+
+        let mut pattern = Pattern::from_source("(((a)))".to_string());
+        assert!(pattern.compile_with(&Flags::default(), Engine::Default, &limits).is_err());
+        let nested = RegexLimits { nest_limit: Some(10), ..RegexLimits::default() }.or(&limits);
+        assert_eq!(nested, RegexLimits { size_limit: Some(1000), dfa_size_limit: None, nest_limit: Some(10) });
+        pattern.compile_with(&Flags::default(), Engine::Default, &nested).unwrap();
+
+        assert!(serde_json::from_str::<RegexLimits>(r#"{"timeout": 1}"#).is_err());
+    }
+
+    #[test]
+    fn builtin_pattern_test() {
+        let mut pattern: Pattern = serde_json::from_str(r#"{"pattern": "ssn"}"#).unwrap();
+        pattern.compile(&Flags::default()).unwrap();
+        assert!(pattern.is_match(b"ssn 123-45-6789"));
+
+        assert!(serde_json::from_str::<Pattern>(r#"{"pattern": "passport"}"#).is_err());
+        assert!(serde_json::from_str::<Pattern>(r#"{"pattern": "ssn", "regex": "\d+"}"#).is_err());
+        assert!(serde_json::from_str::<Pattern>(r#"{}"#).is_err());
+
+        let pattern: Pattern = serde_json::from_str(r#""\\d+""#).unwrap();
+        assert_eq!(pattern.source, r"\d+");
+    }
+}
@@ -0,0 +1,284 @@
+use std::sync::{Arc, PoisonError, RwLock};
+
+use fluvio_smartmodule::{Record, Result, SmartModuleRecord, dataplane::smartmodule::SmartModuleExtraParams};
+
+use crate::{CompiledSpec, Spec, PARAM_NAME, apply_regex_ops_counting, begin_record, filter_json_record, get_spec, map_record, tenant_spec};
+
+/// The operations of a spec outside of the SmartModule harness, e.g. to reuse them
+/// in native connectors, tests or benchmarks. The spec can be swapped with `update` while
+/// other threads run records, each record runs entirely on the spec it started with.
+#[derive(Debug)]
+pub struct Pipeline {
+    spec: RwLock<Arc<Spec>>,
+}
+
+impl Pipeline {
+    /// Parse and resolve a JSON spec with default settings for the other params
+    pub fn from_json(spec: &str) -> Result<Self> {
+        Pipeline::from_params([(PARAM_NAME, spec)])
+    }
+
+    /// Parse the spec and record handling params the same way the SmartModule `init` does
+    pub fn from_params<'a>(params: impl IntoIterator<Item = (&'a str, &'a str)>) -> Result<Self> {
+        Ok(Pipeline { spec: RwLock::new(Arc::new(get_spec(extra_params(params))?)) })
+    }
+
+    /// Replace the operations with those of a JSON spec, keeping the current ones when it does not resolve
+    pub fn update(&self, spec: &str) -> Result<()> {
+        self.update_params([(PARAM_NAME, spec)])
+    }
+
+    /// Replace the spec and record handling params as a new `init` would, keeping the current ones on error
+    pub fn update_params<'a>(&self, params: impl IntoIterator<Item = (&'a str, &'a str)>) -> Result<()> {
+        let spec = Arc::new(get_spec(extra_params(params))?);
+        *self.spec.write().unwrap_or_else(PoisonError::into_inner) = spec;
+        Ok(())
+    }
+
+    /// The current spec, held by the caller so an `update` does not change it mid-record
+    fn spec(&self) -> Arc<Spec> {
+        Arc::clone(&self.spec.read().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Run the operations on a record value, as the map build does
+    pub fn apply(&self, value: &[u8]) -> Result<Vec<u8>> {
+        let (_, value) = self.map(None, value)?;
+        Ok(value)
+    }
+
+    /// Run the operations on a record key and value, as the map build does
+    pub fn map(&self, key: Option<&[u8]>, value: &[u8]) -> Result<(Option<Vec<u8>>, Vec<u8>)> {
+        let (key, value) = map_record(&record(key, value), &self.spec())?;
+        Ok((key.map(|key| key.as_ref().to_vec()), value.as_ref().to_vec()))
+    }
+
+    /// Matches of each value operation on a record, keyed by operation name or position like `stats_field` counts
+    pub fn matches(&self, key: Option<&[u8]>, value: &[u8]) -> Result<Vec<(String, usize)>> {
+        let spec = self.spec();
+        let record = record(key, value);
+        begin_record(&spec, &record);
+        let spec = tenant_spec(&spec, &record)?;
+        let (_, counts) = apply_regex_ops_counting(value, key, &spec.ops)?;
+        Ok(counts)
+    }
+
+    /// One line per operation as compiled, e.g. to check the regexes of an escaped JSON spec
+    pub fn describe(&self) -> Vec<String> {
+        self.spec().describe()
+    }
+
+    /// Whether the `match`/`not_match` predicates keep the record, as the filter build does
+    pub fn keeps(&self, key: Option<&[u8]>, value: &[u8]) -> Result<bool> {
+        let record = record(key, value);
+        let spec = self.spec();
+        begin_record(&spec, &record);
+        let spec = tenant_spec(&spec, &record)?;
+        filter_json_record(&record, value, &spec.ops, &spec.filter_mode)
+    }
+}
+
+/// Validate the spec params as `init` does and pack the operation lists into a `compiled_spec`
+/// param value, to deploy in place of `spec`, `spec.1`... and `spec_encoding`
+pub fn compile_spec<'a>(params: impl IntoIterator<Item = (&'a str, &'a str)>) -> Result<String> {
+    CompiledSpec::compile(&extra_params(params))
+}
+
+fn extra_params<'a>(params: impl IntoIterator<Item = (&'a str, &'a str)>) -> SmartModuleExtraParams {
+    let mut extra_params = SmartModuleExtraParams::default();
+    for (name, value) in params {
+        extra_params.insert(name.to_string(), value.to_string());
+    }
+    extra_params
+}
+
+fn record(key: Option<&[u8]>, value: &[u8]) -> SmartModuleRecord {
+    let record = match key {
+        Some(key) => Record::new_key_value(key.to_vec(), value.to_vec()),
+        None => Record::new(value.to_vec()),
+    };
+    SmartModuleRecord::new(record, 0, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pipeline_test() {
+        let pipeline = Pipeline::from_json(r#"[{"mask": {"pattern": "ssn"}}, {"match": {"regex": "ssn"}}]"#).unwrap();
+        assert_eq!(pipeline.apply(b"ssn 123-45-6789").unwrap(), b"ssn ***-**-****");
+        assert!(pipeline.keeps(None, b"ssn 123-45-6789").unwrap());
+        assert!(!pipeline.keeps(None, b"id 1").unwrap());
+        assert_eq!(pipeline.matches(None, b"ssn 123-45-6789, 234-56-7890").unwrap(), [("#0".to_string(), 2), ("#1".to_string(), 1)]);
+        assert_eq!(pipeline.describe()[1], r"operation #2 `match` regex=`ssn`");
+
+        let pipeline = Pipeline::from_params([
+            ("spec", r#"[{"replace": {"regex": "^\\w+", "with": "user", "target": "key"}}]"#),
+            ("format", "text"),
+        ]).unwrap();
+        let (key, value) = pipeline.map(Some(b"abby-1"), b"hello").unwrap();
+        assert_eq!((key.unwrap().as_slice(), value.as_slice()), (&b"user-1"[..], &b"hello"[..]));
+
+        assert!(Pipeline::from_json("[{\"mask\": {}}]").is_err());
+    }
+
+    #[test]
+    fn update_test() {
+        let pipeline = Arc::new(Pipeline::from_json(r#"[{"mask": {"pattern": "ssn"}}]"#).unwrap());
+        let workers: Vec<_> = (0..4).map(|_| {
+            let pipeline = Arc::clone(&pipeline);
+            std::thread::spawn(move || {
+                for _ in 0..100 {
+                    let output = pipeline.apply(b"ssn 123-45-6789").unwrap();
+                    assert!(output == b"ssn ***-**-****" || output == b"ssn [redacted]", "{output:?}");
+                }
+            })
+        }).collect();
+        pipeline.update(r#"[{"replace": {"pattern": "ssn", "with": "[redacted]"}}]"#).unwrap();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(pipeline.apply(b"ssn 123-45-6789").unwrap(), b"ssn [redacted]");
+
+        // a spec that does not resolve leaves the current one in place
+        assert!(pipeline.update(r#"[{"mask": {"regex": "("}}]"#).is_err());
+        assert_eq!(pipeline.apply(b"ssn 123-45-6789").unwrap(), b"ssn [redacted]");
+        pipeline.update_params([("spec", r#"[{"replace": {"regex": "^\\w+", "with": "id", "target": "key"}}]"#), ("format", "text")]).unwrap();
+        assert_eq!(pipeline.map(Some(b"abby-1"), b"x").unwrap().0.unwrap(), b"id-1");
+    }
+
+    #[test]
+    fn compile_spec_test() {
+        let spec = "- mask:\n    pattern: ssn\n";
+        let compiled = compile_spec([("spec", spec), ("spec.1", r#"[{"replace": {"regex": "Abby", "with": "A."}}]"#), ("spec_format", "yaml")]).unwrap();
+        let pipeline = Pipeline::from_params([("compiled_spec", compiled.as_str()), ("spec_format", "yaml")]).unwrap();
+        assert_eq!(pipeline.apply(b"Abby 123-45-6789").unwrap(), b"A. ***-**-****");
+        let pipeline = Pipeline::from_params([("compiled_spec", compiled.as_str())]).unwrap();
+        assert_eq!(pipeline.describe().len(), 2);
+
+        let err = compile_spec([("spec", r#"[{"mask": {"regex": "("}}]"#)]).unwrap_err();
+        assert!(err.to_string().contains("invalid regex"), "{err}");
+        let err = Pipeline::from_params([("compiled_spec", compiled.as_str()), ("spec", "[]")]).unwrap_err();
+        assert!(err.to_string().contains("`compiled_spec` replaces the `spec`"), "{err}");
+        let err = Pipeline::from_params([("compiled_spec", compiled.as_str()), ("spec.1", "[]")]).unwrap_err();
+        assert!(err.to_string().contains("`compiled_spec` replaces the `spec.N` params"), "{err}");
+        let err = Pipeline::from_params([("compiled_spec", "W10=")]).unwrap_err();
+        assert!(err.to_string().contains("was not produced by `compile_spec`"), "{err}");
+        let truncated = &compiled[..compiled.len() - 8];
+        assert!(Pipeline::from_params([("compiled_spec", truncated)]).is_err());
+
+        let stale = CompiledSpec { version: "0.0.1".to_string(), sources: vec![("spec".to_string(), "[]".to_string())] };
+        let err = Pipeline::from_params([("compiled_spec", stale.encode().unwrap().as_str())]).unwrap_err();
+        assert!(err.to_string().contains("was compiled for regex-map 0.0.1"), "{err}");
+    }
+
+    /// Seeded xorshift generator for the property tests, failures print the seed to reproduce them
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+            &items[self.below(items.len())]
+        }
+    }
+
+    /// Text mixing sensitive values, regex and JSON metacharacters, whitespace and multi-byte characters,
+    /// its pieces joined with `separator`
+    fn arbitrary_text(rng: &mut Rng, separator: &str) -> String {
+        const FRAGMENTS: &[&str] = &[
+            "123-45-6789", "abby.hardy@school.edu", "4111 1111 1111 1111", "192.168.17.42", "2001:db8::1",
+            "https://abby:pw@example.com/a?token=x&page=2#top", "Abby Hardy", "secret-42", " ", "  \t", "\n",
+            "$1", "${x}", "\\", "\"", "{}", "[", "é", "e\u{301}", "日本", "🙂", "\u{feff}", "\u{0}", "-", ".", ":", "@",
+        ];
+        let mut text = String::new();
+        for _ in 0..rng.below(12) {
+            if !text.is_empty() {
+                text.push_str(separator);
+            }
+            match rng.below(3) {
+                0 => text.push_str(rng.pick(FRAGMENTS)),
+                1 => text.push(char::from(b'0' + rng.below(10) as u8)),
+                _ => text.push(char::from_u32(rng.below(0x3000) as u32).unwrap_or('?')),
+            }
+        }
+        text
+    }
+
+    fn arbitrary_json(rng: &mut Rng, depth: usize) -> serde_json::Value {
+        use serde_json::Value;
+
+        match rng.below(if depth == 0 { 4 } else { 6 }) {
+            0 => Value::String(arbitrary_text(rng, "")),
+            1 => Value::from(rng.next() as i64 % 100_000),
+            2 => Value::Bool(rng.below(2) == 0),
+            3 => Value::Null,
+            4 => Value::Array((0..rng.below(4)).map(|_| arbitrary_json(rng, depth - 1)).collect()),
+            _ => Value::Object((0..rng.below(4)).map(|_| (arbitrary_text(rng, ""), arbitrary_json(rng, depth - 1))).collect()),
+        }
+    }
+
+    /// Operations that do not match their own output, nor text an earlier one wrote, so running them
+    /// twice changes nothing more. Rewrites that can create word boundaries go before the masks.
+    const IDEMPOTENT_SPEC: &str = r#"[
+        {"normalize": {"presets": ["strip_control_chars", "collapse_whitespace", "trim"]}},
+        {"replace": {"regex": "(?P<first>[A-Z])[a-z]+ (?P<last>[A-Z])[a-z]+", "with": "${first}. ${last}."}},
+        {"mask": {"pattern": "ssn"}},
+        {"mask": {"pattern": "email"}},
+        {"mask_credit_card": {}},
+        {"anonymize_ip": {}},
+        {"url": {"strip": ["token"], "redact_userinfo": true, "strip_fragment": true}}
+    ]"#;
+
+    const SEEDS: std::ops::Range<u64> = 1..500;
+
+    #[test]
+    fn utf8_property_test() {
+        let pipeline = Pipeline::from_params([
+            ("spec", IDEMPOTENT_SPEC),
+            ("spec.1", r#"[{"hash": {"regex": "secret-\\w+"}}, {"replace": {"regex": "\\d", "with": "${0|mul(2)}"}}, {"case": {"regex": "\\w+", "to": "upper"}}]"#),
+            ("format", "text"),
+        ]).unwrap();
+        for seed in SEEDS {
+            let text = arbitrary_text(&mut Rng(seed), "");
+            let output = pipeline.apply(text.as_bytes()).unwrap();
+            assert!(std::str::from_utf8(&output).is_ok(), "seed {seed}: {text:?} became {output:?}");
+        }
+    }
+
+    #[test]
+    fn json_property_test() {
+        let pipeline = Pipeline::from_params([("spec", IDEMPOTENT_SPEC), ("format", "json")]).unwrap();
+        for seed in SEEDS {
+            let mut rng = Rng(seed);
+            let value = serde_json::Value::Object((0..rng.below(5)).map(|_| (arbitrary_text(&mut rng, ""), arbitrary_json(&mut rng, 3))).collect());
+            let input = value.to_string();
+            let output = pipeline.apply(input.as_bytes()).unwrap();
+            let parsed: serde_json::Value = serde_json::from_slice(&output)
+                .unwrap_or_else(|err| panic!("seed {seed}: {input} became unparsable {:?}: {err}", String::from_utf8_lossy(&output)));
+            let keys = |value: &serde_json::Value| value.as_object().map(|object| object.keys().cloned().collect::<Vec<_>>());
+            assert_eq!(keys(&parsed), keys(&value), "seed {seed}: {input}");
+        }
+    }
+
+    #[test]
+    fn idempotence_property_test() {
+        let pipeline = Pipeline::from_params([("spec", IDEMPOTENT_SPEC), ("format", "text")]).unwrap();
+        for seed in SEEDS {
+            // masks keep the separators around values, glued values can start matching once a neighbour is masked
+            let text = arbitrary_text(&mut Rng(seed), " ");
+            let once = pipeline.apply(text.as_bytes()).unwrap();
+            let twice = pipeline.apply(&once).unwrap();
+            assert_eq!(String::from_utf8_lossy(&twice), String::from_utf8_lossy(&once), "seed {seed}: {text:?}");
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use prost_reflect::prost::Message;
+use prost_reflect::{DescriptorPool, DynamicMessage, MessageDescriptor, SerializeOptions};
+
+use fluvio_smartmodule::{Result, eyre};
+
+/// Message type of protobuf records, which operations rewrite through their JSON mapping
+/// with the field names of the `.proto` file
+#[derive(Debug)]
+pub struct ProtoCodec {
+    message: MessageDescriptor,
+}
+
+impl ProtoCodec {
+    /// `descriptor` is a serialized `FileDescriptorSet`, e.g. from `protoc --include_imports --descriptor_set_out`
+    pub fn new(descriptor: &[u8], message: &str) -> Result<Self> {
+        let pool = DescriptorPool::decode(descriptor)
+            .map_err(|err| eyre!("invalid protobuf descriptor: {err}"))?;
+        let message = pool.get_message_by_name(message)
+            .ok_or_else(|| eyre!("message `{message}` is not in the protobuf descriptor"))?;
+        Ok(ProtoCodec { message })
+    }
+
+    /// JSON text of a protobuf record, fields left at their default value are omitted
+    pub fn decode(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let message = DynamicMessage::decode(self.message.clone(), data)
+            .map_err(|err| eyre!("cannot decode `{}` protobuf record: {err}", self.message.full_name()))?;
+        let options = SerializeOptions::new()
+            .use_proto_field_name(true)
+            .stringify_64_bit_integers(false);
+        let mut serializer = serde_json::Serializer::new(vec![]);
+        message.serialize_with_options(&mut serializer, &options)?;
+        Ok(serializer.into_inner())
+    }
+
+    /// Protobuf record from the JSON text of a decoded one
+    pub fn encode(&self, json: &[u8]) -> Result<Vec<u8>> {
+        let mut deserializer = serde_json::Deserializer::from_slice(json);
+        let message = DynamicMessage::deserialize(self.message.clone(), &mut deserializer)
+            .map_err(|err| eyre!("cannot encode `{}` protobuf record: {err}", self.message.full_name()))?;
+        Ok(message.encode_to_vec())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use prost_reflect::prost_types::{
+        field_descriptor_proto::{Label, Type},
+        DescriptorProto, FieldDescriptorProto, FileDescriptorProto, FileDescriptorSet,
+    };
+
+    use super::*;
+
+    /// `school.Student {string name = 1; string ssn = 2; int64 id = 3; repeated Contact contacts = 4;}`
+    /// with `school.Contact {string email = 1;}`
+    pub fn student_descriptor() -> Vec<u8> {
+        let field = |name: &str, number: i32, kind: Type, label: Label, type_name: Option<&str>| FieldDescriptorProto {
+            name: Some(name.to_string()),
+            number: Some(number),
+            r#type: Some(kind as i32),
+            label: Some(label as i32),
+            type_name: type_name.map(str::to_string),
+            ..Default::default()
+        };
+        let file = FileDescriptorProto {
+            name: Some("school.proto".to_string()),
+            package: Some("school".to_string()),
+            syntax: Some("proto3".to_string()),
+            message_type: vec![
+                DescriptorProto {
+                    name: Some("Student".to_string()),
+                    field: vec![
+                        field("name", 1, Type::String, Label::Optional, None),
+                        field("ssn", 2, Type::String, Label::Optional, None),
+                        field("id", 3, Type::Int64, Label::Optional, None),
+                        field("contacts", 4, Type::Message, Label::Repeated, Some(".school.Contact")),
+                    ],
+                    ..Default::default()
+                },
+                DescriptorProto {
+                    name: Some("Contact".to_string()),
+                    field: vec![field("email", 1, Type::String, Label::Optional, None)],
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        FileDescriptorSet { file: vec![file] }.encode_to_vec()
+    }
+
+    #[test]
+    fn proto_record_test() {
+        let codec = ProtoCodec::new(&student_descriptor(), "school.Student").unwrap();
+        let json = br#"{"name":"Abby","ssn":"123-45-6789","id":42,"contacts":[{"email":"abby@school.edu"}]}"#;
+
+        let record = codec.encode(json).unwrap();
+        assert_eq!(codec.decode(&record).unwrap(), json);
+        assert_eq!(codec.decode(&codec.encode(br#"{"name":"Bob","id":0}"#).unwrap()).unwrap(), br#"{"name":"Bob"}"#);
+
+        assert!(codec.decode(b"\xff\xff").is_err());
+        assert!(codec.encode(br#"{"age":1}"#).is_err());
+        assert!(ProtoCodec::new(&student_descriptor(), "school.Teacher").is_err());
+        assert!(ProtoCodec::new(b"\xff", "school.Student").is_err());
+    }
+}
@@ -0,0 +1,323 @@
+use std::cell::Cell;
+
+use regex::bytes::{Captures, Regex};
+use serde_json::Value;
+
+use eyre::WrapErr;
+
+use fluvio_smartmodule::{Result, eyre};
+
+use crate::HashAlgorithm;
+
+/// Replacement with function calls on capture groups, e.g. `${first|upper} ${last|truncate(1)}.`,
+/// or hashed groups with `${group:algorithm:length}`, short for `${group|hash(algorithm)|truncate(length)}`.
+/// `${__offset}` and `${__timestamp}` insert the offset and timestamp (epoch milliseconds) of the record.
+/// Text outside of function calls keeps the regex crate `$name` expansion.
+#[derive(Debug)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug)]
+enum Part {
+    Literal(Vec<u8>),
+    Call { group: Group, functions: Vec<Function> },
+}
+
+#[derive(Debug)]
+enum Group {
+    Index(usize),
+    Name(String),
+    Record(RecordField),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum RecordField {
+    Offset,
+    Timestamp,
+}
+
+thread_local! {
+    /// Offset and timestamp of the record being processed
+    static RECORD: Cell<(i64, i64)> = const { Cell::new((0, 0)) };
+}
+
+/// Make the offset and timestamp of the record being processed available to templates
+pub fn set_record(offset: i64, timestamp: i64) {
+    RECORD.with(|record| record.set((offset, timestamp)));
+}
+
+/// Offset of the record being processed, as given to `set_record`
+pub fn record_offset() -> i64 {
+    RECORD.with(Cell::get).0
+}
+
+#[derive(Debug)]
+enum Function {
+    Upper,
+    Lower,
+    Trim,
+    Truncate(usize),
+    Hash(HashAlgorithm),
+    Add(f64),
+    Mul(f64),
+    Div(f64),
+    /// Drop the fractional part
+    Int,
+    /// Round to this many decimals
+    Round(i32),
+}
+
+impl Template {
+    /// `None` when the replacement has no function calls and can be expanded by the regex crate as is
+    pub fn parse(raw: &str) -> Result<Option<Self>> {
+        let mut parts = vec![];
+        let mut literal = String::new();
+        let mut rest = raw;
+
+        while let Some(start) = rest.find('$') {
+            literal.push_str(&rest[..start]);
+            rest = &rest[start..];
+            if rest.starts_with("$$") {
+                literal.push_str("$$");
+                rest = &rest[2..];
+                continue;
+            }
+            let call = rest.strip_prefix("${")
+                .and_then(|inner| inner.find('}').map(|end| &inner[..end]))
+                .filter(|inner| inner.contains(['|', ':']) || inner.starts_with("__"));
+            match call {
+                Some(inner) => {
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal).into_bytes()));
+                    }
+                    parts.push(parse_call(inner)?);
+                    rest = &rest[inner.len() + 3..];
+                }
+                None => {
+                    literal.push('$');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        literal.push_str(rest);
+
+        if !parts.iter().any(|part| matches!(part, Part::Call { .. })) {
+            return Ok(None);
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal.into_bytes()));
+        }
+        Ok(Some(Template { parts }))
+    }
+
+    /// Check that every group used in a function call exists in the regex
+    pub fn check(&self, regex: &Regex) -> Result<()> {
+        for part in &self.parts {
+            match part {
+                Part::Call { group: Group::Index(index), .. } if *index >= regex.captures_len() => {
+                    return Err(eyre!("replacement uses group `{index}` but the regex has {} groups", regex.captures_len() - 1));
+                }
+                Part::Call { group: Group::Name(name), .. } if !regex.capture_names().flatten().any(|n| n == name) => {
+                    return Err(eyre!("replacement uses group `{name}` that is not named in the regex"));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    pub fn render(&self, caps: &Captures) -> Vec<u8> {
+        let mut out = vec![];
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => caps.expand(literal, &mut out),
+                Part::Call { group, functions } => {
+                    let matched = match group {
+                        Group::Index(index) => caps.get(*index),
+                        Group::Name(name) => caps.name(name),
+                        Group::Record(field) => {
+                            let (offset, timestamp) = RECORD.with(Cell::get);
+                            let value = match field {
+                                RecordField::Offset => offset,
+                                RecordField::Timestamp => timestamp,
+                            };
+                            let text = functions.iter().fold(value.to_string(), |text, function| function.call(text));
+                            out.extend_from_slice(text.as_bytes());
+                            continue;
+                        }
+                    };
+                    let text = String::from_utf8_lossy(matched.map_or(&b""[..], |m| m.as_bytes())).into_owned();
+                    let text = functions.iter().fold(text, |text, function| function.call(text));
+                    out.extend_from_slice(text.as_bytes());
+                }
+            }
+        }
+        out
+    }
+}
+
+fn parse_call(inner: &str) -> Result<Part> {
+    if let Some((group, hash)) = inner.split_once(':').filter(|_| !inner.contains('|')) {
+        let (algorithm, length) = match hash.split_once(':') {
+            Some((algorithm, length)) => (algorithm, Some(length)),
+            None => (hash, None),
+        };
+        let call = match length {
+            Some(length) => format!("{group}|hash({algorithm})|truncate({length})"),
+            None => format!("{group}|hash({algorithm})"),
+        };
+        return parse_call(&call).wrap_err_with(|| format!("invalid `${{{inner}}}`"));
+    }
+    let mut pieces = inner.split('|').map(str::trim);
+    let group = pieces.next().unwrap_or_default();
+    let group = match group.parse() {
+        Ok(index) => Group::Index(index),
+        Err(_) if group.starts_with("__") => Group::Record(record_field(group)?),
+        Err(_) if !group.is_empty() => Group::Name(group.to_string()),
+        Err(_) => return Err(eyre!("missing group name in `${{{inner}}}`")),
+    };
+    let functions = pieces.map(parse_function).collect::<Result<_>>()?;
+    Ok(Part::Call { group, functions })
+}
+
+fn record_field(name: &str) -> Result<RecordField> {
+    match name {
+        "__offset" => Ok(RecordField::Offset),
+        "__timestamp" => Ok(RecordField::Timestamp),
+        "__partition" => Err(eyre!("`${{__partition}}` is not available: SmartModule records do not carry their partition")),
+        _ => Err(eyre!("unknown record variable `{name}`, expected `__offset` or `__timestamp`")),
+    }
+}
+
+fn parse_function(raw: &str) -> Result<Function> {
+    let (name, arg) = match raw.split_once('(') {
+        Some((name, arg)) => {
+            let arg = arg.strip_suffix(')')
+                .ok_or_else(|| eyre!("missing `)` in template function `{raw}`"))?;
+            (name.trim(), Some(arg.trim()))
+        }
+        None => (raw, None),
+    };
+
+    match (name, arg) {
+        ("upper", None) => Ok(Function::Upper),
+        ("lower", None) => Ok(Function::Lower),
+        ("trim", None) => Ok(Function::Trim),
+        ("truncate", Some(len)) => len.parse().map(Function::Truncate)
+            .map_err(|_| eyre!("`truncate` expects a length, got `{len}`")),
+        ("add", Some(operand)) => number_arg(name, operand).map(Function::Add),
+        ("mul", Some(operand)) => number_arg(name, operand).map(Function::Mul),
+        ("div", Some(operand)) => match number_arg(name, operand)? {
+            0.0 => Err(eyre!("`div` by zero")),
+            operand => Ok(Function::Div(operand)),
+        },
+        ("int", None) => Ok(Function::Int),
+        ("round", None) => Ok(Function::Round(0)),
+        ("round", Some(decimals)) => decimals.parse().map(Function::Round)
+            .map_err(|_| eyre!("`round` expects a number of decimals, got `{decimals}`")),
+        ("hash", None) => Ok(Function::Hash(HashAlgorithm::default())),
+        ("hash", Some(algorithm)) => serde_json::from_value(Value::String(algorithm.to_string()))
+            .map(Function::Hash)
+            .map_err(|err| eyre!("invalid `hash` algorithm: {err}")),
+        _ => Err(eyre!("unknown template function `{raw}`, expected `upper`, `lower`, `trim`, `truncate(N)`, `hash`, `add(N)`, `mul(N)`, `div(N)`, `int` or `round(N)`")),
+    }
+}
+
+fn number_arg(name: &str, operand: &str) -> Result<f64> {
+    operand.parse::<f64>().ok().filter(|operand| operand.is_finite())
+        .ok_or_else(|| eyre!("`{name}` expects a number, got `{operand}`"))
+}
+
+/// Shortest decimal form, rounded to 10 decimals so `12.34|mul(100)` gives `1234` rather than float noise
+fn format_number(number: f64) -> String {
+    let text = format!("{number:.10}");
+    let text = text.trim_end_matches('0').trim_end_matches('.');
+    match text {
+        "-0" => "0".to_string(),
+        text => text.to_string(),
+    }
+}
+
+impl Function {
+    fn call(&self, text: String) -> String {
+        match self {
+            Function::Upper => text.to_uppercase(),
+            Function::Lower => text.to_lowercase(),
+            Function::Trim => text.trim().to_string(),
+            Function::Truncate(len) => text.chars().take(*len).collect(),
+            Function::Hash(algorithm) => algorithm.hex_digest(b"", text.as_bytes()),
+            // text that is not a number is left as is
+            _ => match text.trim().parse::<f64>() {
+                Ok(number) if number.is_finite() => format_number(self.compute(number)),
+                _ => text,
+            },
+        }
+    }
+
+    fn compute(&self, number: f64) -> f64 {
+        match self {
+            Function::Add(operand) => number + operand,
+            Function::Mul(operand) => number * operand,
+            Function::Div(operand) => number / operand,
+            Function::Int => number.trunc(),
+            Function::Round(decimals) => {
+                let scale = 10f64.powi(*decimals);
+                (number * scale).round() / scale
+            }
+            _ => number,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(regex: &str, with: &str, text: &str) -> String {
+        let regex = Regex::new(regex).unwrap();
+        let template = Template::parse(with).unwrap().unwrap();
+        template.check(&regex).unwrap();
+        String::from_utf8(template.render(&regex.captures(text.as_bytes()).unwrap())).unwrap()
+    }
+
+    #[test]
+    fn template_test() {
+        let name = r"(?P<first>\w+) (?P<last>\w+)";
+        assert_eq!(render(name, "${first|upper} ${last|truncate(1)}.", "abby hardy"), "ABBY h.");
+        assert_eq!(render(name, "${last | lower | truncate(3)}, $first $$5", "Abby HARDY"), "har, Abby $5");
+        assert_eq!(render(r"(\w+)@", "${1|hash}@", "abby@"), format!("{}@", HashAlgorithm::Sha256.hex_digest(b"", b"abby")));
+        assert_eq!(render(r"(\w+)@", "${1|hash(sha1)|truncate(8)}", "abby@").len(), 8);
+        assert_eq!(render(r"=(.*)", "=${1|trim}", "=  x "), "=x");
+        let amount = r"(?P<amount>-?[\d.]+) USD";
+        assert_eq!(render(amount, "${amount|mul(100)|int} cents", "12.34 USD"), "1234 cents");
+        assert_eq!(render(amount, "${amount|div(3)|round(2)}", "10 USD"), "3.33");
+        assert_eq!(render(amount, "${amount|add(-0.5)}", "-1.25 USD"), "-1.75");
+        assert_eq!(render(amount, "${amount|round}", "2.5 USD"), "3");
+        assert_eq!(render(amount, "${amount|mul(-1)|int}", "0.5 USD"), "0");
+        assert_eq!(render(r"year (\d+)", "${1|add(-1900)}", "year 2025"), "125");
+        assert_eq!(render(amount, "${amount|mul(2)}", "1.2.3 USD"), "1.2.3");
+        let sha = HashAlgorithm::Sha256.hex_digest(b"", b"abby");
+        assert_eq!(render(r"(?P<user>\w+)@(\w+\.edu)", "${user:sha256:8}@$2", "abby@school.edu"), format!("{}@school.edu", &sha[..8]));
+        assert_eq!(render(r"(\w+)@", "u-${1:sha256}-x", "abby@"), format!("u-{sha}-x"));
+
+        assert!(Template::parse("${first} $last $$").unwrap().is_none());
+        assert!(Template::parse("${first|reverse}").is_err());
+        assert!(Template::parse("${first|truncate(x)}").is_err());
+        assert!(Template::parse("${|upper}").is_err());
+        assert!(Template::parse("${1|hash(md5)}").is_err());
+        assert!(Template::parse("${1:md5:8}").is_err());
+        assert!(Template::parse("${1:sha256:x}").is_err());
+        assert!(Template::parse("${1|mul}").is_err());
+        assert!(Template::parse("${1|add(x)}").is_err());
+        assert!(Template::parse("${1|div(0)}").is_err());
+        assert!(Template::parse("${1|round(1.5)}").is_err());
+        assert!(Template::parse("${:sha256}").is_err());
+        assert!(Template::parse("${__offset}").unwrap().is_some());
+        assert!(Template::parse("${__partition}").is_err());
+
+        let regex = Regex::new(name).unwrap();
+        assert!(Template::parse("${middle|upper}").unwrap().unwrap().check(&regex).is_err());
+        assert!(Template::parse("${3|upper}").unwrap().unwrap().check(&regex).is_err());
+    }
+}
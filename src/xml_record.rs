@@ -0,0 +1,199 @@
+use std::borrow::Cow;
+use std::str::FromStr;
+
+use quick_xml::events::attributes::Attribute;
+use quick_xml::events::{BytesCData, BytesStart, BytesText, Event};
+use quick_xml::name::QName;
+use quick_xml::{Reader, Writer};
+
+use fluvio_smartmodule::{Result, eyre};
+
+/// Events of an XML record, so operations can target the text of the elements or attributes
+/// selected by an [`XmlPath`]. Events no operation changed are written back as they were read.
+#[derive(Debug, Clone)]
+pub struct XmlRecord {
+    events: Vec<Event<'static>>,
+}
+
+/// Simple path to XML elements such as `/order/customer/ssn`: `*` matches any element name,
+/// a leading `//` matches at any depth and a final `@name` selects an attribute
+#[derive(Debug, Clone, PartialEq)]
+pub struct XmlPath {
+    anywhere: bool,
+    elements: Vec<String>,
+    attribute: Option<String>,
+}
+
+impl XmlRecord {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let mut reader = Reader::from_reader(data);
+        let mut events = vec![];
+        loop {
+            match reader.read_event().map_err(|err| eyre!("xml path operations require an XML record: {err}"))? {
+                Event::Eof => break,
+                event => events.push(event.into_owned()),
+            }
+        }
+        Ok(XmlRecord { events })
+    }
+
+    /// Call `f` on the unescaped text of every element or attribute selected by `path`
+    pub fn for_each_text_mut(&mut self, path: &XmlPath, f: &mut dyn FnMut(&mut Vec<u8>)) -> Result<()> {
+        let mut stack: Vec<Vec<u8>> = vec![];
+        for event in &mut self.events {
+            match event {
+                Event::Start(start) => {
+                    stack.push(start.name().as_ref().to_vec());
+                    if let (Some(attribute), true) = (&path.attribute, path.matches(&stack)) {
+                        rewrite_attribute(start, attribute, f)?;
+                    }
+                }
+                Event::Empty(start) => {
+                    stack.push(start.name().as_ref().to_vec());
+                    if let (Some(attribute), true) = (&path.attribute, path.matches(&stack)) {
+                        rewrite_attribute(start, attribute, f)?;
+                    }
+                    stack.pop();
+                }
+                Event::End(_) => {
+                    stack.pop();
+                }
+                Event::Text(text) if path.attribute.is_none() && path.matches(&stack) => {
+                    let original = text.unescape()?.into_owned();
+                    if let Some(changed) = call(&original, f) {
+                        *text = BytesText::new(&changed).into_owned();
+                    }
+                }
+                Event::CData(cdata) if path.attribute.is_none() && path.matches(&stack) => {
+                    let original = String::from_utf8_lossy(cdata).into_owned();
+                    if let Some(changed) = call(&original, f) {
+                        *cdata = BytesCData::new(changed);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Unescaped text of every element or attribute selected by `path`
+    pub fn texts(&self, path: &XmlPath) -> Result<Vec<Vec<u8>>> {
+        let mut texts = vec![];
+        self.clone().for_each_text_mut(path, &mut |text| texts.push(text.clone()))?;
+        Ok(texts)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = Writer::new(vec![]);
+        for event in &self.events {
+            // writing to a Vec cannot fail
+            let _ = writer.write_event(event.borrow());
+        }
+        writer.into_inner()
+    }
+}
+
+/// `f` applied to `text`, `None` when it left the text unchanged
+fn call(text: &str, f: &mut dyn FnMut(&mut Vec<u8>)) -> Option<String> {
+    let mut bytes = text.as_bytes().to_vec();
+    f(&mut bytes);
+    (bytes != text.as_bytes()).then(|| String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Rebuild the attributes of `start` when `f` changed the value of `name`
+fn rewrite_attribute(start: &mut BytesStart<'static>, name: &str, f: &mut dyn FnMut(&mut Vec<u8>)) -> Result<()> {
+    let mut changed = None;
+    for attribute in start.attributes() {
+        let attribute = attribute?;
+        if attribute.key.as_ref() == name.as_bytes() {
+            changed = call(&attribute.unescape_value()?, f);
+        }
+    }
+    let Some(changed) = changed else {
+        return Ok(());
+    };
+
+    let attributes: Vec<(Vec<u8>, Vec<u8>)> = start.attributes()
+        .map(|attribute| attribute.map(|attribute| (attribute.key.as_ref().to_vec(), attribute.value.into_owned())))
+        .collect::<std::result::Result<_, _>>()?;
+    start.clear_attributes();
+    for (key, value) in &attributes {
+        if key == name.as_bytes() {
+            start.push_attribute((name, changed.as_str()));
+        } else {
+            start.push_attribute(Attribute { key: QName(key), value: Cow::Borrowed(value) });
+        }
+    }
+    Ok(())
+}
+
+impl XmlPath {
+    fn matches(&self, stack: &[Vec<u8>]) -> bool {
+        let tail = match stack.len().checked_sub(self.elements.len()) {
+            Some(0) => stack,
+            Some(start) if self.anywhere => &stack[start..],
+            _ => return false,
+        };
+        self.elements.iter().zip(tail).all(|(name, element)| name == "*" || name.as_bytes() == element.as_slice())
+    }
+}
+
+impl FromStr for XmlPath {
+    type Err = eyre::Error;
+
+    fn from_str(raw: &str) -> Result<Self> {
+        let rest = raw.strip_prefix('/')
+            .ok_or_else(|| eyre!("xml path `{raw}` must start with `/`"))?;
+        let (anywhere, rest) = match rest.strip_prefix('/') {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+        let mut elements: Vec<String> = rest.split('/').map(str::to_string).collect();
+        let attribute = match elements.last() {
+            Some(last) if last.starts_with('@') => elements.pop().map(|last| last[1..].to_string()),
+            _ => None,
+        };
+        if elements.iter().any(String::is_empty) || attribute.as_deref() == Some("") {
+            return Err(eyre!("xml path `{raw}` has an empty name"));
+        }
+        Ok(XmlPath { anywhere, elements, attribute })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrite(xml: &str, path: &str) -> String {
+        let mut record = XmlRecord::parse(xml.as_bytes()).unwrap();
+        record.for_each_text_mut(&path.parse().unwrap(), &mut |text| {
+            text.iter_mut().filter(|c| c.is_ascii_digit()).for_each(|c| *c = b'*');
+        }).unwrap();
+        String::from_utf8(record.to_bytes()).unwrap()
+    }
+
+    #[test]
+    fn xml_record_test() {
+        let xml = r#"<?xml version="1.0"?><order id="7"><customer ssn='123' kind="a &amp; b"><ssn>123-45 &lt;x&gt;</ssn><![CDATA[42]]></customer><ssn>9</ssn></order>"#;
+
+        assert_eq!(
+            rewrite(xml, "/order/customer/ssn"),
+            r#"<?xml version="1.0"?><order id="7"><customer ssn='123' kind="a &amp; b"><ssn>***-** &lt;x&gt;</ssn><![CDATA[42]]></customer><ssn>9</ssn></order>"#
+        );
+        assert_eq!(
+            rewrite(xml, "//ssn"),
+            r#"<?xml version="1.0"?><order id="7"><customer ssn='123' kind="a &amp; b"><ssn>***-** &lt;x&gt;</ssn><![CDATA[42]]></customer><ssn>*</ssn></order>"#
+        );
+        assert_eq!(
+            rewrite(xml, "/order/*/@ssn"),
+            r#"<?xml version="1.0"?><order id="7"><customer ssn="***" kind="a &amp; b"><ssn>123-45 &lt;x&gt;</ssn><![CDATA[42]]></customer><ssn>9</ssn></order>"#
+        );
+        assert!(rewrite(xml, "/order/customer").contains("<![CDATA[**]]>"));
+        assert_eq!(rewrite(xml, "/customer/ssn"), xml);
+
+        assert!("order/ssn".parse::<XmlPath>().is_err());
+        assert!("/order//ssn".parse::<XmlPath>().is_err());
+        assert!("/order/@".parse::<XmlPath>().is_err());
+        assert!(XmlRecord::parse(b"<a><b></a>").is_err());
+    }
+}